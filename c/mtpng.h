@@ -59,7 +59,11 @@ typedef enum mtpng_result_t {
 // MTPNG_FILTER_ADAPTIVE is the default behavior, which uses
 // a heuristic to try to guess the best compressing filter.
 //
+// MTPNG_FILTER_BRUTE_FORCE compresses each row with every filter
+// and keeps the smallest; much slower, for when size matters most.
+//
 typedef enum mtpng_filter_t {
+    MTPNG_FILTER_BRUTE_FORCE = -2,
     MTPNG_FILTER_ADAPTIVE = -1,
     MTPNG_FILTER_NONE = 0,
     MTPNG_FILTER_SUB = 1,
@@ -68,12 +72,30 @@ typedef enum mtpng_filter_t {
     MTPNG_FILTER_PAETH = 4
 } mtpng_filter;
 
+//
+// Heuristics for choosing each row's filter in adaptive filter
+// mode, for mtpng_encoder_options_set_filter_heuristic().
+//
+// MTPNG_HEURISTIC_MINSUM is the default, the same as libpng's.
+// The others also consider the none filter.
+//
+typedef enum mtpng_filter_heuristic_t {
+    MTPNG_HEURISTIC_MINSUM = 0,
+    MTPNG_HEURISTIC_MINSUM_NONE = 1,
+    MTPNG_HEURISTIC_ENTROPY = 2,
+    MTPNG_HEURISTIC_BIGRAMS = 3
+} mtpng_filter_heuristic;
+
 //
 // Strategy types for mtpng_encoder_set_strategy_mode().
 //
 // MTPNG_STRATEGY_ADAPTIVE is the default behavior.
 //
+// MTPNG_STRATEGY_BRUTE_FORCE compresses each chunk with several
+// strategies in parallel and keeps the smallest.
+//
 typedef enum mtpng_strategy_t {
+    MTPNG_STRATEGY_BRUTE_FORCE = -2,
     MTPNG_STRATEGY_ADAPTIVE = -1,
     MTPNG_STRATEGY_DEFAULT = 0,
     MTPNG_STRATEGY_FILTERED = 1,
@@ -88,7 +110,8 @@ typedef enum mtpng_strategy_t {
 typedef enum mtpng_compression_level_t {
     MTPNG_COMPRESSION_LEVEL_FAST = 1,
     MTPNG_COMPRESSION_LEVEL_DEFAULT = 6,
-    MTPNG_COMPRESSION_LEVEL_HIGH = 9
+    MTPNG_COMPRESSION_LEVEL_HIGH = 9,
+    MTPNG_COMPRESSION_LEVEL_EXTREME = 10
 } mtpng_compression_level;
 
 //
@@ -102,6 +125,69 @@ typedef enum mtpng_color_t {
     MTPNG_COLOR_TRUECOLOR_ALPHA = 6
 } mtpng_color;
 
+//
+// Interlace methods for mtpng_header_set_interlace().
+//
+typedef enum mtpng_interlace_t {
+    MTPNG_INTERLACE_NONE = 0,
+    MTPNG_INTERLACE_ADAM7 = 1
+} mtpng_interlace;
+
+//
+// Input pixel layouts for mtpng_input_format_set_layout().
+//
+typedef enum mtpng_pixel_layout_t {
+    MTPNG_PIXEL_LAYOUT_STANDARD = 0,
+    MTPNG_PIXEL_LAYOUT_BGR = 1,
+    MTPNG_PIXEL_LAYOUT_BGRA = 2,
+    MTPNG_PIXEL_LAYOUT_ARGB = 3,
+    MTPNG_PIXEL_LAYOUT_ABGR = 4,
+    MTPNG_PIXEL_LAYOUT_PLANAR = 5
+} mtpng_pixel_layout;
+
+//
+// Transfer functions for mtpng_input_format_set_transfer().
+//
+typedef enum mtpng_transfer_t {
+    MTPNG_TRANSFER_CLAMP = 0,
+    MTPNG_TRANSFER_LINEAR_TO_SRGB = 1
+} mtpng_transfer;
+
+//
+// Units for mtpng_encoder_write_physical_dimensions().
+//
+typedef enum mtpng_physical_unit_t {
+    MTPNG_PHYSICAL_UNIT_UNKNOWN = 0,
+    MTPNG_PHYSICAL_UNIT_METER = 1
+} mtpng_physical_unit;
+
+//
+// Rendering intents for mtpng_encoder_write_srgb().
+//
+typedef enum mtpng_rendering_intent_t {
+    MTPNG_RENDERING_INTENT_PERCEPTUAL = 0,
+    MTPNG_RENDERING_INTENT_RELATIVE_COLORIMETRIC = 1,
+    MTPNG_RENDERING_INTENT_SATURATION = 2,
+    MTPNG_RENDERING_INTENT_ABSOLUTE_COLORIMETRIC = 3
+} mtpng_rendering_intent;
+
+//
+// Frame disposal for mtpng_encoder_write_frame_control().
+//
+typedef enum mtpng_dispose_t {
+    MTPNG_DISPOSE_NONE = 0,
+    MTPNG_DISPOSE_BACKGROUND = 1,
+    MTPNG_DISPOSE_PREVIOUS = 2
+} mtpng_dispose;
+
+//
+// Frame blending for mtpng_encoder_write_frame_control().
+//
+typedef enum mtpng_blend_t {
+    MTPNG_BLEND_SOURCE = 0,
+    MTPNG_BLEND_OVER = 1
+} mtpng_blend;
+
 #pragma mark Structs
 
 //
@@ -127,6 +213,29 @@ typedef struct mtpng_encoder_options_struct mtpng_encoder_options;
 //
 typedef struct mtpng_header_struct mtpng_header;
 
+//
+// Represents the layout of input rows given to the encoder,
+// when they are not already packed in the PNG format.
+//
+// The contents are private; you will only ever use pointers.
+//
+typedef struct mtpng_input_format_struct mtpng_input_format;
+
+//
+// Represents an indexed-color palette of RGBA entries.
+//
+// The contents are private; you will only ever use pointers.
+//
+typedef struct mtpng_palette_struct mtpng_palette;
+
+//
+// Represents an image losslessly reduced to its smallest color
+// type and bit depth, from mtpng_reduce().
+//
+// The contents are private; you will only ever use pointers.
+//
+typedef struct mtpng_reduction_struct mtpng_reduction;
+
 //
 // Represents a PNG encoder instance, which can encode a single
 // image and then must be released. Multiple encoders may share
@@ -136,9 +245,24 @@ typedef struct mtpng_header_struct mtpng_header;
 //
 typedef struct mtpng_encoder_struct mtpng_encoder;
 
+//
+// Represents configuration options for the PNG decoder.
+//
+// The contents are private; you will only ever use pointers.
+//
+typedef struct mtpng_decoder_options_struct mtpng_decoder_options;
+
+//
+// Represents a PNG decoder instance, which can decode a single
+// image and then must be released. Multiple decoders and encoders
+// may share a single thread pool.
+//
+// The contents are private; you will only ever use pointers.
+//
+typedef struct mtpng_decoder_struct mtpng_decoder;
+
 #pragma mark Function types
 
-#if 0
 //
 // Read callback type for mtpng_decoder_new().
 //
@@ -152,7 +276,6 @@ typedef struct mtpng_encoder_struct mtpng_encoder;
 typedef size_t (*mtpng_read_func)(void* user_data,
                                   uint8_t* p_bytes,
                                   size_t len);
-#endif
 
 //
 // Write callback type for mtpng_encoder_new().
@@ -282,6 +405,18 @@ extern mtpng_result
 mtpng_encoder_options_set_filter(mtpng_encoder_options* p_options,
                                  mtpng_filter filter_mode);
 
+//
+// Override the heuristic used to pick each row's filter in
+// MTPNG_FILTER_ADAPTIVE mode. Entropy-based selection often
+// does better on line art and screenshots, at some cost in
+// speed.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_options_set_filter_heuristic(mtpng_encoder_options* p_options,
+                                           mtpng_filter_heuristic heuristic);
+
 //
 // Override the default PNG strategy mode selection.
 //
@@ -323,6 +458,20 @@ extern mtpng_result
 mtpng_encoder_options_set_chunk_size(mtpng_encoder_options* p_options,
                                      size_t chunk_size);
 
+//
+// Enable or disable alpha cleaning, which rewrites the color of
+// fully transparent pixels to whatever the filter predicts, so
+// they compress to almost nothing. The visible image is unchanged,
+// but color hidden under transparent pixels is not kept.
+//
+// Has no effect on images without an alpha channel.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_options_set_alpha_cleaning(mtpng_encoder_options* p_options,
+                                         bool alpha_cleaning);
+
 #pragma mark Header
 
 //
@@ -384,154 +533,1125 @@ mtpng_header_set_color(mtpng_header* p_header,
                        mtpng_color color_type,
                        uint8_t depth);
 
-#pragma mark Encoder
+//
+// Set the interlace method for the image.
+//
+// MTPNG_INTERLACE_ADAM7 allows progressive display while loading,
+// at some cost in file size. The encoder must buffer the entire
+// image in memory before compression can begin.
+//
+// If you do not call this function, the image will not be
+// interlaced.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_header_set_interlace(mtpng_header* p_header,
+                           mtpng_interlace interlace_method);
 
 //
-// Create a new PNG encoder instance.
-// Copies the options data.
+// Get the image size in pixels.
 //
-// On input, *pp_encoder must be NULL.
-// On output, *pp_encoder will be an instance pointer on success,
-// or remain unchanged in case of failure.
+// Check the return value for errors.
 //
-// The write_func and flush_func callbacks are required, and must
-// not be NULL.
+extern mtpng_result
+mtpng_header_get_size(mtpng_header* p_header,
+                      uint32_t* p_width,
+                      uint32_t* p_height);
+
 //
-// user_data is passed to the callback functions, and may be any
-// value such as a private object pointer or NULL.
+// Get the color type and depth for the image.
 //
-// p_options may be NULL, in which case default options will
-// be used including a global threadpool.
+// Check the return value for errors.
 //
-// Check the return values for errors.
+extern mtpng_result
+mtpng_header_get_color(mtpng_header* p_header,
+                       mtpng_color* p_color_type,
+                       uint8_t* p_depth);
+
+//
+// Get the interlace method for the image.
+//
+// Check the return value for errors.
 //
 extern mtpng_result
-mtpng_encoder_new(mtpng_encoder** pp_encoder,
-                  mtpng_write_func write_func,
-                  mtpng_flush_func flush_func,
-                  void* const user_data,
-                  mtpng_encoder_options* p_options);
+mtpng_header_get_interlace(mtpng_header* p_header,
+                           mtpng_interlace* p_interlace_method);
 
 //
-// Releases the encoder's memory and clears the pointer.
+// Get the length in bytes of a packed row of image data,
+// as passed to mtpng_encoder_write_image_rows() or returned
+// from mtpng_decoder_read_image_rows().
 //
-// This need only be used if aborting encoding early due to
-// errors etc; normally the call to mtpng_encoder_finish()
-// at the end of encoding will consume the instance and
-// release its memory.
+// Check the return value for errors.
 //
-// On input, *pp_encoder must be a valid instance pointer.
-// On output, *pp_encoder will be NULL on success, or remain
-// unchanged in case of failure.
+extern mtpng_result
+mtpng_header_get_stride(mtpng_header* p_header,
+                        size_t* p_stride);
+
+#pragma mark Input format
+
 //
-// If using a threadpool, must be called before releasing the
-// threadpool!
+// Creates a new input format with default settings, expecting rows
+// packed in the PNG format. Pass in to mtpng_encoder_set_input_format().
+// May be reused on multiple encoders.
 //
-// If the encoder is still in use, this may explode.
+// Free with mtpng_input_format_release().
+//
+// On input, *pp_format must be NULL.
+// On output, *pp_format will be a pointer to an input format instance
+// if successful, or remain unchanged in case of error.
 //
 // Check the return value for errors.
 //
 extern mtpng_result
-mtpng_encoder_release(mtpng_encoder** pp_encoder);
+mtpng_input_format_new(mtpng_input_format** pp_format);
 
 //
-// Signal that we're done setting up, and start writing
-// header data to the output.
+// Releases the input format's memory and clears the pointer.
 //
-// Must be called before mtpng_encoder_append_row() or
-// mtpng_encoder_finish().
+// On input, *pp_format must be a valid instance pointer.
+// On output, *pp_format will be NULL on success or remain unchanged
+// in case of failure.
 //
 // Check the return value for errors.
 //
 extern mtpng_result
-mtpng_encoder_write_header(mtpng_encoder* p_encoder,
-                           mtpng_header* p_header);
+mtpng_input_format_release(mtpng_input_format** pp_format);
 
 //
-// Write a palette entry for an indexed-color image, or a
-// suggested quantization palette for a truecolor image.
+// Set whether 1, 2, and 4-bit samples are provided one per byte,
+// to be bit-packed by the encoder. Values that don't fit in the
+// image's depth cause an error when the rows are encoded.
 //
-// See https://www.w3.org/TR/PNG/#11PLTE for the data format.
+// Has no effect at depths of 8 and above.
 //
-// Must be called after mtpng_encoder_write_header() and before
-// mtpng_encoder_write_image() or mtpng_encoder_write_image_data().
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_input_format_set_unpacked(mtpng_input_format* p_format,
+                                bool unpacked);
+
+//
+// Set the channel layout of input pixels, to be reordered into
+// PNG channel order by the encoder. Interleaved layouts must have
+// the same channels as the image's color type.
+//
+// Planar input must be written with mtpng_encoder_write_image_planes().
 //
 // Check the return value for errors.
 //
 extern mtpng_result
-mtpng_encoder_write_palette(mtpng_encoder* p_encoder,
-                            const uint8_t* p_bytes,
-                            size_t len);
+mtpng_input_format_set_layout(mtpng_input_format* p_format,
+                              mtpng_pixel_layout layout);
 
 //
-// Write alpha transparency entries for an indexed-color image, or a
-// single transparent color for a greyscale or truecolor image.
+// Set the transfer function used to quantise floating-point input
+// from mtpng_encoder_write_image_rows_f32(). MTPNG_TRANSFER_CLAMP
+// clamps to 0.0-1.0 and scales; MTPNG_TRANSFER_LINEAR_TO_SRGB also
+// applies the sRGB curve to color samples, leaving alpha linear.
 //
-// See https://www.w3.org/TR/PNG/#11tRNS for the data format.
+// Check the return value for errors.
 //
-// Must be called after mtpng_encoder_write_palette() for indexed
-// images, or mtpng_encoder_write_header() for others; and before
-// mtpng_encoder_write_image() or mtpng_encoder_write_image_data().
+extern mtpng_result
+mtpng_input_format_set_transfer(mtpng_input_format* p_format,
+                                mtpng_transfer transfer);
+
+//
+// Set whether input color samples are premultiplied by alpha, as
+// produced by many compositors. The encoder divides them back out
+// to the straight alpha PNG requires, rounding to nearest. Color
+// is set to 0 where alpha is 0.
+//
+// Requires an image with an alpha channel.
 //
 // Check the return value for errors.
 //
 extern mtpng_result
-mtpng_encoder_write_transparency(mtpng_encoder* p_encoder,
-                                 const uint8_t* p_bytes,
-                                 size_t len);
+mtpng_input_format_set_premultiplied(mtpng_input_format* p_format,
+                                     bool premultiplied);
 
 //
-// Write a custom ancillary chunk to the output stream.
-// The tag must be a 4-byte string. The data should be provided
-// in the appropriate format for the tag.
+// Set the distance in bytes between the starts of input rows,
+// for buffers with padding at the end of rows. 0 means rows are
+// tightly packed. For planar input, this is the pitch within
+// each plane.
+//
+// The pitch must cover the row's pixel data, including any crop
+// offset. The padding may be left off the last row of a buffer.
 //
 // Check the return value for errors.
 //
 extern mtpng_result
-mtpng_encoder_write_chunk(mtpng_encoder* p_encoder,
-                          const char* p_tag,
-                          const uint8_t* p_bytes,
-                          size_t len);
+mtpng_input_format_set_pitch(mtpng_input_format* p_format,
+                             size_t pitch);
 
 //
-// Load one or more rows of input data into the encoder, to be
-// filtered and compressed as data is provided.
+// Set whether input rows are stored bottom-up, with the last row
+// of each buffer being the topmost.
 //
-// Must be called after mtpng_encoder_write_header() and before
-// mtpng_encoder_finish().
+// Check the return value for errors.
 //
-// Image data must be pre-packed in the correct bit depth and
-// channel order. If not all rows are provided before calling
-// mtpng_encoder_finish(), failure will result.
+extern mtpng_result
+mtpng_input_format_set_bottom_up(mtpng_input_format* p_format,
+                                 bool bottom_up);
+
+//
+// Set the pixel offset of the image's top-left corner within each
+// buffer passed to mtpng_encoder_write_image_rows(), to encode a
+// region of a larger surface. Rows above the region and below it
+// are skipped, so the whole surface may be passed in at once.
+//
+// For packed sub-byte input, the x offset must fall on a byte
+// boundary.
 //
 // Check the return value for errors.
 //
 extern mtpng_result
-mtpng_encoder_write_image_rows(mtpng_encoder* p_encoder,
-                               const uint8_t* p_bytes,
-                               size_t len);
+mtpng_input_format_set_crop_offset(mtpng_input_format* p_format,
+                                   uint32_t x,
+                                   uint32_t y);
 
 //
-// Wait for any outstanding work blocks, flush output,
-// release the encoder instance and clear the pointer.
+// Get the length in bytes of an input row's pixel data for the
+// given header, not counting any padding.
 //
-// Must be called after all rows have been appended with
-// mtpng_encoder_append_row().
+// Check the return value for errors.
 //
-// On input, *pp_encoder must be a valid instance pointer.
-// On output, *pp_encoder will be NULL on success, or remain
-// unchanged in case of failure.
+extern mtpng_result
+mtpng_input_format_get_stride(mtpng_input_format* p_format,
+                              mtpng_header* p_header,
+                              size_t* p_stride);
+
+#pragma mark Palette
+
 //
-// You do not need to call mtpng_encoder_release after
-// this returns, and should not try.
+// Creates a new, empty palette. Pass in to
+// mtpng_encoder_write_rgba_palette().
 //
-// If using a threadpool, must be called before releasing
-// the threadpool!
+// Free with mtpng_palette_release().
+//
+// On input, *pp_palette must be NULL.
+// On output, *pp_palette will be a pointer to a palette instance
+// if successful, or remain unchanged in case of error.
 //
 // Check the return value for errors.
 //
 extern mtpng_result
-mtpng_encoder_finish(mtpng_encoder** pp_encoder);
+mtpng_palette_new(mtpng_palette** pp_palette);
+
+//
+// Releases the palette's memory and clears the pointer.
+//
+// On input, *pp_palette must be a valid instance pointer.
+// On output, *pp_palette will be NULL on success or remain unchanged
+// in case of failure.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_palette_release(mtpng_palette** pp_palette);
+
+//
+// Add an RGBA entry to the end of the palette. If p_index is
+// not NULL, the new entry's index is stored there.
+//
+// Returns an error if the palette already has 256 entries.
+//
+extern mtpng_result
+mtpng_palette_push(mtpng_palette* p_palette,
+                   uint8_t red,
+                   uint8_t green,
+                   uint8_t blue,
+                   uint8_t alpha,
+                   uint8_t* p_index);
+
+//
+// Get the number of entries in the palette.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_palette_get_length(mtpng_palette* p_palette,
+                         size_t* p_length);
+
+//
+// Remove repeated entries, keeping the first of each.
+//
+// If p_map is not NULL, it must have room for len bytes, at least
+// the palette's length before the call. The new index of each old
+// entry is stored there, for rewriting image data.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_palette_dedup(mtpng_palette* p_palette,
+                    uint8_t* p_map,
+                    size_t len);
+
+//
+// Move entries that aren't fully opaque to the front, most
+// transparent first, so the tRNS chunk needs fewer bytes.
+//
+// If p_map is not NULL, it must have room for len bytes, at least
+// the palette's length. The new index of each old entry is stored
+// there, for rewriting image data.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_palette_sort_by_alpha(mtpng_palette* p_palette,
+                            uint8_t* p_map,
+                            size_t len);
+
+//
+// Reorder the palette of an indexed-color image so that its data
+// compresses better, and rewrite the indices in p_data to match.
+// The pixel colors don't change.
+//
+// Orderings by luminance, popularity and adjacency are each
+// trial-compressed with the filter mode the image will be encoded
+// with, and the smallest is kept. Without filtering, 8-bit indices
+// compress the same in any order.
+//
+// p_data holds the packed rows of the image described by p_header.
+// If p_pool is NULL, the global thread pool is used.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_palette_optimize(mtpng_palette* p_palette,
+                       mtpng_header* p_header,
+                       mtpng_filter filter_mode,
+                       mtpng_threadpool* p_pool,
+                       uint8_t* p_data,
+                       size_t len);
+
+#pragma mark Reduction
+
+//
+// Find the smallest lossless color type and bit depth for an image,
+// and repack its rows to match, ready to hand to the encoder.
+//
+// Takes the header, PLTE and tRNS data the image would otherwise be
+// written with, and its packed rows. p_palette and p_transparency
+// may be NULL if the image has no palette or transparency.
+// If p_pool is NULL, the global thread pool is used.
+//
+// Palette and greyscale candidates are compared by the size of their
+// uncompressed data plus PLTE and tRNS, not by trial compression.
+//
+// Free with mtpng_reduction_release().
+//
+// On input, *pp_reduction must be NULL.
+// On output, *pp_reduction will be a pointer to a reduction instance
+// if successful, or remain unchanged in case of error.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_reduce(mtpng_header* p_header,
+             const uint8_t* p_palette,
+             size_t palette_len,
+             const uint8_t* p_transparency,
+             size_t transparency_len,
+             mtpng_threadpool* p_pool,
+             const uint8_t* p_data,
+             size_t len,
+             mtpng_reduction** pp_reduction);
+
+//
+// Releases the reduction's memory and clears the pointer.
+//
+// On input, *pp_reduction must be a valid instance pointer.
+// On output, *pp_reduction will be NULL on success or remain unchanged
+// in case of failure.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_reduction_release(mtpng_reduction** pp_reduction);
+
+//
+// Copy the reduced image's header into the given header
+// instance from mtpng_header_new().
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_reduction_get_header(mtpng_reduction* p_reduction,
+                           mtpng_header* p_header);
+
+//
+// Copy out the reduced image's palette, in the same format as
+// mtpng_encoder_write_palette() takes.
+//
+// len is the size of the buffer at p_bytes; 768 bytes is always
+// enough. On success *p_len is set to the palette's length in
+// bytes, or 0 if there is no palette.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_reduction_get_palette(mtpng_reduction* p_reduction,
+                            uint8_t* p_bytes,
+                            size_t len,
+                            size_t* p_len);
+
+//
+// Copy out the reduced image's transparency data, in the same
+// format as mtpng_encoder_write_transparency() takes.
+//
+// len is the size of the buffer at p_bytes; 256 bytes is always
+// enough. On success *p_len is set to the data's length in bytes,
+// or 0 if there is no transparency chunk.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_reduction_get_transparency(mtpng_reduction* p_reduction,
+                                 uint8_t* p_bytes,
+                                 size_t len,
+                                 size_t* p_len);
+
+//
+// Get the reduced image's packed rows, in the format
+// mtpng_encoder_write_image_rows() takes.
+//
+// On success *pp_bytes points to the data, which remains valid
+// until the reduction is released, and *p_len is set to its length.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_reduction_get_data(mtpng_reduction* p_reduction,
+                         const uint8_t** pp_bytes,
+                         size_t* p_len);
+
+#pragma mark Encoder
+
+//
+// Create a new PNG encoder instance.
+// Copies the options data.
+//
+// On input, *pp_encoder must be NULL.
+// On output, *pp_encoder will be an instance pointer on success,
+// or remain unchanged in case of failure.
+//
+// The write_func and flush_func callbacks are required, and must
+// not be NULL.
+//
+// user_data is passed to the callback functions, and may be any
+// value such as a private object pointer or NULL.
+//
+// p_options may be NULL, in which case default options will
+// be used including a global threadpool.
+//
+// Check the return values for errors.
+//
+extern mtpng_result
+mtpng_encoder_new(mtpng_encoder** pp_encoder,
+                  mtpng_write_func write_func,
+                  mtpng_flush_func flush_func,
+                  void* const user_data,
+                  mtpng_encoder_options* p_options);
+
+//
+// Releases the encoder's memory and clears the pointer.
+//
+// This need only be used if aborting encoding early due to
+// errors etc; normally the call to mtpng_encoder_finish()
+// at the end of encoding will consume the instance and
+// release its memory.
+//
+// On input, *pp_encoder must be a valid instance pointer.
+// On output, *pp_encoder will be NULL on success, or remain
+// unchanged in case of failure.
+//
+// If using a threadpool, must be called before releasing the
+// threadpool!
+//
+// If the encoder is still in use, this may explode.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_release(mtpng_encoder** pp_encoder);
+
+//
+// Signal that we're done setting up, and start writing
+// header data to the output.
+//
+// Must be called before mtpng_encoder_append_row() or
+// mtpng_encoder_finish().
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_header(mtpng_encoder* p_encoder,
+                           mtpng_header* p_header);
+
+//
+// Write a palette entry for an indexed-color image, or a
+// suggested quantization palette for a truecolor image.
+//
+// See https://www.w3.org/TR/PNG/#11PLTE for the data format.
+//
+// Must be called after mtpng_encoder_write_header() and before
+// mtpng_encoder_write_image() or mtpng_encoder_write_image_data().
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_palette(mtpng_encoder* p_encoder,
+                            const uint8_t* p_bytes,
+                            size_t len);
+
+//
+// Write an indexed-color palette of RGBA entries as a PLTE chunk,
+// followed by a tRNS chunk of its alpha values unless every entry
+// is opaque.
+//
+// Pixel indices in the image data are checked against the palette
+// length as the rows are processed.
+//
+// Must be called after mtpng_encoder_write_header() and before
+// mtpng_encoder_write_image_rows().
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_rgba_palette(mtpng_encoder* p_encoder,
+                                 mtpng_palette* p_palette);
+
+//
+// Write alpha transparency entries for an indexed-color image, or a
+// single transparent color for a greyscale or truecolor image.
+//
+// See https://www.w3.org/TR/PNG/#11tRNS for the data format.
+//
+// Must be called after mtpng_encoder_write_palette() for indexed
+// images, or mtpng_encoder_write_header() for others; and before
+// mtpng_encoder_write_image() or mtpng_encoder_write_image_data().
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_transparency(mtpng_encoder* p_encoder,
+                                 const uint8_t* p_bytes,
+                                 size_t len);
+
+//
+// Write a background color: a palette index byte for indexed-color
+// images, or 16-bit samples in the image's bit depth for others.
+//
+// See https://www.w3.org/TR/PNG/#11bKGD for the data format.
+//
+// Must be called after mtpng_encoder_write_palette() for indexed
+// images, or mtpng_encoder_write_header() for others; and before
+// mtpng_encoder_write_image_rows().
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_background(mtpng_encoder* p_encoder,
+                               const uint8_t* p_bytes,
+                               size_t len);
+
+//
+// Write the number of significant bits per channel in the
+// original data, one byte per channel (three for indexed).
+//
+// See https://www.w3.org/TR/PNG/#11sBIT for the data format.
+//
+// Must be called after mtpng_encoder_write_header() and before
+// mtpng_encoder_write_palette() or any image data.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_significant_bits(mtpng_encoder* p_encoder,
+                                     const uint8_t* p_bytes,
+                                     size_t len);
+
+//
+// Write a palette histogram, with one frequency per palette entry.
+//
+// See https://www.w3.org/TR/PNG/#11hIST for details.
+//
+// Must be called after mtpng_encoder_write_palette() and before
+// mtpng_encoder_write_image_rows().
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_histogram(mtpng_encoder* p_encoder,
+                              const uint16_t* p_frequencies,
+                              size_t count);
+
+//
+// Write physical pixel dimensions as pixels per unit on each
+// axis. 300 DPI is 11811 pixels per meter.
+//
+// See https://www.w3.org/TR/PNG/#11pHYs for details.
+//
+// Must be called after mtpng_encoder_write_header() and before
+// mtpng_encoder_write_image_rows().
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_physical_dimensions(mtpng_encoder* p_encoder,
+                                        uint32_t x,
+                                        uint32_t y,
+                                        mtpng_physical_unit unit);
+
+//
+// Write the time of last modification, in UTC.
+//
+// See https://www.w3.org/TR/PNG/#11tIME for details.
+//
+// Must be called after mtpng_encoder_write_header().
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_time(mtpng_encoder* p_encoder,
+                         uint16_t year,
+                         uint8_t month,
+                         uint8_t day,
+                         uint8_t hour,
+                         uint8_t minute,
+                         uint8_t second);
+
+//
+// Write a named suggested palette of the given sample depth
+// (8 or 16). Each entry is red, green, blue, and alpha samples
+// followed by a 16-bit frequency.
+//
+// See https://www.w3.org/TR/PNG/#11sPLT for the data format.
+//
+// Must be called after mtpng_encoder_write_header() and before
+// mtpng_encoder_write_image_rows().
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_suggested_palette(mtpng_encoder* p_encoder,
+                                      const char* p_name,
+                                      uint8_t depth,
+                                      const uint8_t* p_bytes,
+                                      size_t len);
+
+//
+// Write EXIF metadata, starting with a TIFF header in either
+// byte order, without the "Exif\0\0" prefix used in JPEG.
+//
+// See https://www.w3.org/TR/png-3/#eXIf for details.
+//
+// Must be called after mtpng_encoder_write_header() and before
+// mtpng_encoder_write_image_rows().
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_exif(mtpng_encoder* p_encoder,
+                         const uint8_t* p_bytes,
+                         size_t len);
+
+//
+// Write a gAMA chunk giving the image gamma times 100000,
+// e.g. 45455 for 1/2.2.
+//
+// See https://www.w3.org/TR/PNG/#11gAMA for details.
+//
+// Color space chunks must be written after mtpng_encoder_write_header()
+// and before mtpng_encoder_write_palette() or any image data.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_gamma(mtpng_encoder* p_encoder,
+                          uint32_t gamma);
+
+//
+// Write a cHRM chunk giving the CIE x,y chromaticities of the
+// white point and primaries, each times 100000.
+//
+// See https://www.w3.org/TR/PNG/#11cHRM for details.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_chromaticities(mtpng_encoder* p_encoder,
+                                   uint32_t white_x,
+                                   uint32_t white_y,
+                                   uint32_t red_x,
+                                   uint32_t red_y,
+                                   uint32_t green_x,
+                                   uint32_t green_y,
+                                   uint32_t blue_x,
+                                   uint32_t blue_y);
+
+//
+// Write an sRGB chunk with the given rendering intent.
+// Cannot be combined with mtpng_encoder_write_icc_profile().
+//
+// See https://www.w3.org/TR/PNG/#11sRGB for details.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_srgb(mtpng_encoder* p_encoder,
+                         mtpng_rendering_intent intent);
+
+//
+// Write an iCCP chunk embedding the given ICC profile data,
+// which will be compressed. The name follows the same rules
+// as text keywords. Cannot be combined with mtpng_encoder_write_srgb().
+//
+// See https://www.w3.org/TR/PNG/#11iCCP for details.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_icc_profile(mtpng_encoder* p_encoder,
+                                const char* p_name,
+                                const uint8_t* p_bytes,
+                                size_t len);
+
+//
+// Write a cICP chunk giving the image's H.273 color primaries and
+// transfer function code points, e.g. 9 and 16 for BT.2100 PQ or
+// 9 and 18 for BT.2100 HLG.
+//
+// See https://www.w3.org/TR/png-3/#cICP-chunk for details.
+//
+// Must be called after mtpng_encoder_write_header() and before
+// mtpng_encoder_write_palette() or any image data.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_cicp(mtpng_encoder* p_encoder,
+                         uint8_t color_primaries,
+                         uint8_t transfer_function,
+                         bool full_range);
+
+//
+// Write an mDCV chunk describing the mastering display. Chromaticities
+// are in units of 0.00002, and luminance in units of 0.0001 cd/m^2.
+//
+// See https://www.w3.org/TR/png-3/#mDCV-chunk for details.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_mastering_display(mtpng_encoder* p_encoder,
+                                      uint16_t red_x,
+                                      uint16_t red_y,
+                                      uint16_t green_x,
+                                      uint16_t green_y,
+                                      uint16_t blue_x,
+                                      uint16_t blue_y,
+                                      uint16_t white_x,
+                                      uint16_t white_y,
+                                      uint32_t max_luminance,
+                                      uint32_t min_luminance);
+
+//
+// Write a cLLI chunk giving the maximum content light level and
+// maximum frame-average light level, in units of 0.0001 cd/m^2.
+//
+// See https://www.w3.org/TR/png-3/#cLLI-chunk for details.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_content_light_level(mtpng_encoder* p_encoder,
+                                        uint32_t max_content,
+                                        uint32_t max_frame_average);
+
+//
+// Declare the image as an animated PNG with the given number
+// of frames, looping num_plays times (0 to loop forever).
+//
+// See https://wiki.mozilla.org/APNG_Specification for details.
+//
+// Must be called after mtpng_encoder_write_header() and before
+// mtpng_encoder_write_image_rows(). Any image data written before
+// the first mtpng_encoder_write_frame_control() becomes a default
+// image that is not part of the animation.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_animation_control(mtpng_encoder* p_encoder,
+                                      uint32_t num_frames,
+                                      uint32_t num_plays);
+
+//
+// Start a new animation frame covering the given region, shown
+// for delay_num / delay_den seconds. Follow it with the frame's
+// rows via mtpng_encoder_write_image_rows(), packed at the width
+// of the frame.
+//
+// If called before any image data, the frame must cover the
+// whole image and is also used as the default image.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_frame_control(mtpng_encoder* p_encoder,
+                                  uint32_t width,
+                                  uint32_t height,
+                                  uint32_t x_offset,
+                                  uint32_t y_offset,
+                                  uint16_t delay_num,
+                                  uint16_t delay_den,
+                                  mtpng_dispose dispose_op,
+                                  mtpng_blend blend_op);
+
+//
+// Write a tEXt chunk with the given keyword and text, as
+// null-terminated UTF-8 strings. Both must contain only
+// characters representable in Latin-1.
+//
+// The keyword must be 1-79 characters, without leading,
+// trailing, or consecutive spaces.
+//
+// Must be called after mtpng_encoder_write_header().
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_text(mtpng_encoder* p_encoder,
+                         const char* p_keyword,
+                         const char* p_text);
+
+//
+// Write a zTXt chunk, as for mtpng_encoder_write_text() but
+// with the text compressed.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_compressed_text(mtpng_encoder* p_encoder,
+                                    const char* p_keyword,
+                                    const char* p_text);
+
+//
+// Write an iTXt chunk with UTF-8 text, optionally compressed.
+// The language tag and translated keyword may be empty strings.
+//
+// Must be called after mtpng_encoder_write_header().
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_international_text(mtpng_encoder* p_encoder,
+                                       const char* p_keyword,
+                                       const char* p_language_tag,
+                                       const char* p_translated_keyword,
+                                       const char* p_text,
+                                       bool compressed);
+
+//
+// Write a custom ancillary chunk to the output stream.
+// The tag must be a 4-byte string. The data should be provided
+// in the appropriate format for the tag.
+//
+// Known chunks must follow their placement rules; critical chunks
+// must be written with their own functions. Unknown chunks may
+// come before or after the image data.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_chunk(mtpng_encoder* p_encoder,
+                          const char* p_tag,
+                          const uint8_t* p_bytes,
+                          size_t len);
+
+//
+// Set the layout of rows given to mtpng_encoder_write_image_rows(),
+// if they are not already packed in the PNG format. Conversion is
+// done on the worker threads along with filtering. Copies the
+// input format data.
+//
+// May be changed between images or animation frames, but not
+// while one is partly written.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_set_input_format(mtpng_encoder* p_encoder,
+                               mtpng_input_format* p_format);
+
+//
+// Load one or more rows of input data into the encoder, to be
+// filtered and compressed as data is provided.
+//
+// Must be called after mtpng_encoder_write_header() and before
+// mtpng_encoder_finish().
+//
+// Image data must be pre-packed in the correct bit depth and
+// channel order, unless set otherwise with
+// mtpng_encoder_set_input_format(). If not all rows are provided
+// before calling mtpng_encoder_finish(), failure will result.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_image_rows(mtpng_encoder* p_encoder,
+                               const uint8_t* p_bytes,
+                               size_t len);
+
+//
+// Load one or more rows of 16-bit input data into the encoder,
+// as with mtpng_encoder_write_image_rows(), taking count samples
+// in native byte order. Requires a 16-bit image.
+//
+// Each image or animation frame must be written with a single
+// one of the image data functions.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_image_rows_u16(mtpng_encoder* p_encoder,
+                                   const uint16_t* p_samples,
+                                   size_t count);
+
+//
+// Load one or more rows of floating-point input data into the
+// encoder, as with mtpng_encoder_write_image_rows(), taking count
+// samples. They are quantised to the image's depth, which must be
+// 8 or 16 bits, using the input format's transfer function.
+//
+// Each image or animation frame must be written with a single
+// one of the image data functions.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_image_rows_f32(mtpng_encoder* p_encoder,
+                                   const float* p_samples,
+                                   size_t count);
+
+//
+// Load one or more rows of planar input data into the encoder,
+// as with mtpng_encoder_write_image_rows(). Requires the
+// MTPNG_PIXEL_LAYOUT_PLANAR input layout.
+//
+// pp_planes holds one plane per channel in PNG channel order,
+// each len bytes long.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_write_image_planes(mtpng_encoder* p_encoder,
+                                 const uint8_t* const* pp_planes,
+                                 size_t count,
+                                 size_t len);
+
+//
+// Wait for any outstanding work blocks, flush output,
+// release the encoder instance and clear the pointer.
+//
+// Must be called after all rows have been appended with
+// mtpng_encoder_append_row().
+//
+// On input, *pp_encoder must be a valid instance pointer.
+// On output, *pp_encoder will be NULL on success, or remain
+// unchanged in case of failure.
+//
+// You do not need to call mtpng_encoder_release after
+// this returns, and should not try.
+//
+// If using a threadpool, must be called before releasing
+// the threadpool!
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_encoder_finish(mtpng_encoder** pp_encoder);
+
+#pragma mark Decoder options
+
+//
+// Creates a new set of decoder options. Fill out the details
+// and pass in to mtpng_decoder_new(). May be reused on multiple
+// decoders.
+//
+// Free with mtpng_decoder_options_release().
+//
+// On input, *pp_options must be NULL.
+// On output, *pp_options will be a pointer to an options instance
+// if successful, or remain unchanged in case of error.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_decoder_options_new(mtpng_decoder_options** pp_options);
+
+//
+// Releases the option set's memory and clears the pointer.
+//
+// On input, *pp_options must be a valid instance pointer.
+// On output, *pp_options will be NULL on success or remain unchanged
+// in case of failure.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_decoder_options_release(mtpng_decoder_options** pp_options);
+
+//
+// Set the thread pool instance to queue work on.
+//
+// If a thread pool is provided, it is the caller's responsibility
+// to keep the thread pool alive until all decoders using it have
+// been released.
+//
+// Check the return values for errors.
+//
+extern mtpng_result
+mtpng_decoder_options_set_thread_pool(mtpng_decoder_options* p_options,
+                                      mtpng_threadpool* p_pool);
+
+//
+// Override the default chunk size for pipelined decoding.
+// Decompressed data is handed off for unfiltering in chunks
+// of whole rows of at least the given size in bytes.
+//
+// chunk_size must be at least 32768 bytes.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_decoder_options_set_chunk_size(mtpng_decoder_options* p_options,
+                                     size_t chunk_size);
+
+#pragma mark Decoder
+
+//
+// Create a new PNG decoder instance.
+// Copies the options data.
+//
+// On input, *pp_decoder must be NULL.
+// On output, *pp_decoder will be an instance pointer on success,
+// or remain unchanged in case of failure.
+//
+// The read_func callback is required, and must not be NULL.
+//
+// user_data is passed to the callback function, and may be any
+// value such as a private object pointer or NULL.
+//
+// p_options may be NULL, in which case default options will
+// be used including a global threadpool.
+//
+// Check the return values for errors.
+//
+extern mtpng_result
+mtpng_decoder_new(mtpng_decoder** pp_decoder,
+                  mtpng_read_func read_func,
+                  void* const user_data,
+                  mtpng_decoder_options* p_options);
+
+//
+// Releases the decoder's memory and clears the pointer.
+//
+// This need only be used if aborting decoding early due to
+// errors etc; normally the call to mtpng_decoder_finish()
+// at the end of decoding will consume the instance and
+// release its memory.
+//
+// If using a threadpool, must be called before releasing the
+// threadpool!
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_decoder_release(mtpng_decoder** pp_decoder);
+
+//
+// Read the PNG signature and header chunk, filling out
+// the given header instance from mtpng_header_new().
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_decoder_read_header(mtpng_decoder* p_decoder,
+                          mtpng_header* p_header);
+
+//
+// Read ahead to the image data and copy out the palette, if any,
+// in the same format as mtpng_encoder_write_palette() takes.
+//
+// len is the size of the buffer at p_bytes; 768 bytes is always
+// enough. On success *p_len is set to the palette's length in
+// bytes, or 0 if there was no palette.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_decoder_read_palette(mtpng_decoder* p_decoder,
+                           uint8_t* p_bytes,
+                           size_t len,
+                           size_t* p_len);
+
+//
+// Read ahead to the image data and copy out the transparency
+// info, if any, in the same format as mtpng_encoder_write_transparency()
+// takes.
+//
+// len is the size of the buffer at p_bytes; 256 bytes is always
+// enough. On success *p_len is set to the data's length in bytes,
+// or 0 if there was no transparency chunk.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_decoder_read_transparency(mtpng_decoder* p_decoder,
+                                uint8_t* p_bytes,
+                                size_t len,
+                                size_t* p_len);
+
+//
+// Decode one or more rows of image data into the given buffer,
+// packed in the same format as mtpng_encoder_write_image_rows()
+// takes. len must be a multiple of the row stride, which may be
+// found with mtpng_header_get_stride().
+//
+// On success *p_rows is set to the number of rows read, which
+// will be less than requested once the end of the image is reached.
+//
+// Interlaced images are returned in normal top-to-bottom order,
+// but must be fully decoded before the first row is available.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_decoder_read_image_rows(mtpng_decoder* p_decoder,
+                              uint8_t* p_bytes,
+                              size_t len,
+                              size_t* p_rows);
+
+//
+// Read the rest of the file, verifying the image data checksum,
+// then release the decoder instance and clear the pointer.
+//
+// Must be called after all rows have been read with
+// mtpng_decoder_read_image_rows().
+//
+// On input, *pp_decoder must be a valid instance pointer.
+// On output, *pp_decoder will be NULL on success, or remain
+// unchanged in case of failure.
+//
+// Check the return value for errors.
+//
+extern mtpng_result
+mtpng_decoder_finish(mtpng_decoder** pp_decoder);
 
 #pragma mark footer
 
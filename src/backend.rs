@@ -0,0 +1,159 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// backend.rs - pluggable deflate compression for image data chunks
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Deflate compression backends for the encoder's image data.
+//!
+//! The encoder splits filtered image data into chunks and compresses
+//! each one on its own thread as a segment of a single deflate stream,
+//! then joins the segments and their checksums. A `DeflateBackend` does
+//! the compressing and checksumming; the encoder still writes the zlib
+//! header and trailer around the joined stream.
+//!
+//! `Builtin` is the default. Set another with
+//! `encoder::Options::set_deflate_backend`.
+
+use std::io;
+
+use super::CompressionLevel;
+use super::Strategy;
+
+use super::deflate;
+use super::deflate::Deflate;
+use super::deflate::Flush;
+
+/// Parameters for compressing one segment of a deflate stream.
+#[derive(Copy, Clone)]
+pub struct Segment<'a> {
+    level: CompressionLevel,
+    strategy: Strategy,
+    dictionary: Option<&'a [u8]>,
+    last: bool,
+}
+
+impl<'a> Segment<'a> {
+    /// Describe a segment with the given compression settings.
+    pub fn new(level: CompressionLevel,
+               strategy: Strategy,
+               dictionary: Option<&'a [u8]>,
+               last: bool) -> Segment<'a>
+    {
+        Segment {
+            level,
+            strategy,
+            dictionary,
+            last,
+        }
+    }
+
+    /// Get the compression level.
+    ///
+    /// The encoder compresses the Extreme level itself without calling
+    /// the backend, so backends need only handle Fast, Default and High.
+    pub fn level(&self) -> CompressionLevel {
+        self.level
+    }
+
+    /// Get the deflate strategy.
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Get the preset dictionary: up to the last 32 KiB of the previous
+    /// segment's uncompressed data, which matches may refer back into.
+    /// None for the first segment.
+    pub fn dictionary(&self) -> Option<&'a [u8]> {
+        self.dictionary
+    }
+
+    /// Get whether this is the last segment of the stream.
+    pub fn is_last(&self) -> bool {
+        self.last
+    }
+}
+
+/// Compresses segments of a deflate stream, and checksums their data.
+///
+/// Segments are compressed independently on the encoder's threads, so
+/// implementations must be shareable between them.
+pub trait DeflateBackend: Send + Sync {
+    /// Compress a segment's data as raw deflate with no zlib header or
+    /// trailer, appending it to the output.
+    ///
+    /// Matches may refer back into the segment's dictionary, if it has
+    /// one. The last segment must end with a final block. Others must
+    /// not set the final bit, and must end on a byte boundary as after
+    /// a zlib sync flush, so the next segment can follow directly.
+    fn compress(&self, segment: &Segment, data: &[u8], output: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Update an adler32 checksum with more data.
+    fn adler32(&self, sum: u32, data: &[u8]) -> u32;
+
+    /// Combine the adler32 checksums of two runs of data into the
+    /// checksum of the two together, given the second one's length.
+    fn adler32_combine(&self, sum_a: u32, sum_b: u32, len_b: usize) -> u32;
+}
+
+/// The default backend, using the deflate implementation mtpng was built
+/// with: zlib with the zlib feature, which is on by default, or otherwise
+/// miniz_oxide with the rust-deflate feature.
+#[derive(Copy, Clone, Default)]
+pub struct Builtin;
+
+impl DeflateBackend for Builtin {
+    fn compress(&self, segment: &Segment, data: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        let mut options = deflate::Options::new();
+
+        // Negative forces raw stream output; the encoder writes
+        // the zlib header and trailer itself.
+        options.set_window_bits(-15);
+
+        match segment.level() {
+            CompressionLevel::Default => {},
+            CompressionLevel::Fast => options.set_level(1),
+            CompressionLevel::High | CompressionLevel::Extreme => options.set_level(9),
+        }
+        options.set_strategy(segment.strategy());
+
+        let mut encoder = Deflate::new(options, output);
+        if let Some(dictionary) = segment.dictionary() {
+            encoder.set_dictionary(dictionary)?;
+        }
+        encoder.write(data, if segment.is_last() {
+            Flush::Finish
+        } else {
+            Flush::SyncFlush
+        })?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    fn adler32(&self, sum: u32, data: &[u8]) -> u32 {
+        deflate::adler32(sum, data)
+    }
+
+    fn adler32_combine(&self, sum_a: u32, sum_b: u32, len_b: usize) -> u32 {
+        deflate::adler32_combine(sum_a, sum_b, len_b)
+    }
+}
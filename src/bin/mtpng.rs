@@ -44,9 +44,12 @@ use time::precise_time_s;
 
 // Hey that's us!
 extern crate mtpng;
-use mtpng::{ColorType, CompressionLevel, Header};
-use mtpng::Mode::{Adaptive, Fixed};
+use mtpng::{ColorType, CompressionLevel, Header, InterlaceMethod, Palette};
+use mtpng::Mode::{Adaptive, Fixed, BruteForce};
 use mtpng::encoder::{Encoder, Options};
+use mtpng::heuristic::{Bigrams, Entropy, MinSum, MinSumWithNone};
+use mtpng::quantize;
+use mtpng::reduce::{optimize_palette, reduce};
 use mtpng::Strategy;
 use mtpng::Filter;
 
@@ -55,8 +58,10 @@ pub fn err(payload: &str) -> Error
     Error::new(ErrorKind::Other, payload)
 }
 
-fn read_png(filename: &str)
-    -> io::Result<(Header, Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>)>
+// Header, image data, palette and transparency of a decoded file.
+type PngImage = (Header, Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>);
+
+fn read_png(filename: &str) -> io::Result<PngImage>
 {
     use png::Decoder;
     use png::HasParameters;
@@ -104,15 +109,26 @@ fn write_png(pool: &ThreadPool,
         },
     }
 
-    match args.value_of("filter") {
-        None             => {},
-        Some("adaptive") => options.set_filter_mode(Adaptive)?,
-        Some("none")     => options.set_filter_mode(Fixed(Filter::None))?,
-        Some("up")       => options.set_filter_mode(Fixed(Filter::Up))?,
-        Some("sub")      => options.set_filter_mode(Fixed(Filter::Sub))?,
-        Some("average")  => options.set_filter_mode(Fixed(Filter::Average))?,
-        Some("paeth")    => options.set_filter_mode(Fixed(Filter::Paeth))?,
+    let filter_mode = match args.value_of("filter") {
+        None             => Adaptive,
+        Some("adaptive") => Adaptive,
+        Some("brute")    => BruteForce,
+        Some("none")     => Fixed(Filter::None),
+        Some("up")       => Fixed(Filter::Up),
+        Some("sub")      => Fixed(Filter::Sub),
+        Some("average")  => Fixed(Filter::Average),
+        Some("paeth")    => Fixed(Filter::Paeth),
         _                => return Err(err("Unsupported filter type")),
+    };
+    options.set_filter_mode(filter_mode)?;
+
+    match args.value_of("heuristic") {
+        None                => {},
+        Some("minsum")      => options.set_filter_heuristic(&MinSum)?,
+        Some("minsum-none") => options.set_filter_heuristic(&MinSumWithNone)?,
+        Some("entropy")     => options.set_filter_heuristic(&Entropy)?,
+        Some("bigrams")     => options.set_filter_heuristic(&Bigrams)?,
+        _                   => return Err(err("Unsupported filter heuristic")),
     }
 
     match args.value_of("level") {
@@ -120,12 +136,14 @@ fn write_png(pool: &ThreadPool,
         Some("default") => options.set_compression_level(CompressionLevel::Default)?,
         Some("1")       => options.set_compression_level(CompressionLevel::Fast)?,
         Some("9")       => options.set_compression_level(CompressionLevel::High)?,
-        _               => return Err(err("Unsupported compression level (try default, 1, or 9)")),
+        Some("extreme") => options.set_compression_level(CompressionLevel::Extreme)?,
+        _               => return Err(err("Unsupported compression level (try default, 1, 9, or extreme)")),
     }
 
     match args.value_of("strategy") {
         None             => {},
         Some("auto")     => options.set_strategy_mode(Adaptive)?,
+        Some("brute")    => options.set_strategy_mode(BruteForce)?,
         Some("default")  => options.set_strategy_mode(Fixed(Strategy::Default))?,
         Some("filtered") => options.set_strategy_mode(Fixed(Strategy::Filtered))?,
         Some("huffman")  => options.set_strategy_mode(Fixed(Strategy::HuffmanOnly))?,
@@ -141,19 +159,91 @@ fn write_png(pool: &ThreadPool,
         _           => return Err(err("Invalid streaming mode, try yes or no."))
     }
 
+    match args.value_of("clean-alpha") {
+        None        => {},
+        Some("yes") => options.set_alpha_cleaning(true)?,
+        Some("no")  => options.set_alpha_cleaning(false)?,
+        _           => return Err(err("Invalid alpha cleaning mode, try yes or no."))
+    }
+
+    let mut header = *header;
+    match args.value_of("interlace") {
+        None          => {},
+        Some("none")  => header.set_interlace_method(InterlaceMethod::Standard)?,
+        Some("adam7") => header.set_interlace_method(InterlaceMethod::Adam7)?,
+        _             => return Err(err("Invalid interlace method, try none or adam7.")),
+    }
+
+    let quantized;
+    let (header, data, palette, transparency) = match args.value_of("quantize") {
+        None => (header, data, palette.as_deref(), transparency.as_deref()),
+        Some(s) => {
+            let mut quantize_options = quantize::Options::new();
+            let colors = s.parse::<usize>().map_err(|_e| err("Invalid number of colors"))?;
+            quantize_options.set_max_colors(colors)?;
+            if let Some(s) = args.value_of("dither") {
+                let strength = s.parse::<f32>().map_err(|_e| err("Invalid dither strength"))?;
+                quantize_options.set_dither_strength(strength)?;
+            }
+            let result = pool.install(|| quantize::quantize(&header, data, &quantize_options))?;
+            quantized = (result.palette().palette_data(), result.palette().transparency_data(), result);
+            (*quantized.2.header(),
+             quantized.2.data(),
+             Some(&quantized.0[..]),
+             Some(&quantized.1[..]).filter(|v| !v.is_empty()))
+        },
+    };
+
+    let reduction;
+    let (header, data, palette, transparency) = match args.value_of("reduce") {
+        None | Some("no") => (header, data, palette, transparency),
+        Some("yes") => {
+            reduction = pool.install(|| {
+                reduce(&header,
+                       palette.unwrap_or(&[]),
+                       transparency.unwrap_or(&[]),
+                       data)
+            })?;
+            (*reduction.header(),
+             reduction.data(),
+             Some(reduction.palette()).filter(|v| !v.is_empty()),
+             Some(reduction.transparency()).filter(|v| !v.is_empty()))
+        },
+        _ => return Err(err("Invalid reduce mode, try yes or no.")),
+    };
+
+    let optimized;
+    let (data, palette, transparency) = match (args.value_of("optimize-palette"), palette) {
+        (None, _) | (Some("no"), _) => (data, palette, transparency),
+        (Some("yes"), Some(plte)) if matches!(header.color_type(), ColorType::IndexedColor) => {
+            // Unfiltered 8-bit indices compress the same in any order.
+            if header.depth() == 8 && matches!(filter_mode, Adaptive | Fixed(Filter::None)) {
+                eprintln!("Warning: palette order only affects the tRNS size of unfiltered 8-bit images; \
+                           set a filter to optimize it for compression.");
+            }
+            let mut entries = Palette::from_chunks(plte, transparency.unwrap_or(&[]))?;
+            let mut rows = data.to_vec();
+            pool.install(|| optimize_palette(&header, filter_mode, &mut entries, &mut rows))?;
+            optimized = (rows, entries.palette_data(), entries.transparency_data());
+            (&optimized.0[..],
+             Some(&optimized.1[..]),
+             Some(&optimized.2[..]).filter(|v| !v.is_empty()))
+        },
+        (Some("yes"), _) => (data, palette, transparency),
+        _ => return Err(err("Invalid palette optimization mode, try yes or no.")),
+    };
+
     let mut encoder = Encoder::new(writer, &options);
 
     // Image data
     encoder.write_header(&header)?;
-    match palette {
-        Some(v) => encoder.write_palette(&v)?,
-        None => {},
+    if let Some(v) = palette {
+        encoder.write_palette(v)?;
     }
-    match transparency {
-        Some(v) => encoder.write_transparency(&v)?,
-        None => {},
+    if let Some(v) = transparency {
+        encoder.write_transparency(v)?;
     }
-    encoder.write_image_rows(&data)?;
+    encoder.write_image_rows(data)?;
     encoder.finish()?;
 
     Ok(())
@@ -184,11 +274,11 @@ fn doit(args: ArgMatches) -> io::Result<()> {
     let outfile = args.value_of("output").unwrap();
 
     println!("{} -> {}", infile, outfile);
-    let (header, data, palette, transparency) = read_png(&infile)?;
+    let (header, data, palette, transparency) = read_png(infile)?;
 
     for _i in 0 .. reps {
         let start_time = precise_time_s();
-        write_png(&pool, &args, &outfile, &header, &data, &palette, &transparency)?;
+        write_png(&pool, &args, outfile, &header, &data, &palette, &transparency)?;
         let delta = precise_time_s() - start_time;
 
         println!("Done in {} ms", (delta * 1000.0).round());
@@ -210,19 +300,47 @@ pub fn main() {
         .arg(Arg::with_name("filter")
             .long("filter")
             .value_name("filter")
-            .help("Set a fixed filter: one of none, sub, up, average, or paeth."))
+            .help("Set a fixed filter: one of none, sub, up, average, or paeth; or brute to try each."))
+        .arg(Arg::with_name("heuristic")
+            .long("heuristic")
+            .value_name("heuristic")
+            .help("Adaptive filter heuristic: one of minsum, minsum-none, entropy, or bigrams."))
         .arg(Arg::with_name("level")
             .long("level")
             .value_name("level")
-            .help("Set deflate compression level, from 1-9."))
+            .help("Set deflate compression level, from 1-9, or extreme for much slower optimal parsing."))
         .arg(Arg::with_name("strategy")
             .long("strategy")
             .value_name("strategy")
-            .help("Deflate strategy: one of filtered, huffman, rle, or fixed."))
+            .help("Deflate strategy: one of filtered, huffman, rle, or fixed; or brute to try several per chunk."))
         .arg(Arg::with_name("streaming")
             .long("streaming")
             .value_name("streaming")
             .help("Use streaming output mode; trades off file size for lower latency and memory usage"))
+        .arg(Arg::with_name("clean-alpha")
+            .long("clean-alpha")
+            .value_name("clean-alpha")
+            .help("Rewrite the color of fully transparent pixels to compress better: yes or no."))
+        .arg(Arg::with_name("interlace")
+            .long("interlace")
+            .value_name("interlace")
+            .help("Interlace method: one of none or adam7."))
+        .arg(Arg::with_name("quantize")
+            .long("quantize")
+            .value_name("colors")
+            .help("Quantize truecolor images to a palette of at most this many colors, from 1 to 256."))
+        .arg(Arg::with_name("dither")
+            .long("dither")
+            .value_name("strength")
+            .help("Floyd-Steinberg dither strength when quantizing, from 0 to 1."))
+        .arg(Arg::with_name("reduce")
+            .long("reduce")
+            .value_name("reduce")
+            .help("Losslessly reduce color type and bit depth before encoding: yes or no."))
+        .arg(Arg::with_name("optimize-palette")
+            .long("optimize-palette")
+            .value_name("optimize-palette")
+            .help("Reorder the palette of indexed-color images to compress better: yes or no."))
         .arg(Arg::with_name("threads")
             .long("threads")
             .value_name("threads")
@@ -29,6 +29,7 @@ use rayon::ThreadPoolBuilder;
 use std::convert::TryFrom;
 
 use std::io;
+use std::io::Read;
 use std::io::Write;
 
 use std::ptr;
@@ -41,13 +42,33 @@ use libc::{c_void, c_int, size_t};
 use super::ColorType;
 use super::Strategy;
 use super::CompressionLevel;
-use super::Mode::{Adaptive, Fixed};
+use super::Mode;
+use super::Mode::{Adaptive, Fixed, BruteForce};
 use super::Header;
+use super::InputFormat;
+use super::InterlaceMethod;
+use super::Palette;
+use super::PixelLayout;
+use super::Transfer;
+use super::FrameControl;
+use super::DisposeOp;
+use super::BlendOp;
+use super::RenderingIntent;
+use super::PhysicalUnit;
+use super::Cicp;
+use super::MasteringDisplay;
+use super::ContentLightLevel;
+
+use super::reduce::{optimize_palette, reduce, Reduction};
+
+use super::decoder::Decoder;
+use super::decoder;
 
 use super::encoder::Encoder;
 use super::encoder::Options;
 
 use super::filter::Filter;
+use super::heuristic::{Bigrams, Entropy, MinSum, MinSumWithNone};
 
 use super::utils::invalid_input;
 use super::utils::other;
@@ -67,10 +88,8 @@ impl From<Result<(),io::Error>> for CResult {
     }
 }
 
-/*
 pub type CReadFunc = unsafe extern "C"
     fn(*const c_void, *mut u8, size_t) -> size_t;
-*/
 
 pub type CWriteFunc = unsafe extern "C"
     fn(*const c_void, *const u8, size_t) -> size_t;
@@ -78,8 +97,6 @@ pub type CWriteFunc = unsafe extern "C"
 pub type CFlushFunc = unsafe extern "C"
     fn(*const c_void) -> bool;
 
-/*
-
 //
 // Adapter for Read trait to use C callback.
 //
@@ -102,19 +119,23 @@ impl CReader {
 
 impl Read for CReader {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
         let ret = unsafe {
             (self.read_func)(self.user_data,
                              &mut buf[0],
                              buf.len())
         };
-        if ret == buf.len() {
+        if ret <= buf.len() {
+            // Short reads signal end of file, which the
+            // decoder will report if it wasn't expected.
             Ok(ret)
         } else {
             Err(other("mtpng read callback returned failure"))
         }
     }
 }
-*/
 
 //
 // Adapter for Write trait to use C callbacks.
@@ -167,11 +188,17 @@ impl Write for CWriter {
 
 // Cheat on the lifetimes?
 type CEncoder = Encoder<'static, CWriter>;
+type CDecoder = Decoder<'static, CReader>;
 
 pub type PThreadPool = *mut ThreadPool;
 pub type PEncoderOptions = *mut Options<'static>;
 pub type PEncoder = *mut CEncoder;
+pub type PDecoderOptions = *mut decoder::Options<'static>;
+pub type PDecoder = *mut CDecoder;
 pub type PHeader = *mut Header;
+pub type PInputFormat = *mut InputFormat;
+pub type PPalette = *mut Palette;
+pub type PReduction = *mut Reduction;
 
 
 #[no_mangle]
@@ -264,6 +291,18 @@ fn mtpng_encoder_options_set_thread_pool(p_options: PEncoderOptions,
 }
 
 
+//
+// Map an mtpng_filter value to a filter mode.
+//
+fn filter_mode_from(filter_mode: c_int) -> io::Result<Mode<Filter>> {
+    match filter_mode {
+        -2 => Ok(BruteForce),
+        -1 => Ok(Adaptive),
+        0 ..= 4 => Filter::try_from(filter_mode as u8).map(Fixed),
+        _ => Err(invalid_input("Invalid filter mode")),
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_encoder_options_set_filter(p_options: PEncoderOptions,
@@ -274,15 +313,27 @@ fn mtpng_encoder_options_set_filter(p_options: PEncoderOptions,
         if p_options.is_null() {
             return Err(invalid_input("p_options must not be null"));
         }
-        if filter_mode > u8::max_value() as c_int {
-            return Err(invalid_input("Invalid filter mode"));
+        (*p_options).set_filter_mode(filter_mode_from(filter_mode)?)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_options_set_filter_heuristic(p_options: PEncoderOptions,
+                                              heuristic: c_int)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_options.is_null() {
+            return Err(invalid_input("p_options must not be null"));
+        }
+        match heuristic {
+            0 => (*p_options).set_filter_heuristic(&MinSum),
+            1 => (*p_options).set_filter_heuristic(&MinSumWithNone),
+            2 => (*p_options).set_filter_heuristic(&Entropy),
+            3 => (*p_options).set_filter_heuristic(&Bigrams),
+            _ => Err(invalid_input("Invalid filter heuristic")),
         }
-        let mode = if filter_mode < 0 {
-            Adaptive
-        } else {
-            Fixed(Filter::try_from(filter_mode as u8)?)
-        };
-        (*p_options).set_filter_mode(mode)
     }())
 }
 
@@ -296,13 +347,11 @@ fn mtpng_encoder_options_set_strategy(p_options: PEncoderOptions,
         if p_options.is_null() {
             return Err(invalid_input("p_options must not be null"));
         }
-        if strategy_mode > u8::max_value() as c_int {
-            return Err(invalid_input("Invalid strategy mode"));
-        }
-        let mode = if strategy_mode < 0 {
-            Adaptive
-        } else {
-            Fixed(Strategy::try_from(strategy_mode as u8)?)
+        let mode = match strategy_mode {
+            -2 => BruteForce,
+            -1 => Adaptive,
+            0 ..= 4 => Fixed(Strategy::try_from(strategy_mode as u8)?),
+            _ => return Err(invalid_input("Invalid strategy mode")),
         };
         (*p_options).set_strategy_mode(mode)
     }())
@@ -318,7 +367,7 @@ fn mtpng_encoder_options_set_compression_level(p_options: PEncoderOptions,
         if p_options.is_null() {
             return Err(invalid_input("p_options must not be null"));
         }
-        if compression_level < 0 || compression_level > 9 {
+        if compression_level < 0 || compression_level > 10 {
             return Err(invalid_input("Invalid compression level"));
         }
         let level = CompressionLevel::try_from(compression_level as u8)?;
@@ -341,6 +390,21 @@ fn mtpng_encoder_options_set_chunk_size(p_options: PEncoderOptions,
 }
 
 
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_options_set_alpha_cleaning(p_options: PEncoderOptions,
+                                            alpha_cleaning: bool)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_options.is_null() {
+            return Err(invalid_input("p_options must not be null"));
+        }
+        (*p_options).set_alpha_cleaning(alpha_cleaning)
+    }())
+}
+
+
 #[no_mangle]
 pub unsafe extern "C"
 fn mtpng_header_new(pp_header: *mut PHeader)
@@ -410,177 +474,1460 @@ fn mtpng_header_set_color(p_header: PHeader,
     }())
 }
 
-
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_header_set_interlace(p_header: PHeader,
+                              interlace_method: c_int)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_header.is_null() {
+            return Err(invalid_input("p_header must not be null"));
+        }
+        if interlace_method < 0 || interlace_method > u8::max_value() as c_int {
+            return Err(invalid_input("Invalid interlace method"));
+        }
+        let method = InterlaceMethod::try_from(interlace_method as u8)?;
+        (*p_header).set_interlace_method(method)
+    }())
+}
 
 #[no_mangle]
 pub unsafe extern "C"
-fn mtpng_encoder_new(pp_encoder: *mut PEncoder,
-                     write_func: Option<CWriteFunc>,
-                     flush_func: Option<CFlushFunc>,
-                     user_data: *mut c_void,
-                     p_options: PEncoderOptions)
+fn mtpng_header_get_size(p_header: PHeader,
+                         p_width: *mut u32,
+                         p_height: *mut u32)
 -> CResult
 {
     CResult::from(|| -> io::Result<()> {
-        if pp_encoder.is_null() {
-            return Err(invalid_input("pp_encoder must not be null"));
+        if p_header.is_null() {
+            return Err(invalid_input("p_header must not be null"));
         }
-        if !(*pp_encoder).is_null() {
-            return Err(invalid_input("*pp_encoder must be null"));
+        if p_width.is_null() || p_height.is_null() {
+            return Err(invalid_input("p_width and p_height must not be null"));
         }
-        let writer = match (write_func, flush_func) {
-            (Some(wf), Some(ff)) => CWriter::new(wf, ff, user_data),
-            _ => return Err(invalid_input("write_func and flush_func must not be null"))
-        };
-        let default = Options::<'static>::new();
-        let options = if p_options.is_null() {
-            &default
-        } else {
-            &*p_options
-        };
-        let encoder = Encoder::new(writer, options);
-        *pp_encoder = Box::into_raw(Box::new(encoder));
+        *p_width = (*p_header).width();
+        *p_height = (*p_header).height();
         Ok(())
     }())
 }
 
 #[no_mangle]
 pub unsafe extern "C"
-fn mtpng_encoder_release(pp_encoder: *mut PEncoder)
+fn mtpng_header_get_color(p_header: PHeader,
+                          p_color_type: *mut c_int,
+                          p_depth: *mut u8)
 -> CResult
 {
     CResult::from(|| -> io::Result<()> {
-        if pp_encoder.is_null() {
-            return Err(invalid_input("pp_encoder must not be null"))
+        if p_header.is_null() {
+            return Err(invalid_input("p_header must not be null"));
         }
-        if (*pp_encoder).is_null() {
-            return Err(invalid_input("*pp_encoder must not be null"))
+        if p_color_type.is_null() || p_depth.is_null() {
+            return Err(invalid_input("p_color_type and p_depth must not be null"));
         }
-        drop(Box::from_raw(*pp_encoder));
-        *pp_encoder = ptr::null_mut();
+        *p_color_type = (*p_header).color_type() as c_int;
+        *p_depth = (*p_header).depth();
         Ok(())
     }())
 }
 
-
 #[no_mangle]
 pub unsafe extern "C"
-fn mtpng_encoder_write_header(p_encoder: PEncoder,
-                              p_header: PHeader)
+fn mtpng_header_get_interlace(p_header: PHeader,
+                              p_interlace_method: *mut c_int)
 -> CResult
 {
     CResult::from(|| -> io::Result<()> {
-        if p_encoder.is_null() {
-            return Err(invalid_input("p_encoder must not be null"));
+        if p_header.is_null() {
+            return Err(invalid_input("p_header must not be null"));
         }
+        if p_interlace_method.is_null() {
+            return Err(invalid_input("p_interlace_method must not be null"));
+        }
+        *p_interlace_method = (*p_header).interlace_method() as c_int;
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_header_get_stride(p_header: PHeader,
+                           p_stride: *mut size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
         if p_header.is_null() {
             return Err(invalid_input("p_header must not be null"));
         }
-        (*p_encoder).write_header(&*p_header)?;
+        if p_stride.is_null() {
+            return Err(invalid_input("p_stride must not be null"));
+        }
+        *p_stride = (*p_header).stride();
         Ok(())
     }())
 }
 
+
+
 #[no_mangle]
 pub unsafe extern "C"
-fn mtpng_encoder_write_palette(p_encoder: PEncoder,
-                               p_bytes: *const u8,
-                               len: size_t)
+fn mtpng_input_format_new(pp_format: *mut PInputFormat)
 -> CResult
 {
     CResult::from(|| -> io::Result<()> {
-        if p_encoder.is_null() {
-            return Err(invalid_input("p_encoder must not be null"));
+        if pp_format.is_null() {
+            return Err(invalid_input("pp_format must not be null"));
         }
-        if p_bytes.is_null() {
-            return Err(invalid_input("p_bytes must not be null"));
+        if !(*pp_format).is_null() {
+            return Err(invalid_input("*pp_format must be null"))
         }
-        let slice = ::std::slice::from_raw_parts(p_bytes, len);
-        (*p_encoder).write_palette(slice)
+        *pp_format = Box::into_raw(Box::new(InputFormat::new()));
+        Ok(())
     }())
 }
 
 #[no_mangle]
 pub unsafe extern "C"
-fn mtpng_encoder_write_transparency(p_encoder: PEncoder,
-                                    p_bytes: *const u8,
-                                    len: size_t)
+fn mtpng_input_format_release(pp_format: *mut PInputFormat)
 -> CResult
 {
     CResult::from(|| -> io::Result<()> {
-        if p_encoder.is_null() {
-            return Err(invalid_input("p_encoder must not be null"));
+        if pp_format.is_null() {
+            return Err(invalid_input("pp_format must not be null"));
         }
-        if p_bytes.is_null() {
-            return Err(invalid_input("p_bytes must not be null"));
+        if (*pp_format).is_null() {
+            return Err(invalid_input("*pp_format must not be null"));
         }
-        let slice = ::std::slice::from_raw_parts(p_bytes, len);
-        (*p_encoder).write_transparency(slice)
+        drop(Box::from_raw(*pp_format));
+        *pp_format = ptr::null_mut();
+        Ok(())
     }())
 }
 
 #[no_mangle]
 pub unsafe extern "C"
-fn mtpng_encoder_write_chunk(p_encoder: PEncoder,
-                             p_tag: *const c_char,
-                             p_bytes: *const u8,
-                             len: size_t)
+fn mtpng_input_format_set_unpacked(p_format: PInputFormat,
+                                   unpacked: bool)
 -> CResult
 {
     CResult::from(|| -> io::Result<()> {
-        if p_encoder.is_null() {
-            return Err(invalid_input("p_encoder must not be null"));
+        if p_format.is_null() {
+            return Err(invalid_input("p_format must not be null"));
         }
-        if p_tag.is_null() {
-            return Err(invalid_input("p_tag must not be null"));
+        (*p_format).set_unpacked(unpacked)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_input_format_set_layout(p_format: PInputFormat,
+                                 layout: c_int)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_format.is_null() {
+            return Err(invalid_input("p_format must not be null"));
         }
-        if p_bytes.is_null() {
-            return Err(invalid_input("p_bytes must not be null"));
+        if layout < 0 || layout > u8::max_value() as c_int {
+            return Err(invalid_input("Invalid pixel layout"));
         }
-        let tag = CStr::from_ptr(p_tag).to_bytes();
-        let slice = ::std::slice::from_raw_parts(p_bytes, len);
-        (*p_encoder).write_chunk(tag, slice)
+        (*p_format).set_layout(PixelLayout::try_from(layout as u8)?)
     }())
 }
 
 #[no_mangle]
 pub unsafe extern "C"
-fn mtpng_encoder_write_image_rows(p_encoder: PEncoder,
-                                  p_bytes: *const u8,
-                                  len: size_t)
+fn mtpng_input_format_set_transfer(p_format: PInputFormat,
+                                   transfer: c_int)
 -> CResult
 {
     CResult::from(|| -> io::Result<()> {
-        if p_encoder.is_null() {
-            return Err(invalid_input("p_encoder must not be null"));
+        if p_format.is_null() {
+            return Err(invalid_input("p_format must not be null"));
         }
-        if p_bytes.is_null() {
-            return Err(invalid_input("p_bytes must not be null"));
+        if transfer < 0 || transfer > u8::max_value() as c_int {
+            return Err(invalid_input("Invalid transfer function"));
         }
-        let slice = ::std::slice::from_raw_parts(p_bytes, len);
-        (*p_encoder).write_image_rows(slice)
+        (*p_format).set_transfer(Transfer::try_from(transfer as u8)?)
     }())
 }
 
 #[no_mangle]
 pub unsafe extern "C"
-fn mtpng_encoder_finish(pp_encoder: *mut PEncoder)
+fn mtpng_input_format_set_premultiplied(p_format: PInputFormat,
+                                        premultiplied: bool)
 -> CResult
 {
     CResult::from(|| -> io::Result<()> {
-        if pp_encoder.is_null() {
-            return Err(invalid_input("pp_encoder must not be null"));
+        if p_format.is_null() {
+            return Err(invalid_input("p_format must not be null"));
         }
-        if (*pp_encoder).is_null() {
-            return Err(invalid_input("*pp_encoder must not be null"));
+        (*p_format).set_premultiplied(premultiplied)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_input_format_set_pitch(p_format: PInputFormat,
+                                pitch: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_format.is_null() {
+            return Err(invalid_input("p_format must not be null"));
         }
+        (*p_format).set_pitch(pitch)
+    }())
+}
 
-        // Take ownership back from C...
-        let b_encoder = Box::from_raw(*pp_encoder);
-        *pp_encoder = ptr::null_mut();
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_input_format_set_bottom_up(p_format: PInputFormat,
+                                    bottom_up: bool)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_format.is_null() {
+            return Err(invalid_input("p_format must not be null"));
+        }
+        (*p_format).set_bottom_up(bottom_up)
+    }())
+}
 
-        // And finish it out.
-        b_encoder.finish()?;
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_input_format_set_crop_offset(p_format: PInputFormat,
+                                      x: u32,
+                                      y: u32)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_format.is_null() {
+            return Err(invalid_input("p_format must not be null"));
+        }
+        (*p_format).set_crop_offset(x, y)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_input_format_get_stride(p_format: PInputFormat,
+                                 p_header: PHeader,
+                                 p_stride: *mut size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_format.is_null() {
+            return Err(invalid_input("p_format must not be null"));
+        }
+        if p_header.is_null() {
+            return Err(invalid_input("p_header must not be null"));
+        }
+        if p_stride.is_null() {
+            return Err(invalid_input("p_stride must not be null"));
+        }
+        *p_stride = (*p_format).stride(&*p_header);
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_palette_new(pp_palette: *mut PPalette)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if pp_palette.is_null() {
+            return Err(invalid_input("pp_palette must not be null"));
+        }
+        if !(*pp_palette).is_null() {
+            return Err(invalid_input("*pp_palette must be null"))
+        }
+        *pp_palette = Box::into_raw(Box::new(Palette::new()));
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_palette_release(pp_palette: *mut PPalette)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if pp_palette.is_null() {
+            return Err(invalid_input("pp_palette must not be null"));
+        }
+        if (*pp_palette).is_null() {
+            return Err(invalid_input("*pp_palette must not be null"));
+        }
+        drop(Box::from_raw(*pp_palette));
+        *pp_palette = ptr::null_mut();
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_palette_push(p_palette: PPalette,
+                      red: u8,
+                      green: u8,
+                      blue: u8,
+                      alpha: u8,
+                      p_index: *mut u8)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_palette.is_null() {
+            return Err(invalid_input("p_palette must not be null"));
+        }
+        let index = (*p_palette).push([red, green, blue, alpha])?;
+        if !p_index.is_null() {
+            *p_index = index;
+        }
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_palette_get_length(p_palette: PPalette,
+                            p_length: *mut size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_palette.is_null() {
+            return Err(invalid_input("p_palette must not be null"));
+        }
+        if p_length.is_null() {
+            return Err(invalid_input("p_length must not be null"));
+        }
+        *p_length = (*p_palette).len();
+        Ok(())
+    }())
+}
+
+//
+// Copy an index map out to the caller, if they asked for one;
+// the length has been checked before changing the palette.
+//
+unsafe fn copy_map(map: &[u8], p_map: *mut u8) {
+    if !p_map.is_null() {
+        ptr::copy_nonoverlapping(map.as_ptr(), p_map, map.len());
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_palette_dedup(p_palette: PPalette,
+                       p_map: *mut u8,
+                       len: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_palette.is_null() {
+            return Err(invalid_input("p_palette must not be null"));
+        }
+        if !p_map.is_null() && len < (*p_palette).len() {
+            return Err(invalid_input("p_map must have room for every palette entry"));
+        }
+        let map = (*p_palette).dedup();
+        copy_map(&map, p_map);
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_palette_sort_by_alpha(p_palette: PPalette,
+                               p_map: *mut u8,
+                               len: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_palette.is_null() {
+            return Err(invalid_input("p_palette must not be null"));
+        }
+        if !p_map.is_null() && len < (*p_palette).len() {
+            return Err(invalid_input("p_map must have room for every palette entry"));
+        }
+        let map = (*p_palette).sort_by_alpha();
+        copy_map(&map, p_map);
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_palette_optimize(p_palette: PPalette,
+                          p_header: PHeader,
+                          filter_mode: c_int,
+                          p_pool: PThreadPool,
+                          p_data: *mut u8,
+                          len: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_palette.is_null() {
+            return Err(invalid_input("p_palette must not be null"));
+        }
+        if p_header.is_null() {
+            return Err(invalid_input("p_header must not be null"));
+        }
+        if p_data.is_null() {
+            return Err(invalid_input("p_data must not be null"));
+        }
+        let mode = filter_mode_from(filter_mode)?;
+        let header = &*p_header;
+        let palette = &mut *p_palette;
+        let data = ::std::slice::from_raw_parts_mut(p_data, len);
+        if p_pool.is_null() {
+            optimize_palette(header, mode, palette, data)
+        } else {
+            (*p_pool).install(|| optimize_palette(header, mode, palette, data))
+        }
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_reduce(p_header: PHeader,
+                p_palette: *const u8,
+                palette_len: size_t,
+                p_transparency: *const u8,
+                transparency_len: size_t,
+                p_pool: PThreadPool,
+                p_data: *const u8,
+                len: size_t,
+                pp_reduction: *mut PReduction)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_header.is_null() {
+            return Err(invalid_input("p_header must not be null"));
+        }
+        if p_data.is_null() {
+            return Err(invalid_input("p_data must not be null"));
+        }
+        if pp_reduction.is_null() {
+            return Err(invalid_input("pp_reduction must not be null"));
+        }
+        if !(*pp_reduction).is_null() {
+            return Err(invalid_input("*pp_reduction must be null"))
+        }
+        let palette = if p_palette.is_null() {
+            &[]
+        } else {
+            ::std::slice::from_raw_parts(p_palette, palette_len)
+        };
+        let transparency = if p_transparency.is_null() {
+            &[]
+        } else {
+            ::std::slice::from_raw_parts(p_transparency, transparency_len)
+        };
+        let header = &*p_header;
+        let data = ::std::slice::from_raw_parts(p_data, len);
+        let reduction = if p_pool.is_null() {
+            reduce(header, palette, transparency, data)?
+        } else {
+            (*p_pool).install(|| reduce(header, palette, transparency, data))?
+        };
+        *pp_reduction = Box::into_raw(Box::new(reduction));
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_reduction_release(pp_reduction: *mut PReduction)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if pp_reduction.is_null() {
+            return Err(invalid_input("pp_reduction must not be null"));
+        }
+        if (*pp_reduction).is_null() {
+            return Err(invalid_input("*pp_reduction must not be null"));
+        }
+        drop(Box::from_raw(*pp_reduction));
+        *pp_reduction = ptr::null_mut();
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_reduction_get_header(p_reduction: PReduction,
+                              p_header: PHeader)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_reduction.is_null() {
+            return Err(invalid_input("p_reduction must not be null"));
+        }
+        if p_header.is_null() {
+            return Err(invalid_input("p_header must not be null"));
+        }
+        *p_header = *(*p_reduction).header();
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_reduction_get_palette(p_reduction: PReduction,
+                               p_bytes: *mut u8,
+                               len: size_t,
+                               p_len: *mut size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_reduction.is_null() {
+            return Err(invalid_input("p_reduction must not be null"));
+        }
+        if p_bytes.is_null() || p_len.is_null() {
+            return Err(invalid_input("p_bytes and p_len must not be null"));
+        }
+        copy_out(Some((*p_reduction).palette().to_vec()), p_bytes, len, p_len)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_reduction_get_transparency(p_reduction: PReduction,
+                                    p_bytes: *mut u8,
+                                    len: size_t,
+                                    p_len: *mut size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_reduction.is_null() {
+            return Err(invalid_input("p_reduction must not be null"));
+        }
+        if p_bytes.is_null() || p_len.is_null() {
+            return Err(invalid_input("p_bytes and p_len must not be null"));
+        }
+        copy_out(Some((*p_reduction).transparency().to_vec()), p_bytes, len, p_len)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_reduction_get_data(p_reduction: PReduction,
+                            pp_bytes: *mut *const u8,
+                            p_len: *mut size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_reduction.is_null() {
+            return Err(invalid_input("p_reduction must not be null"));
+        }
+        if pp_bytes.is_null() || p_len.is_null() {
+            return Err(invalid_input("pp_bytes and p_len must not be null"));
+        }
+        let data = (*p_reduction).data();
+        *pp_bytes = data.as_ptr();
+        *p_len = data.len();
+        Ok(())
+    }())
+}
+
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_new(pp_encoder: *mut PEncoder,
+                     write_func: Option<CWriteFunc>,
+                     flush_func: Option<CFlushFunc>,
+                     user_data: *mut c_void,
+                     p_options: PEncoderOptions)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if pp_encoder.is_null() {
+            return Err(invalid_input("pp_encoder must not be null"));
+        }
+        if !(*pp_encoder).is_null() {
+            return Err(invalid_input("*pp_encoder must be null"));
+        }
+        let writer = match (write_func, flush_func) {
+            (Some(wf), Some(ff)) => CWriter::new(wf, ff, user_data),
+            _ => return Err(invalid_input("write_func and flush_func must not be null"))
+        };
+        let default = Options::<'static>::new();
+        let options = if p_options.is_null() {
+            &default
+        } else {
+            &*p_options
+        };
+        let encoder = Encoder::new(writer, options);
+        *pp_encoder = Box::into_raw(Box::new(encoder));
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_release(pp_encoder: *mut PEncoder)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if pp_encoder.is_null() {
+            return Err(invalid_input("pp_encoder must not be null"))
+        }
+        if (*pp_encoder).is_null() {
+            return Err(invalid_input("*pp_encoder must not be null"))
+        }
+        drop(Box::from_raw(*pp_encoder));
+        *pp_encoder = ptr::null_mut();
+        Ok(())
+    }())
+}
+
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_header(p_encoder: PEncoder,
+                              p_header: PHeader)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_header.is_null() {
+            return Err(invalid_input("p_header must not be null"));
+        }
+        (*p_encoder).write_header(&*p_header)?;
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_palette(p_encoder: PEncoder,
+                               p_bytes: *const u8,
+                               len: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_bytes.is_null() {
+            return Err(invalid_input("p_bytes must not be null"));
+        }
+        let slice = ::std::slice::from_raw_parts(p_bytes, len);
+        (*p_encoder).write_palette(slice)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_rgba_palette(p_encoder: PEncoder,
+                                    p_palette: PPalette)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_palette.is_null() {
+            return Err(invalid_input("p_palette must not be null"));
+        }
+        (*p_encoder).write_rgba_palette(&*p_palette)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_transparency(p_encoder: PEncoder,
+                                    p_bytes: *const u8,
+                                    len: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_bytes.is_null() {
+            return Err(invalid_input("p_bytes must not be null"));
+        }
+        let slice = ::std::slice::from_raw_parts(p_bytes, len);
+        (*p_encoder).write_transparency(slice)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_background(p_encoder: PEncoder,
+                                     p_bytes: *const u8,
+                                     len: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_bytes.is_null() {
+            return Err(invalid_input("p_bytes must not be null"));
+        }
+        let slice = ::std::slice::from_raw_parts(p_bytes, len);
+        (*p_encoder).write_background(slice)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_significant_bits(p_encoder: PEncoder,
+                                           p_bytes: *const u8,
+                                           len: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_bytes.is_null() {
+            return Err(invalid_input("p_bytes must not be null"));
+        }
+        let slice = ::std::slice::from_raw_parts(p_bytes, len);
+        (*p_encoder).write_significant_bits(slice)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_histogram(p_encoder: PEncoder,
+                                 p_frequencies: *const u16,
+                                 count: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_frequencies.is_null() {
+            return Err(invalid_input("p_frequencies must not be null"));
+        }
+        let slice = ::std::slice::from_raw_parts(p_frequencies, count);
+        (*p_encoder).write_histogram(slice)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_physical_dimensions(p_encoder: PEncoder,
+                                           x: u32,
+                                           y: u32,
+                                           unit: c_int)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if unit < 0 || unit > u8::max_value() as c_int {
+            return Err(invalid_input("Invalid physical unit"));
+        }
+        (*p_encoder).write_physical_dimensions(x, y, PhysicalUnit::try_from(unit as u8)?)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_time(p_encoder: PEncoder,
+                            year: u16,
+                            month: u8,
+                            day: u8,
+                            hour: u8,
+                            minute: u8,
+                            second: u8)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        (*p_encoder).write_time(year, month, day, hour, minute, second)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_suggested_palette(p_encoder: PEncoder,
+                                         p_name: *const c_char,
+                                         depth: u8,
+                                         p_bytes: *const u8,
+                                         len: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_bytes.is_null() {
+            return Err(invalid_input("p_bytes must not be null"));
+        }
+        let name = c_str(p_name, "p_name")?;
+        let slice = ::std::slice::from_raw_parts(p_bytes, len);
+        (*p_encoder).write_suggested_palette(name, depth, slice)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_exif(p_encoder: PEncoder,
+                            p_bytes: *const u8,
+                            len: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_bytes.is_null() {
+            return Err(invalid_input("p_bytes must not be null"));
+        }
+        let slice = ::std::slice::from_raw_parts(p_bytes, len);
+        (*p_encoder).write_exif(slice)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_gamma(p_encoder: PEncoder,
+                             gamma: u32)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        (*p_encoder).write_gamma(gamma)
+    }())
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C"
+fn mtpng_encoder_write_chromaticities(p_encoder: PEncoder,
+                                      white_x: u32,
+                                      white_y: u32,
+                                      red_x: u32,
+                                      red_y: u32,
+                                      green_x: u32,
+                                      green_y: u32,
+                                      blue_x: u32,
+                                      blue_y: u32)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        (*p_encoder).write_chromaticities((white_x, white_y),
+                                          (red_x, red_y),
+                                          (green_x, green_y),
+                                          (blue_x, blue_y))
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_srgb(p_encoder: PEncoder,
+                            intent: c_int)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if intent < 0 || intent > u8::max_value() as c_int {
+            return Err(invalid_input("Invalid rendering intent"));
+        }
+        (*p_encoder).write_srgb(RenderingIntent::try_from(intent as u8)?)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_icc_profile(p_encoder: PEncoder,
+                                   p_name: *const c_char,
+                                   p_bytes: *const u8,
+                                   len: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_bytes.is_null() {
+            return Err(invalid_input("p_bytes must not be null"));
+        }
+        let name = c_str(p_name, "p_name")?;
+        let slice = ::std::slice::from_raw_parts(p_bytes, len);
+        (*p_encoder).write_icc_profile(name, slice)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_cicp(p_encoder: PEncoder,
+                            color_primaries: u8,
+                            transfer_function: u8,
+                            full_range: bool)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        let mut cicp = Cicp::new();
+        cicp.set_color_primaries(color_primaries)?;
+        cicp.set_transfer_function(transfer_function)?;
+        cicp.set_full_range(full_range)?;
+        (*p_encoder).write_cicp(&cicp)
+    }())
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C"
+fn mtpng_encoder_write_mastering_display(p_encoder: PEncoder,
+                                         red_x: u16,
+                                         red_y: u16,
+                                         green_x: u16,
+                                         green_y: u16,
+                                         blue_x: u16,
+                                         blue_y: u16,
+                                         white_x: u16,
+                                         white_y: u16,
+                                         max_luminance: u32,
+                                         min_luminance: u32)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        let mut display = MasteringDisplay::new();
+        display.set_primaries((red_x, red_y), (green_x, green_y), (blue_x, blue_y))?;
+        display.set_white_point(white_x, white_y)?;
+        display.set_luminance(max_luminance, min_luminance)?;
+        (*p_encoder).write_mastering_display(&display)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_content_light_level(p_encoder: PEncoder,
+                                           max_content: u32,
+                                           max_frame_average: u32)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        let mut level = ContentLightLevel::new();
+        level.set_levels(max_content, max_frame_average)?;
+        (*p_encoder).write_content_light_level(&level)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_animation_control(p_encoder: PEncoder,
+                                         num_frames: u32,
+                                         num_plays: u32)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        (*p_encoder).write_animation_control(num_frames, num_plays)
+    }())
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C"
+fn mtpng_encoder_write_frame_control(p_encoder: PEncoder,
+                                     width: u32,
+                                     height: u32,
+                                     x_offset: u32,
+                                     y_offset: u32,
+                                     delay_num: u16,
+                                     delay_den: u16,
+                                     dispose_op: c_int,
+                                     blend_op: c_int)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if dispose_op < 0 || dispose_op > u8::max_value() as c_int {
+            return Err(invalid_input("Invalid dispose op"));
+        }
+        if blend_op < 0 || blend_op > u8::max_value() as c_int {
+            return Err(invalid_input("Invalid blend op"));
+        }
+        let mut frame = FrameControl::new();
+        frame.set_size(width, height)?;
+        frame.set_offset(x_offset, y_offset)?;
+        frame.set_delay(delay_num, delay_den)?;
+        frame.set_dispose_op(DisposeOp::try_from(dispose_op as u8)?)?;
+        frame.set_blend_op(BlendOp::try_from(blend_op as u8)?)?;
+        (*p_encoder).write_frame_control(&frame)
+    }())
+}
+
+//
+// Borrow a C string as UTF-8 text.
+//
+unsafe fn c_str<'a>(p_str: *const c_char, name: &str) -> io::Result<&'a str> {
+    if p_str.is_null() {
+        return Err(invalid_input(&format!("{} must not be null", name)));
+    }
+    CStr::from_ptr(p_str).to_str().map_err(|_| invalid_input(&format!("{} must be valid UTF-8", name)))
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_text(p_encoder: PEncoder,
+                            p_keyword: *const c_char,
+                            p_text: *const c_char)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        let keyword = c_str(p_keyword, "p_keyword")?;
+        let text = c_str(p_text, "p_text")?;
+        (*p_encoder).write_text(keyword, text)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_compressed_text(p_encoder: PEncoder,
+                                       p_keyword: *const c_char,
+                                       p_text: *const c_char)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        let keyword = c_str(p_keyword, "p_keyword")?;
+        let text = c_str(p_text, "p_text")?;
+        (*p_encoder).write_compressed_text(keyword, text)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_international_text(p_encoder: PEncoder,
+                                          p_keyword: *const c_char,
+                                          p_language_tag: *const c_char,
+                                          p_translated_keyword: *const c_char,
+                                          p_text: *const c_char,
+                                          compressed: bool)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        let keyword = c_str(p_keyword, "p_keyword")?;
+        let language_tag = c_str(p_language_tag, "p_language_tag")?;
+        let translated_keyword = c_str(p_translated_keyword, "p_translated_keyword")?;
+        let text = c_str(p_text, "p_text")?;
+        (*p_encoder).write_international_text(keyword, language_tag, translated_keyword, text, compressed)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_chunk(p_encoder: PEncoder,
+                             p_tag: *const c_char,
+                             p_bytes: *const u8,
+                             len: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_tag.is_null() {
+            return Err(invalid_input("p_tag must not be null"));
+        }
+        if p_bytes.is_null() {
+            return Err(invalid_input("p_bytes must not be null"));
+        }
+        let tag = CStr::from_ptr(p_tag).to_bytes();
+        let slice = ::std::slice::from_raw_parts(p_bytes, len);
+        (*p_encoder).write_chunk(tag, slice)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_set_input_format(p_encoder: PEncoder,
+                                  p_format: PInputFormat)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_format.is_null() {
+            return Err(invalid_input("p_format must not be null"));
+        }
+        (*p_encoder).set_input_format(&*p_format)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_image_rows(p_encoder: PEncoder,
+                                  p_bytes: *const u8,
+                                  len: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_bytes.is_null() {
+            return Err(invalid_input("p_bytes must not be null"));
+        }
+        let slice = ::std::slice::from_raw_parts(p_bytes, len);
+        (*p_encoder).write_image_rows(slice)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_image_rows_u16(p_encoder: PEncoder,
+                                      p_samples: *const u16,
+                                      count: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_samples.is_null() {
+            return Err(invalid_input("p_samples must not be null"));
+        }
+        let slice = ::std::slice::from_raw_parts(p_samples, count);
+        (*p_encoder).write_image_rows_u16(slice)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_image_rows_f32(p_encoder: PEncoder,
+                                      p_samples: *const f32,
+                                      count: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if p_samples.is_null() {
+            return Err(invalid_input("p_samples must not be null"));
+        }
+        let slice = ::std::slice::from_raw_parts(p_samples, count);
+        (*p_encoder).write_image_rows_f32(slice)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_write_image_planes(p_encoder: PEncoder,
+                                    pp_planes: *const *const u8,
+                                    count: size_t,
+                                    len: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_encoder.is_null() {
+            return Err(invalid_input("p_encoder must not be null"));
+        }
+        if pp_planes.is_null() {
+            return Err(invalid_input("pp_planes must not be null"));
+        }
+        let pointers = ::std::slice::from_raw_parts(pp_planes, count);
+        if pointers.iter().any(|p_bytes| p_bytes.is_null()) {
+            return Err(invalid_input("pp_planes must not contain null"));
+        }
+        let planes: Vec<&[u8]> = pointers.iter().map(|&p_bytes| {
+            ::std::slice::from_raw_parts(p_bytes, len)
+        }).collect();
+        (*p_encoder).write_image_planes(&planes)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_encoder_finish(pp_encoder: *mut PEncoder)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if pp_encoder.is_null() {
+            return Err(invalid_input("pp_encoder must not be null"));
+        }
+        if (*pp_encoder).is_null() {
+            return Err(invalid_input("*pp_encoder must not be null"));
+        }
+
+        // Take ownership back from C...
+        let b_encoder = Box::from_raw(*pp_encoder);
+        *pp_encoder = ptr::null_mut();
+
+        // And finish it out.
+        b_encoder.finish()?;
+        Ok(())
+    }())
+}
+
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_decoder_options_new(pp_options: *mut PDecoderOptions)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if pp_options.is_null() {
+            return Err(invalid_input("pp_options must not be null"));
+        }
+        if !(*pp_options).is_null() {
+            return Err(invalid_input("*pp_options must be null"))
+        }
+        *pp_options = Box::into_raw(Box::new(decoder::Options::new()));
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_decoder_options_release(pp_options: *mut PDecoderOptions)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if pp_options.is_null() {
+            return Err(invalid_input("pp_options must not be null"));
+        }
+        if (*pp_options).is_null() {
+            return Err(invalid_input("*pp_options must not be null"));
+        }
+        drop(Box::from_raw(*pp_options));
+        *pp_options = ptr::null_mut();
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_decoder_options_set_thread_pool(p_options: PDecoderOptions,
+                                         p_pool: PThreadPool)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_options.is_null() {
+            return Err(invalid_input("p_options must not be null"));
+        }
+        (*p_options).set_thread_pool(&*p_pool)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_decoder_options_set_chunk_size(p_options: PDecoderOptions,
+                                        chunk_size: size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_options.is_null() {
+            return Err(invalid_input("p_options must not be null"));
+        }
+        (*p_options).set_chunk_size(chunk_size)
+    }())
+}
+
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_decoder_new(pp_decoder: *mut PDecoder,
+                     read_func: Option<CReadFunc>,
+                     user_data: *mut c_void,
+                     p_options: PDecoderOptions)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if pp_decoder.is_null() {
+            return Err(invalid_input("pp_decoder must not be null"));
+        }
+        if !(*pp_decoder).is_null() {
+            return Err(invalid_input("*pp_decoder must be null"));
+        }
+        let reader = match read_func {
+            Some(rf) => CReader::new(rf, user_data),
+            None => return Err(invalid_input("read_func must not be null"))
+        };
+        let default = decoder::Options::<'static>::new();
+        let options = if p_options.is_null() {
+            &default
+        } else {
+            &*p_options
+        };
+        let decoder = Decoder::new(reader, options);
+        *pp_decoder = Box::into_raw(Box::new(decoder));
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_decoder_release(pp_decoder: *mut PDecoder)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if pp_decoder.is_null() {
+            return Err(invalid_input("pp_decoder must not be null"))
+        }
+        if (*pp_decoder).is_null() {
+            return Err(invalid_input("*pp_decoder must not be null"))
+        }
+        drop(Box::from_raw(*pp_decoder));
+        *pp_decoder = ptr::null_mut();
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_decoder_read_header(p_decoder: PDecoder,
+                             p_header: PHeader)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_decoder.is_null() {
+            return Err(invalid_input("p_decoder must not be null"));
+        }
+        if p_header.is_null() {
+            return Err(invalid_input("p_header must not be null"));
+        }
+        *p_header = (*p_decoder).read_header()?;
+        Ok(())
+    }())
+}
+
+//
+// Copy optional chunk data out to a caller-provided buffer,
+// reporting the actual length (0 if not present).
+//
+unsafe fn copy_out(data: Option<Vec<u8>>,
+                   p_bytes: *mut u8,
+                   len: size_t,
+                   p_len: *mut size_t)
+-> io::Result<()>
+{
+    let data = data.unwrap_or_default();
+    if data.len() > len {
+        return Err(invalid_input("Buffer too small"));
+    }
+    if !data.is_empty() {
+        ptr::copy_nonoverlapping(data.as_ptr(), p_bytes, data.len());
+    }
+    *p_len = data.len();
+    Ok(())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_decoder_read_palette(p_decoder: PDecoder,
+                              p_bytes: *mut u8,
+                              len: size_t,
+                              p_len: *mut size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_decoder.is_null() {
+            return Err(invalid_input("p_decoder must not be null"));
+        }
+        if p_bytes.is_null() || p_len.is_null() {
+            return Err(invalid_input("p_bytes and p_len must not be null"));
+        }
+        copy_out((*p_decoder).read_palette()?, p_bytes, len, p_len)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_decoder_read_transparency(p_decoder: PDecoder,
+                                   p_bytes: *mut u8,
+                                   len: size_t,
+                                   p_len: *mut size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_decoder.is_null() {
+            return Err(invalid_input("p_decoder must not be null"));
+        }
+        if p_bytes.is_null() || p_len.is_null() {
+            return Err(invalid_input("p_bytes and p_len must not be null"));
+        }
+        copy_out((*p_decoder).read_transparency()?, p_bytes, len, p_len)
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_decoder_read_image_rows(p_decoder: PDecoder,
+                                 p_bytes: *mut u8,
+                                 len: size_t,
+                                 p_rows: *mut size_t)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if p_decoder.is_null() {
+            return Err(invalid_input("p_decoder must not be null"));
+        }
+        if p_bytes.is_null() || p_rows.is_null() {
+            return Err(invalid_input("p_bytes and p_rows must not be null"));
+        }
+        let slice = ::std::slice::from_raw_parts_mut(p_bytes, len);
+        *p_rows = (*p_decoder).read_image_rows(slice)?;
+        Ok(())
+    }())
+}
+
+#[no_mangle]
+pub unsafe extern "C"
+fn mtpng_decoder_finish(pp_decoder: *mut PDecoder)
+-> CResult
+{
+    CResult::from(|| -> io::Result<()> {
+        if pp_decoder.is_null() {
+            return Err(invalid_input("pp_decoder must not be null"));
+        }
+        if (*pp_decoder).is_null() {
+            return Err(invalid_input("*pp_decoder must not be null"));
+        }
+
+        // Take ownership back from C...
+        let b_decoder = Box::from_raw(*pp_decoder);
+        *pp_decoder = ptr::null_mut();
+
+        // And finish it out.
+        b_decoder.finish()?;
         Ok(())
     }())
 }
@@ -0,0 +1,443 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// convert.rs - conversion of input rows into the packed PNG format
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+use std::cmp;
+use std::io;
+
+use super::ColorType;
+use super::Header;
+use super::InputFormat;
+use super::PixelLayout;
+use super::SampleType;
+use super::Transfer;
+
+use super::utils::*;
+
+//
+// Pack one-byte samples into a row at a sub-byte depth,
+// most significant bits first.
+//
+fn pack_samples(depth: u8, input: &[u8], output: &mut Vec<u8>) -> IoResult {
+    let bits = depth as usize;
+    let max = (1u16 << bits) - 1;
+    let mut acc = 0u8;
+    let mut filled = 0;
+    for &sample in input {
+        if sample as u16 > max {
+            return Err(invalid_input(&format!("Sample value {} does not fit in {}-bit depth", sample, depth)));
+        }
+        acc |= sample << (8 - bits - filled);
+        filled += bits;
+        if filled == 8 {
+            output.push(acc);
+            acc = 0;
+            filled = 0;
+        }
+    }
+    if filled > 0 {
+        output.push(acc);
+    }
+    Ok(())
+}
+
+//
+// Input channel for each PNG channel, for interleaved layouts.
+//
+fn channel_order(layout: PixelLayout) -> &'static [usize] {
+    match layout {
+        PixelLayout::Bgr => &[2, 1, 0],
+        PixelLayout::Bgra => &[2, 1, 0, 3],
+        PixelLayout::Argb => &[1, 2, 3, 0],
+        PixelLayout::Abgr => &[3, 2, 1, 0],
+        _ => &[],
+    }
+}
+
+//
+// Reorder the channels of interleaved pixels.
+//
+fn swizzle(order: &[usize], sample: usize, input: &[u8], output: &mut Vec<u8>) {
+    for pixel in input.chunks(order.len() * sample) {
+        for &channel in order {
+            output.extend_from_slice(&pixel[channel * sample .. (channel + 1) * sample]);
+        }
+    }
+}
+
+//
+// Interleave a row made up of one run of samples per channel.
+//
+fn interleave(channels: usize, sample: usize, input: &[u8], output: &mut Vec<u8>) {
+    let plane = input.len() / channels;
+    for x in (0 .. plane).step_by(sample) {
+        for channel in 0 .. channels {
+            let i = channel * plane + x;
+            output.extend_from_slice(&input[i .. i + sample]);
+        }
+    }
+}
+
+//
+// Byte-swap native-endian 16-bit samples to big-endian.
+//
+fn swap_samples(input: &[u8], output: &mut Vec<u8>) {
+    for sample in input.chunks(2) {
+        let val = u16::from_ne_bytes([sample[0], sample[1]]);
+        output.extend_from_slice(&val.to_be_bytes());
+    }
+}
+
+//
+// Apply the sRGB transfer curve to a linear value in 0.0-1.0.
+//
+// https://www.w3.org/Graphics/Color/srgb
+//
+fn linear_to_srgb(val: f32) -> f32 {
+    if val <= 0.003_130_8 {
+        val * 12.92
+    } else {
+        1.055 * val.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+//
+// Index of the alpha channel, if any.
+//
+fn alpha_channel(color_type: ColorType) -> Option<usize> {
+    match color_type {
+        ColorType::GreyscaleAlpha | ColorType::TruecolorAlpha => Some(color_type.channels() - 1),
+        _ => None,
+    }
+}
+
+//
+// Quantise native-endian float samples to the image's depth,
+// leaving the alpha channel, if any, linear. Premultiplied color
+// is divided by alpha first, while still linear.
+//
+fn quantise_samples(header: &Header, format: &InputFormat, input: &[u8], output: &mut Vec<u8>) {
+    let channels = header.color_type().channels();
+    let alpha = alpha_channel(header.color_type());
+    let max = if header.depth() == 16 {
+        65535.0
+    } else {
+        255.0
+    };
+
+    let mut pixel = [0f32; 4];
+    for samples in input.chunks(channels * 4) {
+        for (val, sample) in pixel.iter_mut().zip(samples.chunks(4)) {
+            *val = f32::from_ne_bytes([sample[0], sample[1], sample[2], sample[3]]);
+        }
+        let scale = match alpha {
+            Some(a) if format.premultiplied() => if pixel[a] > 0.0 {
+                1.0 / pixel[a]
+            } else {
+                0.0
+            },
+            _ => 1.0,
+        };
+
+        for (channel, &val) in pixel[.. channels].iter().enumerate() {
+            let is_alpha = alpha == Some(channel);
+            let val = if is_alpha {
+                val
+            } else {
+                val * scale
+            };
+
+            // Written to map NaN to 0.
+            let val = if val > 0.0 {
+                val.min(1.0)
+            } else {
+                0.0
+            };
+            let val = match format.transfer() {
+                Transfer::LinearToSrgb if !is_alpha => linear_to_srgb(val),
+                _ => val,
+            };
+
+            let quantised = (val * max + 0.5) as u16;
+            if header.depth() == 16 {
+                output.extend_from_slice(&quantised.to_be_bytes());
+            } else {
+                output.push(quantised as u8);
+            }
+        }
+    }
+}
+
+//
+// Divide premultiplied color samples by alpha in place, in a row
+// in the PNG format with alpha as the last channel.
+//
+fn unpremultiply(depth: u8, channels: usize, row: &mut [u8]) {
+    let divide = |val: u32, alpha: u32, max: u32| {
+        (val * max + alpha / 2).checked_div(alpha)
+                               .map_or(0, |straight| cmp::min(max, straight))
+    };
+    if depth == 16 {
+        for pixel in row.chunks_mut(channels * 2) {
+            let (color, alpha) = pixel.split_at_mut((channels - 1) * 2);
+            let alpha = read_be16(alpha) as u32;
+            for sample in color.chunks_mut(2) {
+                let val = divide(read_be16(sample) as u32, alpha, 65535) as u16;
+                sample.copy_from_slice(&val.to_be_bytes());
+            }
+        }
+    } else {
+        for pixel in row.chunks_mut(channels) {
+            let (color, alpha) = pixel.split_at_mut(channels - 1);
+            let alpha = alpha[0] as u32;
+            for sample in color.iter_mut() {
+                *sample = divide(*sample as u32, alpha, 255) as u8;
+            }
+        }
+    }
+}
+
+//
+// Check that an input format can be used for the given image.
+//
+pub fn check_format(header: &Header, format: &InputFormat) -> IoResult {
+    let order = channel_order(format.layout());
+    if !order.is_empty() && order.len() != header.color_type().channels() {
+        return Err(invalid_input("Pixel layout does not match the image's color type"));
+    }
+    if format.premultiplied() && alpha_channel(header.color_type()).is_none() {
+        return Err(invalid_input("Premultiplied input requires an image with alpha"));
+    }
+    match format.samples {
+        SampleType::Bytes => Ok(()),
+        SampleType::U16 if header.depth() != 16 => {
+            Err(invalid_input("16-bit sample input requires a 16-bit image"))
+        },
+        SampleType::U16 => Ok(()),
+        SampleType::F32 => match header.color_type() {
+            ColorType::IndexedColor => {
+                Err(invalid_input("Floating-point input cannot be used for indexed-color images"))
+            },
+            _ if header.depth() != 8 && header.depth() != 16 => {
+                Err(invalid_input("Floating-point input requires an 8 or 16-bit image"))
+            },
+            _ => Ok(()),
+        },
+    }
+}
+
+//
+// Converts input rows of one image or interlace pass to the
+// PNG format, reusing an internal buffer between rows.
+//
+pub struct Converter {
+    header: Header,
+    format: InputFormat,
+    // Reordered samples, before conversion.
+    scratch: Vec<u8>,
+    row: Vec<u8>,
+}
+
+impl Converter {
+    pub fn new(header: Header, format: InputFormat) -> Converter {
+        Converter {
+            header,
+            format,
+            scratch: Vec::new(),
+            row: Vec::with_capacity(header.stride()),
+        }
+    }
+
+    fn is_packing(&self) -> bool {
+        self.format.unpacked() && self.header.depth() < 8
+    }
+
+    //
+    // Return the given input row in the PNG format; this is the
+    // row itself if no conversion is needed.
+    //
+    pub fn convert<'a>(&'a mut self, input: &'a [u8]) -> io::Result<&'a [u8]> {
+        let channels = self.header.color_type().channels();
+        let depth = self.header.depth();
+        let sample = match self.format.samples {
+            SampleType::Bytes if depth > 8 => 2,
+            SampleType::Bytes => 1,
+            SampleType::U16 => 2,
+            SampleType::F32 => 4,
+        };
+
+        self.row.clear();
+        if self.is_packing() {
+            pack_samples(depth, input, &mut self.row)?;
+            return Ok(&self.row);
+        }
+
+        self.scratch.clear();
+        let reordered = match self.format.layout() {
+            PixelLayout::Standard => input,
+            PixelLayout::Planar => {
+                interleave(channels, sample, input, &mut self.scratch);
+                &self.scratch
+            },
+            layout => {
+                swizzle(channel_order(layout), sample, input, &mut self.scratch);
+                &self.scratch
+            },
+        };
+
+        match self.format.samples {
+            SampleType::Bytes if !self.format.premultiplied() => return Ok(reordered),
+            SampleType::Bytes => self.row.extend_from_slice(reordered),
+            SampleType::U16 => swap_samples(reordered, &mut self.row),
+            SampleType::F32 => quantise_samples(&self.header, &self.format, reordered, &mut self.row),
+        }
+
+        // Float input was already divided out before quantising.
+        if self.format.premultiplied() && self.format.samples != SampleType::F32 {
+            unpremultiply(depth, channels, &mut self.row);
+        }
+        Ok(&self.row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Converter;
+    use super::check_format;
+    use super::super::Header;
+    use super::super::ColorType;
+    use super::super::InputFormat;
+    use super::super::PixelLayout;
+    use super::super::SampleType;
+    use super::super::Transfer;
+
+    #[test]
+    fn pack_bits() {
+        let mut header = Header::new();
+        header.set_size(10, 1).unwrap();
+        header.set_color(ColorType::Greyscale, 2).unwrap();
+
+        let mut format = InputFormat::new();
+        format.set_unpacked(true).unwrap();
+        assert_eq!(format.stride(&header), 10);
+
+        let mut converter = Converter::new(header, format);
+        let row = converter.convert(&[0, 1, 2, 3, 0, 1, 2, 3, 0, 1]).unwrap();
+        assert_eq!(row, &[0b0001_1011, 0b0001_1011, 0b0001_0000]);
+
+        assert!(converter.convert(&[0, 1, 2, 4, 0, 1, 2, 3, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn reorder() {
+        let mut header = Header::new();
+        header.set_size(2, 1).unwrap();
+        header.set_color(ColorType::TruecolorAlpha, 8).unwrap();
+
+        let mut format = InputFormat::new();
+        format.set_layout(PixelLayout::Argb).unwrap();
+        let mut converter = Converter::new(header, format);
+        assert_eq!(converter.convert(&[4, 1, 2, 3, 8, 5, 6, 7]).unwrap(), &[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        format.set_layout(PixelLayout::Planar).unwrap();
+        let mut converter = Converter::new(header, format);
+        assert_eq!(converter.convert(&[1, 5, 2, 6, 3, 7, 4, 8]).unwrap(), &[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        header.set_color(ColorType::Truecolor, 16).unwrap();
+        format.set_layout(PixelLayout::Bgr).unwrap();
+        let mut converter = Converter::new(header, format);
+        assert_eq!(converter.convert(&[5, 6, 3, 4, 1, 2, 11, 12, 9, 10, 7, 8]).unwrap(),
+                   &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+
+        assert!(check_format(&header, &format).is_ok());
+        format.set_layout(PixelLayout::Bgra).unwrap();
+        assert!(check_format(&header, &format).is_err());
+    }
+
+    #[test]
+    fn samples() {
+        let mut header = Header::new();
+        header.set_size(2, 1).unwrap();
+        header.set_color(ColorType::GreyscaleAlpha, 16).unwrap();
+
+        let mut format = InputFormat::new();
+        format.samples = SampleType::U16;
+        let input: Vec<u8> = [0x0102u16, 0x0304, 0x0506, 0x0708].iter().flat_map(|val| val.to_ne_bytes()).collect();
+        let mut converter = Converter::new(header, format);
+        assert_eq!(converter.convert(&input).unwrap(), &[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        header.set_color(ColorType::GreyscaleAlpha, 8).unwrap();
+        assert!(check_format(&header, &format).is_err());
+
+        format.samples = SampleType::F32;
+        format.set_transfer(Transfer::LinearToSrgb).unwrap();
+        let input: Vec<u8> = [0.5f32, 0.5, -1.0, f32::NAN].iter().flat_map(|val| val.to_ne_bytes()).collect();
+        let mut converter = Converter::new(header, format);
+        assert_eq!(converter.convert(&input).unwrap(), &[188, 128, 0, 0]);
+
+        format.set_transfer(Transfer::Clamp).unwrap();
+        let mut converter = Converter::new(header, format);
+        assert_eq!(converter.convert(&input).unwrap(), &[128, 128, 0, 0]);
+    }
+
+    #[test]
+    fn premultiplied() {
+        let mut header = Header::new();
+        header.set_size(2, 1).unwrap();
+        header.set_color(ColorType::TruecolorAlpha, 8).unwrap();
+
+        let mut format = InputFormat::new();
+        format.set_premultiplied(true).unwrap();
+        let mut converter = Converter::new(header, format);
+        assert_eq!(converter.convert(&[128, 64, 0, 128, 9, 9, 9, 0]).unwrap(),
+                   &[255, 128, 0, 128, 0, 0, 0, 0]);
+
+        header.set_color(ColorType::GreyscaleAlpha, 16).unwrap();
+        let mut converter = Converter::new(header, format);
+        assert_eq!(converter.convert(&[0x40, 0x00, 0x80, 0x00, 0xff, 0xff, 0xff, 0xff]).unwrap(),
+                   &[0x80, 0x00, 0x80, 0x00, 0xff, 0xff, 0xff, 0xff]);
+
+        format.samples = SampleType::F32;
+        let input: Vec<u8> = [0.25f32, 0.5, 0.25, 0.0].iter().flat_map(|val| val.to_ne_bytes()).collect();
+        let mut converter = Converter::new(header, format);
+        assert_eq!(converter.convert(&input).unwrap(), &[0x80, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+        header.set_color(ColorType::Greyscale, 16).unwrap();
+        assert!(check_format(&header, &format).is_err());
+    }
+
+    #[test]
+    fn pass_through() {
+        let mut header = Header::new();
+        header.set_size(2, 1).unwrap();
+        header.set_color(ColorType::Greyscale, 8).unwrap();
+
+        let mut format = InputFormat::new();
+        format.set_unpacked(true).unwrap();
+        assert_eq!(format.stride(&header), 2);
+
+        let mut converter = Converter::new(header, format);
+        assert_eq!(converter.convert(&[255, 7]).unwrap(), &[255, 7]);
+    }
+}
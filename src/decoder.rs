@@ -0,0 +1,803 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// decoder.rs - implements the public decoder interface & internals
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+use rayon::ThreadPool;
+
+use std::io;
+use std::io::Read;
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+
+use std::sync::mpsc;
+use std::sync::mpsc::{Sender, Receiver};
+
+use super::ColorType;
+use super::Header;
+use super::InterlaceMethod;
+
+use super::filter;
+use super::inflate::Inflate;
+use super::interlace;
+use super::interlace::ChunkSpan;
+use super::reader::Chunk;
+use super::reader::Reader;
+
+use super::utils::*;
+
+
+/// Options setup struct for the PNG decoder.
+/// May be modified and reused.
+#[derive(Copy, Clone)]
+pub struct Options<'a> {
+    chunk_size: usize,
+    thread_pool: Option<&'a ThreadPool>,
+}
+
+impl<'a> Options<'a> {
+    /// Create a new Options struct using default options:
+    /// * chunk_size: 256 KiB
+    /// * thread_pool: global default
+    pub fn new() -> Options<'a> {
+        Options {
+            chunk_size: 256 * 1024,
+            thread_pool: None,
+        }
+    }
+
+    /// Use a custom Rayon ThreadPool instance instead of the global pool.
+    pub fn set_thread_pool(&mut self, thread_pool: &'a ThreadPool) -> IoResult {
+        self.thread_pool = Some(thread_pool);
+        Ok(())
+    }
+
+    /// Set the size in bytes of the decompressed chunks handed off to
+    /// a thread for unfiltering. The actual chunk size used will be a
+    /// multiple of row lengths approximating the requested size.
+    ///
+    /// Smaller chunks reduce latency to the first rows being available,
+    /// but add overhead. Chunk size must be at least 32 KiB.
+    pub fn set_chunk_size(&mut self, chunk_size: usize) -> IoResult {
+        if chunk_size < 32768 {
+            Err(invalid_input("chunk size must be at least 32768"))
+        } else {
+            self.chunk_size = chunk_size;
+            Ok(())
+        }
+    }
+}
+
+impl<'a> Default for Options<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Takes inflated but still filtered rows as input and
+// reconstructs the original pixel data.
+struct UnfilterChunk {
+    span: ChunkSpan,
+    index: usize,
+
+    // The last reconstructed row of the previous chunk in the
+    // same pass, or zeroes at the start of the image or a pass.
+    prior_row: Vec<u8>,
+
+    // Filtered input bytes, with a filter type byte on each row
+    input: Vec<u8>,
+
+    // Reconstructed output bytes
+    data: Vec<u8>,
+}
+
+impl UnfilterChunk {
+    fn new(span: ChunkSpan, index: usize, input: Vec<u8>) -> UnfilterChunk {
+        UnfilterChunk {
+            span,
+            index,
+            prior_row: Vec::new(),
+            input,
+            data: Vec::new(),
+        }
+    }
+
+    //
+    // Run the unfiltering, on a background thread.
+    //
+    fn run(&mut self) -> IoResult {
+        let stride = self.span.header.stride();
+        let bpp = self.span.header.bytes_per_pixel();
+        let rows = self.span.end_row - self.span.start_row;
+
+        self.data = vec![0u8; stride * rows];
+        for (i, src) in self.input.chunks(stride + 1).enumerate() {
+            let (done, rest) = self.data.split_at_mut(i * stride);
+            let prev = if i == 0 {
+                &self.prior_row[..]
+            } else {
+                &done[(i - 1) * stride ..]
+            };
+            filter::unfilter(bpp, prev, src, &mut rest[.. stride])?;
+        }
+        Ok(())
+    }
+
+    //
+    // Chunks at the start of the image or of an interlace pass
+    // filter against zeroes, so don't need to wait for any other.
+    //
+    fn starts_pass(&self) -> bool {
+        self.span.start_row == 0
+    }
+
+    fn last_row(&self) -> &[u8] {
+        let stride = self.span.header.stride();
+        &self.data[self.data.len() - stride ..]
+    }
+}
+
+enum ThreadMessage {
+    UnfilterDone(UnfilterChunk),
+    Error(io::Error),
+}
+
+/// Pipelined PNG decoder state.
+/// Takes an Options struct with initializer data and a Read struct
+/// to take input from.
+///
+/// Decompression runs on the calling thread, while reconstruction of
+/// filtered rows runs on the thread pool. Each chunk of rows depends on
+/// the last row of the chunk before it, except at the start of the image
+/// or of an interlace pass, so interlaced images spread across threads
+/// while the chunks of other images are reconstructed in turn, overlapping
+/// with decompression of the chunks that follow.
+pub struct Decoder<'a, R: Read> {
+    reader: Reader<R>,
+    options: Options<'a>,
+
+    header: Header,
+
+    read_header: bool,
+    palette: Option<Vec<u8>>,
+    transparency: Option<Vec<u8>>,
+
+    // A chunk read ahead of where it's needed, such as the first IDAT.
+    pending: Option<Chunk>,
+    ended_image_data: bool,
+
+    inflate: Inflate,
+
+    spans: Vec<ChunkSpan>,
+    chunks_inflated: usize,
+
+    // Inflated chunks waiting for the chunk before them to finish.
+    queued: VecDeque<UnfilterChunk>,
+    running: usize,
+
+    // Last rows of finished chunks, by index of the chunk that needs them.
+    prior_rows: HashMap<usize, Vec<u8>>,
+
+    // Finished chunks waiting to be returned in order.
+    finished: HashMap<usize, UnfilterChunk>,
+    chunks_returned: usize,
+
+    // Reconstructed rows not yet returned to the caller.
+    output: Vec<u8>,
+    output_pos: usize,
+    current_row: u32,
+
+    // For messages from the thread pool.
+    tx: Sender<ThreadMessage>,
+    rx: Receiver<ThreadMessage>,
+}
+
+impl<'a, R: Read> Decoder<'a, R> {
+    /// Creates a new Decoder instance with the given Read input source and options.
+    pub fn new(read: R, options: &Options<'a>) -> Decoder<'a, R> {
+        let (tx, rx) = mpsc::channel();
+        Decoder {
+            reader: Reader::new(read),
+            options: *options,
+
+            header: Header::new(),
+
+            read_header: false,
+            palette: None,
+            transparency: None,
+
+            pending: None,
+            ended_image_data: false,
+
+            inflate: Inflate::new(),
+
+            spans: Vec::new(),
+            chunks_inflated: 0,
+
+            queued: VecDeque::new(),
+            running: 0,
+
+            prior_rows: HashMap::new(),
+
+            finished: HashMap::new(),
+            chunks_returned: 0,
+
+            output: Vec::new(),
+            output_pos: 0,
+            current_row: 0,
+
+            tx,
+            rx,
+        }
+    }
+
+    /// Read the remainder of the file through the end chunk, verifying
+    /// the image data's checksum, and return the Read source for further
+    /// use. Consumes the decoder instance.
+    ///
+    /// All image rows must have been read first.
+    pub fn finish(mut self) -> io::Result<R> {
+        if !self.read_header || self.current_row < self.header.height {
+            return Err(other("Incomplete image read"));
+        }
+
+        // Run out the zlib stream so its checksum gets checked.
+        let mut scratch = [0u8; 4096];
+        while !self.inflate.is_finished() {
+            if self.inflate.needs_input() {
+                match self.next_image_data()? {
+                    Some(data) => self.inflate.set_input(data),
+                    None => return Err(invalid_data("Unexpected end of image data")),
+                }
+            }
+            self.inflate.read(&mut scratch)?;
+        }
+
+        loop {
+            let chunk = match self.pending.take() {
+                Some(chunk) => chunk,
+                None => self.reader.read_chunk()?,
+            };
+            match &chunk.tag {
+                b"IEND" => break,
+                b"IDAT" if self.ended_image_data => {
+                    return Err(invalid_data("IDAT chunks must be consecutive"));
+                },
+                b"IDAT" => {},
+                b"PLTE" => {
+                    return Err(invalid_data("PLTE chunk must come before image data"));
+                },
+                _ => {
+                    self.ended_image_data = true;
+                    if chunk.is_critical() {
+                        return Err(invalid_data("Unsupported critical chunk"));
+                    }
+                },
+            }
+        }
+
+        Ok(self.reader.finish())
+    }
+
+    fn dispatch(&mut self, mut chunk: UnfilterChunk) {
+        chunk.prior_row = if chunk.starts_pass() {
+            vec![0u8; chunk.span.header.stride()]
+        } else {
+            self.prior_rows.remove(&chunk.index).unwrap_or_default()
+        };
+        self.running += 1;
+
+        let tx = self.tx.clone();
+        let func = move || {
+            // The decoder may have been dropped after an error,
+            // in which case there's nobody left to tell.
+            let _ = tx.send(match chunk.run() {
+                Ok(()) => ThreadMessage::UnfilterDone(chunk),
+                Err(e) => ThreadMessage::Error(e),
+            });
+        };
+        match self.options.thread_pool {
+            Some(pool) => pool.spawn(func),
+            None => ::rayon::spawn(func),
+        }
+    }
+
+    //
+    // Dispatch any queued chunks that no longer wait on another.
+    //
+    fn dispatch_ready(&mut self) {
+        let mut i = 0;
+        while i < self.queued.len() {
+            let chunk = &self.queued[i];
+            if chunk.starts_pass() || self.prior_rows.contains_key(&chunk.index) {
+                if let Some(chunk) = self.queued.remove(i) {
+                    self.dispatch(chunk);
+                }
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    //
+    // Inflate chunks ahead on this thread, handing each one to the
+    // thread pool as soon as it can run, until there's enough work
+    // in hand to keep every thread busy.
+    //
+    fn fill_queue(&mut self) -> IoResult {
+        let threads = match self.options.thread_pool {
+            Some(pool) => pool.current_num_threads(),
+            None => ::rayon::current_num_threads(),
+        };
+        self.dispatch_ready();
+        while self.running + self.queued.len() <= threads {
+            match self.inflate_chunk()? {
+                Some(chunk) => self.queued.push_back(chunk),
+                None => break,
+            }
+            self.dispatch_ready();
+        }
+        Ok(())
+    }
+
+    //
+    // Return the next chunk of reconstructed rows in order, or None
+    // once all chunks are done. While chunks are being unfiltered on
+    // the thread pool, those following are inflated on this thread.
+    //
+    fn next_chunk(&mut self) -> io::Result<Option<UnfilterChunk>> {
+        loop {
+            if let Some(chunk) = self.finished.remove(&self.chunks_returned) {
+                self.chunks_returned += 1;
+
+                // Keep the pool busy while the caller consumes this one.
+                self.fill_queue()?;
+                return Ok(Some(chunk));
+            }
+            if self.chunks_returned == self.spans.len() {
+                return Ok(None);
+            }
+
+            self.fill_queue()?;
+            if self.running == 0 {
+                return Err(other("invalid internal state"));
+            }
+            let chunk = match self.rx.recv() {
+                Ok(ThreadMessage::UnfilterDone(chunk)) => chunk,
+                Ok(ThreadMessage::Error(e)) => return Err(e),
+                Err(_) => return Err(other("Lost contact with thread pool")),
+            };
+            self.running -= 1;
+
+            let next = chunk.index + 1;
+            if next < self.spans.len() && self.spans[next].start_row != 0 {
+                self.prior_rows.insert(next, chunk.last_row().to_vec());
+            }
+            self.finished.insert(chunk.index, chunk);
+        }
+    }
+
+    //
+    // Decompress the filtered data for the next chunk of rows,
+    // reading more IDAT chunks from input as needed.
+    //
+    fn inflate_chunk(&mut self) -> io::Result<Option<UnfilterChunk>> {
+        if self.chunks_inflated == self.spans.len() {
+            return Ok(None);
+        }
+        let span = self.spans[self.chunks_inflated];
+        let len = (span.header.stride() + 1) * (span.end_row - span.start_row);
+
+        let mut input = vec![0u8; len];
+        let mut filled = 0;
+        while filled < len {
+            if self.inflate.is_finished() {
+                return Err(invalid_data("Image data too short"));
+            }
+            if self.inflate.needs_input() {
+                match self.next_image_data()? {
+                    Some(data) => self.inflate.set_input(data),
+                    None => return Err(invalid_data("Unexpected end of image data")),
+                }
+            }
+            filled += self.inflate.read(&mut input[filled ..])?;
+        }
+
+        let index = self.chunks_inflated;
+        self.chunks_inflated += 1;
+        Ok(Some(UnfilterChunk::new(span, index, input)))
+    }
+
+    //
+    // Return the contents of the next IDAT chunk, or None
+    // once the run of IDAT chunks has ended.
+    //
+    fn next_image_data(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.ended_image_data {
+            return Ok(None);
+        }
+        let chunk = match self.pending.take() {
+            Some(chunk) => chunk,
+            None => self.reader.read_chunk()?,
+        };
+        if chunk.tag == *b"IDAT" {
+            Ok(Some(chunk.data))
+        } else {
+            self.ended_image_data = true;
+            self.pending = Some(chunk);
+            Ok(None)
+        }
+    }
+
+    /// Read the PNG signature and header chunk.
+    /// Must be done before anything else is input; other read
+    /// methods will call this automatically if needed.
+    ///
+    /// Returns the header describing the image.
+    pub fn read_header(&mut self) -> io::Result<Header> {
+        if !self.read_header {
+            self.reader.read_signature()?;
+            self.header = self.reader.read_header()?;
+
+            // A hostile header could describe more image data than can
+            // be addressed, overflowing the chunk layout and buffers.
+            let stride = self.header.stride() + 1;
+            match stride.checked_mul(self.header.height as usize) {
+                Some(len) if len <= isize::MAX as usize => {},
+                _ => return Err(invalid_data("Image is too large")),
+            }
+
+            self.spans = interlace::layout_chunks(&self.header, self.options.chunk_size);
+            self.read_header = true;
+        }
+        Ok(self.header)
+    }
+
+    //
+    // Read and validate chunks up to the start of the image data.
+    //
+    fn read_to_image_data(&mut self) -> IoResult {
+        self.read_header()?;
+        while self.pending.is_none() && !self.ended_image_data {
+            let chunk = self.reader.read_chunk()?;
+            match &chunk.tag {
+                b"IDAT" => {
+                    self.pending = Some(chunk);
+                },
+                b"PLTE" => {
+                    self.check_palette(&chunk.data)?;
+                    self.palette = Some(chunk.data);
+                },
+                b"tRNS" => {
+                    self.check_transparency(&chunk.data)?;
+                    self.transparency = Some(chunk.data);
+                },
+                b"IHDR" => {
+                    return Err(invalid_data("Duplicate IHDR chunk"));
+                },
+                b"IEND" => {
+                    return Err(invalid_data("No image data"));
+                },
+                _ => {
+                    if chunk.is_critical() {
+                        return Err(invalid_data("Unsupported critical chunk"));
+                    }
+                },
+            }
+        }
+        if let ColorType::IndexedColor = self.header.color_type {
+            if self.palette.is_none() {
+                return Err(invalid_data("Missing palette for indexed-color image"));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_palette(&self, data: &[u8]) -> IoResult {
+        if self.palette.is_some() {
+            return Err(invalid_data("Duplicate PLTE chunk"));
+        }
+        if self.transparency.is_some() {
+            return Err(invalid_data("PLTE chunk must come before tRNS"));
+        }
+        match self.header.color_type {
+            ColorType::Greyscale | ColorType::GreyscaleAlpha => {
+                return Err(invalid_data("PLTE chunk not allowed for greyscale images"));
+            },
+            _ => {},
+        }
+        if data.is_empty() || data.len() % 3 != 0 {
+            return Err(invalid_data("Palette must have an integral number of entries"));
+        }
+        if data.len() / 3 > 256 {
+            return Err(invalid_data("Palette cannot have more than 256 entries"));
+        }
+        Ok(())
+    }
+
+    fn check_transparency(&self, data: &[u8]) -> IoResult {
+        if self.transparency.is_some() {
+            return Err(invalid_data("Duplicate tRNS chunk"));
+        }
+        match self.header.color_type {
+            ColorType::Greyscale => {
+                if data.len() != 2 {
+                    return Err(invalid_data("Greyscale transparency data must be exactly 2 bytes"));
+                }
+            },
+            ColorType::Truecolor => {
+                if data.len() != 6 {
+                    return Err(invalid_data("Truecolor transparency data must be exactly 6 bytes"));
+                }
+            },
+            ColorType::IndexedColor => {
+                match self.palette {
+                    Some(ref palette) => {
+                        if data.len() > palette.len() / 3 {
+                            return Err(invalid_data("Transparency data cannot contain more entries than palette"));
+                        }
+                    },
+                    None => {
+                        return Err(invalid_data("tRNS chunk must come after PLTE"));
+                    },
+                }
+            },
+            _ => {
+                return Err(invalid_data("Transparency chunk is invalid for color types with alpha"));
+            },
+        }
+        Ok(())
+    }
+
+    /// Read ahead to the image data and return the indexed-color palette,
+    /// if one was present, in the same format as accepted by
+    /// Encoder::write_palette.
+    ///
+    /// Palettes are required for indexed-color images, and optional
+    /// suggestions for truecolor images.
+    pub fn read_palette(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.read_to_image_data()?;
+        Ok(self.palette.clone())
+    }
+
+    /// Read ahead to the image data and return the transparency info,
+    /// if present, in the same format as accepted by
+    /// Encoder::write_transparency.
+    pub fn read_transparency(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.read_to_image_data()?;
+        Ok(self.transparency.clone())
+    }
+
+    //
+    // Decode the whole of an interlaced image, placing
+    // each pass's pixels into the full-size output.
+    //
+    fn read_passes(&mut self) -> IoResult {
+        let header = self.header;
+        let stride = header.stride();
+        let mut image = vec![0u8; stride * header.height as usize];
+
+        while let Some(chunk) = self.next_chunk()? {
+            let pass = match chunk.span.pass {
+                Some(index) => &interlace::PASSES[index],
+                None => return Err(other("invalid internal state")),
+            };
+            let pass_stride = chunk.span.header.stride();
+            let rows = pass.rows(&header).skip(chunk.span.start_row);
+            for (pass_row, y) in chunk.data.chunks(pass_stride).zip(rows) {
+                pass.scatter_row(&header, pass_row, &mut image[y * stride .. (y + 1) * stride]);
+            }
+        }
+
+        self.output = image;
+        self.output_pos = 0;
+        Ok(())
+    }
+
+    fn read_row(&mut self, row: &mut [u8]) -> IoResult {
+        match self.header.interlace_method {
+            InterlaceMethod::Standard => {
+                while self.output_pos == self.output.len() {
+                    match self.next_chunk()? {
+                        Some(chunk) => {
+                            self.output = chunk.data;
+                            self.output_pos = 0;
+                        },
+                        None => return Err(other("invalid internal state")),
+                    }
+                }
+            },
+            InterlaceMethod::Adam7 => {
+                if self.current_row == 0 {
+                    self.read_passes()?;
+                }
+            },
+        }
+
+        let end = self.output_pos + row.len();
+        row.clone_from_slice(&self.output[self.output_pos .. end]);
+        self.output_pos = end;
+        self.current_row += 1;
+        Ok(())
+    }
+
+    /// Decode image data into the given buffer, packed in the same
+    /// format as accepted by Encoder::write_image_rows, with no padding
+    /// at the end of rows.
+    ///
+    /// The buffer must hold an integral number of rows. Returns the
+    /// number of rows read, which will be less than requested once
+    /// the end of the image is reached.
+    ///
+    /// Interlaced images are returned in normal top-to-bottom order,
+    /// but must be fully decoded before the first row is available.
+    pub fn read_image_rows(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_to_image_data()?;
+
+        let stride = self.header.stride();
+        if buf.len() % stride != 0 {
+            return Err(invalid_input("Buffer must be an integral number of rows"));
+        }
+
+        let mut rows = 0;
+        for row in buf.chunks_mut(stride) {
+            if self.current_row == self.header.height {
+                break;
+            }
+            self.read_row(row)?;
+            rows += 1;
+        }
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Header;
+    use super::super::ColorType;
+    use super::super::InterlaceMethod;
+    use super::super::encoder;
+    use super::super::writer::Writer;
+    use super::Decoder;
+    use super::Options;
+
+    use rayon::ThreadPoolBuilder;
+
+    use std::io;
+
+    fn round_trip(header: &Header, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = encoder::Encoder::new(Vec::<u8>::new(), &encoder::Options::new());
+        encoder.write_header(header)?;
+        if let ColorType::IndexedColor = header.color_type() {
+            encoder.write_palette(&vec![0u8; 3 << header.depth()])?;
+        }
+        encoder.write_image_rows(data)?;
+        let png = encoder.finish()?;
+
+        let mut decoder = Decoder::new(io::Cursor::new(png), &Options::new());
+        let read = decoder.read_header()?;
+        assert_eq!(read.width(), header.width());
+        assert_eq!(read.height(), header.height());
+
+        let mut output = vec![0u8; data.len()];
+        assert_eq!(decoder.read_image_rows(&mut output)?, header.height() as usize);
+        decoder.finish()?;
+        Ok(output)
+    }
+
+    fn test_data(header: &Header) -> Vec<u8> {
+        let len = header.stride() * header.height() as usize;
+        (0 .. len).map(|i| (i * 7 + i / 1000) as u8).collect()
+    }
+
+    #[test]
+    fn it_works() {
+        let mut header = Header::new();
+        header.set_size(1024, 768).unwrap();
+        header.set_color(ColorType::TruecolorAlpha, 8).unwrap();
+        let data = test_data(&header);
+        assert!(round_trip(&header, &data).unwrap() == data);
+    }
+
+    #[test]
+    fn adam7_works() {
+        let mut header = Header::new();
+        header.set_size(333, 222).unwrap();
+        header.set_color(ColorType::IndexedColor, 4).unwrap();
+        header.set_interlace_method(InterlaceMethod::Adam7).unwrap();
+
+        // Clear the unused bits at the end of each row.
+        let mut data = test_data(&header);
+        let stride = header.stride();
+        for row in data.chunks_mut(stride) {
+            row[stride - 1] &= 0xf0;
+        }
+        assert!(round_trip(&header, &data).unwrap() == data);
+    }
+
+    #[test]
+    fn thread_pool() {
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let mut options = Options::new();
+        options.set_thread_pool(&pool).unwrap();
+        options.set_chunk_size(32768).unwrap();
+
+        for &interlace_method in &[InterlaceMethod::Standard, InterlaceMethod::Adam7] {
+            let mut header = Header::new();
+            header.set_size(640, 480).unwrap();
+            header.set_color(ColorType::Truecolor, 8).unwrap();
+            header.set_interlace_method(interlace_method).unwrap();
+            let data = test_data(&header);
+
+            let mut encoder = encoder::Encoder::new(Vec::<u8>::new(), &encoder::Options::new());
+            encoder.write_header(&header).unwrap();
+            encoder.write_image_rows(&data).unwrap();
+            let png = encoder.finish().unwrap();
+
+            let mut decoder = Decoder::new(io::Cursor::new(png), &options);
+            let mut output = vec![0u8; data.len()];
+            assert_eq!(decoder.read_image_rows(&mut output).unwrap(), 480);
+            decoder.finish().unwrap();
+            assert!(output == data);
+        }
+    }
+
+    #[test]
+    fn corrupt_data() {
+        let mut header = Header::new();
+        header.set_size(64, 64).unwrap();
+        header.set_color(ColorType::Greyscale, 8).unwrap();
+
+        let mut encoder = encoder::Encoder::new(Vec::<u8>::new(), &encoder::Options::new());
+        encoder.write_header(&header).unwrap();
+        encoder.write_image_rows(&test_data(&header)).unwrap();
+        let mut png = encoder.finish().unwrap();
+
+        // Flip a bit in the middle of the IDAT payload.
+        let len = png.len();
+        png[len / 2] ^= 0x10;
+
+        let mut decoder = Decoder::new(io::Cursor::new(png), &Options::new());
+        let mut output = vec![0u8; header.stride() * 64];
+        assert!(decoder.read_image_rows(&mut output).is_err());
+    }
+
+    #[test]
+    fn oversized_header() {
+        // 2^31 - 1 square at 64 bits per pixel.
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&0x7fff_ffffu32.to_be_bytes());
+        ihdr.extend_from_slice(&0x7fff_ffffu32.to_be_bytes());
+        ihdr.extend_from_slice(&[16, 6, 0, 0, 0]);
+
+        let mut writer = Writer::new(Vec::<u8>::new());
+        writer.write_signature().unwrap();
+        writer.write_chunk(b"IHDR", &ihdr).unwrap();
+        let png = writer.finish().unwrap();
+
+        let mut decoder = Decoder::new(io::Cursor::new(png), &Options::new());
+        assert!(decoder.read_header().is_err());
+    }
+}
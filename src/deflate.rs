@@ -1,6 +1,6 @@
 //
 // mtpng - a multithreaded parallel PNG encoder in Rust
-// deflate.rs - wrapper for libz_sys suitable for making chunked deflate streams
+// deflate.rs - wrapper for zlib or miniz_oxide suitable for making chunked deflate streams
 //
 // Copyright (c) 2018 Brion Vibber
 //
@@ -24,54 +24,73 @@
 //
 
 use std::io;
+#[cfg(feature="zlib")]
 use std::io::Write;
 
+#[cfg(feature="zlib")]
 use std::mem;
 
+#[cfg(feature="zlib")]
 use std::ptr;
 
 use std::convert::TryFrom;
 
+#[cfg(feature="zlib")]
 use std::os::raw::*;
 
+#[cfg(feature="zlib")]
 use ::libz_sys::*;
 
 use super::utils::*;
 
+//
+// The streams and checksums come from zlib when the zlib feature is on,
+// and otherwise from miniz_oxide with the rust-deflate feature, behind
+// the same interface.
+//
+#[cfg(all(feature="rust-deflate", not(feature="zlib")))]
+pub use super::miniz::{adler32, adler32_initial, adler32_combine, Deflate};
+
+#[cfg(not(any(feature="zlib", feature="rust-deflate")))]
+pub use super::unavailable::{adler32, adler32_initial, adler32_combine, Deflate};
+
+#[cfg(feature="zlib")]
 pub fn adler32(sum: u32, bytes: &[u8]) -> u32 {
     unsafe {
-        ::libz_sys::adler32(c_ulong::from(sum), &bytes[0], bytes.len() as c_uint) as u32
+        ::libz_sys::adler32(c_ulong::from(sum), bytes.as_ptr(), bytes.len() as c_uint) as u32
     }
 }
 
+#[cfg(feature="zlib")]
 pub fn adler32_initial() -> u32 {
     unsafe {
         ::libz_sys::adler32(0, ptr::null(), 0) as u32
     }
 }
 
+#[cfg(feature="zlib")]
 pub fn adler32_combine(sum_a: u32, sum_b: u32, len_b: usize) -> u32 {
     unsafe {
         ::libz_sys::adler32_combine(c_ulong::from(sum_a), c_ulong::from(sum_b), len_b as c_long) as u32
     }
 }
 
+#[derive(Copy, Clone)]
 pub struct Options {
-    level: c_int,
-    method: c_int,
-    window_bits: c_int,
-    mem_level: c_int,
-    strategy: c_int,
+    level: i32,
+    window_bits: i32,
+    strategy: i32,
 }
 
 #[repr(i32)]
 #[derive(Copy, Clone)]
 pub enum Strategy {
-    Default = Z_DEFAULT_STRATEGY,
-    Filtered = Z_FILTERED,
-    HuffmanOnly = Z_HUFFMAN_ONLY,
-    RLE = Z_RLE,
-    Fixed = Z_FIXED,
+    // Same values as zlib's constants.
+    Default = 0,
+    Filtered = 1,
+    HuffmanOnly = 2,
+    RLE = 3,
+    Fixed = 4,
 }
 
 impl TryFrom<u8> for Strategy {
@@ -92,11 +111,10 @@ impl TryFrom<u8> for Strategy {
 impl Options {
     pub fn new() -> Options {
         Options {
-            level: Z_DEFAULT_COMPRESSION,
-            method: Z_DEFLATED,
+            // Same defaults as zlib.
+            level: -1,
             window_bits: 15,
-            mem_level: 8,
-            strategy: Z_DEFAULT_STRATEGY,
+            strategy: Strategy::Default as i32,
         }
     }
 
@@ -104,7 +122,7 @@ impl Options {
     // Compression level, 1 (fast) - 9 (high)
     //
     pub fn set_level(&mut self, level: i32) {
-        self.level = level as c_int;
+        self.level = level;
     }
 
     //
@@ -112,25 +130,44 @@ impl Options {
     // Set negative value for raw stream (no header/checksum)
     //
     pub fn set_window_bits(&mut self, bits: i32) {
-        self.window_bits = bits as c_int;
+        self.window_bits = bits;
     }
 
     pub fn set_strategy(&mut self, strategy: Strategy) {
-        self.strategy = strategy as c_int;
+        self.strategy = strategy as i32;
+    }
+}
+
+// Read back by the miniz_oxide streams.
+#[cfg(all(feature="rust-deflate", not(feature="zlib")))]
+impl Options {
+    pub fn level(&self) -> i32 {
+        self.level
+    }
+
+    pub fn window_bits(&self) -> i32 {
+        self.window_bits
+    }
+
+    pub fn strategy(&self) -> i32 {
+        self.strategy
     }
 }
 
 #[derive(Copy, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Flush {
-    // Only SyncFlush and Finish are used internally.
+    // Only NoFlush, SyncFlush and Finish are used internally.
 
-    //NoFlush = Z_NO_FLUSH as isize,
-    //PartialFlush = Z_PARTIAL_FLUSH as isize,
-    SyncFlush = Z_SYNC_FLUSH as isize,
-    //FullFlush = Z_FULL_FLUSH as isize,
-    Finish = Z_FINISH as isize,
+    // Same values as zlib's constants.
+    NoFlush = 0,
+    //PartialFlush = 1,
+    SyncFlush = 2,
+    //FullFlush = 3,
+    Finish = 4,
 }
 
+#[cfg(feature="zlib")]
 pub struct Deflate<W: Write> {
     output: W,
     options: Options,
@@ -139,6 +176,7 @@ pub struct Deflate<W: Write> {
     stream: Box<z_stream>,
 }
 
+#[cfg(feature="zlib")]
 impl<W: Write> Deflate<W> {
     pub fn new(options: Options, w: W) -> Deflate<W> {
         Deflate {
@@ -160,9 +198,9 @@ impl<W: Write> Deflate<W> {
             let ret = unsafe {
                 deflateInit2_(&mut *self.stream,
                               self.options.level,
-                              self.options.method,
+                              Z_DEFLATED,
                               self.options.window_bits,
-                              self.options.mem_level,
+                              8, // default memory level
                               self.options.strategy,
                               zlibVersion(),
                               mem::size_of::<z_stream>() as c_int)
@@ -184,7 +222,7 @@ impl<W: Write> Deflate<W> {
         self.init()?;
         let ret = unsafe {
             deflateSetDictionary(&mut *self.stream,
-                                 &dict[0],
+                                 dict.as_ptr(),
                                  dict.len() as c_uint)
         };
         match ret {
@@ -194,11 +232,33 @@ impl<W: Write> Deflate<W> {
         }
     }
 
+    //
+    // Make an independent copy of the stream state so far, which
+    // writes any further output to the given writer.
+    //
+    pub fn copy<V: Write>(&mut self, output: V) -> io::Result<Deflate<V>> {
+        self.init()?;
+        let mut copy = Deflate::new(self.options, output);
+        let ret = unsafe {
+            deflateCopy(&mut *copy.stream, &mut *self.stream)
+        };
+        match ret {
+            Z_OK => {
+                copy.initialized = true;
+                copy.finished = self.finished;
+                Ok(copy)
+            },
+            Z_MEM_ERROR => Err(other("Out of memory")),
+            Z_STREAM_ERROR => Err(invalid_input("Inconsistent stream state")),
+            _ => Err(other("Unexpected error")),
+        }
+    }
+
     fn deflate(&mut self, data: &[u8], flush: Flush) -> IoResult {
         self.init()?;
         let mut buffer = [0u8; 128 * 1024];
         let stream = &mut *self.stream;
-        stream.next_in = &data[0] as *const u8 as *mut u8;
+        stream.next_in = data.as_ptr() as *mut u8;
         stream.avail_in = data.len() as c_uint;
         loop {
             stream.next_out = &mut buffer[0] as *mut u8;
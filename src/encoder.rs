@@ -24,30 +24,59 @@
 //
 
 use rayon::ThreadPool;
+use rayon::prelude::*;
 
+use std::cmp;
 use std::collections::VecDeque;
 
 use std::io;
 use std::io::Write;
 
+use std::mem;
+use std::slice;
+
 use std::sync::Arc;
 use std::sync::mpsc;
 use std::sync::mpsc::{Sender, Receiver};
 
 use super::ColorType;
 use super::CompressionLevel;
+use super::Cicp;
+use super::ContentLightLevel;
+use super::FrameControl;
+use super::MasteringDisplay;
+use super::PhysicalUnit;
+use super::RenderingIntent;
 use super::Strategy;
 use super::Header;
+use super::InputFormat;
+use super::InterlaceMethod;
+use super::PixelLayout;
+use super::SampleType;
 use super::Mode;
-use super::Mode::{Adaptive, Fixed};
-
+use super::Mode::{Adaptive, Fixed, BruteForce};
+use super::Palette;
+
+use super::backend::DeflateBackend;
+use super::backend::Segment;
+use super::backend::Builtin;
+use super::convert;
+use super::convert::Converter;
+use super::filter;
 use super::filter::AdaptiveFilter;
 use super::filter::Filter;
+use super::heuristic::FilterHeuristic;
+use super::heuristic::MinSum;
+use super::interlace;
+use super::interlace::ChunkSpan;
+use super::optimal;
+use super::ordering::ChunkOrder;
+use super::ordering::Phase;
+use super::palette;
 use super::writer::Writer;
+use super::exif;
 
 use super::deflate;
-use super::deflate::Deflate;
-use super::deflate::Flush;
 
 use super::utils::*;
 
@@ -60,7 +89,10 @@ pub struct Options<'a> {
     compression_level: CompressionLevel,
     strategy_mode: Mode<Strategy>,
     filter_mode: Mode<Filter>,
+    filter_heuristic: &'static dyn FilterHeuristic,
+    deflate_backend: &'static dyn DeflateBackend,
     streaming: bool,
+    alpha_cleaning: bool,
     thread_pool: Option<&'a ThreadPool>,
 }
 
@@ -70,7 +102,10 @@ impl<'a> Options<'a> {
     /// * compression_level: Default
     /// * strategy_mode: Adaptive
     /// * filter_mode: Adaptive
+    /// * filter_heuristic: MinSum
+    /// * deflate_backend: Builtin
     /// * streaming: off
+    /// * alpha_cleaning: off
     /// * thread_pool: global default
     ///
     /// The compression, strategy, and filtering use the same
@@ -89,6 +124,8 @@ impl<'a> Options<'a> {
             compression_level: CompressionLevel::Default,
             strategy_mode: Adaptive,
             filter_mode: Adaptive,
+            filter_heuristic: &MinSum,
+            deflate_backend: &Builtin,
 
             //
             // Streaming mode can produce lower latency to first bytes hitting
@@ -100,6 +137,12 @@ impl<'a> Options<'a> {
             //
             streaming: false,
 
+            //
+            // Color under fully transparent pixels is kept as given
+            // unless asked for.
+            //
+            alpha_cleaning: false,
+
             //
             // Use the global thread pool.
             //
@@ -129,7 +172,8 @@ impl<'a> Options<'a> {
 
     /// Set the deflate compression level.
     /// Currently supported are Fast (equivalent to gzip -1),
-    /// Default (gzip -6), and High (gzip -9).
+    /// Default (gzip -6), High (gzip -9), and Extreme, which searches
+    /// for the smallest encoding of each chunk like Zopfli does.
     pub fn set_compression_level(&mut self, level: CompressionLevel) -> IoResult {
         self.compression_level = level;
         Ok(())
@@ -143,14 +187,52 @@ impl<'a> Options<'a> {
     /// which often does well but can pick poorly on some images.
     /// Fixed<*> may be used to override the mode for the whole image,
     /// which sometimes produces better results than the heuristic.
+    ///
+    /// BruteForce instead compresses each row with every filter, carrying
+    /// a trial deflate stream across the rows of each chunk, and keeps the
+    /// filter that adds the fewest bytes. This is many times slower, but
+    /// chunks still run in parallel.
     pub fn set_filter_mode(&mut self, filter_mode: Mode<Filter>) -> IoResult {
         self.filter_mode = filter_mode;
         Ok(())
     }
 
+    /// Set the heuristic used by the Adaptive filter mode to pick each
+    /// row's filter. By default it will use MinSum, the same as libpng;
+    /// see the heuristic module for the alternatives, or implement
+    /// FilterHeuristic for your own.
+    ///
+    /// The heuristic is shared with the thread pool, which may outlive
+    /// the encoder, so must be static; unit structs such as `&Entropy`
+    /// are promoted to statics automatically.
+    pub fn set_filter_heuristic(&mut self, heuristic: &'static dyn FilterHeuristic) -> IoResult {
+        self.filter_heuristic = heuristic;
+        Ok(())
+    }
+
+    /// Set the backend used to deflate the image data. By default it
+    /// will use Builtin, which is zlib or miniz_oxide depending on the
+    /// build features; see the backend module, or implement DeflateBackend
+    /// to use another compressor.
+    ///
+    /// As with the filter heuristic, the backend is shared with the
+    /// thread pool so must be static.
+    ///
+    /// Text and other ancillary chunks are always compressed with the
+    /// built-in implementation.
+    pub fn set_deflate_backend(&mut self, backend: &'static dyn DeflateBackend) -> IoResult {
+        self.deflate_backend = backend;
+        Ok(())
+    }
+
     /// Set the deflate compression strategy. By default it will use Adaptive,
     /// which picks Default for Fixed<None> or Filtered for other filter types.
     /// This matches libpng's logic as well.
+    ///
+    /// BruteForce compresses each chunk with the Default, Filtered, RLE and
+    /// HuffmanOnly strategies in parallel, and keeps the smallest result,
+    /// which helps images whose regions differ in content. The Huffman-only
+    /// pass rarely wins, but costs little next to the others.
     pub fn set_strategy_mode(&mut self, strategy_mode: Mode<Strategy>) -> IoResult {
         self.strategy_mode = strategy_mode;
         Ok(())
@@ -164,6 +246,18 @@ impl<'a> Options<'a> {
         self.streaming = streaming;
         Ok(())
     }
+
+    /// Enable or disable alpha cleaning, which rewrites the color of fully
+    /// transparent pixels in images with an alpha channel to whatever the
+    /// filter chosen for each row predicts, so they cost almost nothing to
+    /// compress. The visible image is unchanged, but the hidden color
+    /// under transparent pixels is not preserved.
+    ///
+    /// Has no effect on color types without an alpha channel.
+    pub fn set_alpha_cleaning(&mut self, alpha_cleaning: bool) -> IoResult {
+        self.alpha_cleaning = alpha_cleaning;
+        Ok(())
+    }
 }
 
 impl<'a> Default for Options<'a> {
@@ -176,6 +270,7 @@ impl<'a> Default for Options<'a> {
 // to the deflate jobs.
 struct PixelChunk {
     header: Header,
+    format: InputFormat,
 
     index: usize,
     start_row: usize,
@@ -185,29 +280,32 @@ struct PixelChunk {
 
     stride: usize,
 
-    // Rows of pixel data, each with stride bytes per row
+    // Rows of pixel data in the input format, each with stride bytes per row
     rows: Vec<Vec<u8>>,
 }
 
 impl PixelChunk {
-    fn new(header: Header, index: usize, start_row: usize, end_row: usize) -> PixelChunk {
-        assert!(start_row <= end_row);
+    fn new(span: ChunkSpan, format: InputFormat, index: usize, chunks_total: usize) -> PixelChunk {
+        assert!(span.start_row <= span.end_row);
 
-        let height = header.height as usize;
-        assert!(end_row <= height);
+        let height = span.header.height as usize;
+        assert!(span.end_row <= height);
 
+        // Start and end refer to the whole deflate stream,
+        // which may cover multiple interlace passes.
         PixelChunk {
-            header,
+            header: span.header,
+            format,
 
             index,
-            start_row,
-            end_row,
-            is_start: start_row == 0,
-            is_end: end_row == height,
+            start_row: span.start_row,
+            end_row: span.end_row,
+            is_start: index == 0,
+            is_end: index + 1 == chunks_total,
 
-            stride: header.stride(),
+            stride: format.stride(&span.header),
 
-            rows: Vec::with_capacity(end_row - start_row),
+            rows: Vec::with_capacity(span.end_row - span.start_row),
         }
     }
 
@@ -215,10 +313,13 @@ impl PixelChunk {
         self.rows.len() == (self.end_row - self.start_row)
     }
 
-    fn read_row(&mut self, row: &[u8])
+    // Planar input arrives as one part per plane.
+    fn read_row(&mut self, parts: &[&[u8]])
     {
         let mut row_copy = Vec::with_capacity(self.stride);
-        row_copy.extend_from_slice(row);
+        for part in parts {
+            row_copy.extend_from_slice(part);
+        }
 
         self.rows.push(row_copy);
     }
@@ -244,6 +345,14 @@ struct FilterChunk {
 
     stride: usize,
     filter_mode: Mode<Filter>,
+    filter_heuristic: &'static dyn FilterHeuristic,
+
+    // Number of palette entries to check indices against,
+    // for indexed-color images.
+    palette_length: Option<usize>,
+
+    // Whether to clean the color of fully transparent pixels.
+    alpha_cleaning: bool,
 
     // The input pixels for chunk n-1
     // Needed for its last row only.
@@ -259,10 +368,13 @@ struct FilterChunk {
 impl FilterChunk {
     fn new(prior_input: Option<Arc<PixelChunk>>,
            input: Arc<PixelChunk>,
-           filter_mode: Mode<Filter>) -> FilterChunk
+           filter_mode: Mode<Filter>,
+           filter_heuristic: &'static dyn FilterHeuristic,
+           palette_length: Option<usize>,
+           alpha_cleaning: bool) -> FilterChunk
     {
         // Prepend one byte for the filter selector.
-        let stride = input.stride + 1;
+        let stride = input.header.stride() + 1;
         let nbytes = stride * (input.end_row - input.start_row);
 
         FilterChunk {
@@ -274,6 +386,9 @@ impl FilterChunk {
 
             stride,
             filter_mode,
+            filter_heuristic,
+            palette_length,
+            alpha_cleaning,
 
             prior_input,
             input,
@@ -294,31 +409,67 @@ impl FilterChunk {
     }
 
     //
-    // Run the filtering, on a background thread.
+    // Run the input conversion and filtering, on a background thread.
     //
     fn run(&mut self) -> IoResult {
-        let mut filter = AdaptiveFilter::new(self.input.header, self.filter_mode);
-        let zero = vec![0u8; self.stride - 1];
-        for i in self.start_row .. self.end_row {
-            let prior = if i == self.start_row {
-                match self.prior_input {
-                    Some(ref input) => &input,
-                    None => &self.input, // Won't get used.
-                }
-            } else {
-                &self.input
-            };
-            let prev = if i == 0 {
-                &zero
-            } else {
-                prior.get_row(i - 1)
+        let header = self.input.header;
+        let mut filter = AdaptiveFilter::new(header, self.filter_mode, self.filter_heuristic);
+        let mut converter = Converter::new(header, self.input.format);
+
+        //
+        // With alpha cleaning, each row's transparent pixels are predicted
+        // from the cleaned row above, which in turn depends on the rows
+        // above it. To keep chunks independent, the last row of each chunk
+        // is cleaned from its own left neighbors only, so the next chunk
+        // can reproduce it from the input alone.
+        //
+        let mut edge = if self.alpha_cleaning {
+            vec![0u8; self.stride - 1]
+        } else {
+            Vec::new()
+        };
+
+        // The previous row, converted; all zero for the first row.
+        let mut prev = vec![0u8; self.stride - 1];
+        if self.start_row > 0 {
+            let prior = match self.prior_input {
+                Some(ref input) => input,
+                None => &self.input, // Won't get used.
             };
+            prev.copy_from_slice(converter.convert(prior.get_row(self.start_row - 1))?);
+            if self.alpha_cleaning {
+                filter::clean_alpha(&header, Filter::Sub, &edge, &mut prev);
+            }
+        }
+
+        for i in self.start_row .. self.end_row {
+            let row = converter.convert(self.input.get_row(i))?;
+            if let Some(entries) = self.palette_length {
+                palette::check_indices(&header, entries, row)?;
+            }
 
-            let row = self.input.get_row(i);
+            let is_edge = self.alpha_cleaning && i + 1 == self.end_row;
+            if is_edge {
+                edge.copy_from_slice(row);
+                filter::clean_alpha(&header, Filter::Sub, &prev, &mut edge);
+            }
 
-            let output = filter.filter(prev, row);
+            let output = if is_edge {
+                filter.filter(&prev, &edge)?
+            } else if self.alpha_cleaning {
+                filter.filter_clean(&prev, row)?
+            } else {
+                filter.filter(&prev, row)?
+            };
+            self.data.write_all(output)?;
 
-            self.data.write_all(output)?
+            if is_edge {
+                prev.copy_from_slice(&edge);
+            } else if self.alpha_cleaning {
+                prev.copy_from_slice(filter.cleaned_row());
+            } else {
+                prev.copy_from_slice(row);
+            }
         }
         Ok(())
     }
@@ -331,7 +482,12 @@ struct DeflateChunk {
     is_end: bool,
 
     compression_level: CompressionLevel,
-    strategy: Strategy,
+
+    // Strategies to compress with, keeping the smallest output.
+    strategies: Vec<Strategy>,
+
+    // Does the actual compression.
+    backend: &'static dyn DeflateBackend,
 
     // The filtered pixels for chunk n-1
     // Empty on first chunk.
@@ -350,7 +506,8 @@ struct DeflateChunk {
 
 impl DeflateChunk {
     fn new(compression_level: CompressionLevel,
-           strategy: Strategy,
+           strategies: Vec<Strategy>,
+           backend: &'static dyn DeflateBackend,
            prior_input: Option<Arc<FilterChunk>>,
            input: Arc<FilterChunk>) -> DeflateChunk {
 
@@ -360,7 +517,8 @@ impl DeflateChunk {
             is_end: input.is_end,
 
             compression_level,
-            strategy,
+            strategies,
+            backend,
 
             prior_input,
             input,
@@ -370,56 +528,65 @@ impl DeflateChunk {
     }
 
     fn run(&mut self) -> IoResult {
-        // Run the deflate!
-        // Todo: don't create an empty vector earlier, but reuse it sanely.
-        let data = Vec::<u8>::new();
-
-        let mut options = deflate::Options::new();
-
-        options.set_window_bits(if self.is_start {
-            // 15 means 2^15 (32 KiB), the max supported.
-            15
+        self.data = if self.strategies.len() == 1 {
+            self.compress(self.strategies[0])?
         } else {
-            // Negative forces raw stream output so we don't get
-            // a second header...
-            -15
-        });
-
-        match self.compression_level {
-            CompressionLevel::Default => {},
-            CompressionLevel::Fast => options.set_level(1),
-            CompressionLevel::High => options.set_level(9),
-        }
-        options.set_strategy(self.strategy);
+            // Trying each strategy is independent, so spread them
+            // over the thread pool too; ties go to the first listed.
+            let results = self.strategies.par_iter().map(|&strategy| {
+                self.compress(strategy)
+            }).collect::<io::Result<Vec<Vec<u8>>>>()?;
+            results.into_iter().min_by_key(|data| data.len()).unwrap()
+        };
 
-        let mut encoder = Deflate::new(options, data);
+        // In raw deflate mode we have to calculate the checksum ourselves.
+        self.adler32 = self.backend.adler32(deflate::adler32_initial(), &self.input.data);
+        Ok(())
+    }
 
+    fn compress(&self, strategy: Strategy) -> io::Result<Vec<u8>> {
+        let mut data = Vec::<u8>::new();
 
-        if let Some(ref filter) = self.prior_input {
-            let trailer = filter.get_trailer();
-            encoder.set_dictionary(trailer)?;
+        // The backend only makes raw deflate, so that the chunks can be
+        // joined; the first one carries the stream's zlib header.
+        if self.is_start {
+            data.extend_from_slice(&zlib_header(self.compression_level, strategy));
         }
 
-        encoder.write(&self.input.data, if self.is_end {
-            Flush::Finish
-        } else {
-            Flush::SyncFlush
-        })?;
-
-        // In raw deflate mode we have to calculate the checksum ourselves.
-        self.adler32 = deflate::adler32(1, &self.input.data);
+        let dictionary = self.prior_input.as_ref().map(|filter| filter.get_trailer());
 
-        match encoder.finish() {
-            Ok(data) => {
-                // This seems lame to move the vector back, but it's actually cheap.
-                self.data = data;
-                Ok(())
-            },
-            Err(e) => Err(e)
+        // The Extreme level is mtpng's own optimal parsing compressor,
+        // whichever backend is set.
+        if let CompressionLevel::Extreme = self.compression_level {
+            optimal::compress(dictionary, &self.input.data, strategy, self.is_end, &mut data);
+            return Ok(data);
         }
+
+        let segment = Segment::new(self.compression_level,
+                                   strategy,
+                                   dictionary,
+                                   self.is_end);
+        self.backend.compress(&segment, &self.input.data, &mut data)?;
+        Ok(data)
     }
 }
 
+//
+// The two-byte zlib stream header for a 32 KiB window, with the
+// level hint set the same way zlib itself would.
+//
+fn zlib_header(level: CompressionLevel, strategy: Strategy) -> [u8; 2] {
+    let flevel = match (strategy, level) {
+        (Strategy::HuffmanOnly, _) | (Strategy::RLE, _) | (Strategy::Fixed, _) => 0,
+        (_, CompressionLevel::Fast) => 0,
+        (_, CompressionLevel::Default) => 2,
+        (_, CompressionLevel::High) | (_, CompressionLevel::Extreme) => 3,
+    };
+    let mut header = 0x7800u16 | flevel << 6;
+    header += 31 - header % 31;
+    header.to_be_bytes()
+}
+
 //
 // List of completed chunks, which may come in in any order
 // but are returned in original order, in pairs with the
@@ -540,15 +707,34 @@ pub struct Encoder<'a, W: Write> {
 
     header: Header,
 
-    wrote_header: bool,
-    wrote_palette: bool,
+    // Layout of the rows given to write_image_rows.
+    input_format: InputFormat,
+
+    // Which chunks have been written, and where we are in the file.
+    order: ChunkOrder,
     palette_length: usize,
-    wrote_transparency: bool,
-    started_image: bool,
+    suggested_palettes: Vec<String>,
+
+    // Animation state; num_frames is 0 for a still image.
+    num_frames: u32,
+    frames_written: u32,
+    sequence_number: u32,
+    frame_pending: bool,
+    frame_data: bool,
+
+    // Dimensions of the image currently being encoded: either
+    // the whole image, or an animation frame's region.
+    image_header: Header,
 
     chunks_total: usize,
     chunks_output: usize,
 
+    // Row ranges for each chunk, in output order.
+    spans: Vec<ChunkSpan>,
+
+    // Holds the full image for Adam7 until all rows are available.
+    interlace_buffer: Vec<u8>,
+
     // Accumulates input rows until enough are ready to fire off a filter job.
     pixel_accumulator: Arc<PixelChunk>,
     pixel_index: usize,
@@ -559,6 +745,10 @@ pub struct Encoder<'a, W: Write> {
     filter_chunks: ChunkMap<FilterChunk>,
     deflate_chunks: ChunkMap<DeflateChunk>,
 
+    // Set once a job on the thread pool has failed, after which
+    // its chunk will never arrive to complete the image.
+    failed: bool,
+
     // Accumulates the checksum of all output chunks in turn.
     adler32: u32,
 
@@ -580,17 +770,33 @@ impl<'a, W: Write> Encoder<'a, W> {
             header: Header::new(),
             options: *options,
 
-            wrote_header: false,
-            wrote_palette: false,
+            input_format: InputFormat::new(),
+
+            order: ChunkOrder::new(),
             palette_length: 0,
-            wrote_transparency: false,
-            started_image: false,
+            suggested_palettes: Vec::new(),
+
+            num_frames: 0,
+            frames_written: 0,
+            sequence_number: 0,
+            frame_pending: false,
+            frame_data: false,
+
+            image_header: Header::new(),
 
             chunks_total: 0,
             chunks_output: 0,
 
+            spans: Vec::new(),
+            interlace_buffer: Vec::new(),
+
             // hack, clean this up later
-            pixel_accumulator: Arc::new(PixelChunk::new(Header::new(), 0, 0, 0)),
+            pixel_accumulator: Arc::new(PixelChunk::new(ChunkSpan {
+                header: Header::new(),
+                pass: None,
+                start_row: 0,
+                end_row: 0,
+            }, InputFormat::new(), 0, 1)),
             pixel_index: 0,
             current_row: 0,
 
@@ -598,6 +804,8 @@ impl<'a, W: Write> Encoder<'a, W> {
             filter_chunks: ChunkMap::new(),
             deflate_chunks: ChunkMap::new(),
 
+            failed: false,
+
             adler32: deflate::adler32_initial(),
             idat_buffer: Vec::new(),
 
@@ -610,11 +818,15 @@ impl<'a, W: Write> Encoder<'a, W> {
     /// Consumes the encoder instance.
     pub fn finish(mut self) -> io::Result<W> {
         self.flush()?;
-        if self.is_finished() {
+        if !self.is_finished() {
+            Err(other("Incomplete image input"))
+        } else if self.frames_written < self.num_frames {
+            Err(other("Animation has fewer frames than declared"))
+        } else {
+            self.check_chunk(b"IEND")?;
             self.writer.write_end()?;
+            self.order.record(b"IEND");
             self.writer.finish()
-        } else {
-            Err(other("Incomplete image input"))
         }
     }
 
@@ -653,12 +865,73 @@ impl<'a, W: Write> Encoder<'a, W> {
         }
     }
 
-    fn start_row(&self, index: usize) -> usize {
-        index * self.header.height() as usize / self.chunks_total
+    fn new_accumulator(&self, index: usize) -> Arc<PixelChunk> {
+        Arc::new(PixelChunk::new(self.spans[index], self.input_format, index, self.chunks_total))
+    }
+
+    //
+    // Reset the chunk pipeline to encode a new image or
+    // animation frame with the given dimensions.
+    //
+    fn start_image(&mut self, header: Header) {
+        self.image_header = header;
+        self.spans = interlace::layout_chunks(&header, self.options.chunk_size);
+        self.chunks_total = self.spans.len();
+        self.chunks_output = 0;
+
+        self.pixel_index = 0;
+        self.current_row = 0;
+        self.pixel_chunks = ChunkMap::new();
+        self.filter_chunks = ChunkMap::new();
+        self.deflate_chunks = ChunkMap::new();
+
+        self.adler32 = deflate::adler32_initial();
+        self.idat_buffer.clear();
+
+        self.pixel_chunks.advance();
+        self.pixel_accumulator = self.new_accumulator(0);
+    }
+
+    //
+    // True once rows have been provided for the whole image,
+    // and for every frame declared for an animation.
+    //
+    fn is_image_complete(&self) -> bool {
+        self.current_row == self.image_header.height &&
+            !self.frame_pending &&
+            self.frames_written == self.num_frames
+    }
+
+    //
+    // Check that a chunk may be written at this point. Once all
+    // image data has been provided, waits for it to be output
+    // first so that trailing chunks follow it.
+    //
+    fn check_chunk(&mut self, tag: &[u8]) -> IoResult {
+        if self.order.phase() == Phase::ImageData && self.is_image_complete() {
+            self.flush()?;
+            self.order.finish_image_data();
+        }
+        self.order.check(tag)
+    }
+
+    fn next_sequence_number(&mut self) -> u32 {
+        let sequence_number = self.sequence_number;
+        self.sequence_number += 1;
+        sequence_number
     }
 
-    fn end_row(&self, index: usize) -> usize {
-        self.start_row(index + 1)
+    //
+    // Write compressed data for the current image, as IDAT for
+    // the default image or as fdAT for later animation frames.
+    //
+    fn write_image_data(&mut self, data: &[u8]) -> IoResult {
+        if self.frame_data {
+            let sequence_number = self.next_sequence_number();
+            self.writer.write_frame_data(sequence_number, data)
+        } else {
+            self.writer.write_chunk(b"IDAT", data)
+        }
     }
 
     fn receive(&mut self, blocking: DispatchMode) -> Option<ThreadMessage> {
@@ -680,21 +953,28 @@ impl<'a, W: Write> Encoder<'a, W> {
             Adaptive => match self.header.color_type {
                 ColorType::IndexedColor => Fixed(Filter::None),
                 _                       => Adaptive,
-            }
+            },
+            BruteForce => BruteForce,
         }
     }
 
-    fn compression_strategy(&self) -> Strategy {
+    fn compression_strategies(&self) -> Vec<Strategy> {
         match self.options.strategy_mode {
-            Fixed(s) => s,
+            Fixed(s) => vec![s],
             Adaptive => match self.filter_mode() {
-                Fixed(Filter::None) => Strategy::Default,
-                _                   => Strategy::Filtered,
+                Fixed(Filter::None) => vec![Strategy::Default],
+                _                   => vec![Strategy::Filtered],
             },
+            BruteForce => vec![Strategy::Default, Strategy::Filtered,
+                               Strategy::RLE, Strategy::HuffmanOnly],
         }
     }
 
     fn dispatch(&mut self, mode: DispatchMode) -> IoResult {
+        if self.failed {
+            return Err(other("Image data could not be encoded after an earlier error."));
+        }
+
         // See if anything interesting happened on the threads.
         let mut blocking_mode = mode;
         while self.filter_chunks.in_flight() || self.deflate_chunks.in_flight() {
@@ -706,6 +986,7 @@ impl<'a, W: Write> Encoder<'a, W> {
                     self.deflate_chunks.land(deflate.index, deflate);
                 },
                 Some(ThreadMessage::Error(e)) => {
+                    self.failed = true;
                     return Err(e);
                 }
                 None => {
@@ -724,10 +1005,15 @@ impl<'a, W: Write> Encoder<'a, W> {
                 Some((previous, current)) => {
                     // Prepare to dispatch the deflate job:
                     let level = self.options.compression_level;
-                    let strategy = self.compression_strategy();
+                    let strategies = self.compression_strategies();
+                    let backend = self.options.deflate_backend;
                     self.deflate_chunks.advance();
                     self.dispatch_func(move |tx| {
-                        let mut deflate = DeflateChunk::new(level, strategy, previous.clone(), current.clone());
+                        let mut deflate = DeflateChunk::new(level,
+                                                            strategies.clone(),
+                                                            backend,
+                                                            previous.clone(),
+                                                            current.clone());
                         tx.send(match deflate.run() {
                             Ok(()) => ThreadMessage::DeflateDone(Arc::new(deflate)),
                             Err(e) => ThreadMessage::Error(e),
@@ -747,10 +1033,20 @@ impl<'a, W: Write> Encoder<'a, W> {
                     // Prepare to dispatch the filter job:
                     self.filter_chunks.advance();
                     let filter_mode = self.filter_mode();
+                    let filter_heuristic = self.options.filter_heuristic;
+                    let palette_length = match self.header.color_type {
+                        ColorType::IndexedColor => Some(self.palette_length),
+                        _ => None,
+                    };
+                    let alpha_cleaning = self.options.alpha_cleaning && matches!(self.header.color_type,
+                        ColorType::GreyscaleAlpha | ColorType::TruecolorAlpha);
                     self.dispatch_func(move |tx| {
                         let mut filter = FilterChunk::new(previous.clone(),
                                                           current.clone(),
-                                                          filter_mode);
+                                                          filter_mode,
+                                                          filter_heuristic,
+                                                          palette_length,
+                                                          alpha_cleaning);
                         tx.send(match filter.run() {
                             Ok(()) => ThreadMessage::FilterDone(Arc::new(filter)),
                             Err(e) => ThreadMessage::Error(e),
@@ -770,30 +1066,27 @@ impl<'a, W: Write> Encoder<'a, W> {
             }
 
             // Combine the checksums!
-            self.adler32 = deflate::adler32_combine(self.adler32,
-                                                    current.adler32,
-                                                    current.input.data.len());
+            self.adler32 = self.options.deflate_backend.adler32_combine(self.adler32,
+                                                                        current.adler32,
+                                                                        current.input.data.len());
 
             // if not streaming, append to an in-memory buffer
             // and output a giant tag later.
             if self.options.streaming {
-                self.writer.write_chunk(b"IDAT", &current.data)?;
+                self.write_image_data(&current.data)?;
 
                 if current.is_end {
                     let mut chunk = Vec::<u8>::new();
-                    if !current.is_start {
-                        write_be32(&mut chunk, self.adler32)?;
-                    }
-                    self.writer.write_chunk(b"IDAT", &chunk)?;
+                    write_be32(&mut chunk, self.adler32)?;
+                    self.write_image_data(&chunk)?;
                 }
             } else {
                 self.idat_buffer.write_all(&current.data)?;
 
                 if current.is_end {
-                    if !current.is_start {
-                        write_be32(&mut self.idat_buffer, self.adler32)?;
-                    }
-                    self.writer.write_chunk(b"IDAT", &self.idat_buffer)?;
+                    write_be32(&mut self.idat_buffer, self.adler32)?;
+                    let buffer = mem::take(&mut self.idat_buffer);
+                    self.write_image_data(&buffer)?;
                 }
             }
 
@@ -808,32 +1101,16 @@ impl<'a, W: Write> Encoder<'a, W> {
     ///
     /// Subsequent image data must match the given header data.
     pub fn write_header(&mut self, header: &Header) -> IoResult {
-        if self.wrote_header {
-            return Err(invalid_input("Cannot write header a second time."));
-        }
+        self.check_chunk(b"IHDR")?;
 
         self.header = *header;
-
-        let stride = self.header.stride() + 1;
-        let height = self.header.height as usize;
-
-        let chunks = stride * height / self.options.chunk_size;
-        self.chunks_total = if chunks < 1 {
-            1
-        } else {
-            chunks
-        };
-
-        self.pixel_chunks.advance();
-        self.pixel_accumulator = Arc::new(PixelChunk::new(self.header,
-                                                          0, // index
-                                                          self.start_row(0),
-                                                          self.end_row(0)));
-
-        self.wrote_header = true;
+        self.start_image(self.header);
 
         self.writer.write_signature()?;
-        self.writer.write_header(self.header)
+        self.writer.write_header(self.header)?;
+
+        self.order.record(b"IHDR");
+        Ok(())
     }
 
     /// Write an indexed-color palette as a PLTE chunk.
@@ -843,28 +1120,43 @@ impl<'a, W: Write> Encoder<'a, W> {
     ///
     /// Note this chunk is allowed on truecolor images, though sPLT is preferred.
     pub fn write_palette(&mut self, palette: &[u8]) -> io::Result<()> {
-        if !self.wrote_header {
-            return Err(invalid_input("Cannot write palette before header."));
-        }
-        if self.wrote_palette {
-            return Err(invalid_input("Cannot write palette a second time."));
-        }
-        if self.wrote_transparency {
-            return Err(invalid_input("Cannot write palette after transparency."));
-        }
-        if self.started_image {
-            return Err(invalid_input("Cannot write palette after image data."));
-        }
+        self.check_chunk(b"PLTE")?;
         if palette.len() < 3 {
             return Err(invalid_input("Palette must have at least one entry."));
         }
         if palette.len() % 3 != 0 {
             return Err(invalid_input("Palette must have an integral number of entries."));
         }
+        if palette.len() / 3 > 256 {
+            return Err(invalid_input("Palette cannot have more than 256 entries."));
+        }
+        if matches!(self.header.color_type, ColorType::IndexedColor) &&
+           palette.len() / 3 > 1 << self.header.depth {
+            return Err(invalid_input("Palette has more entries than the bit depth can index."));
+        }
 
-        self.wrote_palette = true;
+        self.writer.write_chunk(b"PLTE", palette)?;
+        self.order.record(b"PLTE");
         self.palette_length = palette.len() / 3;
-        self.writer.write_chunk(b"PLTE", palette)
+        Ok(())
+    }
+
+    /// Write an indexed-color palette of RGBA entries as a PLTE chunk,
+    /// followed by a tRNS chunk of its alpha values unless every entry
+    /// is opaque.
+    ///
+    /// Pixel indices in the image data are checked against the palette
+    /// length as the rows are processed.
+    pub fn write_rgba_palette(&mut self, palette: &Palette) -> IoResult {
+        let transparency = palette.transparency_data();
+        if !transparency.is_empty() && !matches!(self.header.color_type, ColorType::IndexedColor) {
+            return Err(invalid_input("Palette transparency requires indexed color."));
+        }
+        self.write_palette(&palette.palette_data())?;
+        if !transparency.is_empty() {
+            self.write_transparency(&transparency)?;
+        }
+        Ok(())
     }
 
     /// Write a transparency info chunk.
@@ -877,12 +1169,7 @@ impl<'a, W: Write> Encoder<'a, W> {
     ///
     /// https://www.w3.org/TR/PNG/#11tRNS
     pub fn write_transparency(&mut self, data: &[u8]) -> io::Result<()> {
-        if !self.wrote_header {
-            return Err(invalid_input("Cannot write transparency before header."));
-        }
-        if self.started_image {
-            return Err(invalid_input("Cannot write transparency after image data."));
-        }
+        self.check_chunk(b"tRNS")?;
         match self.header.color_type {
             ColorType::Greyscale => {
                 if data.len() != 2 {
@@ -895,7 +1182,7 @@ impl<'a, W: Write> Encoder<'a, W> {
                 }
             },
             ColorType::IndexedColor => {
-                if !self.wrote_palette {
+                if !self.order.has_written(b"PLTE") {
                     return Err(invalid_input("Cannot write transparency before palette."));
                 }
                 if data.is_empty() {
@@ -910,146 +1197,794 @@ impl<'a, W: Write> Encoder<'a, W> {
             }
 
         }
-        self.wrote_transparency = true;
-        self.writer.write_chunk(b"tRNS", data)
+        self.writer.write_chunk(b"tRNS", data)?;
+        self.order.record(b"tRNS");
+        Ok(())
     }
 
-    //
-    // Write a custom ancillary chunk to the output stream.
-    // The tag must be a 4-byte slice. The data should be provided
-    // in the appropriate format for the tag.
-    //
-    pub fn write_chunk(&mut self, tag: &[u8], data: &[u8]) -> io::Result<()> {
-        self.writer.write_chunk(tag, data)
+    /// Write a background color chunk.
+    ///
+    /// For indexed color, contains a single palette index byte.
+    /// For greyscale, a single 16-bit sample; for truecolor, three
+    /// 16-bit samples. Samples must fit in the image's bit depth.
+    ///
+    /// https://www.w3.org/TR/PNG/#11bKGD
+    pub fn write_background(&mut self, data: &[u8]) -> IoResult {
+        self.check_chunk(b"bKGD")?;
+        match self.header.color_type {
+            ColorType::Greyscale | ColorType::GreyscaleAlpha => {
+                if data.len() != 2 {
+                    return Err(invalid_input("Greyscale background data must be exactly 2 bytes."));
+                }
+            },
+            ColorType::Truecolor | ColorType::TruecolorAlpha => {
+                if data.len() != 6 {
+                    return Err(invalid_input("Truecolor background data must be exactly 6 bytes."));
+                }
+            },
+            ColorType::IndexedColor => {
+                if !self.order.has_written(b"PLTE") {
+                    return Err(invalid_input("Cannot write background before palette."));
+                }
+                if data.len() != 1 {
+                    return Err(invalid_input("Indexed-color background data must be exactly 1 byte."));
+                }
+                if data[0] as usize >= self.palette_length {
+                    return Err(invalid_input("Background index must be within the palette."));
+                }
+            },
+        }
+        let max = (1u32 << self.header.depth) - 1;
+        if !matches!(self.header.color_type, ColorType::IndexedColor) &&
+           data.chunks(2).any(|sample| u32::from(read_be16(sample)) > max) {
+            return Err(invalid_input("Background color exceeds the image bit depth."));
+        }
+        self.writer.write_chunk(b"bKGD", data)?;
+        self.order.record(b"bKGD");
+        Ok(())
     }
 
-    //
-    // Copy a row's pixel data into buffers for async compression.
-    // Returns immediately after copying.
-    //
-    fn process_row(&mut self, row: &[u8]) -> io::Result<RowStatus>
-    {
-        if self.pixel_index >= self.chunks_total {
-            return Err(other("invalid internal state"));
+    /// Write a significant bits chunk, giving the number of bits
+    /// per channel that were significant in the original data.
+    ///
+    /// Contains one byte per channel, or three for indexed color,
+    /// each between 1 and the sample depth (8 for indexed color).
+    ///
+    /// https://www.w3.org/TR/PNG/#11sBIT
+    pub fn write_significant_bits(&mut self, data: &[u8]) -> IoResult {
+        self.check_chunk(b"sBIT")?;
+        let (channels, depth) = match self.header.color_type {
+            ColorType::IndexedColor => (3, 8),
+            color_type => (color_type.channels(), self.header.depth),
+        };
+        if data.len() != channels {
+            return Err(invalid_input("Significant bits data must have one byte per channel."));
         }
-        if !self.wrote_header {
-            return Err(invalid_input("Cannot write image data before header."));
+        if data.iter().any(|&bits| bits == 0 || bits > depth) {
+            return Err(invalid_input("Significant bits must be between 1 and the sample depth."));
         }
-        if let ColorType::IndexedColor = self.header.color_type {
-            if !self.wrote_palette {
-                return Err(invalid_input("Cannot write indexed-color image data before palette."));
-            }
+        self.writer.write_chunk(b"sBIT", data)?;
+        self.order.record(b"sBIT");
+        Ok(())
+    }
+
+    /// Write a palette histogram chunk, giving the approximate
+    /// usage frequency of each palette entry.
+    ///
+    /// Must contain exactly one entry per palette entry.
+    ///
+    /// https://www.w3.org/TR/PNG/#11hIST
+    pub fn write_histogram(&mut self, frequencies: &[u16]) -> IoResult {
+        self.check_chunk(b"hIST")?;
+        if !self.order.has_written(b"PLTE") {
+            return Err(invalid_input("Cannot write histogram before palette."));
         }
-        if !self.started_image {
-            self.started_image = true;
+        if frequencies.len() != self.palette_length {
+            return Err(invalid_input("Histogram must have one entry per palette entry."));
         }
+        self.writer.write_histogram(frequencies)?;
+        self.order.record(b"hIST");
+        Ok(())
+    }
 
-        Arc::get_mut(&mut self.pixel_accumulator).unwrap().read_row(row);
-
-        if self.pixel_accumulator.is_full() {
-            // Move the item off to the completed stack...
-            self.pixel_chunks.land(self.pixel_index, self.pixel_accumulator.clone());
-
-            // Make a nice new buffer to accumulate data into.
-            self.pixel_index += 1;
-            if self.pixel_index < self.chunks_total {
-                self.pixel_chunks.advance();
-                self.pixel_accumulator = Arc::new(PixelChunk::new(self.header,
-                                                                  self.pixel_index,
-                                                                  self.start_row(self.pixel_index),
-                                                                  self.end_row(self.pixel_index)));
-            }
-
-            // Dispatch any available async tasks and output.
-            while self.running_jobs() >= self.max_threads() {
-                self.dispatch(DispatchMode::Blocking)?;
-            }
-            self.dispatch(DispatchMode::NonBlocking)?;
+    /// Write a physical pixel dimensions chunk, giving pixels per
+    /// unit on each axis; with PhysicalUnit::Unknown only the aspect
+    /// ratio is defined.
+    ///
+    /// For example, 300 DPI is 11811 pixels per meter.
+    ///
+    /// https://www.w3.org/TR/PNG/#11pHYs
+    pub fn write_physical_dimensions(&mut self, x: u32, y: u32, unit: PhysicalUnit) -> IoResult {
+        self.check_chunk(b"pHYs")?;
+        if x == 0 || y == 0 || x > i32::MAX as u32 || y > i32::MAX as u32 {
+            return Err(invalid_input("Pixels per unit must be between 1 and 2^31 - 1."));
         }
+        self.writer.write_physical_dimensions(x, y, unit)?;
+        self.order.record(b"pHYs");
+        Ok(())
+    }
 
-        self.current_row += 1;
-        if self.current_row == self.header.height {
-            Ok(RowStatus::Done)
-        } else {
-            Ok(RowStatus::Continue)
+    /// Write a modification time chunk, in UTC.
+    ///
+    /// May be written anywhere after the header, including after
+    /// all image data has been provided.
+    ///
+    /// https://www.w3.org/TR/PNG/#11tIME
+    pub fn write_time(&mut self, year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> IoResult {
+        self.check_chunk(b"tIME")?;
+        if !(1 ..= 12).contains(&month) || !(1 ..= 31).contains(&day) {
+            return Err(invalid_input("Invalid date."));
+        }
+        // Seconds may be 60 to allow for leap seconds.
+        if hour > 23 || minute > 59 || second > 60 {
+            return Err(invalid_input("Invalid time of day."));
         }
+        self.writer.write_time(year, month, day, hour, minute, second)?;
+        self.order.record(b"tIME");
+        Ok(())
     }
 
-    /// Encode and compress the given image data and write to output.
-    /// Input data must be packed in the correct format for the given
-    /// color type and depth, with no padding at the end of rows.
+    /// Write a named suggested palette chunk.
     ///
-    /// An integral number of rows must be provided at once.
+    /// Each entry holds red, green, blue, and alpha samples of the
+    /// given depth (8 or 16) followed by a 16-bit frequency, for 6 or
+    /// 10 bytes per entry. Multiple suggested palettes may be written,
+    /// but their names must differ.
     ///
-    /// If not all of the image rows are provided, multiple calls are
-    /// required to finish out the data.
-    pub fn write_image_rows(&mut self, buf: &[u8]) -> IoResult {
-        let stride = self.header.stride();
-        if buf.len() % stride != 0 {
-            Err(invalid_input("Buffer must be an integral number of rows"))
-        } else {
-            for row in buf.chunks(stride) {
-                self.process_row(& &*row)?;
-            }
-            Ok(())
+    /// https://www.w3.org/TR/PNG/#11sPLT
+    pub fn write_suggested_palette(&mut self, name: &str, depth: u8, entries: &[u8]) -> IoResult {
+        self.check_chunk(b"sPLT")?;
+        let entry_size = match depth {
+            8 => 6,
+            16 => 10,
+            _ => return Err(invalid_input("Suggested palette depth must be 8 or 16.")),
+        };
+        if entries.len() % entry_size != 0 {
+            return Err(invalid_input("Suggested palette must have an integral number of entries."));
+        }
+        if self.suggested_palettes.iter().any(|other| other == name) {
+            return Err(invalid_input("Suggested palette names must be unique."));
         }
+        self.writer.write_suggested_palette(name, depth, entries)?;
+        self.order.record(b"sPLT");
+        self.suggested_palettes.push(name.to_string());
+        Ok(())
     }
 
-    /// Return completion progress as a fraction of 1.0
+    /// Write an eXIf chunk with EXIF metadata.
     ///
-    /// Currently progress is measured in chunks, so small files may
-    /// not report values between 0.0 and 1.0.
-    pub fn progress(&self) -> f64 {
-        self.chunks_output as f64 / self.chunks_total as f64
-    }
+    /// Data must begin with a TIFF header ("II" or "MM" byte order),
+    /// without the "Exif\0\0" prefix used in JPEG files. Use the
+    /// exif module to read or update the Orientation tag first.
+    ///
+    /// https://www.w3.org/TR/png-3/#eXIf
+    pub fn write_exif(&mut self, data: &[u8]) -> IoResult {
+        self.check_chunk(b"eXIf")?;
+        exif::check_header(data)?;
 
-    /// Return finished-ness state.
-    /// Is it finished? Yeah or no.
-    pub fn is_finished(&self) -> bool {
-        self.chunks_output == self.chunks_total
+        self.writer.write_chunk(b"eXIf", data)?;
+        self.order.record(b"eXIf");
+        Ok(())
     }
 
-    /// Flush all currently in-progress data to output
-    /// Warning: this may block.
-    pub fn flush(&mut self) -> IoResult {
-        while self.chunks_output < self.pixel_index {
-            // Dispatch any available async tasks and output.
-            self.dispatch(DispatchMode::Blocking)?;
+    /// Write a gAMA chunk giving the image gamma, times 100000.
+    ///
+    /// For example, 45455 encodes a gamma of 1/2.2.
+    ///
+    /// https://www.w3.org/TR/PNG/#11gAMA
+    pub fn write_gamma(&mut self, gamma: u32) -> IoResult {
+        self.check_chunk(b"gAMA")?;
+        if gamma == 0 || gamma > i32::MAX as u32 {
+            return Err(invalid_input("Gamma must be between 1 and 2^31 - 1."));
         }
+
+        self.writer.write_gamma(gamma)?;
+        self.order.record(b"gAMA");
         Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::super::Header;
-    use super::super::ColorType;
-    use super::Encoder;
-    use super::Options;
-    use super::IoResult;
-
-    use std::io;
 
-    fn test_encoder<F>(width: u32, height: u32, func: F)
-        where F: Fn(&mut Encoder<Vec<u8>>, &[u8]) -> IoResult
+    /// Write a cHRM chunk giving the CIE 1931 x,y chromaticities
+    /// of the white point and the red, green, and blue primaries,
+    /// each times 100000.
+    ///
+    /// https://www.w3.org/TR/PNG/#11cHRM
+    pub fn write_chromaticities(&mut self,
+                                white_point: (u32, u32),
+                                red: (u32, u32),
+                                green: (u32, u32),
+                                blue: (u32, u32))
+    -> IoResult
     {
-        match {
-            || -> io::Result<Vec<u8>> {
-                let mut data = Vec::<u8>::with_capacity(width as usize * 3);
-                for i in 0 .. width as usize * 3 {
-                    data.push((i % 255) as u8);
-                }
-
-                let writer = Vec::<u8>::new();
-                let options = Options::new();
-                let mut encoder = Encoder::new(writer, &options);
+        self.check_chunk(b"cHRM")?;
+        let points = [white_point, red, green, blue];
+        if points.iter().any(|&(x, y)| x > i32::MAX as u32 || y > i32::MAX as u32) {
+            return Err(invalid_input("Chromaticities cannot exceed 2^31 - 1."));
+        }
 
-                let mut header = Header::new();
-                header.set_size(width, height).unwrap();
-                header.set_color(ColorType::Truecolor, 8).unwrap();
-                encoder.write_header(&header)?;
+        self.writer.write_chromaticities(&points)?;
+        self.order.record(b"cHRM");
+        Ok(())
+    }
 
-                func(&mut encoder, &data)?;
+    /// Write an sRGB chunk declaring the image to be in the sRGB
+    /// color space, with the given rendering intent.
+    ///
+    /// Cannot be combined with an ICC profile.
+    ///
+    /// https://www.w3.org/TR/PNG/#11sRGB
+    pub fn write_srgb(&mut self, intent: RenderingIntent) -> IoResult {
+        self.check_chunk(b"sRGB")?;
+        if self.order.has_written(b"iCCP") {
+            return Err(invalid_input("Cannot write both sRGB and an ICC profile."));
+        }
+
+        self.writer.write_srgb(intent)?;
+        self.order.record(b"sRGB");
+        Ok(())
+    }
+
+    /// Write an iCCP chunk with an embedded ICC color profile.
+    ///
+    /// The profile name follows the same rules as text keywords;
+    /// the raw profile data is compressed before writing.
+    ///
+    /// Cannot be combined with sRGB.
+    ///
+    /// https://www.w3.org/TR/PNG/#11iCCP
+    pub fn write_icc_profile(&mut self, name: &str, profile: &[u8]) -> IoResult {
+        self.check_chunk(b"iCCP")?;
+        if self.order.has_written(b"sRGB") {
+            return Err(invalid_input("Cannot write both sRGB and an ICC profile."));
+        }
+
+        self.writer.write_icc_profile(name, profile)?;
+        self.order.record(b"iCCP");
+        Ok(())
+    }
+
+    /// Write a cICP chunk identifying the image's color space by
+    /// H.273 code points, such as BT.2100 PQ or HLG for HDR images.
+    ///
+    /// Decoders that understand cICP give it precedence over the
+    /// other color space chunks.
+    ///
+    /// https://www.w3.org/TR/png-3/#cICP-chunk
+    pub fn write_cicp(&mut self, cicp: &Cicp) -> IoResult {
+        self.check_chunk(b"cICP")?;
+        self.writer.write_cicp(cicp)?;
+        self.order.record(b"cICP");
+        Ok(())
+    }
+
+    /// Write an mDCV chunk describing the mastering display
+    /// color volume of HDR content.
+    ///
+    /// https://www.w3.org/TR/png-3/#mDCV-chunk
+    pub fn write_mastering_display(&mut self, display: &MasteringDisplay) -> IoResult {
+        self.check_chunk(b"mDCV")?;
+        self.writer.write_mastering_display(display)?;
+        self.order.record(b"mDCV");
+        Ok(())
+    }
+
+    /// Write a cLLI chunk giving the content light levels
+    /// of HDR content.
+    ///
+    /// https://www.w3.org/TR/png-3/#cLLI-chunk
+    pub fn write_content_light_level(&mut self, level: &ContentLightLevel) -> IoResult {
+        self.check_chunk(b"cLLI")?;
+        self.writer.write_content_light_level(level)?;
+        self.order.record(b"cLLI");
+        Ok(())
+    }
+
+    /// Declare the image as an animated PNG, with the given number of
+    /// frames and number of times to loop (0 for infinite looping).
+    ///
+    /// Must be written after the header and before any image data.
+    /// Each frame is then started with write_frame_control and its
+    /// pixel data supplied with write_image_rows.
+    ///
+    /// If image data is written before the first frame control, it
+    /// becomes a default image shown by decoders that do not support
+    /// animation, and is not part of the animation.
+    ///
+    /// https://wiki.mozilla.org/APNG_Specification
+    pub fn write_animation_control(&mut self, num_frames: u32, num_plays: u32) -> IoResult {
+        self.check_chunk(b"acTL")?;
+        if num_frames == 0 || num_frames > i32::MAX as u32 {
+            return Err(invalid_input("Animation frame count must be between 1 and 2^31 - 1."));
+        }
+        if num_plays > i32::MAX as u32 {
+            return Err(invalid_input("Animation play count cannot exceed 2^31 - 1."));
+        }
+
+        self.writer.write_animation_control(num_frames, num_plays)?;
+        self.order.record(b"acTL");
+        self.num_frames = num_frames;
+        Ok(())
+    }
+
+    /// Start a new animation frame, writing its fcTL chunk.
+    ///
+    /// Image data for the frame must follow with write_image_rows,
+    /// packed with the frame's width rather than the full image's.
+    ///
+    /// If this comes before any image data, the first frame is also
+    /// the default image and must cover the whole image area.
+    pub fn write_frame_control(&mut self, frame: &FrameControl) -> IoResult {
+        if self.num_frames == 0 {
+            return Err(invalid_input("Cannot write frame control before animation control."));
+        }
+        if self.frame_pending {
+            return Err(invalid_input("Cannot write frame control before the previous frame's image data."));
+        }
+        if self.frames_written == self.num_frames {
+            return Err(invalid_input("Cannot write more frames than declared in animation control."));
+        }
+        self.check_chunk(b"fcTL")?;
+        if u64::from(frame.x_offset) + u64::from(frame.width) > u64::from(self.header.width) ||
+           u64::from(frame.y_offset) + u64::from(frame.height) > u64::from(self.header.height) {
+            return Err(invalid_input("Frame must fit within the image."));
+        }
+
+        if self.order.phase() == Phase::ImageData {
+            if self.current_row < self.image_header.height {
+                return Err(invalid_input("Cannot start a new frame before the previous image data is complete."));
+            }
+            self.flush()?;
+
+            let mut frame_header = self.header;
+            frame_header.set_size(frame.width, frame.height)?;
+            self.frame_data = true;
+            self.start_image(frame_header);
+        } else if frame.x_offset != 0 || frame.y_offset != 0 ||
+                  frame.width != self.header.width || frame.height != self.header.height {
+            return Err(invalid_input("First frame must cover the whole image when used as the default image."));
+        }
+
+        let sequence_number = self.next_sequence_number();
+        self.writer.write_frame_control(sequence_number, frame)?;
+        self.order.record(b"fcTL");
+        self.frames_written += 1;
+        self.frame_pending = true;
+        Ok(())
+    }
+
+    /// Write a tEXt chunk with uncompressed text.
+    ///
+    /// Keyword and text must be representable in Latin-1; the keyword
+    /// must be 1-79 characters with no leading, trailing, or consecutive
+    /// spaces. May be written anywhere after the header, including after
+    /// all image data has been provided.
+    ///
+    /// https://www.w3.org/TR/PNG/#11tEXt
+    pub fn write_text(&mut self, keyword: &str, text: &str) -> IoResult {
+        self.check_chunk(b"tEXt")?;
+        self.writer.write_text(keyword, text)?;
+        self.order.record(b"tEXt");
+        Ok(())
+    }
+
+    /// Write a zTXt chunk with zlib-compressed text.
+    ///
+    /// Same restrictions apply as for write_text.
+    ///
+    /// https://www.w3.org/TR/PNG/#11zTXt
+    pub fn write_compressed_text(&mut self, keyword: &str, text: &str) -> IoResult {
+        self.check_chunk(b"zTXt")?;
+        self.writer.write_compressed_text(keyword, text)?;
+        self.order.record(b"zTXt");
+        Ok(())
+    }
+
+    /// Write an iTXt chunk with UTF-8 text, optionally compressed.
+    ///
+    /// The keyword has the same restrictions as for write_text. The
+    /// language tag may be empty, or an RFC 3066 tag such as "en-GB";
+    /// the translated keyword may be empty.
+    ///
+    /// https://www.w3.org/TR/PNG/#11iTXt
+    pub fn write_international_text(&mut self,
+                                    keyword: &str,
+                                    language_tag: &str,
+                                    translated_keyword: &str,
+                                    text: &str,
+                                    compressed: bool)
+    -> IoResult
+    {
+        self.check_chunk(b"iTXt")?;
+        self.writer.write_international_text(keyword, language_tag, translated_keyword, text, compressed)?;
+        self.order.record(b"iTXt");
+        Ok(())
+    }
+
+    /// Write a custom ancillary chunk to the output stream.
+    /// The tag must be 4 ASCII letters. The data should be provided
+    /// in the appropriate format for the tag.
+    ///
+    /// Known chunk types must follow their placement rules, and
+    /// critical chunks must be written with their own methods.
+    /// Unknown chunks must be ancillary, and may come before or
+    /// after the image data.
+    ///
+    /// Once all image data has been provided, this waits for it to be
+    /// written out so that the chunk follows it.
+    ///
+    /// https://www.w3.org/TR/png-3/#5ChunkOrdering
+    pub fn write_chunk(&mut self, tag: &[u8], data: &[u8]) -> io::Result<()> {
+        if self.order.phase() == Phase::ImageData && self.is_image_complete() {
+            self.flush()?;
+            self.order.finish_image_data();
+        }
+        self.order.check_custom(tag)?;
+
+        self.writer.write_chunk(tag, data)?;
+        self.order.record(tag);
+        Ok(())
+    }
+
+    //
+    // Copy a row's pixel data into buffers for async compression.
+    // Returns immediately after copying.
+    //
+    fn process_row(&mut self, parts: &[&[u8]]) -> io::Result<RowStatus>
+    {
+        if self.order.phase() < Phase::ImageData {
+            self.order.check(b"IDAT")?;
+            if let ColorType::IndexedColor = self.header.color_type {
+                if !self.order.has_written(b"PLTE") {
+                    return Err(invalid_input("Cannot write indexed-color image data before palette."));
+                }
+            }
+        }
+        if self.current_row >= self.image_header.height {
+            return Err(invalid_input("Cannot write more rows than the image height."));
+        }
+        self.order.record(if self.frame_data { b"fdAT" } else { b"IDAT" });
+        self.frame_pending = false;
+
+        match self.image_header.interlace_method {
+            InterlaceMethod::Standard => {
+                self.accumulate_row(parts)?;
+            },
+            InterlaceMethod::Adam7 => {
+                // Every pass draws on rows from across the whole image,
+                // so nothing can be filtered until the last row arrives.
+                for part in parts {
+                    self.interlace_buffer.extend_from_slice(part);
+                }
+                if self.current_row + 1 == self.image_header.height {
+                    self.accumulate_passes()?;
+                }
+            },
+        }
+
+        self.current_row += 1;
+        if self.current_row == self.image_header.height {
+            Ok(RowStatus::Done)
+        } else {
+            Ok(RowStatus::Continue)
+        }
+    }
+
+    //
+    // Split the buffered full-size image into its Adam7 passes,
+    // feeding each reduced row to the chunk accumulators in turn.
+    //
+    fn accumulate_passes(&mut self) -> IoResult {
+        let image = mem::take(&mut self.interlace_buffer);
+        let header = self.image_header;
+        let stride = self.input_format.stride(&header);
+
+        // Planar rows are split up plane by plane.
+        let planes = self.input_format.planes(&header);
+        let plane_stride = stride / planes;
+        let bits = self.input_format.bits_per_pixel(&header) / planes;
+        let mut pass_row = Vec::new();
+
+        for pass in interlace::PASSES.iter() {
+            if pass.header(&header).is_none() {
+                continue;
+            }
+            for y in pass.rows(&header) {
+                pass_row.clear();
+                for plane in image[y * stride .. (y + 1) * stride].chunks(plane_stride) {
+                    pass.extract_row(&header, bits, plane, &mut pass_row);
+                }
+                self.accumulate_row(&[&pass_row])?;
+            }
+        }
+        Ok(())
+    }
+
+    //
+    // Add a row to the current pixel chunk, dispatching it for
+    // filtering once it's full.
+    //
+    fn accumulate_row(&mut self, parts: &[&[u8]]) -> IoResult {
+        if self.pixel_index >= self.chunks_total {
+            return Err(other("invalid internal state"));
+        }
+
+        Arc::get_mut(&mut self.pixel_accumulator).unwrap().read_row(parts);
+
+        if self.pixel_accumulator.is_full() {
+            // Move the item off to the completed stack...
+            self.pixel_chunks.land(self.pixel_index, self.pixel_accumulator.clone());
+
+            // Make a nice new buffer to accumulate data into.
+            self.pixel_index += 1;
+            if self.pixel_index < self.chunks_total {
+                self.pixel_chunks.advance();
+                self.pixel_accumulator = self.new_accumulator(self.pixel_index);
+            }
+
+            // Dispatch any available async tasks and output.
+            while self.running_jobs() >= self.max_threads() {
+                self.dispatch(DispatchMode::Blocking)?;
+            }
+            self.dispatch(DispatchMode::NonBlocking)?;
+        }
+        Ok(())
+    }
+
+    /// Set the layout of rows given to write_image_rows, if they are not
+    /// already packed in the PNG format. Conversion is done on the
+    /// worker threads along with filtering.
+    ///
+    /// May be changed between images or animation frames, but not
+    /// while one is partly written.
+    pub fn set_input_format(&mut self, format: &InputFormat) -> IoResult {
+        let mut format = *format;
+        format.samples = self.input_format.samples;
+        self.change_input_format(format)
+    }
+
+    fn change_input_format(&mut self, format: InputFormat) -> IoResult {
+        if self.current_row > 0 && self.current_row < self.image_header.height {
+            return Err(invalid_input("Cannot change input format in the middle of an image."));
+        }
+        self.input_format = format;
+        if self.current_row == 0 && self.order.phase() > Phase::Start {
+            // The first chunk's accumulator was already set up.
+            self.pixel_accumulator = self.new_accumulator(self.pixel_index);
+        }
+        Ok(())
+    }
+
+    //
+    // Switch the type of input samples, which is set by the
+    // entry point used for each image.
+    //
+    fn set_sample_type(&mut self, samples: SampleType) -> IoResult {
+        if self.input_format.samples == samples {
+            return Ok(());
+        }
+        let mut format = self.input_format;
+        format.samples = samples;
+        self.change_input_format(format)
+    }
+
+    /// Encode and compress the given image data and write to output.
+    /// Input data must be packed in the correct format for the given
+    /// color type and depth, with no padding at the end of rows,
+    /// unless a different input format has been set.
+    ///
+    /// For animation frames, rows are the width of the frame's region.
+    ///
+    /// An integral number of rows must be provided at once.
+    ///
+    /// If not all of the image rows are provided, multiple calls are
+    /// required to finish out the data.
+    pub fn write_image_rows(&mut self, buf: &[u8]) -> IoResult {
+        if self.input_format.layout() == PixelLayout::Planar {
+            return Err(invalid_input("Planar input must be written with write_image_planes"));
+        }
+        self.set_sample_type(SampleType::Bytes)?;
+        self.write_surface(&[buf])
+    }
+
+    /// Encode and compress the given 16-bit image data, as with
+    /// write_image_rows, taking samples in native byte order.
+    /// Requires a 16-bit image. Row pitches are given in bytes.
+    ///
+    /// Byte swapping is done on the worker threads. Each image or
+    /// animation frame must be written with a single entry point.
+    pub fn write_image_rows_u16(&mut self, buf: &[u16]) -> IoResult {
+        if self.input_format.layout() == PixelLayout::Planar {
+            return Err(invalid_input("Planar input must be written with write_image_planes"));
+        }
+        self.set_sample_type(SampleType::U16)?;
+
+        // Safe as u8 has no alignment requirements or invalid values.
+        let bytes = unsafe {
+            slice::from_raw_parts(buf.as_ptr() as *const u8, mem::size_of_val(buf))
+        };
+        self.write_surface(&[bytes])
+    }
+
+    /// Encode and compress the given floating-point image data, as with
+    /// write_image_rows. Samples are quantised to the image's depth,
+    /// which must be 8 or 16 bits, using the input format's transfer
+    /// function. Row pitches are given in bytes.
+    ///
+    /// Quantisation is done on the worker threads. Each image or
+    /// animation frame must be written with a single entry point.
+    pub fn write_image_rows_f32(&mut self, buf: &[f32]) -> IoResult {
+        if self.input_format.layout() == PixelLayout::Planar {
+            return Err(invalid_input("Planar input must be written with write_image_planes"));
+        }
+        self.set_sample_type(SampleType::F32)?;
+
+        // Safe as u8 has no alignment requirements or invalid values.
+        let bytes = unsafe {
+            slice::from_raw_parts(buf.as_ptr() as *const u8, mem::size_of_val(buf))
+        };
+        self.write_surface(&[bytes])
+    }
+
+    /// Encode and compress the given planar image data and write to output,
+    /// as with write_image_rows. Requires the Planar pixel layout.
+    ///
+    /// There must be one plane per channel, in PNG channel order, each
+    /// holding the same rows of that channel's samples.
+    pub fn write_image_planes(&mut self, planes: &[&[u8]]) -> IoResult {
+        if self.input_format.layout() != PixelLayout::Planar {
+            return Err(invalid_input("Planar input requires the Planar pixel layout"));
+        }
+        if planes.len() != self.image_header.color_type.channels() {
+            return Err(invalid_input("Planar input must have one plane per channel"));
+        }
+        if planes.iter().any(|plane| plane.len() != planes[0].len()) {
+            return Err(invalid_input("Planes must all be the same length"));
+        }
+        self.set_sample_type(SampleType::Bytes)?;
+        self.write_surface(planes)
+    }
+
+    //
+    // Walk the rows of the input buffers, or planes, according to
+    // the input format.
+    //
+    fn write_surface(&mut self, planes: &[&[u8]]) -> IoResult {
+        let format = self.input_format;
+        convert::check_format(&self.image_header, &format)?;
+
+        let stride = format.stride(&self.image_header) / planes.len();
+        let pitch = if format.pitch() == 0 {
+            stride
+        } else {
+            format.pitch()
+        };
+        let (x_offset, y_offset) = format.crop_offset().unwrap_or((0, 0));
+
+        let x_bits = x_offset as usize * format.bits_per_pixel(&self.image_header) / planes.len();
+        if x_bits & 7 != 0 {
+            return Err(invalid_input("Crop offset must fall on a byte boundary"));
+        }
+        let start = x_bits >> 3;
+        let end = start + stride;
+        if end > pitch {
+            return Err(invalid_input("Row pitch is too short for the image width"));
+        }
+
+        // The last row's padding may be left off.
+        let len = planes[0].len();
+        let remainder = len % pitch;
+        let rows = if remainder == 0 {
+            len / pitch
+        } else if remainder >= end {
+            len / pitch + 1
+        } else {
+            return Err(invalid_input("Buffer must be an integral number of rows"));
+        };
+
+        // A cropped region skips rows above and below it.
+        let (first, last) = if format.crop_offset().is_some() {
+            let first = cmp::min(y_offset as usize, rows);
+            let remaining = (self.image_header.height - self.current_row) as usize;
+            (first, first + cmp::min(rows - first, remaining))
+        } else {
+            (0, rows)
+        };
+
+        let mut parts = Vec::with_capacity(planes.len());
+        for row in first .. last {
+            let index = if format.bottom_up() {
+                rows - 1 - row
+            } else {
+                row
+            };
+            let offset = index * pitch;
+
+            parts.clear();
+            parts.extend(planes.iter().map(|plane| &plane[offset + start .. offset + end]));
+            self.process_row(&parts)?;
+        }
+        Ok(())
+    }
+
+    /// Return completion progress as a fraction of 1.0
+    ///
+    /// Currently progress is measured in chunks, so small files may
+    /// not report values between 0.0 and 1.0.
+    pub fn progress(&self) -> f64 {
+        self.chunks_output as f64 / self.chunks_total as f64
+    }
+
+    /// Return finished-ness state.
+    /// Is it finished? Yeah or no.
+    pub fn is_finished(&self) -> bool {
+        self.chunks_output == self.chunks_total
+    }
+
+    /// Flush all currently in-progress data to output
+    /// Warning: this may block.
+    pub fn flush(&mut self) -> IoResult {
+        while self.chunks_output < self.pixel_index {
+            // Dispatch any available async tasks and output.
+            self.dispatch(DispatchMode::Blocking)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Header;
+    use super::super::ColorType;
+    use super::super::CompressionLevel;
+    use super::super::InputFormat;
+    use super::super::InterlaceMethod;
+    use super::super::PixelLayout;
+    use super::super::FrameControl;
+    use super::super::RenderingIntent;
+    use super::super::PhysicalUnit;
+    use super::super::exif;
+    use super::super::Cicp;
+    use super::super::ContentLightLevel;
+    use super::super::MasteringDisplay;
+    use super::super::Palette;
+    use super::super::Mode;
+    use super::super::decoder;
+    use super::super::decoder::Decoder;
+    use super::super::filter::Filter;
+    use super::super::backend::{Builtin, DeflateBackend, Segment};
+    use super::super::heuristic::{Bigrams, Entropy, MinSumWithNone};
+    use super::Encoder;
+    use super::Options;
+    use super::IoResult;
+
+    use std::io;
+
+    fn test_encoder<F>(width: u32, height: u32, func: F)
+        where F: Fn(&mut Encoder<Vec<u8>>, &[u8]) -> IoResult
+    {
+        let mut header = Header::new();
+        header.set_size(width, height).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        test_encoder_header(&header, func);
+    }
+
+    fn test_encoder_header<F>(header: &Header, func: F)
+        where F: Fn(&mut Encoder<Vec<u8>>, &[u8]) -> IoResult
+    {
+        match {
+            || -> io::Result<Vec<u8>> {
+                let stride = header.stride();
+                let mut data = Vec::<u8>::with_capacity(stride);
+                for i in 0 .. stride {
+                    data.push((i % 255) as u8);
+                }
+
+                let writer = Vec::<u8>::new();
+                let options = Options::new();
+                let mut encoder = Encoder::new(writer, &options);
+
+                encoder.write_header(header)?;
+
+                func(&mut encoder, &data)?;
                 encoder.finish()
             }()
         } {
@@ -1092,4 +2027,597 @@ mod tests {
             Ok(())
         });
     }
+
+    #[test]
+    fn test_adam7() {
+        let mut header = Header::new();
+        header.set_size(1920, 1080).unwrap();
+        header.set_color(ColorType::Greyscale, 4).unwrap();
+        header.set_interlace_method(InterlaceMethod::Adam7).unwrap();
+        test_encoder_header(&header, |encoder, data| {
+            for _y in 0 .. 1079 {
+                encoder.write_image_rows(data)?;
+            }
+
+            // Nothing can be output until the last row is in.
+            assert_eq!(encoder.progress(), 0.0);
+            encoder.write_image_rows(data)?;
+
+            encoder.flush()?;
+            assert!(encoder.is_finished());
+
+            Ok(())
+        });
+    }
+
+    fn encode_bilevel(interlace_method: InterlaceMethod, unpacked: bool, value: u8) -> io::Result<Vec<u8>> {
+        let mut header = Header::new();
+        header.set_size(640, 480)?;
+        header.set_color(ColorType::Greyscale, 1)?;
+        header.set_interlace_method(interlace_method)?;
+
+        let mut format = InputFormat::new();
+        format.set_unpacked(unpacked)?;
+
+        let options = Options::new();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header)?;
+        encoder.set_input_format(&format)?;
+        for y in 0 .. 480 {
+            let pixels = (0 .. 640).map(|x| if (x + y) % 3 == 0 { value } else { 0 });
+            let row: Vec<u8> = if unpacked {
+                pixels.collect()
+            } else {
+                let mut row = vec![0u8; 80];
+                for (x, pixel) in pixels.enumerate() {
+                    row[x >> 3] |= pixel << (7 - (x & 7));
+                }
+                row
+            };
+            encoder.write_image_rows(&row)?;
+        }
+        encoder.finish()
+    }
+
+    #[test]
+    fn test_unpacked() {
+        for &interlace_method in &[InterlaceMethod::Standard, InterlaceMethod::Adam7] {
+            let packed = encode_bilevel(interlace_method, false, 1).unwrap();
+            let unpacked = encode_bilevel(interlace_method, true, 1).unwrap();
+            assert!(packed == unpacked);
+
+            assert!(encode_bilevel(interlace_method, true, 2).is_err());
+        }
+    }
+
+    fn encode_with_format(header: &Header, format: &InputFormat, buf: &[u8]) -> io::Result<Vec<u8>> {
+        let options = Options::new();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(header)?;
+        encoder.set_input_format(format)?;
+        encoder.write_image_rows(buf)?;
+        encoder.finish()
+    }
+
+    #[test]
+    fn test_surface() {
+        let mut header = Header::new();
+        header.set_size(64, 48).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let pixel = |x: usize, y: usize, c: usize| ((x * 3 + y * 7 + c * 11) % 251) as u8;
+
+        let mut packed = Vec::new();
+        for y in 0 .. 48 {
+            for x in 0 .. 64 {
+                for c in 0 .. 3 {
+                    packed.push(pixel(x, y, c));
+                }
+            }
+        }
+        let expected = encode_with_format(&header, &InputFormat::new(), &packed).unwrap();
+
+        // The image sits at (10, 20) in a 100x80 bottom-up surface,
+        // with rows padded to 320 bytes.
+        let mut surface = vec![0u8; 320 * 80];
+        for y in 0 .. 48 {
+            let row = 79 - (y + 20);
+            for x in 0 .. 64 {
+                for c in 0 .. 3 {
+                    surface[row * 320 + (x + 10) * 3 + c] = pixel(x, y, c);
+                }
+            }
+        }
+        let mut format = InputFormat::new();
+        format.set_pitch(320).unwrap();
+        format.set_bottom_up(true).unwrap();
+        format.set_crop_offset(10, 20).unwrap();
+        assert!(encode_with_format(&header, &format, &surface).unwrap() == expected);
+
+        // Padding may be left off the last row.
+        assert!(encode_with_format(&header, &format, &surface[.. 320 * 79 + 222]).unwrap() == expected);
+        assert!(encode_with_format(&header, &format, &surface[.. 320 * 79 + 221]).is_err());
+
+        format.set_pitch(200).unwrap();
+        assert!(encode_with_format(&header, &format, &surface).is_err());
+    }
+
+    #[test]
+    fn test_layouts() {
+        let mut header = Header::new();
+        header.set_size(64, 48).unwrap();
+        header.set_color(ColorType::TruecolorAlpha, 8).unwrap();
+        let pixel = |i: usize, c: usize| ((i * 5 + c * 63) % 256) as u8;
+
+        let rgba: Vec<u8> = (0 .. 64 * 48 * 4).map(|i| pixel(i / 4, i % 4)).collect();
+        let bgra: Vec<u8> = (0 .. 64 * 48 * 4).map(|i| pixel(i / 4, [2, 1, 0, 3][i % 4])).collect();
+        let planes: Vec<Vec<u8>> = (0 .. 4).map(|c| {
+            (0 .. 64 * 48).map(|i| pixel(i, c)).collect()
+        }).collect();
+
+        for &interlace_method in &[InterlaceMethod::Standard, InterlaceMethod::Adam7] {
+            header.set_interlace_method(interlace_method).unwrap();
+            let expected = encode_with_format(&header, &InputFormat::new(), &rgba).unwrap();
+
+            let mut format = InputFormat::new();
+            format.set_layout(PixelLayout::Bgra).unwrap();
+            assert!(encode_with_format(&header, &format, &bgra).unwrap() == expected);
+
+            format.set_layout(PixelLayout::Planar).unwrap();
+            assert!(encode_with_format(&header, &format, &rgba).is_err());
+
+            let options = Options::new();
+            let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+            encoder.write_header(&header).unwrap();
+            encoder.set_input_format(&format).unwrap();
+            assert!(encoder.write_image_planes(&[&planes[0], &planes[1], &planes[2]]).is_err());
+            encoder.write_image_planes(&[&planes[0], &planes[1], &planes[2], &planes[3]]).unwrap();
+            assert!(encoder.finish().unwrap() == expected);
+        }
+
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let mut format = InputFormat::new();
+        format.set_layout(PixelLayout::Argb).unwrap();
+        assert!(encode_with_format(&header, &format, &rgba[.. 64 * 48 * 3]).is_err());
+    }
+
+    #[test]
+    fn test_typed_samples() {
+        let mut header = Header::new();
+        header.set_size(64, 48).unwrap();
+        header.set_color(ColorType::Truecolor, 16).unwrap();
+
+        let samples: Vec<u16> = (0 .. 64 * 48 * 3).map(|i| (i * 257 % 65536) as u16).collect();
+        let bytes: Vec<u8> = samples.iter().flat_map(|val| val.to_be_bytes()).collect();
+        let expected = encode_with_format(&header, &InputFormat::new(), &bytes).unwrap();
+
+        let options = Options::new();
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        encoder.write_image_rows_u16(&samples[.. 64 * 3]).unwrap();
+        assert!(encoder.write_image_rows(&bytes[64 * 6 .. 64 * 12]).is_err());
+        encoder.write_image_rows_u16(&samples[64 * 3 ..]).unwrap();
+        assert!(encoder.finish().unwrap() == expected);
+
+        header.set_color(ColorType::Greyscale, 8).unwrap();
+        header.set_interlace_method(InterlaceMethod::Adam7).unwrap();
+        let bytes: Vec<u8> = (0 .. 64 * 48).map(|i| (i % 256) as u8).collect();
+        let floats: Vec<f32> = bytes.iter().map(|&val| val as f32 / 255.0).collect();
+        let expected = encode_with_format(&header, &InputFormat::new(), &bytes).unwrap();
+
+        let mut encoder = Encoder::new(Vec::<u8>::new(), &options);
+        encoder.write_header(&header).unwrap();
+        assert!(encoder.write_image_rows_u16(&samples[.. 64]).is_err());
+        encoder.write_image_rows_f32(&floats).unwrap();
+        assert!(encoder.finish().unwrap() == expected);
+    }
+
+    #[test]
+    fn test_premultiplied() {
+        let mut header = Header::new();
+        header.set_size(64, 48).unwrap();
+        header.set_color(ColorType::TruecolorAlpha, 8).unwrap();
+
+        // Straight and premultiplied are the same where opaque or clear.
+        let straight: Vec<u8> = (0 .. 64 * 48).flat_map(|i| {
+            if i % 3 == 0 {
+                vec![0, 0, 0, 0]
+            } else {
+                vec![(i % 256) as u8, 7, 99, 255]
+            }
+        }).collect();
+        let expected = encode_with_format(&header, &InputFormat::new(), &straight).unwrap();
+
+        let mut format = InputFormat::new();
+        format.set_premultiplied(true).unwrap();
+        assert!(encode_with_format(&header, &format, &straight).unwrap() == expected);
+
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        assert!(encode_with_format(&header, &format, &straight[.. 64 * 48 * 3]).is_err());
+    }
+
+    #[test]
+    fn test_animation() {
+        test_encoder(640, 480, |encoder, data| {
+            let mut frame = FrameControl::new();
+
+            // Frames must be declared before the first one starts.
+            assert!(encoder.write_frame_control(&frame).is_err());
+            encoder.write_animation_control(2, 0)?;
+
+            // The first frame doubles as the default image.
+            assert!(encoder.write_frame_control(&frame).is_err());
+            frame.set_size(640, 480)?;
+            encoder.write_frame_control(&frame)?;
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
+            }
+
+            frame.set_size(100, 50)?;
+            frame.set_offset(540, 430)?;
+            encoder.write_frame_control(&frame)?;
+            assert!(!encoder.is_finished());
+            for _y in 0 .. 50 {
+                encoder.write_image_rows(&data[.. 300])?;
+            }
+            assert!(encoder.write_image_rows(&data[.. 300]).is_err());
+
+            // Only two frames were declared.
+            assert!(encoder.write_frame_control(&frame).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_color_space() {
+        test_encoder(640, 480, |encoder, data| {
+            encoder.write_srgb(RenderingIntent::Perceptual)?;
+            encoder.write_gamma(45455)?;
+            encoder.write_chromaticities((31270, 32900), (64000, 33000),
+                                         (30000, 60000), (15000, 6000))?;
+
+            assert!(encoder.write_srgb(RenderingIntent::Perceptual).is_err());
+            assert!(encoder.write_icc_profile("sRGB", b"profile").is_err());
+
+            encoder.write_palette(&[0, 0, 0])?;
+            assert!(encoder.write_gamma(45455).is_err());
+
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_icc_profile() {
+        test_encoder(640, 480, |encoder, data| {
+            // A rejected name leaves the chunk free to retry.
+            assert!(encoder.write_icc_profile(" sRGB", b"profile").is_err());
+            encoder.write_icc_profile("sRGB", b"profile")?;
+            assert!(encoder.write_icc_profile("sRGB", b"profile").is_err());
+            assert!(encoder.write_srgb(RenderingIntent::Perceptual).is_err());
+
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_ancillary() {
+        let mut header = Header::new();
+        header.set_size(640, 480).unwrap();
+        header.set_color(ColorType::IndexedColor, 4).unwrap();
+        test_encoder_header(&header, |encoder, data| {
+            assert!(encoder.write_histogram(&[1, 2]).is_err());
+            assert!(encoder.write_significant_bits(&[8]).is_err());
+            encoder.write_significant_bits(&[5, 6, 5])?;
+            assert!(encoder.write_background(&[0]).is_err());
+
+            encoder.write_palette(&[0, 0, 0, 255, 255, 255])?;
+            assert!(encoder.write_significant_bits(&[5, 6, 5]).is_err());
+            assert!(encoder.write_background(&[2]).is_err());
+            encoder.write_background(&[1])?;
+            assert!(encoder.write_histogram(&[1, 2, 3]).is_err());
+            encoder.write_histogram(&[1, 2])?;
+
+            assert!(encoder.write_physical_dimensions(0, 1, PhysicalUnit::Meter).is_err());
+            encoder.write_physical_dimensions(11811, 11811, PhysicalUnit::Meter)?;
+            assert!(encoder.write_suggested_palette("Web ", 8, &[]).is_err());
+            encoder.write_suggested_palette("Web", 8, &[0, 0, 0, 255, 0, 1])?;
+            assert!(encoder.write_suggested_palette("Web", 8, &[]).is_err());
+            assert!(encoder.write_suggested_palette("Deep", 16, &[0; 6]).is_err());
+
+            // Indices must be within the two-entry palette.
+            let rows = vec![0x01; data.len()];
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(&rows)?;
+            }
+            assert!(encoder.write_time(2018, 13, 1, 0, 0, 0).is_err());
+            encoder.write_time(2018, 9, 26, 12, 30, 0)?;
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_exif() {
+        test_encoder(640, 480, |encoder, data| {
+            let mut exif = b"MM\0*\0\0\0\x08\0\0\0\0\0\0".to_vec();
+            exif::set_orientation(&mut exif, 6)?;
+
+            assert!(encoder.write_exif(b"Exif\0\0MM\0*").is_err());
+            encoder.write_exif(&exif)?;
+            assert!(encoder.write_exif(&exif).is_err());
+
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_hdr() {
+        let mut header = Header::new();
+        header.set_size(640, 480).unwrap();
+        header.set_color(ColorType::Truecolor, 16).unwrap();
+        test_encoder_header(&header, |encoder, data| {
+            let mut cicp = Cicp::bt2100_pq();
+            assert!(cicp.set_transfer_function(0).is_err());
+            cicp.set_full_range(true)?;
+            encoder.write_cicp(&cicp)?;
+            assert!(encoder.write_cicp(&cicp).is_err());
+
+            let mut display = MasteringDisplay::new();
+            assert!(display.set_luminance(50, 10_000_000).is_err());
+            assert!(display.set_white_point(50001, 0).is_err());
+            encoder.write_mastering_display(&display)?;
+
+            let mut level = ContentLightLevel::new();
+            assert!(level.set_levels(100, 200).is_err());
+            level.set_levels(10_000_000, 4_000_000)?;
+
+            for _y in 0 .. 480 {
+                encoder.write_image_rows(data)?;
+            }
+            assert!(encoder.write_content_light_level(&level).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_chunk_order() {
+        test_encoder(640, 480, |encoder, data| {
+            assert!(encoder.write_chunk(b"IDAT", &[]).is_err());
+            assert!(encoder.write_chunk(b"abcd", &[]).is_err());
+            assert!(encoder.write_chunk(b"ABCd", &[]).is_err());
+            encoder.write_chunk(b"abCD", &[1, 2, 3])?;
+            encoder.write_chunk(b"abCd", &[1, 2, 3])?;
+
+            encoder.write_image_rows(data)?;
+            assert!(encoder.write_text("Comment", "Too early").is_err());
+            assert!(encoder.write_chunk(b"abCd", &[]).is_err());
+            for _y in 1 .. 480 {
+                encoder.write_image_rows(data)?;
+            }
+            assert!(encoder.write_image_rows(data).is_err());
+
+            encoder.write_text("Comment", "Trailing text")?;
+            encoder.write_time(2018, 9, 26, 12, 30, 0)?;
+            encoder.write_chunk(b"abCd", &[4, 5, 6])?;
+            encoder.write_chunk(b"abCD", &[])?;
+            assert!(encoder.write_gamma(45455).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_palette_indices() {
+        let mut header = Header::new();
+        header.set_size(64, 64).unwrap();
+        header.set_color(ColorType::IndexedColor, 2).unwrap();
+
+        let encode = |entries: usize| -> io::Result<Vec<u8>> {
+            let mut palette = Palette::new();
+            for i in 0 .. entries {
+                palette.push([i as u8, 0, 0, if i == 1 { 0 } else { 255 }])?;
+            }
+            let mut encoder = Encoder::new(Vec::new(), &Options::new());
+            encoder.write_header(&header)?;
+            encoder.write_rgba_palette(&palette)?;
+            // Indices 0, 1, 2, 0 repeated.
+            encoder.write_image_rows(&vec![0b00_01_10_00; header.stride() * 64])?;
+            encoder.finish()
+        };
+        encode(3).unwrap();
+        assert!(encode(2).is_err());
+        assert!(encode(5).is_err());
+    }
+
+    // Stores the data uncompressed, in blocks of up to 64 KiB.
+    struct Stored;
+
+    impl DeflateBackend for Stored {
+        fn compress(&self, segment: &Segment, data: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+            let mut blocks: Vec<&[u8]> = data.chunks(65535).collect();
+            if blocks.is_empty() {
+                blocks.push(&[]);
+            }
+            let count = blocks.len();
+            for (i, block) in blocks.into_iter().enumerate() {
+                let last = segment.is_last() && i == count - 1;
+                output.push(last as u8);
+                output.extend_from_slice(&(block.len() as u16).to_le_bytes());
+                output.extend_from_slice(&(!block.len() as u16).to_le_bytes());
+                output.extend_from_slice(block);
+            }
+            Ok(())
+        }
+
+        fn adler32(&self, sum: u32, data: &[u8]) -> u32 {
+            Builtin.adler32(sum, data)
+        }
+
+        fn adler32_combine(&self, sum_a: u32, sum_b: u32, len_b: usize) -> u32 {
+            Builtin.adler32_combine(sum_a, sum_b, len_b)
+        }
+    }
+
+    //
+    // Encode an image and decode it again, returning the decoded
+    // rows and the size of the file.
+    //
+    fn round_trip(header: &Header, options: &Options, data: &[u8]) -> io::Result<(Vec<u8>, usize)> {
+        let mut encoder = Encoder::new(Vec::<u8>::new(), options);
+        encoder.write_header(header)?;
+        encoder.write_image_rows(data)?;
+        let png = encoder.finish()?;
+        let len = png.len();
+
+        let mut decoder = Decoder::new(io::Cursor::new(png), &decoder::Options::new());
+        let mut output = vec![0u8; data.len()];
+        assert_eq!(decoder.read_image_rows(&mut output)?, header.height() as usize);
+        decoder.finish()?;
+        Ok((output, len))
+    }
+
+    fn sample_data(header: &Header) -> Vec<u8> {
+        let len = header.stride() * header.height() as usize;
+        (0 .. len).map(|i| (i * 7 + i / 1000) as u8).collect()
+    }
+
+    #[test]
+    fn test_alpha_cleaning() {
+        let mut header = Header::new();
+        header.set_size(256, 300).unwrap();
+        header.set_color(ColorType::TruecolorAlpha, 8).unwrap();
+
+        // A smooth gradient, with noise under a transparent checkerboard.
+        let data: Vec<u8> = (0 .. 256 * 300).flat_map(|i| {
+            let (x, y) = (i % 256, i / 256);
+            if (x / 16 + y / 16) % 2 == 0 {
+                vec![(i * 31 % 251) as u8, (i * 17 % 241) as u8, (i * 7 % 239) as u8, 0]
+            } else {
+                vec![x as u8, y as u8, (x + y) as u8, 255]
+            }
+        }).collect();
+
+        for &interlace_method in &[InterlaceMethod::Standard, InterlaceMethod::Adam7] {
+            header.set_interlace_method(interlace_method).unwrap();
+            for &mode in &[Mode::Adaptive, Mode::Fixed(Filter::None), Mode::Fixed(Filter::Sub),
+                           Mode::Fixed(Filter::Up), Mode::Fixed(Filter::Average),
+                           Mode::Fixed(Filter::Paeth)] {
+                let mut options = Options::new();
+                options.set_chunk_size(32768).unwrap();
+                options.set_filter_mode(mode).unwrap();
+                let (_, plain) = round_trip(&header, &options, &data).unwrap();
+
+                options.set_alpha_cleaning(true).unwrap();
+                let (output, cleaned) = round_trip(&header, &options, &data).unwrap();
+                assert!(cleaned < plain);
+                for (out, pixel) in output.chunks(4).zip(data.chunks(4)) {
+                    if pixel[3] == 0 {
+                        assert_eq!(out[3], 0);
+                    } else {
+                        assert_eq!(out, pixel);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_heuristics() {
+        let mut header = Header::new();
+        header.set_size(256, 128).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let data = sample_data(&header);
+
+        let mut options = Options::new();
+        options.set_filter_heuristic(&MinSumWithNone).unwrap();
+        assert!(round_trip(&header, &options, &data).unwrap().0 == data);
+        options.set_filter_heuristic(&Entropy).unwrap();
+        assert!(round_trip(&header, &options, &data).unwrap().0 == data);
+        options.set_filter_heuristic(&Bigrams).unwrap();
+        assert!(round_trip(&header, &options, &data).unwrap().0 == data);
+    }
+
+    #[test]
+    fn test_brute_force() {
+        let mut header = Header::new();
+        header.set_size(200, 150).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        // Flat bands, gradients and noise, which suit different filters.
+        let data: Vec<u8> = (0 .. 200 * 150 * 3).map(|i| {
+            let (x, y) = (i / 3 % 200, i / 600);
+            match y / 30 {
+                0 => (x / 50 * 60) as u8,
+                1 => (x + y) as u8,
+                2 => (i * 97 % 251) as u8,
+                3 => (x * y / 16) as u8,
+                _ => ((x / 8 + y / 8) % 2 * 200) as u8,
+            }
+        }).collect();
+
+        for &interlace_method in &[InterlaceMethod::Standard, InterlaceMethod::Adam7] {
+            header.set_interlace_method(interlace_method).unwrap();
+            let mut options = Options::new();
+            options.set_chunk_size(32768).unwrap();
+            let (_, adaptive) = round_trip(&header, &options, &data).unwrap();
+
+            options.set_filter_mode(Mode::BruteForce).unwrap();
+            let (output, brute_force) = round_trip(&header, &options, &data).unwrap();
+            assert!(output == data);
+            assert!(brute_force < adaptive);
+
+            // No strategy does worse than the one picked for it.
+            options.set_strategy_mode(Mode::BruteForce).unwrap();
+            let (output, strategies) = round_trip(&header, &options, &data).unwrap();
+            assert!(output == data);
+            assert!(strategies <= brute_force);
+        }
+    }
+
+    #[test]
+    fn test_deflate_backend() {
+        let mut header = Header::new();
+        header.set_size(200, 150).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let data = sample_data(&header);
+
+        let mut options = Options::new();
+        let (_, zlib) = round_trip(&header, &options, &data).unwrap();
+
+        // One chunk, then several.
+        options.set_deflate_backend(&Stored).unwrap();
+        let (output, stored) = round_trip(&header, &options, &data).unwrap();
+        assert!(output == data);
+        assert!(stored > data.len());
+        assert!(stored > zlib);
+
+        options.set_chunk_size(32768).unwrap();
+        let (output, _) = round_trip(&header, &options, &data).unwrap();
+        assert!(output == data);
+    }
+
+    #[test]
+    fn test_extreme() {
+        let mut header = Header::new();
+        header.set_size(200, 150).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+        let data = sample_data(&header);
+
+        // Several chunks, each referring back into the one before.
+        let mut options = Options::new();
+        options.set_chunk_size(32768).unwrap();
+        options.set_compression_level(CompressionLevel::High).unwrap();
+        let (_, high) = round_trip(&header, &options, &data).unwrap();
+
+        options.set_compression_level(CompressionLevel::Extreme).unwrap();
+        let (output, extreme) = round_trip(&header, &options, &data).unwrap();
+        assert!(output == data);
+        assert!(extreme < high);
+    }
 }
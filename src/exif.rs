@@ -0,0 +1,272 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// exif.rs - EXIF metadata validation and Orientation tag access
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+use std::io;
+
+use super::utils::*;
+
+// https://www.cipa.jp/std/documents/e/DC-008-2012_E.pdf
+const ORIENTATION_TAG: u16 = 0x0112;
+const SHORT_TYPE: u16 = 3;
+const ENTRY_SIZE: usize = 12;
+
+//
+// Accessor for EXIF data in either TIFF byte order.
+//
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> io::Result<Tiff<'a>> {
+        let little_endian = match data.get(0 .. 4) {
+            Some(b"II*\0") => true,
+            Some(b"MM\0*") => false,
+            _ => return Err(invalid_data("EXIF data must start with a TIFF header")),
+        };
+        Ok(Tiff {
+            data,
+            little_endian,
+        })
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> io::Result<&'a [u8]> {
+        offset.checked_add(len)
+            .and_then(|end| self.data.get(offset .. end))
+            .ok_or_else(|| invalid_data("Truncated EXIF data"))
+    }
+
+    fn read16(&self, offset: usize) -> io::Result<u16> {
+        let bytes = self.bytes(offset, 2)?;
+        Ok(if self.little_endian {
+            u16::from(bytes[1]) << 8 | u16::from(bytes[0])
+        } else {
+            read_be16(bytes)
+        })
+    }
+
+    fn read32(&self, offset: usize) -> io::Result<u32> {
+        let bytes = self.bytes(offset, 4)?;
+        Ok(if self.little_endian {
+            u32::from(bytes[3]) << 24 | u32::from(bytes[2]) << 16 |
+            u32::from(bytes[1]) << 8 | u32::from(bytes[0])
+        } else {
+            read_be32(bytes)
+        })
+    }
+
+    fn encode16(&self, val: u16) -> [u8; 2] {
+        if self.little_endian {
+            val.to_le_bytes()
+        } else {
+            val.to_be_bytes()
+        }
+    }
+
+    fn encode32(&self, val: u32) -> [u8; 4] {
+        if self.little_endian {
+            val.to_le_bytes()
+        } else {
+            val.to_be_bytes()
+        }
+    }
+
+    //
+    // Offset and entry count of the first image file directory.
+    //
+    fn first_ifd(&self) -> io::Result<(usize, usize)> {
+        let offset = self.read32(4)? as usize;
+        let count = self.read16(offset)? as usize;
+        self.bytes(offset + 2, count * ENTRY_SIZE + 4)?;
+        Ok((offset, count))
+    }
+
+    //
+    // Offset of the first IFD entry with the given tag, if any.
+    //
+    fn find_entry(&self, tag: u16) -> io::Result<Option<usize>> {
+        let (offset, count) = self.first_ifd()?;
+        for index in 0 .. count {
+            let entry = offset + 2 + index * ENTRY_SIZE;
+            if self.read16(entry)? == tag {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    //
+    // A SHORT entry with a single value, stored inline.
+    //
+    fn short_entry(&self, tag: u16, val: u16) -> Vec<u8> {
+        let mut entry = Vec::with_capacity(ENTRY_SIZE);
+        entry.extend_from_slice(&self.encode16(tag));
+        entry.extend_from_slice(&self.encode16(SHORT_TYPE));
+        entry.extend_from_slice(&self.encode32(1));
+        entry.extend_from_slice(&self.encode16(val));
+        entry.extend_from_slice(&[0, 0]);
+        entry
+    }
+}
+
+/// Check that data is plausibly EXIF, starting with a TIFF header
+/// in either byte order whose first directory lies within the data.
+///
+/// As used in PNG, the data does not include the "Exif\0\0" prefix
+/// found in JPEG files.
+pub fn check_header(data: &[u8]) -> io::Result<()> {
+    Tiff::new(data)?.first_ifd()?;
+    Ok(())
+}
+
+/// Read the Orientation tag from the first directory of EXIF data.
+///
+/// Values 1-8 describe the transformation from stored pixels to
+/// display: 1 is upright, 3 rotated 180°, 6 needs rotating 90°
+/// clockwise, 8 needs rotating 90° counterclockwise, and 2, 4, 5, 7
+/// are their mirrored forms.
+///
+/// Returns None if there is no Orientation tag.
+pub fn orientation(data: &[u8]) -> io::Result<Option<u16>> {
+    let tiff = Tiff::new(data)?;
+    match tiff.find_entry(ORIENTATION_TAG)? {
+        Some(entry) => {
+            if tiff.read16(entry + 2)? != SHORT_TYPE || tiff.read32(entry + 4)? != 1 {
+                return Err(invalid_data("Orientation tag must be a single SHORT value"));
+            }
+            Ok(Some(tiff.read16(entry + 8)?))
+        },
+        None => Ok(None),
+    }
+}
+
+/// Set the Orientation tag in the first directory of EXIF data,
+/// using values as for orientation().
+///
+/// An existing tag is updated in place. Otherwise, a copy of the
+/// directory including the new tag is appended to the data, so
+/// offsets elsewhere in the data remain valid.
+pub fn set_orientation(data: &mut Vec<u8>, orientation: u16) -> IoResult {
+    if !(1 ..= 8).contains(&orientation) {
+        return Err(invalid_input("Orientation must be between 1 and 8"));
+    }
+
+    let tiff = Tiff::new(data)?;
+    if let Some(entry) = tiff.find_entry(ORIENTATION_TAG)? {
+        let replacement = tiff.short_entry(ORIENTATION_TAG, orientation);
+        data[entry .. entry + ENTRY_SIZE].clone_from_slice(&replacement);
+        return Ok(());
+    }
+
+    // Rebuild the directory with the new entry in tag order.
+    let (offset, count) = tiff.first_ifd()?;
+    let mut entries = Vec::with_capacity(count + 1);
+    for index in 0 .. count {
+        let entry = offset + 2 + index * ENTRY_SIZE;
+        entries.push((tiff.read16(entry)?, tiff.bytes(entry, ENTRY_SIZE)?.to_vec()));
+    }
+    entries.push((ORIENTATION_TAG, tiff.short_entry(ORIENTATION_TAG, orientation)));
+    entries.sort_by_key(|&(tag, _)| tag);
+    if entries.len() > u16::MAX as usize {
+        return Err(invalid_data("Too many EXIF directory entries"));
+    }
+
+    let mut ifd = Vec::with_capacity(2 + entries.len() * ENTRY_SIZE + 4);
+    ifd.extend_from_slice(&tiff.encode16(entries.len() as u16));
+    for (_, entry) in entries.iter() {
+        ifd.extend_from_slice(entry);
+    }
+    ifd.extend_from_slice(tiff.bytes(offset + 2 + count * ENTRY_SIZE, 4)?);
+
+    // Directories must start on a word boundary.
+    let padding = data.len() & 1;
+    let new_offset = data.len() + padding;
+    if new_offset + ifd.len() > u32::MAX as usize {
+        return Err(invalid_input("EXIF data cannot exceed 4 GiB"));
+    }
+    let pointer = tiff.encode32(new_offset as u32);
+
+    data.resize(new_offset, 0);
+    data.extend_from_slice(&ifd);
+    data[4 .. 8].clone_from_slice(&pointer);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tiff;
+    use super::ENTRY_SIZE;
+    use super::check_header;
+    use super::orientation;
+    use super::set_orientation;
+
+    // Minimal EXIF with one directory holding an ImageWidth tag
+    // and a pointer to a following directory.
+    fn test_exif(little_endian: bool) -> Vec<u8> {
+        let mut data = if little_endian {
+            b"II*\0\x08\0\0\0\x01\0\0\x01\x03\0\x01\0\0\0\x40\0\0\0\x1a\0\0\0".to_vec()
+        } else {
+            b"MM\0*\0\0\0\x08\0\x01\x01\0\0\x03\0\0\0\x01\0\x40\0\0\0\0\0\x1a".to_vec()
+        };
+        data.extend_from_slice(b"\0\0\0\0\0\0");
+        data
+    }
+
+    #[test]
+    fn bad_header() {
+        assert!(check_header(b"Exif\0\0II*\0").is_err());
+        assert!(check_header(b"II*\0\x40\0\0\0").is_err());
+        assert!(check_header(&test_exif(true)).is_ok());
+    }
+
+    #[test]
+    fn orientation_round_trip() {
+        for &little_endian in &[true, false] {
+            let mut data = test_exif(little_endian);
+            let len = data.len();
+            assert_eq!(orientation(&data).unwrap(), None);
+
+            set_orientation(&mut data, 6).unwrap();
+            assert_eq!(orientation(&data).unwrap(), Some(6));
+            assert!(data.len() > len);
+
+            // The original tag and next-directory pointer are kept.
+            let tiff = Tiff::new(&data).unwrap();
+            assert!(tiff.find_entry(0x0100).unwrap().is_some());
+            let (offset, count) = tiff.first_ifd().unwrap();
+            assert_eq!(count, 2);
+            assert_eq!(tiff.read32(offset + 2 + count * ENTRY_SIZE).unwrap(), 0x1a);
+
+            // Updating again happens in place.
+            let len = data.len();
+            set_orientation(&mut data, 1).unwrap();
+            assert_eq!(orientation(&data).unwrap(), Some(1));
+            assert_eq!(data.len(), len);
+
+            assert!(set_orientation(&mut data, 9).is_err());
+        }
+    }
+}
@@ -26,13 +26,21 @@
 use std::cmp;
 use std::convert::TryFrom;
 use std::io;
+use std::io::Write;
+use std::mem;
 
 use typenum::Unsigned;
 use typenum::consts::*;
 
+use super::ColorType;
 use super::Header;
 use super::Mode;
-use super::Mode::{Adaptive, Fixed};
+use super::Mode::{Adaptive, Fixed, BruteForce};
+use super::deflate;
+use super::deflate::Deflate;
+use super::deflate::Flush;
+use super::deflate::Strategy;
+use super::heuristic::FilterHeuristic;
 
 use super::utils::invalid_input;
 
@@ -218,69 +226,108 @@ fn filter_paeth(bpp: usize, prev: &[u8], src: &[u8], dest: &mut [u8]) {
     })
 }
 
-
 //
-// For the complexity/compressibility heuristic. Absolute value
-// of the byte treated as a signed value, extended to a u32.
+// Reverse the filter on a row, given the already-reconstructed
+// previous row. Input starts with the filter type byte.
+//
+// Reconstruction is inherently serial along the row, since each
+// byte depends on its reconstructed left neighbor.
 //
-// Note this doesn't produce useful results on the "none" filter,
-// as it's expecting, well, a filter delta. :D
+// https://www.w3.org/TR/PNG/#9Filter-types
 //
-fn filter_complexity_delta(val: u8) -> u32 {
-    i32::abs(i32::from(val as i8)) as u32
+pub fn unfilter(bpp: usize, prev: &[u8], src: &[u8], dest: &mut [u8]) -> io::Result<()> {
+    let filter = Filter::try_from(src[0])?;
+    let src = &src[1 ..];
+    let len = dest.len();
+
+    match filter {
+        Filter::None => {
+            dest.clone_from_slice(src);
+        },
+        Filter::Sub => {
+            dest[.. bpp].clone_from_slice(&src[.. bpp]);
+            for i in bpp .. len {
+                dest[i] = src[i].wrapping_add(dest[i - bpp]);
+            }
+        },
+        Filter::Up => {
+            for (dest, val, above) in izip!(dest.iter_mut(), src, prev) {
+                *dest = val.wrapping_add(*above);
+            }
+        },
+        Filter::Average => {
+            for i in 0 .. bpp {
+                dest[i] = src[i].wrapping_add(prev[i] >> 1);
+            }
+            for i in bpp .. len {
+                let avg = ((u16::from(dest[i - bpp]) + u16::from(prev[i])) / 2) as u8;
+                dest[i] = src[i].wrapping_add(avg);
+            }
+        },
+        Filter::Paeth => {
+            for i in 0 .. bpp {
+                dest[i] = src[i].wrapping_add(prev[i]);
+            }
+            for i in bpp .. len {
+                let predicted = paeth_predictor(dest[i - bpp], prev[i], prev[i - bpp]);
+                dest[i] = src[i].wrapping_add(predicted);
+            }
+        },
+    }
+    Ok(())
 }
 
 //
-// The maximum complexity heuristic value that can be represented
-// without overflow.
+// Rewrite the color of fully transparent pixels in place to the value
+// the given filter predicts for them, so their color filters to zero.
+// Alpha is left alone, so the visible image doesn't change.
 //
-fn complexity_max() -> u32 {
-    u32::max_value() - 256
+// Prediction works byte-wise like the filters themselves, using the
+// already-cleaned pixels to the left and the previous row as it was
+// filtered.
+//
+fn clean_pixels(filter: Filter, bpp: usize, alpha_bytes: usize, prev: &[u8], row: &mut [u8]) {
+    let color_bytes = bpp - alpha_bytes;
+    for start in (0 .. row.len()).step_by(bpp) {
+        if row[start + color_bytes .. start + bpp].iter().any(|&val| val != 0) {
+            continue;
+        }
+        for i in start .. start + color_bytes {
+            let left = if i >= bpp { row[i - bpp] } else { 0 };
+            row[i] = match filter {
+                Filter::None => 0,
+                Filter::Sub => left,
+                Filter::Up => prev[i],
+                Filter::Average => ((u16::from(left) + u16::from(prev[i])) / 2) as u8,
+                Filter::Paeth => {
+                    let upper_left = if i >= bpp { prev[i - bpp] } else { 0 };
+                    paeth_predictor(left, prev[i], upper_left)
+                },
+            };
+        }
+    }
 }
 
 //
-// Any row with this number of bytes needs to check for overflow
-// of the complexity heuristic.
+// Number of bytes in the alpha sample of each pixel, or 0 for color
+// types without an alpha channel.
 //
-fn complexity_big_row(len: usize) -> bool {
-    len >= (1 << 24)
+fn alpha_bytes(header: &Header) -> usize {
+    match header.color_type() {
+        ColorType::GreyscaleAlpha | ColorType::TruecolorAlpha => header.depth() as usize / 8,
+        _ => 0,
+    }
 }
 
 //
-// Complexity/compressibility heuristic recommended by the PNG spec
-// and used in libpng as well.
-//
-// Note this doesn't produce useful results on the "none" filter
-//
-// libpng tries to do this inline with the filter with a clever
-// early return if "too complex", but I find that's slower on large
-// files than just running the whole filter.
+// Clean the transparent pixels of a row for the given filter.
+// Rows without an alpha channel are left as they are.
 //
-fn estimate_complexity(data: &[u8]) -> u32 {
-    let mut sum = 0u32;
-
-    //
-    // Very long rows could overflow the 32-bit complexity heuristic's
-    // accumulator, but it doesn't trigger until tens of millions
-    // of bytes per row. :)
-    //
-    // The check slows down the inner loop on more realistic sizes
-    // (say, ~31k bytes for a 7680 wide RGBA image) so we skip it.
-    //
-    if complexity_big_row(data.len()) {
-        for iter in data.iter() {
-            sum += filter_complexity_delta(*iter);
-            if sum > complexity_max() {
-                return complexity_max();
-            }
-        }
-    } else {
-        for iter in data.iter() {
-            sum += filter_complexity_delta(*iter);
-        }
+pub fn clean_alpha(header: &Header, filter: Filter, prev: &[u8], row: &mut [u8]) {
+    let alpha_bytes = alpha_bytes(header);
+    if alpha_bytes > 0 {
+        clean_pixels(filter, header.bytes_per_pixel(), alpha_bytes, prev, row);
     }
-
-    sum
 }
 
 //
@@ -290,17 +337,22 @@ fn estimate_complexity(data: &[u8]) -> u32 {
 struct Filterator {
     filter: Filter,
     bpp: usize,
+    alpha_bytes: usize,
     data: Vec<u8>,
-    complexity: u32,
+
+    // The source row with transparent pixels cleaned for this filter,
+    // when filtering with alpha cleaning.
+    cleaned: Vec<u8>,
 }
 
 impl Filterator {
-    fn new(filter: Filter, bpp: usize, stride: usize) -> Filterator {
+    fn new(filter: Filter, bpp: usize, alpha_bytes: usize, stride: usize) -> Filterator {
         Filterator {
             filter,
             bpp,
+            alpha_bytes,
             data: vec![0u8; stride + 1],
-            complexity: 0,
+            cleaned: Vec::new(),
         }
     }
 
@@ -313,7 +365,6 @@ impl Filterator {
             Filter::Average => filter_average(self.bpp, prev, src, &mut self.data),
             Filter::Paeth   => filter_paeth(self.bpp, prev, src, &mut self.data),
         }
-        self.complexity = estimate_complexity(&self.data[1..]);
         &self.data
     }
 
@@ -384,17 +435,63 @@ impl Filterator {
         self.do_filter(prev, src)
     }
 
+    fn filter_clean(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
+        if self.alpha_bytes == 0 {
+            return self.filter(prev, src);
+        }
+        let mut cleaned = mem::take(&mut self.cleaned);
+        cleaned.clear();
+        cleaned.extend_from_slice(src);
+        clean_pixels(self.filter, self.bpp, self.alpha_bytes, prev, &mut cleaned);
+        self.filter(prev, &cleaned);
+        self.cleaned = cleaned;
+        &self.data
+    }
+
     fn get_data(&self) -> &[u8] {
         &self.data
     }
 
-    fn get_complexity(&self) -> u32 {
-        self.complexity
+    fn get_cleaned(&self) -> &[u8] {
+        &self.cleaned
+    }
+}
+
+//
+// Output sink for trial compression, which only counts bytes.
+//
+struct Counter {
+    count: usize,
+}
+
+impl Counter {
+    fn new() -> Counter {
+        Counter {
+            count: 0,
+        }
+    }
+}
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.count += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
 
 pub struct AdaptiveFilter {
     mode: Mode<Filter>,
+    heuristic: &'static dyn FilterHeuristic,
+    chosen: Filter,
+
+    // Rows filtered so far in brute force mode, compressed
+    // to compare candidate filters against.
+    trial: Option<Deflate<Counter>>,
+
     filter_none: Filterator,
     filter_up: Filterator,
     filter_sub: Filterator,
@@ -403,64 +500,169 @@ pub struct AdaptiveFilter {
 }
 
 impl AdaptiveFilter {
-    pub fn new(header: Header, mode: Mode<Filter>) -> AdaptiveFilter {
+    pub fn new(header: Header, mode: Mode<Filter>, heuristic: &'static dyn FilterHeuristic) -> AdaptiveFilter {
         let stride = header.stride();
         let bpp = header.bytes_per_pixel();
+        let alpha = alpha_bytes(&header);
         AdaptiveFilter {
             mode,
-            filter_none:    Filterator::new(Filter::None,    bpp, stride),
-            filter_up:      Filterator::new(Filter::Up,      bpp, stride),
-            filter_sub:     Filterator::new(Filter::Sub,     bpp, stride),
-            filter_average: Filterator::new(Filter::Average, bpp, stride),
-            filter_paeth:   Filterator::new(Filter::Paeth,   bpp, stride),
+            heuristic,
+            chosen: Filter::None,
+            trial: None,
+            filter_none:    Filterator::new(Filter::None,    bpp, alpha, stride),
+            filter_up:      Filterator::new(Filter::Up,      bpp, alpha, stride),
+            filter_sub:     Filterator::new(Filter::Sub,     bpp, alpha, stride),
+            filter_average: Filterator::new(Filter::Average, bpp, alpha, stride),
+            filter_paeth:   Filterator::new(Filter::Paeth,   bpp, alpha, stride),
+        }
+    }
+
+    fn filterator(&self, filter: Filter) -> &Filterator {
+        match filter {
+            Filter::None    => &self.filter_none,
+            Filter::Sub     => &self.filter_sub,
+            Filter::Up      => &self.filter_up,
+            Filter::Average => &self.filter_average,
+            Filter::Paeth   => &self.filter_paeth,
+        }
+    }
+
+    fn filterator_mut(&mut self, filter: Filter) -> &mut Filterator {
+        match filter {
+            Filter::None    => &mut self.filter_none,
+            Filter::Sub     => &mut self.filter_sub,
+            Filter::Up      => &mut self.filter_up,
+            Filter::Average => &mut self.filter_average,
+            Filter::Paeth   => &mut self.filter_paeth,
         }
     }
 
-    fn filter_adaptive(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
+    fn run(&mut self, filter: Filter, prev: &[u8], src: &[u8], clean: bool) {
+        let filterator = self.filterator_mut(filter);
+        if clean {
+            filterator.filter_clean(prev, src);
+        } else {
+            filterator.filter(prev, src);
+        }
+    }
+
+    fn score(&mut self, filter: Filter, prev: &[u8], src: &[u8], clean: bool) -> u64 {
+        self.run(filter, prev, src, clean);
+        self.heuristic.score(filter, &self.filterator(filter).get_data()[1 ..])
+    }
+
+    fn filter_adaptive(&mut self, prev: &[u8], src: &[u8], clean: bool) -> Filter {
         //
         // Note the "none" filter is often good for things like
         // line-art diagrams and screenshots that have lots of
         // sharp pixel edges and long runs of solid colors.
         //
-        // The adaptive filter algorithm doesn't work on it, however,
+        // The default minimum sum heuristic doesn't work on it, however,
         // since it measures accumulated filter prediction offets and
         // that gives useless results on absolute color magnitudes.
+        // Heuristics that can judge raw values opt in to trying it.
         //
-        // Compression could be improved for some files if a heuristic
-        // can be devised to check if the none filter will work well.
-        //
 
-        self.filter_sub.filter(prev, src);
-        let mut min = self.filter_sub.get_complexity();
+        let sub = self.score(Filter::Sub, prev, src, clean);
+        let up = self.score(Filter::Up, prev, src, clean);
+        let average = self.score(Filter::Average, prev, src, clean);
+        let paeth = self.score(Filter::Paeth, prev, src, clean);
+        let none = if self.heuristic.includes_none() {
+            self.score(Filter::None, prev, src, clean)
+        } else {
+            u64::MAX
+        };
+        let min = cmp::min(cmp::min(sub, up), cmp::min(average, paeth));
+
+        if none < min {
+            Filter::None
+        } else if min == paeth {
+            Filter::Paeth
+        } else if min == average {
+            Filter::Average
+        } else if min == up {
+            Filter::Up
+        } else /*if min == sub */ {
+            Filter::Sub
+        }
+    }
+
+    //
+    // Compress each candidate onto a copy of the stream of rows chosen
+    // so far, and keep whichever adds the fewest bytes. Each trial is
+    // flushed to count the bytes of its final block, which is the same
+    // for every candidate apart from the candidate row itself.
+    //
+    // The stream starts fresh for each filter chunk, with the default
+    // level and the filtered strategy, so the compressed sizes are only
+    // an estimate of the real ones; the comparisons hold up well.
+    //
+    fn filter_brute_force(&mut self, prev: &[u8], src: &[u8], clean: bool) -> io::Result<Filter> {
+        let mut trial = match self.trial.take() {
+            Some(trial) => trial,
+            None => {
+                let mut options = deflate::Options::new();
+                options.set_window_bits(-15);
+                options.set_strategy(Strategy::Filtered);
+                Deflate::new(options, Counter::new())
+            },
+        };
+
+        // Ties go in the same order of preference as adaptive mode.
+        let mut best = (usize::MAX, Filter::None);
+        for &filter in &[Filter::Paeth, Filter::Average, Filter::Up, Filter::Sub, Filter::None] {
+            self.run(filter, prev, src, clean);
+            let mut copy = trial.copy(Counter::new())?;
+            copy.write(self.filterator(filter).get_data(), Flush::SyncFlush)?;
+            let size = copy.finish()?.count;
+            if size < best.0 {
+                best = (size, filter);
+            }
+        }
+
+        trial.write(self.filterator(best.1).get_data(), Flush::NoFlush)?;
+        self.trial = Some(trial);
+        Ok(best.1)
+    }
 
-        self.filter_up.filter(prev, src);
-        min = cmp::min(min, self.filter_up.get_complexity());
+    fn apply(&mut self, prev: &[u8], src: &[u8], clean: bool) -> io::Result<&[u8]> {
+        self.chosen = match self.mode {
+            Fixed(filter) => {
+                self.run(filter, prev, src, clean);
+                filter
+            },
+            Adaptive => self.filter_adaptive(prev, src, clean),
+            BruteForce => self.filter_brute_force(prev, src, clean)?,
+        };
+        Ok(self.filterator(self.chosen).get_data())
+    }
 
-        self.filter_average.filter(prev, src);
-        min = cmp::min(min, self.filter_average.get_complexity());
+    pub fn filter(&mut self, prev: &[u8], src: &[u8]) -> io::Result<&[u8]> {
+        self.apply(prev, src, false)
+    }
 
-        self.filter_paeth.filter(prev, src);
-        min = cmp::min(min, self.filter_paeth.get_complexity());
+    //
+    // Filter a row after rewriting the color of its fully transparent
+    // pixels to match each candidate filter's prediction. The row as it
+    // was cleaned for the chosen filter is then available from
+    // cleaned_row(), and must be used as the next row's prev.
+    //
+    // Rows without an alpha channel are filtered as they are.
+    //
+    pub fn filter_clean(&mut self, prev: &[u8], src: &[u8]) -> io::Result<&[u8]> {
+        self.apply(prev, src, true)
+    }
 
-        if min == self.filter_paeth.get_complexity() {
-            self.filter_paeth.get_data()
-        } else if min == self.filter_average.get_complexity() {
-            self.filter_average.get_data()
-        } else if min == self.filter_up.get_complexity() {
-            self.filter_up.get_data()
-        } else /*if min == self.filter_sub.get_complexity() */ {
-            self.filter_sub.get_data()
-        }
+    pub fn cleaned_row(&self) -> &[u8] {
+        self.filterator(self.chosen).get_cleaned()
     }
+}
 
-    pub fn filter(&mut self, prev: &[u8], src: &[u8]) -> &[u8] {
-        match self.mode {
-            Fixed(Filter::None)    => self.filter_none.filter(prev, src),
-            Fixed(Filter::Sub)     => self.filter_sub.filter(prev, src),
-            Fixed(Filter::Up)      => self.filter_up.filter(prev, src),
-            Fixed(Filter::Average) => self.filter_average.filter(prev, src),
-            Fixed(Filter::Paeth)   => self.filter_paeth.filter(prev, src),
-            Adaptive               => self.filter_adaptive(prev, src),
+impl Drop for AdaptiveFilter {
+    fn drop(&mut self) {
+        // Free the zlib state of the brute force trial stream.
+        if let Some(trial) = self.trial.take() {
+            let _ = trial.finish();
         }
     }
 }
@@ -468,20 +670,24 @@ impl AdaptiveFilter {
 #[cfg(test)]
 mod tests {
     use super::AdaptiveFilter;
+    use super::Filter;
     use super::Mode;
+    use super::clean_alpha;
+    use super::unfilter;
     use super::super::Header;
     use super::super::ColorType;
+    use super::super::heuristic::{Bigrams, Entropy, FilterHeuristic, MinSum, MinSumWithNone};
 
     #[test]
     fn it_works() {
         let mut header = Header::new();
         header.set_size(1024, 768).unwrap();
         header.set_color(ColorType::Truecolor, 8).unwrap();
-        let mut filter = AdaptiveFilter::new(header, Mode::Adaptive);
+        let mut filter = AdaptiveFilter::new(header, Mode::Adaptive, &MinSum);
 
         let prev = vec![0u8; header.stride()];
         let row = vec![0u8; header.stride()];
-        let filtered_data = filter.filter(&prev, &row);
+        let filtered_data = filter.filter(&prev, &row).unwrap();
         assert_eq!(filtered_data.len(), header.stride() + 1);
     }
 
@@ -490,11 +696,89 @@ mod tests {
         let mut header = Header::new();
         header.set_size(1024, 768).unwrap();
         header.set_color(ColorType::Truecolor, 16).unwrap();
-        let mut filter = AdaptiveFilter::new(header, Mode::Adaptive);
+        let mut filter = AdaptiveFilter::new(header, Mode::Adaptive, &MinSum);
 
         let prev = vec![0u8; header.stride()];
         let row = vec![0u8; header.stride()];
-        let filtered_data = filter.filter(&prev, &row);
+        let filtered_data = filter.filter(&prev, &row).unwrap();
         assert_eq!(filtered_data.len(), header.stride() + 1);
     }
+
+    #[test]
+    fn unfilter_round_trip() {
+        let mut header = Header::new();
+        header.set_size(64, 2).unwrap();
+        header.set_color(ColorType::TruecolorAlpha, 8).unwrap();
+        let stride = header.stride();
+        let bpp = header.bytes_per_pixel();
+
+        let prev: Vec<u8> = (0 .. stride).map(|i| (i * 7 % 251) as u8).collect();
+        let row: Vec<u8> = (0 .. stride).map(|i| (i * 13 % 241) as u8).collect();
+        for &mode in &[Filter::None, Filter::Sub, Filter::Up, Filter::Average, Filter::Paeth] {
+            let mut filter = AdaptiveFilter::new(header, Mode::Fixed(mode), &MinSum);
+            let filtered = filter.filter(&prev, &row).unwrap().to_vec();
+
+            let mut out = vec![0u8; stride];
+            unfilter(bpp, &prev, &filtered, &mut out).unwrap();
+            assert_eq!(out, row);
+        }
+    }
+
+    #[test]
+    fn heuristics() {
+        let mut header = Header::new();
+        header.set_size(64, 2).unwrap();
+        header.set_color(ColorType::Greyscale, 8).unwrap();
+        let stride = header.stride();
+
+        // Line art: sparse dots on a flat background, below a busy row.
+        let prev: Vec<u8> = (0 .. stride).map(|i| (i * 37 % 251) as u8).collect();
+        let row: Vec<u8> = (0 .. stride).map(|i| (i % 4 == 3) as u8).collect();
+
+        // Plain MinSum can't pick None, whatever the row.
+        let mut filter = AdaptiveFilter::new(header, Mode::Adaptive, &MinSum);
+        assert_eq!(filter.filter(&prev, &row).unwrap()[0], Filter::Sub as u8);
+
+        let heuristics: [&'static dyn FilterHeuristic; 3] = [&MinSumWithNone, &Entropy, &Bigrams];
+        for &heuristic in &heuristics {
+            let mut filter = AdaptiveFilter::new(header, Mode::Adaptive, heuristic);
+            assert_eq!(filter.filter(&prev, &row).unwrap()[0], Filter::None as u8);
+        }
+    }
+
+    #[test]
+    fn clean_transparent() {
+        let mut header = Header::new();
+        header.set_size(64, 2).unwrap();
+        header.set_color(ColorType::GreyscaleAlpha, 16).unwrap();
+        let stride = header.stride();
+
+        // Every third pixel is transparent, with garbage color.
+        let prev: Vec<u8> = (0 .. stride).map(|i| (i * 7 % 251) as u8).collect();
+        let row: Vec<u8> = (0 .. stride).map(|i| {
+            if i / 4 % 3 == 0 && i % 4 >= 2 { 0 } else { (i * 13 % 241) as u8 }
+        }).collect();
+        for &mode in &[Filter::None, Filter::Sub, Filter::Up, Filter::Average, Filter::Paeth] {
+            let mut cleaned = row.clone();
+            clean_alpha(&header, mode, &prev, &mut cleaned);
+
+            let mut filter = AdaptiveFilter::new(header, Mode::Fixed(mode), &MinSum);
+            let filtered = filter.filter(&prev, &cleaned).unwrap();
+            for i in 0 .. stride {
+                if i / 4 % 3 == 0 {
+                    if i % 4 < 2 {
+                        assert_eq!(filtered[i + 1], 0);
+                    }
+                } else {
+                    assert_eq!(cleaned[i], row[i]);
+                }
+            }
+
+            // Filtering with cleaning gives the same result.
+            let mut filter = AdaptiveFilter::new(header, Mode::Fixed(mode), &MinSum);
+            let expected = filter.filter(&prev, &cleaned).unwrap().to_vec();
+            assert!(filter.filter_clean(&prev, &row).unwrap() == &expected[..]);
+            assert!(filter.cleaned_row() == &cleaned[..]);
+        }
+    }
 }
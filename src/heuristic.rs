@@ -0,0 +1,262 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// heuristic.rs - scoring of filtered rows for adaptive filter selection
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Heuristics for choosing each row's filter in adaptive filter mode.
+//!
+//! The adaptive filter runs every candidate filter on a row, scores the
+//! results with a `FilterHeuristic`, and keeps the lowest score. Set the
+//! heuristic with `encoder::Options::set_filter_heuristic`; `MinSum` is
+//! the default.
+
+use super::Filter;
+
+/// Scores filtered rows for adaptive filter selection.
+///
+/// Scoring runs on the encoder's filter threads, once per candidate
+/// filter for every row, so it should be cheap.
+pub trait FilterHeuristic: Send + Sync {
+    /// Whether the None filter is a candidate, as well as Sub, Up,
+    /// Average and Paeth. Off by default, since many scores are only
+    /// meaningful on filter deltas and not on raw pixel values.
+    fn includes_none(&self) -> bool {
+        false
+    }
+
+    /// Score a row as filtered with the given filter, not including the
+    /// filter type byte. Lower scores are expected to compress better.
+    fn score(&self, filter: Filter, data: &[u8]) -> u64;
+}
+
+/// The minimum sum of absolute differences heuristic recommended by the
+/// PNG spec, treating each byte as a signed delta. Considers only the
+/// Sub, Up, Average and Paeth filters.
+#[derive(Copy, Clone, Default)]
+pub struct MinSum;
+
+impl FilterHeuristic for MinSum {
+    fn score(&self, _filter: Filter, data: &[u8]) -> u64 {
+        u64::from(sum_abs(data))
+    }
+}
+
+/// The minimum sum heuristic with the None filter also considered,
+/// scoring its raw values the same way, as libpng does.
+///
+/// Helps some line art and screenshots, where runs of flat color pass
+/// through None unchanged.
+#[derive(Copy, Clone, Default)]
+pub struct MinSumWithNone;
+
+impl FilterHeuristic for MinSumWithNone {
+    fn includes_none(&self) -> bool {
+        true
+    }
+
+    fn score(&self, _filter: Filter, data: &[u8]) -> u64 {
+        u64::from(sum_abs(data))
+    }
+}
+
+/// The Shannon entropy of the row's byte values, in bits for the whole
+/// row. Considers all five filters, since it is as meaningful on raw
+/// values as on deltas.
+///
+/// Slower than the minimum sum, but usually picks better on line art,
+/// screenshots, and other images with few distinct values.
+#[derive(Copy, Clone, Default)]
+pub struct Entropy;
+
+impl FilterHeuristic for Entropy {
+    fn includes_none(&self) -> bool {
+        true
+    }
+
+    fn score(&self, _filter: Filter, data: &[u8]) -> u64 {
+        let mut counts = [0u32; 256];
+        for &val in data {
+            counts[val as usize] += 1;
+        }
+
+        let total = data.len() as f64;
+        let mut bits = 0.0;
+        for &count in counts.iter().filter(|&&count| count > 0) {
+            let count = f64::from(count);
+            bits += count * (total / count).log2();
+        }
+        bits.round() as u64
+    }
+}
+
+/// The number of distinct pairs of adjacent bytes in the row. Considers
+/// all five filters.
+///
+/// Approximates how many distinct strings deflate has to match, so
+/// suits images with repeated patterns.
+#[derive(Copy, Clone, Default)]
+pub struct Bigrams;
+
+impl FilterHeuristic for Bigrams {
+    fn includes_none(&self) -> bool {
+        true
+    }
+
+    fn score(&self, _filter: Filter, data: &[u8]) -> u64 {
+        let mut seen = [0u64; 65536 / 64];
+        let mut count = 0;
+        for pair in data.windows(2) {
+            let bigram = (pair[0] as usize) << 8 | pair[1] as usize;
+            let bit = 1 << (bigram & 63);
+            if seen[bigram >> 6] & bit == 0 {
+                seen[bigram >> 6] |= bit;
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+//
+// For the complexity/compressibility heuristic. Absolute value
+// of the byte treated as a signed value, extended to a u32.
+//
+fn filter_complexity_delta(val: u8) -> u32 {
+    i32::abs(i32::from(val as i8)) as u32
+}
+
+//
+// The maximum complexity heuristic value that can be represented
+// without overflow.
+//
+fn complexity_max() -> u32 {
+    u32::max_value() - 256
+}
+
+//
+// Any row with this number of bytes needs to check for overflow
+// of the complexity heuristic.
+//
+fn complexity_big_row(len: usize) -> bool {
+    len >= (1 << 24)
+}
+
+//
+// Complexity/compressibility heuristic recommended by the PNG spec
+// and used in libpng as well.
+//
+// libpng tries to do this inline with the filter with a clever
+// early return if "too complex", but I find that's slower on large
+// files than just running the whole filter.
+//
+#[inline(always)]
+fn estimate_complexity(data: &[u8]) -> u32 {
+    let mut sum = 0u32;
+
+    //
+    // Very long rows could overflow the 32-bit complexity heuristic's
+    // accumulator, but it doesn't trigger until tens of millions
+    // of bytes per row. :)
+    //
+    // The check slows down the inner loop on more realistic sizes
+    // (say, ~31k bytes for a 7680 wide RGBA image) so we skip it.
+    //
+    if complexity_big_row(data.len()) {
+        for iter in data.iter() {
+            sum += filter_complexity_delta(*iter);
+            if sum > complexity_max() {
+                return complexity_max();
+            }
+        }
+    } else {
+        for iter in data.iter() {
+            sum += filter_complexity_delta(*iter);
+        }
+    }
+
+    sum
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+unsafe fn estimate_complexity_avx2(data: &[u8]) -> u32 {
+    estimate_complexity(data)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse4.1")]
+unsafe fn estimate_complexity_sse41(data: &[u8]) -> u32 {
+    estimate_complexity(data)
+}
+
+//
+// The sum runs over every candidate of every row, so pick up
+// wider vector instructions where available, as the filters do.
+//
+fn sum_abs(data: &[u8]) -> u32 {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return unsafe {
+                estimate_complexity_avx2(data)
+            };
+        }
+        if is_x86_feature_detected!("sse4.1") {
+            return unsafe {
+                estimate_complexity_sse41(data)
+            };
+        }
+    }
+    estimate_complexity(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bigrams;
+    use super::Entropy;
+    use super::FilterHeuristic;
+    use super::MinSum;
+    use super::MinSumWithNone;
+    use super::super::Filter;
+
+    #[test]
+    fn scores() {
+        let flat = vec![7u8; 64];
+        let deltas: Vec<u8> = (0 .. 64).map(|i| if i % 2 == 0 { 1 } else { 255 }).collect();
+        let noise: Vec<u8> = (0 .. 64).map(|i| (i * 97 % 256) as u8).collect();
+
+        assert_eq!(MinSum.score(Filter::Sub, &flat), 7 * 64);
+        assert_eq!(MinSum.score(Filter::Sub, &deltas), 64);
+        assert_eq!(MinSumWithNone.score(Filter::None, &deltas), 64);
+        assert!(!MinSum.includes_none());
+        assert!(MinSumWithNone.includes_none());
+
+        assert_eq!(Entropy.score(Filter::None, &flat), 0);
+        assert_eq!(Entropy.score(Filter::None, &deltas), 64);
+        assert_eq!(Entropy.score(Filter::None, &noise), 64 * 6);
+
+        assert_eq!(Bigrams.score(Filter::None, &flat), 1);
+        assert_eq!(Bigrams.score(Filter::None, &deltas), 2);
+        assert_eq!(Bigrams.score(Filter::None, &noise), 63);
+    }
+}
@@ -0,0 +1,174 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// inflate.rs - wrapper for zlib or miniz_oxide suitable for reading incremental zlib streams
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+#[cfg(feature="zlib")]
+use std::io;
+
+#[cfg(feature="zlib")]
+use std::mem;
+
+#[cfg(feature="zlib")]
+use std::os::raw::*;
+
+#[cfg(feature="zlib")]
+use ::libz_sys::*;
+
+#[cfg(feature="zlib")]
+use super::utils::*;
+
+// Without the zlib feature, this comes from miniz_oxide instead.
+#[cfg(all(feature="rust-deflate", not(feature="zlib")))]
+pub use super::miniz::Inflate;
+
+#[cfg(not(any(feature="zlib", feature="rust-deflate")))]
+pub use super::unavailable::Inflate;
+
+//
+// Decompresses a zlib stream whose input arrives in pieces
+// (such as a run of IDAT chunks), producing output on demand
+// into caller-provided buffers.
+//
+#[cfg(feature="zlib")]
+pub struct Inflate {
+    input: Vec<u8>,
+    input_pos: usize,
+    initialized: bool,
+    finished: bool,
+    stream: Box<z_stream>,
+}
+
+#[cfg(feature="zlib")]
+impl Inflate {
+    pub fn new() -> Inflate {
+        Inflate {
+            input: Vec::new(),
+            input_pos: 0,
+            initialized: false,
+            finished: false,
+            stream: Box::new(unsafe {
+                let maybe = mem::MaybeUninit::<z_stream>::zeroed();
+                maybe.assume_init()
+            }),
+        }
+    }
+
+    fn init(&mut self) -> IoResult {
+        if self.initialized {
+            Ok(())
+        } else {
+            let ret = unsafe {
+                inflateInit_(&mut *self.stream,
+                             zlibVersion(),
+                             mem::size_of::<z_stream>() as c_int)
+            };
+            match ret {
+                Z_OK => {
+                    self.initialized = true;
+                    Ok(())
+                },
+                Z_MEM_ERROR => Err(other("Out of memory")),
+                Z_VERSION_ERROR => Err(invalid_input("Incompatible version of zlib")),
+                _ => Err(other("Unexpected error")),
+            }
+        }
+    }
+
+    //
+    // True if all input provided so far has been consumed.
+    //
+    pub fn needs_input(&self) -> bool {
+        self.input_pos == self.input.len()
+    }
+
+    //
+    // True once the end of the zlib stream, including its
+    // checksum, has been reached.
+    //
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    //
+    // Provide the next piece of compressed input.
+    // Any previous input must be fully consumed first.
+    //
+    pub fn set_input(&mut self, data: Vec<u8>) {
+        assert!(self.needs_input());
+        self.input = data;
+        self.input_pos = 0;
+    }
+
+    //
+    // Decompress into the output buffer until it is full,
+    // the input is exhausted, or the stream ends.
+    //
+    // Returns the number of bytes written.
+    //
+    pub fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        self.init()?;
+        if self.finished || out.is_empty() {
+            return Ok(0);
+        }
+
+        let stream = &mut *self.stream;
+        let avail_in = self.input.len() - self.input_pos;
+        stream.next_in = self.input[self.input_pos ..].as_ptr() as *mut u8;
+        stream.avail_in = avail_in as c_uint;
+        stream.next_out = out.as_mut_ptr();
+        stream.avail_out = out.len() as c_uint;
+
+        let ret = unsafe {
+            inflate(stream, Z_NO_FLUSH)
+        };
+
+        self.input_pos += avail_in - stream.avail_in as usize;
+        let written = out.len() - stream.avail_out as usize;
+        match ret {
+            Z_OK => Ok(written),
+            Z_STREAM_END => {
+                self.finished = true;
+                Ok(written)
+            },
+            // No progress possible; more input is needed.
+            Z_BUF_ERROR => Ok(written),
+            Z_NEED_DICT => Err(invalid_data("Unexpected preset dictionary in image data")),
+            Z_DATA_ERROR => Err(invalid_data("Corrupt compressed image data")),
+            Z_MEM_ERROR => Err(other("Out of memory")),
+            Z_STREAM_ERROR => Err(invalid_input("Inconsistent stream state")),
+            _ => Err(other("Unexpected error")),
+        }
+    }
+}
+
+#[cfg(feature="zlib")]
+impl Drop for Inflate {
+    fn drop(&mut self) {
+        if self.initialized {
+            unsafe {
+                inflateEnd(&mut *self.stream);
+            }
+        }
+    }
+}
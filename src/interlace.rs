@@ -0,0 +1,295 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// interlace.rs - Adam7 pass geometry and division of images into chunks
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+use std::cmp;
+use std::iter::StepBy;
+use std::ops::Range;
+
+use super::Header;
+use super::InterlaceMethod;
+
+//
+// Range of rows covered by a single chunk, within either
+// the whole image or the Adam7 pass it belongs to.
+//
+#[derive(Copy, Clone)]
+pub struct ChunkSpan {
+    // Dimensions of the image or pass
+    pub header: Header,
+    // Adam7 pass index, if interlaced
+    pub pass: Option<usize>,
+    pub start_row: usize,
+    pub end_row: usize,
+}
+
+//
+// Divide an image or interlace pass into chunks of rows
+// approximating the requested chunk size in filtered bytes.
+//
+fn layout_pass(header: Header, pass: Option<usize>, chunk_size: usize, spans: &mut Vec<ChunkSpan>) {
+    let stride = header.stride() + 1;
+    let height = header.height as usize;
+
+    // Very wide images may have fewer rows than chunks.
+    let chunks = cmp::min(height, cmp::max(1, stride * height / chunk_size));
+    for index in 0 .. chunks {
+        spans.push(ChunkSpan {
+            header,
+            pass,
+            start_row: index * height / chunks,
+            end_row: (index + 1) * height / chunks,
+        });
+    }
+}
+
+//
+// Divide a whole image into chunks, in data stream order.
+// Interlaced images are split up pass by pass; each non-empty
+// pass gets its own chunks, but all go into a single deflate stream.
+//
+pub fn layout_chunks(header: &Header, chunk_size: usize) -> Vec<ChunkSpan> {
+    let mut spans = Vec::new();
+    match header.interlace_method {
+        InterlaceMethod::Standard => {
+            layout_pass(*header, None, chunk_size, &mut spans);
+        },
+        InterlaceMethod::Adam7 => {
+            for (index, pass) in PASSES.iter().enumerate() {
+                if let Some(pass_header) = pass.header(header) {
+                    layout_pass(pass_header, Some(index), chunk_size, &mut spans);
+                }
+            }
+        },
+    }
+    spans
+}
+
+//
+// One of the seven Adam7 passes, each of which is encoded as its own
+// reduced image with its own rows, filtering, and stride.
+//
+// https://www.w3.org/TR/PNG/#8Interlace
+//
+pub struct Pass {
+    x_start: usize,
+    y_start: usize,
+    x_step: usize,
+    y_step: usize,
+}
+
+pub const PASSES: [Pass; 7] = [
+    Pass { x_start: 0, y_start: 0, x_step: 8, y_step: 8 },
+    Pass { x_start: 4, y_start: 0, x_step: 8, y_step: 8 },
+    Pass { x_start: 0, y_start: 4, x_step: 4, y_step: 8 },
+    Pass { x_start: 2, y_start: 0, x_step: 4, y_step: 4 },
+    Pass { x_start: 0, y_start: 2, x_step: 2, y_step: 4 },
+    Pass { x_start: 1, y_start: 0, x_step: 2, y_step: 2 },
+    Pass { x_start: 0, y_start: 1, x_step: 1, y_step: 2 },
+];
+
+fn pass_extent(size: usize, start: usize, step: usize) -> usize {
+    if size > start {
+        (size - start + step - 1) / step
+    } else {
+        0
+    }
+}
+
+impl Pass {
+    //
+    // Width in pixels of the reduced image for this pass.
+    //
+    pub fn width(&self, header: &Header) -> usize {
+        pass_extent(header.width as usize, self.x_start, self.x_step)
+    }
+
+    //
+    // Height in rows of the reduced image for this pass.
+    //
+    pub fn height(&self, header: &Header) -> usize {
+        pass_extent(header.height as usize, self.y_start, self.y_step)
+    }
+
+    //
+    // Header describing the reduced image, for stride and filter setup.
+    // Returns None if the pass is empty, which happens on small images;
+    // empty passes are skipped entirely in the data stream.
+    //
+    pub fn header(&self, header: &Header) -> Option<Header> {
+        let width = self.width(header);
+        let height = self.height(header);
+        if width == 0 || height == 0 {
+            None
+        } else {
+            let mut pass_header = *header;
+            pass_header.width = width as u32;
+            pass_header.height = height as u32;
+            Some(pass_header)
+        }
+    }
+
+    //
+    // Indices of the full-size image rows that contribute to this pass.
+    //
+    pub fn rows(&self, header: &Header) -> StepBy<Range<usize>> {
+        (self.y_start .. header.height as usize).step_by(self.y_step)
+    }
+
+    //
+    // Gather this pass's pixels from a full-size image row with the
+    // given bits per pixel, appending the reduced row to output.
+    // This is the header's packed size, unless input samples
+    // are unpacked to a byte each.
+    //
+    pub fn extract_row(&self, header: &Header, bits: usize, row: &[u8], out: &mut Vec<u8>) {
+        let width = self.width(header);
+        let columns = (0 .. width).map(|i| self.x_start + i * self.x_step);
+
+        if bits >= 8 {
+            let bytes = bits >> 3;
+            for x in columns {
+                out.extend_from_slice(&row[x * bytes .. (x + 1) * bytes]);
+            }
+        } else {
+            // Sub-byte pixels are packed most significant bits first.
+            let mask = (1u8 << bits) - 1;
+            let mut acc = 0u8;
+            let mut filled = 0;
+            for x in columns {
+                let bit = x * bits;
+                let shift = 8 - bits - (bit & 7);
+                let val = (row[bit >> 3] >> shift) & mask;
+
+                acc |= val << (8 - bits - filled);
+                filled += bits;
+                if filled == 8 {
+                    out.push(acc);
+                    acc = 0;
+                    filled = 0;
+                }
+            }
+            if filled > 0 {
+                out.push(acc);
+            }
+        }
+    }
+
+    //
+    // Inverse of extract_row: distribute a packed reduced row's
+    // pixels into their places in a full-size image row.
+    //
+    pub fn scatter_row(&self, header: &Header, pass_row: &[u8], row: &mut [u8]) {
+        let bits = header.color_type.channels() * header.depth as usize;
+        let width = self.width(header);
+        let columns = (0 .. width).map(|i| self.x_start + i * self.x_step);
+
+        if bits >= 8 {
+            let bytes = bits >> 3;
+            for (i, x) in columns.enumerate() {
+                row[x * bytes .. (x + 1) * bytes].clone_from_slice(&pass_row[i * bytes .. (i + 1) * bytes]);
+            }
+        } else {
+            let mask = (1u8 << bits) - 1;
+            for (i, x) in columns.enumerate() {
+                let src_bit = i * bits;
+                let val = (pass_row[src_bit >> 3] >> (8 - bits - (src_bit & 7))) & mask;
+
+                let dest_bit = x * bits;
+                let shift = 8 - bits - (dest_bit & 7);
+                let byte = &mut row[dest_bit >> 3];
+                *byte = (*byte & !(mask << shift)) | (val << shift);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PASSES;
+    use super::layout_chunks;
+    use super::super::Header;
+    use super::super::ColorType;
+    use super::super::InterlaceMethod;
+
+    #[test]
+    fn pass_sizes() {
+        let mut header = Header::new();
+        header.set_size(10, 3).unwrap();
+
+        let sizes: Vec<(usize, usize)> = PASSES.iter().map(|pass| {
+            (pass.width(&header), pass.height(&header))
+        }).collect();
+        assert_eq!(sizes, vec![(2, 1), (1, 1), (3, 0), (2, 1), (5, 1), (5, 2), (10, 1)]);
+        assert!(PASSES[2].header(&header).is_none());
+    }
+
+    #[test]
+    fn extract_bytes() {
+        let mut header = Header::new();
+        header.set_size(10, 1).unwrap();
+        header.set_color(ColorType::Greyscale, 8).unwrap();
+
+        let row: Vec<u8> = (0 .. 10).collect();
+        let mut out = Vec::new();
+        PASSES[5].extract_row(&header, 8, &row, &mut out);
+        assert_eq!(out, vec![1, 3, 5, 7, 9]);
+    }
+
+    #[test]
+    fn extract_bits() {
+        let mut header = Header::new();
+        header.set_size(10, 1).unwrap();
+        header.set_color(ColorType::Greyscale, 2).unwrap();
+
+        // Pixels 0, 1, 2, 3 ... 9 as 2-bit values 0 1 2 3 0 1 2 3 0 1
+        let row = [0b0001_1011, 0b0001_1011, 0b0001_0000];
+        let mut out = Vec::new();
+        PASSES[4].extract_row(&header, 2, &row, &mut out);
+
+        // Even pixels: 0 2 0 2 0
+        assert_eq!(out, vec![0b0010_0010, 0b0000_0000]);
+
+        let mut row = [0u8; 3];
+        PASSES[4].scatter_row(&header, &out, &mut row);
+        assert_eq!(row, [0b0000_1000, 0b0000_1000, 0b0000_0000]);
+    }
+
+    #[test]
+    fn layout() {
+        let mut header = Header::new();
+        header.set_size(1024, 1024).unwrap();
+        header.set_color(ColorType::Truecolor, 8).unwrap();
+
+        let spans = layout_chunks(&header, 256 * 1024);
+        assert_eq!(spans.len(), 12);
+        assert_eq!(spans[0].start_row, 0);
+        assert_eq!(spans[11].end_row, 1024);
+
+        header.set_interlace_method(InterlaceMethod::Adam7).unwrap();
+        let spans = layout_chunks(&header, 256 * 1024);
+        let rows: usize = spans.iter().map(|span| span.end_row - span.start_row).sum();
+        assert_eq!(rows, 128 + 128 + 128 + 256 + 256 + 512 + 512);
+    }
+}
@@ -24,10 +24,17 @@
 //
 
 //! mtpng - a multithreaded parallel PNG encoder in Rust
+//!
+//! Also includes a pipelined decoder for reading PNG files back.
 
 extern crate rayon;
 extern crate crc;
+#[cfg(feature="zlib")]
 extern crate libz_sys;
+#[cfg(all(feature="rust-deflate", not(feature="zlib")))]
+extern crate miniz_oxide;
+#[cfg(not(any(feature="zlib", feature="rust-deflate")))]
+compile_error!("either the zlib or the rust-deflate feature is needed for compression");
 #[macro_use] extern crate itertools;
 extern crate typenum;
 
@@ -36,14 +43,32 @@ extern crate libc;
 #[cfg(feature="capi")]
 pub mod capi;
 
+pub mod backend;
+mod convert;
 mod deflate;
 mod filter;
+pub mod decoder;
 pub mod encoder;
+pub mod exif;
+pub mod heuristic;
+mod inflate;
+mod interlace;
+#[cfg(all(feature="rust-deflate", not(feature="zlib")))]
+mod miniz;
+mod optimal;
+mod ordering;
+mod palette;
+pub mod quantize;
+mod reader;
+pub mod reduce;
+#[cfg(not(any(feature="zlib", feature="rust-deflate")))]
+mod unavailable;
 mod utils;
 mod writer;
 
 pub type Strategy = deflate::Strategy;
 pub type Filter = filter::Filter;
+pub type Palette = palette::Palette;
 
 use std::convert::TryFrom;
 use std::io;
@@ -57,6 +82,9 @@ pub enum Mode<T> {
     Adaptive,
     /// Fixed value
     Fixed(T),
+    /// Try each value by compressing with it, and keep whichever is
+    /// smallest. Much slower than Adaptive, for when size matters most.
+    BruteForce,
 }
 
 /// PNG color types.
@@ -148,8 +176,6 @@ pub enum FilterMethod {
 }
 
 /// PNG header interlace method representation.
-///
-/// Currently only Standard is supported; Adam7 interlacing will throw an error if used.
 #[derive(Copy, Clone)]
 #[repr(u8)]
 pub enum InterlaceMethod {
@@ -159,10 +185,28 @@ pub enum InterlaceMethod {
     Standard = 0,
     /// Adam7 interlacing.
     ///
-    /// Not yet supported.
+    /// Pixels are split into seven passes of increasing resolution,
+    /// allowing progressive display while loading. The encoder must
+    /// buffer the entire image before it can begin compressing, and
+    /// files are usually somewhat larger.
     Adam7 = 1,
 }
 
+impl TryFrom<u8> for InterlaceMethod {
+    type Error = io::Error;
+
+    /// Validate and produce an InterlaceMethod from one of the PNG header constants.
+    ///
+    /// Will return an error on invalid input.
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(InterlaceMethod::Standard),
+            1 => Ok(InterlaceMethod::Adam7),
+            _ => Err(invalid_input("Invalid interlace method")),
+        }
+    }
+}
+
 /// PNG header representation.
 ///
 /// You must create one of these with image metadata when encoding,
@@ -255,7 +299,7 @@ impl Header {
 
         // And round up to nearest byte.
         let stride_bytes = stride_bits >> 3;
-        let remainder = stride_bits & 3;
+        let remainder = stride_bits & 7;
         if remainder > 0 {
             stride_bytes + 1
         } else {
@@ -316,13 +360,7 @@ impl Header {
     }
 
     /// Set the interlace method.
-    ///
-    /// Currently only Standard is supported; requesting Adam7 will return an error.
     pub fn set_interlace_method(&mut self, interlace_method: InterlaceMethod) -> io::Result<()> {
-        match interlace_method {
-            InterlaceMethod::Standard => {},
-            InterlaceMethod::Adam7 => return Err(invalid_input("Adam7 interlacing not yet")),
-        }
         self.interlace_method = interlace_method;
         Ok(())
     }
@@ -334,6 +372,753 @@ impl Default for Header {
     }
 }
 
+/// Channel layout of input pixels.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PixelLayout {
+    /// Interleaved in PNG channel order, as described by the header.
+    Standard = 0,
+    /// Interleaved blue, green, red, for truecolor images.
+    Bgr = 1,
+    /// Interleaved blue, green, red, alpha, for truecolor images with alpha.
+    Bgra = 2,
+    /// Interleaved alpha, red, green, blue, for truecolor images with alpha.
+    Argb = 3,
+    /// Interleaved alpha, blue, green, red, for truecolor images with alpha.
+    Abgr = 4,
+    /// One plane per channel in PNG channel order, each holding just that
+    /// channel's samples. Rows are written with Encoder::write_image_planes.
+    Planar = 5,
+}
+
+impl TryFrom<u8> for PixelLayout {
+    type Error = io::Error;
+
+    /// Validate and produce a PixelLayout from its numeric value.
+    ///
+    /// Will return an error on invalid input.
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(PixelLayout::Standard),
+            1 => Ok(PixelLayout::Bgr),
+            2 => Ok(PixelLayout::Bgra),
+            3 => Ok(PixelLayout::Argb),
+            4 => Ok(PixelLayout::Abgr),
+            5 => Ok(PixelLayout::Planar),
+            _ => Err(invalid_input("Invalid pixel layout")),
+        }
+    }
+}
+
+/// Transfer function for floating-point input samples, which are
+/// quantised to the image's 8 or 16-bit depth.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Transfer {
+    /// Clamp to 0.0-1.0 and scale to the full sample range.
+    Clamp = 0,
+    /// Encode linear light with the sRGB transfer curve, after clamping.
+    /// Alpha samples are left linear.
+    LinearToSrgb = 1,
+}
+
+impl TryFrom<u8> for Transfer {
+    type Error = io::Error;
+
+    /// Validate and produce a Transfer from its numeric value.
+    ///
+    /// Will return an error on invalid input.
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(Transfer::Clamp),
+            1 => Ok(Transfer::LinearToSrgb),
+            _ => Err(invalid_input("Invalid transfer function")),
+        }
+    }
+}
+
+//
+// Type of input samples, set by the encoder entry point used.
+//
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum SampleType {
+    // Bytes in PNG order, big-endian at 16 bits.
+    Bytes,
+    // Native-endian 16-bit integers.
+    U16,
+    // Native-endian 32-bit floats.
+    F32,
+}
+
+/// Input row format representation.
+///
+/// Describes how the pixel rows passed to the encoder are laid out,
+/// when they differ from the packed PNG format. Rows are converted to
+/// the PNG format on the encoder's worker threads, just before filtering.
+#[derive(Copy, Clone)]
+pub struct InputFormat {
+    unpacked: bool,
+    layout: PixelLayout,
+    transfer: Transfer,
+    premultiplied: bool,
+    samples: SampleType,
+    pitch: usize,
+    bottom_up: bool,
+    crop_offset: Option<(u32, u32)>,
+}
+
+impl InputFormat {
+    /// Create a new InputFormat struct with default settings.
+    ///
+    /// This will expect rows already packed in the PNG format,
+    /// as described by the image header, stored top-down with no
+    /// padding between them.
+    /// You can mutate the state using the set_* methods.
+    pub fn new() -> InputFormat {
+        InputFormat {
+            unpacked: false,
+            layout: PixelLayout::Standard,
+            transfer: Transfer::Clamp,
+            premultiplied: false,
+            samples: SampleType::Bytes,
+            pitch: 0,
+            bottom_up: false,
+            crop_offset: None,
+        }
+    }
+
+    /// Get whether sub-byte samples are provided one per byte.
+    pub fn unpacked(&self) -> bool {
+        self.unpacked
+    }
+
+    /// Get the channel layout of input pixels.
+    pub fn layout(&self) -> PixelLayout {
+        self.layout
+    }
+
+    /// Get the transfer function for floating-point input.
+    pub fn transfer(&self) -> Transfer {
+        self.transfer
+    }
+
+    /// Get whether input color samples are premultiplied by alpha.
+    pub fn premultiplied(&self) -> bool {
+        self.premultiplied
+    }
+
+    /// Get the distance in bytes between the starts of input rows,
+    /// or 0 if rows are tightly packed.
+    pub fn pitch(&self) -> usize {
+        self.pitch
+    }
+
+    /// Get whether input rows are stored bottom-up.
+    pub fn bottom_up(&self) -> bool {
+        self.bottom_up
+    }
+
+    /// Get the pixel offset of the image within the input buffer,
+    /// if it is cropped from a larger surface.
+    pub fn crop_offset(&self) -> Option<(u32, u32)> {
+        self.crop_offset
+    }
+
+    /// Set whether sub-byte samples are provided one per byte.
+    ///
+    /// At 1, 2, and 4 bit depths, each sample is then given as a byte
+    /// holding its value, and is bit-packed by the encoder. Values that
+    /// don't fit in the depth are rejected when the row is encoded.
+    ///
+    /// Has no effect at depths of 8 and above.
+    pub fn set_unpacked(&mut self, unpacked: bool) -> io::Result<()> {
+        self.unpacked = unpacked;
+        Ok(())
+    }
+
+    /// Set the channel layout of input pixels, to be reordered into
+    /// PNG channel order by the encoder.
+    ///
+    /// Interleaved layouts must have the same channels as the image's
+    /// color type; this is checked when rows are written.
+    pub fn set_layout(&mut self, layout: PixelLayout) -> io::Result<()> {
+        self.layout = layout;
+        Ok(())
+    }
+
+    /// Set the transfer function used to quantise floating-point
+    /// input from Encoder::write_image_rows_f32.
+    pub fn set_transfer(&mut self, transfer: Transfer) -> io::Result<()> {
+        self.transfer = transfer;
+        Ok(())
+    }
+
+    /// Set whether input color samples are premultiplied by alpha, as
+    /// produced by many compositors. PNG stores straight alpha, so the
+    /// encoder divides them back out, rounding to nearest. Color is
+    /// set to 0 where alpha is 0.
+    ///
+    /// Requires an image with an alpha channel; this is checked
+    /// when rows are written.
+    pub fn set_premultiplied(&mut self, premultiplied: bool) -> io::Result<()> {
+        self.premultiplied = premultiplied;
+        Ok(())
+    }
+
+    /// Set the distance in bytes between the starts of input rows,
+    /// for buffers with padding at the end of rows. 0 means rows are
+    /// tightly packed at the input stride. For planar input, this is
+    /// the pitch within each plane.
+    ///
+    /// The pitch must cover the row's pixel data, including any
+    /// crop offset; this is checked when rows are written. The
+    /// padding may be left off the last row of a buffer.
+    pub fn set_pitch(&mut self, pitch: usize) -> io::Result<()> {
+        self.pitch = pitch;
+        Ok(())
+    }
+
+    /// Set whether input rows are stored bottom-up, with the last row
+    /// of each buffer being the topmost, as in BMP files and some
+    /// graphics APIs.
+    pub fn set_bottom_up(&mut self, bottom_up: bool) -> io::Result<()> {
+        self.bottom_up = bottom_up;
+        Ok(())
+    }
+
+    /// Set the pixel offset of the image's top-left corner within each
+    /// input buffer, to encode a region of a larger surface.
+    ///
+    /// Rows above the offset are skipped, as are any rows beyond the
+    /// bottom of the image, so the whole surface may be passed in at
+    /// once. For packed sub-byte input, the x offset must fall on a
+    /// byte boundary.
+    pub fn set_crop_offset(&mut self, x: u32, y: u32) -> io::Result<()> {
+        self.crop_offset = Some((x, y));
+        Ok(())
+    }
+
+    //
+    // Number of buffers the input is split into.
+    //
+    fn planes(&self, header: &Header) -> usize {
+        match self.layout {
+            PixelLayout::Planar => header.color_type.channels(),
+            _ => 1,
+        }
+    }
+
+    //
+    // Bits per pixel in the input rows, across all planes.
+    //
+    fn bits_per_pixel(&self, header: &Header) -> usize {
+        let depth = match self.samples {
+            SampleType::U16 => 16,
+            SampleType::F32 => 32,
+            SampleType::Bytes if self.unpacked && header.depth < 8 => 8,
+            SampleType::Bytes => header.depth as usize,
+        };
+        header.color_type.channels() * depth
+    }
+
+    /// Calculate the stride in bytes for input rows of the given image.
+    /// This is the length of a row's pixel data, not counting padding;
+    /// for planar input, it covers all planes together.
+    ///
+    /// Will panic on arithmetic overflow if given pathologically long rows.
+    pub fn stride(&self, header: &Header) -> usize {
+        let stride_bits = self.bits_per_pixel(header)
+                              .checked_mul(header.width as usize)
+                              .unwrap();
+        (stride_bits + 7) >> 3
+    }
+}
+
+impl Default for InputFormat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// APNG frame disposal operation, applied to the frame's region
+/// of the output buffer after the frame is displayed.
+#[derive(Copy, Clone)]
+#[repr(u8)]
+pub enum DisposeOp {
+    /// Leave the output buffer as it is.
+    None = 0,
+    /// Clear the frame's region to fully transparent black.
+    Background = 1,
+    /// Revert the frame's region to its contents before the frame.
+    Previous = 2,
+}
+
+impl TryFrom<u8> for DisposeOp {
+    type Error = io::Error;
+
+    /// Validate and produce a DisposeOp from one of the APNG constants.
+    ///
+    /// Will return an error on invalid input.
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(DisposeOp::None),
+            1 => Ok(DisposeOp::Background),
+            2 => Ok(DisposeOp::Previous),
+            _ => Err(invalid_input("Invalid dispose op")),
+        }
+    }
+}
+
+/// APNG frame blending operation, determining how the frame's
+/// pixels are combined with the output buffer.
+#[derive(Copy, Clone)]
+#[repr(u8)]
+pub enum BlendOp {
+    /// Replace the region's pixels, including alpha.
+    Source = 0,
+    /// Composite the frame over the region using its alpha.
+    Over = 1,
+}
+
+impl TryFrom<u8> for BlendOp {
+    type Error = io::Error;
+
+    /// Validate and produce a BlendOp from one of the APNG constants.
+    ///
+    /// Will return an error on invalid input.
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(BlendOp::Source),
+            1 => Ok(BlendOp::Over),
+            _ => Err(invalid_input("Invalid blend op")),
+        }
+    }
+}
+
+/// APNG frame control representation.
+///
+/// Describes the region, timing, and compositing of one frame of
+/// an animated PNG. The frame's pixel data follows, sized to the
+/// frame's region rather than the full image.
+///
+/// See [the APNG spec](https://wiki.mozilla.org/APNG_Specification#.60fcTL.60:_The_Frame_Control_Chunk)
+/// for details.
+#[derive(Copy, Clone)]
+pub struct FrameControl {
+    width: u32,
+    height: u32,
+    x_offset: u32,
+    y_offset: u32,
+    delay_num: u16,
+    delay_den: u16,
+    dispose_op: DisposeOp,
+    blend_op: BlendOp,
+}
+
+impl FrameControl {
+    /// Create a new FrameControl struct with default settings.
+    ///
+    /// This will be 1x1 pixels at the top-left corner, with no delay,
+    /// no disposal, and source blending. You can mutate the state using
+    /// the set_* methods.
+    pub fn new() -> FrameControl {
+        FrameControl {
+            width: 1,
+            height: 1,
+            x_offset: 0,
+            y_offset: 0,
+            delay_num: 0,
+            delay_den: 100,
+            dispose_op: DisposeOp::None,
+            blend_op: BlendOp::Source,
+        }
+    }
+
+    /// Get the pixel width of the frame.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Get the pixel height of the frame.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Get the horizontal offset of the frame within the image.
+    pub fn x_offset(&self) -> u32 {
+        self.x_offset
+    }
+
+    /// Get the vertical offset of the frame within the image.
+    pub fn y_offset(&self) -> u32 {
+        self.y_offset
+    }
+
+    /// Get the frame delay as a (numerator, denominator) fraction of a second.
+    pub fn delay(&self) -> (u16, u16) {
+        (self.delay_num, self.delay_den)
+    }
+
+    /// Get the disposal operation.
+    pub fn dispose_op(&self) -> DisposeOp {
+        self.dispose_op
+    }
+
+    /// Get the blending operation.
+    pub fn blend_op(&self) -> BlendOp {
+        self.blend_op
+    }
+
+    /// Set the pixel dimensions of the frame.
+    ///
+    /// Returns error if width or height are 0. The frame must fit within
+    /// the image, which is checked when the frame is written.
+    pub fn set_size(&mut self, width: u32, height: u32) -> io::Result<()> {
+        if width == 0 {
+            Err(invalid_input("width cannot be 0"))
+        } else if height == 0 {
+            Err(invalid_input("height cannot be 0"))
+        } else {
+            self.width = width;
+            self.height = height;
+            Ok(())
+        }
+    }
+
+    /// Set the position of the frame's top-left corner within the image.
+    pub fn set_offset(&mut self, x_offset: u32, y_offset: u32) -> io::Result<()> {
+        self.x_offset = x_offset;
+        self.y_offset = y_offset;
+        Ok(())
+    }
+
+    /// Set the time to display the frame, as a fraction of a second.
+    ///
+    /// A denominator of 0 is treated as 100 by decoders.
+    pub fn set_delay(&mut self, delay_num: u16, delay_den: u16) -> io::Result<()> {
+        self.delay_num = delay_num;
+        self.delay_den = delay_den;
+        Ok(())
+    }
+
+    /// Set the disposal operation.
+    pub fn set_dispose_op(&mut self, dispose_op: DisposeOp) -> io::Result<()> {
+        self.dispose_op = dispose_op;
+        Ok(())
+    }
+
+    /// Set the blending operation.
+    pub fn set_blend_op(&mut self, blend_op: BlendOp) -> io::Result<()> {
+        self.blend_op = blend_op;
+        Ok(())
+    }
+}
+
+impl Default for FrameControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Unit for physical pixel dimensions.
+#[derive(Copy, Clone)]
+#[repr(u8)]
+pub enum PhysicalUnit {
+    /// Unit not specified; only the aspect ratio is defined.
+    Unknown = 0,
+    /// Pixels per meter.
+    Meter = 1,
+}
+
+impl TryFrom<u8> for PhysicalUnit {
+    type Error = io::Error;
+
+    /// Validate and produce a PhysicalUnit from one of the PNG constants.
+    ///
+    /// Will return an error on invalid input.
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(PhysicalUnit::Unknown),
+            1 => Ok(PhysicalUnit::Meter),
+            _ => Err(invalid_input("Invalid physical unit")),
+        }
+    }
+}
+
+/// sRGB rendering intent, describing how colors outside the
+/// output device's gamut should be handled.
+///
+/// See [the PNG spec](https://www.w3.org/TR/PNG/#11sRGB) for details.
+#[derive(Copy, Clone)]
+#[repr(u8)]
+pub enum RenderingIntent {
+    /// Preserve the overall appearance; good for photographs.
+    Perceptual = 0,
+    /// Match in-gamut colors exactly relative to the white point.
+    RelativeColorimetric = 1,
+    /// Preserve saturation; good for charts and graphics.
+    Saturation = 2,
+    /// Match in-gamut colors exactly, without white point adaptation.
+    AbsoluteColorimetric = 3,
+}
+
+impl TryFrom<u8> for RenderingIntent {
+    type Error = io::Error;
+
+    /// Validate and produce a RenderingIntent from one of the PNG constants.
+    ///
+    /// Will return an error on invalid input.
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(RenderingIntent::Perceptual),
+            1 => Ok(RenderingIntent::RelativeColorimetric),
+            2 => Ok(RenderingIntent::Saturation),
+            3 => Ok(RenderingIntent::AbsoluteColorimetric),
+            _ => Err(invalid_input("Invalid rendering intent")),
+        }
+    }
+}
+
+/// Coding-independent code points, identifying the color space of
+/// the image by its ITU-T H.273 color primaries and transfer function.
+///
+/// PNG data is always RGB, so the matrix coefficients are always 0.
+///
+/// See [the PNG spec](https://www.w3.org/TR/png-3/#cICP-chunk) for details.
+#[derive(Copy, Clone)]
+pub struct Cicp {
+    color_primaries: u8,
+    transfer_function: u8,
+    full_range: bool,
+}
+
+impl Cicp {
+    /// Create code points for full-range sRGB.
+    pub fn new() -> Cicp {
+        Cicp {
+            color_primaries: 1,
+            transfer_function: 13,
+            full_range: true,
+        }
+    }
+
+    /// Create code points for full-range BT.2100 with the
+    /// perceptual quantizer (PQ) transfer function.
+    pub fn bt2100_pq() -> Cicp {
+        Cicp {
+            color_primaries: 9,
+            transfer_function: 16,
+            full_range: true,
+        }
+    }
+
+    /// Create code points for full-range BT.2100 with the
+    /// hybrid log-gamma (HLG) transfer function.
+    pub fn bt2100_hlg() -> Cicp {
+        Cicp {
+            color_primaries: 9,
+            transfer_function: 18,
+            full_range: true,
+        }
+    }
+
+    /// Get the H.273 color primaries code point.
+    pub fn color_primaries(&self) -> u8 {
+        self.color_primaries
+    }
+
+    /// Get the H.273 transfer characteristics code point.
+    pub fn transfer_function(&self) -> u8 {
+        self.transfer_function
+    }
+
+    /// Get whether samples use the full range rather than video range.
+    pub fn full_range(&self) -> bool {
+        self.full_range
+    }
+
+    /// Set the H.273 color primaries code point.
+    ///
+    /// Returns error on values reserved by H.273.
+    pub fn set_color_primaries(&mut self, color_primaries: u8) -> io::Result<()> {
+        if matches!(color_primaries, 1 | 2 | 4 ..= 12 | 22) {
+            self.color_primaries = color_primaries;
+            Ok(())
+        } else {
+            Err(invalid_input("Reserved color primaries code point"))
+        }
+    }
+
+    /// Set the H.273 transfer characteristics code point.
+    ///
+    /// Returns error on values reserved by H.273.
+    pub fn set_transfer_function(&mut self, transfer_function: u8) -> io::Result<()> {
+        if matches!(transfer_function, 1 | 2 | 4 ..= 18) {
+            self.transfer_function = transfer_function;
+            Ok(())
+        } else {
+            Err(invalid_input("Reserved transfer function code point"))
+        }
+    }
+
+    /// Set whether samples use the full range rather than video range.
+    pub fn set_full_range(&mut self, full_range: bool) -> io::Result<()> {
+        self.full_range = full_range;
+        Ok(())
+    }
+}
+
+impl Default for Cicp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Mastering display color volume, describing the display used to
+/// grade HDR content.
+///
+/// Chromaticities are CIE 1931 x,y coordinates in units of 0.00002,
+/// and luminance is in units of 0.0001 cd/m².
+///
+/// See [the PNG spec](https://www.w3.org/TR/png-3/#mDCV-chunk) for details.
+#[derive(Copy, Clone)]
+pub struct MasteringDisplay {
+    primaries: [(u16, u16); 3],
+    white_point: (u16, u16),
+    max_luminance: u32,
+    min_luminance: u32,
+}
+
+impl MasteringDisplay {
+    /// Create a mastering display description with BT.2020 primaries,
+    /// a D65 white point, and luminance from 0.005 to 1000 cd/m².
+    pub fn new() -> MasteringDisplay {
+        MasteringDisplay {
+            primaries: [(35400, 14600), (8500, 39850), (6550, 2300)],
+            white_point: (15635, 16450),
+            max_luminance: 10_000_000,
+            min_luminance: 50,
+        }
+    }
+
+    /// Get the red, green, and blue primaries.
+    pub fn primaries(&self) -> [(u16, u16); 3] {
+        self.primaries
+    }
+
+    /// Get the white point.
+    pub fn white_point(&self) -> (u16, u16) {
+        self.white_point
+    }
+
+    /// Get the (maximum, minimum) luminance.
+    pub fn luminance(&self) -> (u32, u32) {
+        (self.max_luminance, self.min_luminance)
+    }
+
+    /// Set the red, green, and blue primaries.
+    ///
+    /// Returns error if any coordinate exceeds 1.0 (50000).
+    pub fn set_primaries(&mut self, red: (u16, u16), green: (u16, u16), blue: (u16, u16)) -> io::Result<()> {
+        let primaries = [red, green, blue];
+        if primaries.iter().any(|&(x, y)| x > 50000 || y > 50000) {
+            Err(invalid_input("Chromaticity coordinates cannot exceed 1.0"))
+        } else {
+            self.primaries = primaries;
+            Ok(())
+        }
+    }
+
+    /// Set the white point.
+    ///
+    /// Returns error if either coordinate exceeds 1.0 (50000).
+    pub fn set_white_point(&mut self, x: u16, y: u16) -> io::Result<()> {
+        if x > 50000 || y > 50000 {
+            Err(invalid_input("Chromaticity coordinates cannot exceed 1.0"))
+        } else {
+            self.white_point = (x, y);
+            Ok(())
+        }
+    }
+
+    /// Set the maximum and minimum luminance.
+    ///
+    /// Returns error if the maximum is not greater than the minimum,
+    /// or exceeds 2^31 - 1.
+    pub fn set_luminance(&mut self, max_luminance: u32, min_luminance: u32) -> io::Result<()> {
+        if max_luminance <= min_luminance {
+            Err(invalid_input("Maximum luminance must be greater than minimum"))
+        } else if max_luminance > i32::MAX as u32 {
+            Err(invalid_input("Luminance cannot exceed 2^31 - 1"))
+        } else {
+            self.max_luminance = max_luminance;
+            self.min_luminance = min_luminance;
+            Ok(())
+        }
+    }
+}
+
+impl Default for MasteringDisplay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Content light level information for HDR content: the maximum
+/// light level of any pixel (MaxCLL) and the maximum frame-average
+/// light level (MaxFALL), in units of 0.0001 cd/m².
+///
+/// A value of 0 means the level is unknown.
+///
+/// See [the PNG spec](https://www.w3.org/TR/png-3/#cLLI-chunk) for details.
+#[derive(Copy, Clone)]
+pub struct ContentLightLevel {
+    max_content: u32,
+    max_frame_average: u32,
+}
+
+impl ContentLightLevel {
+    /// Create content light level information with both levels unknown.
+    pub fn new() -> ContentLightLevel {
+        ContentLightLevel {
+            max_content: 0,
+            max_frame_average: 0,
+        }
+    }
+
+    /// Get the maximum content light level (MaxCLL).
+    pub fn max_content(&self) -> u32 {
+        self.max_content
+    }
+
+    /// Get the maximum frame-average light level (MaxFALL).
+    pub fn max_frame_average(&self) -> u32 {
+        self.max_frame_average
+    }
+
+    /// Set the maximum content and frame-average light levels.
+    ///
+    /// Returns error if either exceeds 2^31 - 1, or if the frame
+    /// average exceeds a known maximum.
+    pub fn set_levels(&mut self, max_content: u32, max_frame_average: u32) -> io::Result<()> {
+        if max_content > i32::MAX as u32 || max_frame_average > i32::MAX as u32 {
+            Err(invalid_input("Light levels cannot exceed 2^31 - 1"))
+        } else if max_content != 0 && max_frame_average > max_content {
+            Err(invalid_input("Frame-average light level cannot exceed maximum"))
+        } else {
+            self.max_content = max_content;
+            self.max_frame_average = max_frame_average;
+            Ok(())
+        }
+    }
+}
+
+impl Default for ContentLightLevel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Representation of deflate compression level.
 #[derive(Copy, Clone)]
 pub enum CompressionLevel {
@@ -342,7 +1127,10 @@ pub enum CompressionLevel {
     /// Good balance of speed and compression (zlib level 6).
     Default,
     /// Best compression but slow (zlib level 9).
-    High
+    High,
+    /// Smallest output, but around a hundred times slower than High,
+    /// using iterative optimal parsing in the style of Zopfli.
+    Extreme,
 }
 
 impl TryFrom<u8> for CompressionLevel {
@@ -356,6 +1144,7 @@ impl TryFrom<u8> for CompressionLevel {
             1 => Ok(CompressionLevel::Fast),
             6 => Ok(CompressionLevel::Default),
             9 => Ok(CompressionLevel::High),
+            10 => Ok(CompressionLevel::Extreme),
             _ => Err(invalid_input("Compression level not supported")),
         }
     }
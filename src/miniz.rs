@@ -0,0 +1,246 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// miniz.rs - pure Rust deflate and inflate streams using miniz_oxide
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//
+// Stand-ins for the libz_sys wrappers in deflate.rs and inflate.rs,
+// with the same interfaces, for builds with the rust-deflate feature
+// and without the zlib feature.
+//
+
+use std::io;
+use std::io::Write;
+
+use ::miniz_oxide::deflate::core::{compress_to_output, create_comp_flags_from_zip_params};
+use ::miniz_oxide::deflate::core::{CompressorOxide, TDEFLFlush, TDEFLStatus};
+use ::miniz_oxide::inflate::stream::{inflate, InflateState};
+use ::miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+
+use super::deflate::Flush;
+use super::deflate::Options;
+
+use super::utils::*;
+
+const ADLER_BASE: u64 = 65521;
+
+pub fn adler32(sum: u32, bytes: &[u8]) -> u32 {
+    ::miniz_oxide::mz_adler32_oxide(sum, bytes)
+}
+
+pub fn adler32_initial() -> u32 {
+    1
+}
+
+//
+// The checksum of two runs of data joined together, from each one's
+// checksum and the second one's length, worked out as zlib does.
+//
+pub fn adler32_combine(sum_a: u32, sum_b: u32, len_b: usize) -> u32 {
+    let rem = len_b as u64 % ADLER_BASE;
+    let a1 = u64::from(sum_a & 0xffff);
+    let b1 = u64::from(sum_a >> 16);
+    let a2 = u64::from(sum_b & 0xffff);
+    let b2 = u64::from(sum_b >> 16);
+
+    let a = (a1 + a2 + ADLER_BASE - 1) % ADLER_BASE;
+    let b = (rem * a1 + b1 + b2 + ADLER_BASE - rem) % ADLER_BASE;
+    (b << 16 | a) as u32
+}
+
+pub struct Deflate<W: Write> {
+    output: W,
+    options: Options,
+    compressor: CompressorOxide,
+}
+
+impl<W: Write> Deflate<W> {
+    pub fn new(options: Options, w: W) -> Deflate<W> {
+        let flags = create_comp_flags_from_zip_params(options.level(),
+                                                      options.window_bits(),
+                                                      options.strategy());
+        Deflate {
+            output: w,
+            options,
+            compressor: CompressorOxide::new(flags),
+        }
+    }
+
+    //
+    // miniz_oxide has no way to preset the dictionary, so compress it
+    // into the stream and throw that output away. After the sync flush,
+    // what follows starts on a byte boundary, and may refer back into
+    // the dictionary just as with zlib's deflateSetDictionary.
+    //
+    pub fn set_dictionary(&mut self, dict: &[u8]) -> IoResult {
+        let (status, _) = compress_to_output(&mut self.compressor, dict, TDEFLFlush::Sync, |_| true);
+        match status {
+            TDEFLStatus::Okay => Ok(()),
+            _ => Err(other("Unexpected error")),
+        }
+    }
+
+    //
+    // Make an independent copy of the stream state so far, which
+    // writes any further output to the given writer.
+    //
+    pub fn copy<V: Write>(&mut self, output: V) -> io::Result<Deflate<V>> {
+        Ok(Deflate {
+            output,
+            options: self.options,
+            compressor: self.compressor.clone(),
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8], flush: Flush) -> IoResult {
+        let flush = match flush {
+            Flush::NoFlush => TDEFLFlush::None,
+            Flush::SyncFlush => TDEFLFlush::Sync,
+            Flush::Finish => TDEFLFlush::Finish,
+        };
+
+        let output = &mut self.output;
+        let mut error = None;
+        let mut input = data;
+        loop {
+            let (status, consumed) = compress_to_output(&mut self.compressor, input, flush, |buffer| {
+                match output.write_all(buffer) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        error = Some(e);
+                        false
+                    },
+                }
+            });
+            input = &input[consumed ..];
+            match status {
+                TDEFLStatus::Okay if input.is_empty() => return Ok(()),
+                TDEFLStatus::Okay => continue,
+                TDEFLStatus::Done => return Ok(()),
+                TDEFLStatus::PutBufFailed => return Err(error.unwrap_or_else(|| other("Write failed"))),
+                TDEFLStatus::BadParam => return Err(invalid_input("Inconsistent stream state")),
+            }
+        }
+    }
+
+    //
+    // Return the writer.
+    //
+    pub fn finish(self) -> io::Result<W> {
+        Ok(self.output)
+    }
+}
+
+//
+// Decompresses a zlib stream whose input arrives in pieces
+// (such as a run of IDAT chunks), producing output on demand
+// into caller-provided buffers.
+//
+pub struct Inflate {
+    input: Vec<u8>,
+    input_pos: usize,
+    finished: bool,
+    state: Box<InflateState>,
+}
+
+impl Inflate {
+    pub fn new() -> Inflate {
+        Inflate {
+            input: Vec::new(),
+            input_pos: 0,
+            finished: false,
+            state: InflateState::new_boxed(DataFormat::Zlib),
+        }
+    }
+
+    //
+    // True if all input provided so far has been consumed.
+    //
+    pub fn needs_input(&self) -> bool {
+        self.input_pos == self.input.len()
+    }
+
+    //
+    // True once the end of the zlib stream, including its
+    // checksum, has been reached.
+    //
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    //
+    // Provide the next piece of compressed input.
+    // Any previous input must be fully consumed first.
+    //
+    pub fn set_input(&mut self, data: Vec<u8>) {
+        assert!(self.needs_input());
+        self.input = data;
+        self.input_pos = 0;
+    }
+
+    //
+    // Decompress into the output buffer until it is full,
+    // the input is exhausted, or the stream ends.
+    //
+    // Returns the number of bytes written.
+    //
+    pub fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.finished || out.is_empty() {
+            return Ok(0);
+        }
+
+        let result = inflate(&mut self.state, &self.input[self.input_pos ..], out, MZFlush::None);
+        self.input_pos += result.bytes_consumed;
+        match result.status {
+            Ok(MZStatus::StreamEnd) => {
+                self.finished = true;
+                Ok(result.bytes_written)
+            },
+            Ok(_) => Ok(result.bytes_written),
+            // No progress possible; more input is needed.
+            Err(MZError::Buf) => Ok(result.bytes_written),
+            Err(MZError::Data) => Err(invalid_data("Corrupt compressed image data")),
+            Err(MZError::Stream) => Err(invalid_input("Inconsistent stream state")),
+            Err(_) => Err(other("Unexpected error")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::adler32;
+    use super::adler32_combine;
+    use super::adler32_initial;
+
+    #[test]
+    fn combine() {
+        let data: Vec<u8> = (0 .. 100000).map(|i| (i * 31 % 251) as u8).collect();
+        let whole = adler32(adler32_initial(), &data);
+        for &split in &[0, 1, 5552, 65521, 99999] {
+            let (first, second) = data.split_at(split);
+            assert_eq!(adler32_combine(adler32(adler32_initial(), first),
+                                       adler32(adler32_initial(), second),
+                                       second.len()), whole);
+        }
+    }
+}
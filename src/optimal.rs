@@ -0,0 +1,1007 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// optimal.rs - iterative optimal-parsing deflate compressor
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//
+// A slow, thorough deflate compressor in the style of Zopfli, used for
+// the Extreme compression level.
+//
+// Instead of zlib's greedy and lazy matching, each block is parsed as a
+// shortest path through the data, where every literal and match is an
+// edge weighted by its estimated size in bits. The weights start from
+// the fixed Huffman codes, then are re-estimated from the symbol counts
+// of the previous parse, for several rounds. The result is split into
+// blocks where separate Huffman codes pay for themselves, and each
+// block is parsed again on its own before being written out with
+// whichever of dynamic, fixed or stored coding is smallest.
+//
+// Output follows the same rules as zlib's raw streams with a preset
+// dictionary: matches may refer back into the dictionary, and segments
+// other than the last end with a sync flush.
+//
+
+use std::cmp;
+
+use super::Strategy;
+
+// Deflate's own limits.
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+// Match finding stops after following this many hash chain links,
+// the same limit Zopfli uses.
+const MAX_CHAIN: usize = 8192;
+
+const HASH_BITS: usize = 16;
+const NONE: u32 = u32::MAX;
+
+// Rounds of re-estimating symbol costs per block. Stops early
+// once a round fails to improve on the last.
+const ITERATIONS: usize = 15;
+
+// Splitting happens at most this many levels deep, for up to
+// 16 blocks per segment.
+const MAX_SPLIT_DEPTH: usize = 4;
+
+// Don't bother looking for a split in fewer symbols than this.
+const MIN_SPLIT_ITEMS: usize = 1024;
+
+const MAX_STORED: usize = 65535;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+// The order code length code lengths are sent in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+const LITERAL_SYMBOLS: usize = 288;
+const DIST_SYMBOLS: usize = 30;
+const END_OF_BLOCK: usize = 256;
+
+// Length code (0-28) for each match length.
+const LENGTH_CODES: [u8; MAX_MATCH + 1] = length_codes();
+
+const fn length_codes() -> [u8; MAX_MATCH + 1] {
+    let mut codes = [0u8; MAX_MATCH + 1];
+    let mut code = 0;
+    let mut len = MIN_MATCH;
+    while len <= MAX_MATCH {
+        while code < 28 && LENGTH_BASE[code + 1] as usize <= len {
+            code += 1;
+        }
+        codes[len] = code as u8;
+        len += 1;
+    }
+    codes
+}
+
+fn dist_code(dist: usize) -> usize {
+    if dist <= 4 {
+        dist - 1
+    } else {
+        let d = dist - 1;
+        let log = (usize::BITS - 1 - d.leading_zeros()) as usize;
+        2 * log + ((d >> (log - 1)) & 1)
+    }
+}
+
+//
+// One literal or match of a parse.
+//
+#[derive(Copy, Clone)]
+struct Item {
+    // The literal byte, or the match length.
+    litlen: u16,
+    // The match distance, or 0 for a literal.
+    dist: u16,
+}
+
+impl Item {
+    fn literal(val: u8) -> Item {
+        Item {
+            litlen: u16::from(val),
+            dist: 0,
+        }
+    }
+
+    fn matched(len: usize, dist: usize) -> Item {
+        Item {
+            litlen: len as u16,
+            dist: dist as u16,
+        }
+    }
+
+    // Number of bytes of input covered.
+    fn len(&self) -> usize {
+        if self.dist == 0 {
+            1
+        } else {
+            self.litlen as usize
+        }
+    }
+}
+
+//
+// Symbol counts for a run of items, plus its end-of-block code.
+//
+struct Histogram {
+    literals: [usize; LITERAL_SYMBOLS],
+    dists: [usize; DIST_SYMBOLS],
+}
+
+impl Histogram {
+    fn new(items: &[Item]) -> Histogram {
+        let mut histogram = Histogram {
+            literals: [0; LITERAL_SYMBOLS],
+            dists: [0; DIST_SYMBOLS],
+        };
+        for item in items {
+            if item.dist == 0 {
+                histogram.literals[item.litlen as usize] += 1;
+            } else {
+                histogram.literals[257 + LENGTH_CODES[item.litlen as usize] as usize] += 1;
+                histogram.dists[dist_code(item.dist as usize)] += 1;
+            }
+        }
+        histogram.literals[END_OF_BLOCK] = 1;
+        histogram
+    }
+
+    // Size of the coded symbols in bits, not counting any block header.
+    fn data_bits(&self, literal_lengths: &[u8], dist_lengths: &[u8]) -> usize {
+        let mut bits = 0;
+        for (sym, (&count, &length)) in self.literals.iter().zip(literal_lengths).enumerate() {
+            bits += count * length as usize;
+            if sym > END_OF_BLOCK && count > 0 {
+                bits += count * LENGTH_EXTRA[sym - 257] as usize;
+            }
+        }
+        for (sym, &count) in self.dists.iter().enumerate() {
+            bits += count * (dist_lengths[sym] + DIST_EXTRA[sym]) as usize;
+        }
+        bits
+    }
+}
+
+//
+// Estimated cost in bits of each literal, match length and
+// match distance, for the shortest path search.
+//
+struct CostModel {
+    literals: [f64; 256],
+    lengths: [f64; MAX_MATCH + 1],
+    dists: [f64; DIST_SYMBOLS],
+}
+
+impl CostModel {
+    // Costs from the fixed Huffman codes.
+    fn fixed() -> CostModel {
+        let lengths = fixed_literal_lengths();
+        CostModel::from_bits(|sym| f64::from(lengths[sym]), |_| 5.0)
+    }
+
+    // Costs from the entropy of the previous parse's symbol counts.
+    // Unused symbols cost as much as ones used once.
+    fn from_histogram(histogram: &Histogram) -> CostModel {
+        fn entropy(counts: &[usize]) -> impl Fn(usize) -> f64 + '_ {
+            let total = counts.iter().sum::<usize>().max(1) as f64;
+            move |sym| (total / counts[sym].max(1) as f64).log2()
+        }
+        CostModel::from_bits(entropy(&histogram.literals), entropy(&histogram.dists))
+    }
+
+    fn from_bits<L, D>(literal_bits: L, dist_bits: D) -> CostModel
+        where L: Fn(usize) -> f64, D: Fn(usize) -> f64
+    {
+        let mut model = CostModel {
+            literals: [0.0; 256],
+            lengths: [0.0; MAX_MATCH + 1],
+            dists: [0.0; DIST_SYMBOLS],
+        };
+        for (sym, cost) in model.literals.iter_mut().enumerate() {
+            *cost = literal_bits(sym);
+        }
+        for (cost, &code) in model.lengths.iter_mut().zip(&LENGTH_CODES).skip(MIN_MATCH) {
+            let code = code as usize;
+            *cost = literal_bits(257 + code) + f64::from(LENGTH_EXTRA[code]);
+        }
+        for (sym, cost) in model.dists.iter_mut().enumerate() {
+            *cost = dist_bits(sym) + f64::from(DIST_EXTRA[sym]);
+        }
+        model
+    }
+}
+
+//
+// Every position's candidate matches: for each match length, the
+// nearest distance that reaches it. Recorded as (length, distance)
+// pairs of increasing length, each covering the lengths since the
+// previous one.
+//
+struct Matches {
+    offsets: Vec<usize>,
+    entries: Vec<(u16, u16)>,
+    // Length of the run of one byte value starting at each position,
+    // if matches are allowed at all.
+    runs: Vec<u16>,
+}
+
+impl Matches {
+    fn find(window: &[u8], start: usize, strategy: Strategy) -> Matches {
+        let mut runs = vec![0u16; window.len()];
+        for pos in (0 .. window.len()).rev() {
+            runs[pos] = match window.get(pos + 1) {
+                Some(&next) if next == window[pos] => runs[pos + 1].saturating_add(1),
+                _ => 1,
+            };
+        }
+
+        let mut matches = Matches {
+            offsets: Vec::with_capacity(window.len() - start + 1),
+            entries: Vec::new(),
+            runs: match strategy {
+                Strategy::HuffmanOnly => vec![0; window.len() - start],
+                _ => runs[start ..].to_vec(),
+            },
+        };
+
+        // Positions are chained by the hash of their first three bytes,
+        // and again by that hash mixed with the length of the run they
+        // start. Once the best match covers the run at the current
+        // position, only candidates starting an equal run can do better,
+        // so the search moves over to the second chain to skip the rest.
+        // This is what keeps flat areas from being very slow, as in Zopfli.
+        let mut head = vec![NONE; 1 << HASH_BITS];
+        let mut prev = vec![NONE; window.len()];
+        let mut head2 = vec![NONE; 1 << HASH_BITS];
+        let mut prev2 = vec![NONE; window.len()];
+        let mut hashes2 = vec![0u32; window.len()];
+
+        for pos in 0 .. window.len() {
+            if pos >= start {
+                matches.offsets.push(matches.entries.len());
+            }
+            if pos + MIN_MATCH > window.len() {
+                continue;
+            }
+            let hash = (window[pos] as usize) << 8
+                     ^ (window[pos + 1] as usize) << 4
+                     ^ window[pos + 2] as usize;
+            let hash2 = hash ^ (runs[pos] as usize & 255) << 8;
+            hashes2[pos] = hash2 as u32;
+
+            if pos >= start {
+                let max_len = cmp::min(MAX_MATCH, window.len() - pos);
+                match strategy {
+                    Strategy::HuffmanOnly => {},
+                    Strategy::RLE => {
+                        if pos > 0 {
+                            let len = match_length(window, pos - 1, pos, max_len);
+                            if len >= MIN_MATCH {
+                                matches.entries.push((len as u16, 1));
+                            }
+                        }
+                    },
+                    _ => {
+                        let mut best = MIN_MATCH - 1;
+                        let mut candidate = head[hash];
+                        let mut chain = &prev;
+                        let mut links = 0;
+                        while candidate != NONE && links < MAX_CHAIN {
+                            let prior = candidate as usize;
+                            if pos - prior > WINDOW_SIZE {
+                                break;
+                            }
+                            // Only a match longer than the best so far is of
+                            // interest, so check its last byte first.
+                            if window[prior + best] == window[pos + best] {
+                                let len = match_length(window, prior, pos, max_len);
+                                if len > best {
+                                    matches.entries.push((len as u16, (pos - prior) as u16));
+                                    best = len;
+                                    if len == max_len {
+                                        break;
+                                    }
+                                }
+                            }
+                            if best >= runs[pos] as usize && hashes2[prior] == hash2 as u32 {
+                                chain = &prev2;
+                            }
+                            candidate = chain[prior];
+                            links += 1;
+                        }
+                    },
+                }
+            }
+
+            prev[pos] = head[hash];
+            head[hash] = pos as u32;
+            prev2[pos] = head2[hash2];
+            head2[hash2] = pos as u32;
+        }
+        matches.offsets.push(matches.entries.len());
+        matches
+    }
+
+    fn at(&self, pos: usize) -> &[(u16, u16)] {
+        &self.entries[self.offsets[pos] .. self.offsets[pos + 1]]
+    }
+}
+
+fn match_length(window: &[u8], prior: usize, pos: usize, max_len: usize) -> usize {
+    window[prior ..].iter()
+        .zip(&window[pos .. pos + max_len])
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+//
+// Finds the cheapest run of literals and matches covering the given
+// range of data under the cost model, by a shortest path search.
+//
+fn parse(data: &[u8], matches: &Matches, start: usize, end: usize, model: &CostModel) -> Vec<Item> {
+    let len = end - start;
+    let mut costs = vec![f64::INFINITY; len + 1];
+    let mut steps = vec![Item::literal(0); len + 1];
+    costs[0] = 0.0;
+
+    let mut i = 0;
+    while i < len {
+        // Deep inside a long run of one value, the best step is always a
+        // maximum length match one byte back, so skip the search there
+        // as Zopfli does.
+        if i > MAX_MATCH && i + 2 * MAX_MATCH < len
+            && matches.runs[start + i] as usize > 2 * MAX_MATCH
+            && matches.runs[start + i - MAX_MATCH] as usize > MAX_MATCH
+        {
+            let step = model.dists[0] + model.lengths[MAX_MATCH];
+            for _ in 0 .. MAX_MATCH {
+                costs[i + MAX_MATCH] = costs[i] + step;
+                steps[i + MAX_MATCH] = Item::matched(MAX_MATCH, 1);
+                i += 1;
+            }
+            continue;
+        }
+
+        let cost = costs[i];
+
+        let literal = cost + model.literals[data[start + i] as usize];
+        if literal < costs[i + 1] {
+            costs[i + 1] = literal;
+            steps[i + 1] = Item::literal(data[start + i]);
+        }
+
+        let max_len = cmp::min(MAX_MATCH, len - i);
+        let mut shortest = MIN_MATCH;
+        for &(longest, dist) in matches.at(start + i) {
+            if shortest > max_len {
+                break;
+            }
+            let dist = dist as usize;
+            let base = cost + model.dists[dist_code(dist)];
+            for match_len in shortest ..= cmp::min(longest as usize, max_len) {
+                let total = base + model.lengths[match_len];
+                if total < costs[i + match_len] {
+                    costs[i + match_len] = total;
+                    steps[i + match_len] = Item::matched(match_len, dist);
+                }
+            }
+            shortest = longest as usize + 1;
+        }
+        i += 1;
+    }
+
+    let mut items = Vec::new();
+    let mut i = len;
+    while i > 0 {
+        let step = steps[i];
+        items.push(step);
+        i -= step.len();
+    }
+    items.reverse();
+    items
+}
+
+//
+// Parses the range repeatedly, re-estimating the cost model from
+// each parse, and keeps whichever codes smallest.
+//
+fn optimize(data: &[u8], matches: &Matches, start: usize, end: usize, strategy: Strategy) -> Vec<Item> {
+    let mut model = CostModel::fixed();
+    if let Strategy::Fixed = strategy {
+        return parse(data, matches, start, end, &model);
+    }
+
+    let mut best = Vec::new();
+    let mut best_bits = usize::MAX;
+    for _ in 0 .. ITERATIONS {
+        let items = parse(data, matches, start, end, &model);
+        let histogram = Histogram::new(&items);
+        let bits = DynamicCodes::new(&histogram).bits(&histogram);
+        if bits >= best_bits {
+            break;
+        }
+        best = items;
+        best_bits = bits;
+        model = CostModel::from_histogram(&histogram);
+    }
+    best
+}
+
+//
+// Picks points at which to start new blocks, returned as item indices
+// including the start and end.
+//
+fn split_blocks(items: &[Item]) -> Vec<usize> {
+    fn split(items: &[Item], start: usize, end: usize, depth: usize, splits: &mut Vec<usize>) {
+        if depth < MAX_SPLIT_DEPTH && end - start >= MIN_SPLIT_ITEMS {
+            if let Some(mid) = find_split(items, start, end) {
+                split(items, start, mid, depth + 1, splits);
+                split(items, mid, end, depth + 1, splits);
+                return;
+            }
+        }
+        splits.push(end);
+    }
+
+    let mut splits = vec![0];
+    split(items, 0, items.len(), 0, &mut splits);
+    splits
+}
+
+//
+// Narrows in on the cheapest place to split the range in two, by
+// trying evenly spaced points and then the stretch around the best.
+// Returns it only if the halves code smaller than the whole.
+//
+fn find_split(items: &[Item], start: usize, end: usize) -> Option<usize> {
+    const POINTS: usize = 9;
+
+    let split_bits = |mid: usize| block_bits(&items[start .. mid]) + block_bits(&items[mid .. end]);
+
+    let mut low = start + 1;
+    let mut high = end - 1;
+    let mut best = None;
+    let mut best_bits = block_bits(&items[start .. end]);
+    while high - low > POINTS {
+        let step = (high - low) / (POINTS + 1);
+        let mut nearest = None;
+        let mut nearest_bits = usize::MAX;
+        for n in 1 ..= POINTS {
+            let mid = low + n * step;
+            let bits = split_bits(mid);
+            if bits < nearest_bits {
+                nearest = Some(mid);
+                nearest_bits = bits;
+            }
+        }
+        let mid = nearest.unwrap();
+        if nearest_bits < best_bits {
+            best = Some(mid);
+            best_bits = nearest_bits;
+        }
+        low = mid - step;
+        high = mid + step;
+    }
+    best
+}
+
+// Estimated size of the items as their own block.
+fn block_bits(items: &[Item]) -> usize {
+    let histogram = Histogram::new(items);
+    cmp::min(DynamicCodes::new(&histogram).bits(&histogram),
+             3 + histogram.data_bits(&fixed_literal_lengths(), &[5; DIST_SYMBOLS]))
+}
+
+//
+// Computes code lengths for the given symbol counts, limited to the
+// given maximum, with the package-merge algorithm.
+//
+// At least two symbols always get codes, so that the code is complete
+// as some decoders require.
+//
+fn code_lengths(counts: &[usize], max_bits: usize) -> Vec<u8> {
+    let mut lengths = vec![0u8; counts.len()];
+
+    let mut leaves: Vec<(usize, usize)> = counts.iter()
+        .enumerate()
+        .filter(|&(_, &count)| count > 0)
+        .map(|(sym, &count)| (count, sym))
+        .collect();
+    if leaves.len() < 2 {
+        for &(_, sym) in &leaves {
+            lengths[sym] = 1;
+        }
+        for length in lengths.iter_mut().filter(|length| **length == 0).take(2 - leaves.len()) {
+            *length = 1;
+        }
+        return lengths;
+    }
+    leaves.sort();
+
+    // Nodes are leaves (a symbol) or packages of two other nodes.
+    struct Node {
+        weight: usize,
+        symbol: Option<usize>,
+        children: (usize, usize),
+    }
+
+    let mut nodes: Vec<Node> = leaves.iter().map(|&(weight, sym)| Node {
+        weight,
+        symbol: Some(sym),
+        children: (0, 0),
+    }).collect();
+    let leaf_nodes: Vec<usize> = (0 .. leaves.len()).collect();
+
+    let mut list = leaf_nodes.clone();
+    for _ in 1 .. max_bits {
+        let mut packages = Vec::with_capacity(list.len() / 2);
+        for pair in list.chunks_exact(2) {
+            nodes.push(Node {
+                weight: nodes[pair[0]].weight + nodes[pair[1]].weight,
+                symbol: None,
+                children: (pair[0], pair[1]),
+            });
+            packages.push(nodes.len() - 1);
+        }
+
+        let mut merged = Vec::with_capacity(leaf_nodes.len() + packages.len());
+        let (mut a, mut b) = (0, 0);
+        while a < leaf_nodes.len() || b < packages.len() {
+            if b == packages.len() || (a < leaf_nodes.len() && nodes[leaf_nodes[a]].weight <= nodes[packages[b]].weight) {
+                merged.push(leaf_nodes[a]);
+                a += 1;
+            } else {
+                merged.push(packages[b]);
+                b += 1;
+            }
+        }
+        list = merged;
+    }
+
+    // Each time a symbol appears among the chosen nodes adds
+    // a bit to its code length.
+    let mut stack: Vec<usize> = list[.. 2 * (leaves.len() - 1)].to_vec();
+    while let Some(node) = stack.pop() {
+        match nodes[node].symbol {
+            Some(sym) => lengths[sym] += 1,
+            None => {
+                stack.push(nodes[node].children.0);
+                stack.push(nodes[node].children.1);
+            },
+        }
+    }
+    lengths
+}
+
+//
+// Canonical Huffman codes for the given code lengths, bit-reversed
+// since deflate sends codes starting from their top bit.
+//
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut counts = [0u16; 16];
+    for &length in lengths {
+        counts[length as usize] += 1;
+    }
+    counts[0] = 0;
+
+    let mut next = [0u16; 16];
+    let mut code = 0;
+    for bits in 1 .. 16 {
+        code = (code + counts[bits - 1]) << 1;
+        next[bits] = code;
+    }
+
+    lengths.iter().map(|&length| {
+        if length == 0 {
+            0
+        } else {
+            let code = next[length as usize];
+            next[length as usize] += 1;
+            code.reverse_bits() >> (16 - length)
+        }
+    }).collect()
+}
+
+fn fixed_literal_lengths() -> [u8; LITERAL_SYMBOLS] {
+    let mut lengths = [0u8; LITERAL_SYMBOLS];
+    for (sym, length) in lengths.iter_mut().enumerate() {
+        *length = match sym {
+            0 ..= 143 => 8,
+            144 ..= 255 => 9,
+            256 ..= 279 => 7,
+            _ => 8,
+        };
+    }
+    lengths
+}
+
+//
+// The Huffman codes for a dynamic block, and their run-length
+// coded description for its header.
+//
+struct DynamicCodes {
+    literal_lengths: Vec<u8>,
+    dist_lengths: Vec<u8>,
+    literal_count: usize,
+    dist_count: usize,
+    // Code length symbols, with their extra bits.
+    header: Vec<(u8, u8)>,
+    header_lengths: Vec<u8>,
+    header_count: usize,
+}
+
+impl DynamicCodes {
+    fn new(histogram: &Histogram) -> DynamicCodes {
+        let literal_lengths = code_lengths(&histogram.literals[.. 286], 15);
+        let dist_lengths = code_lengths(&histogram.dists, 15);
+
+        let literal_count = cmp::max(257, literal_lengths.iter().rposition(|&l| l > 0).unwrap() + 1);
+        let dist_count = dist_lengths.iter().rposition(|&l| l > 0).unwrap() + 1;
+
+        let all: Vec<u8> = literal_lengths[.. literal_count].iter()
+            .chain(&dist_lengths[.. dist_count])
+            .cloned()
+            .collect();
+        let header = run_length_code(&all);
+
+        let mut header_counts = [0usize; 19];
+        for &(sym, _) in &header {
+            header_counts[sym as usize] += 1;
+        }
+        let header_lengths = code_lengths(&header_counts, 7);
+        let header_count = cmp::max(4, CODE_LENGTH_ORDER.iter()
+            .rposition(|&sym| header_lengths[sym] > 0)
+            .unwrap() + 1);
+
+        DynamicCodes {
+            literal_lengths,
+            dist_lengths,
+            literal_count,
+            dist_count,
+            header,
+            header_lengths,
+            header_count,
+        }
+    }
+
+    // Total size of the block in bits.
+    fn bits(&self, histogram: &Histogram) -> usize {
+        let mut bits = 3 + 5 + 5 + 4 + 3 * self.header_count;
+        for &(sym, _) in &self.header {
+            bits += self.header_lengths[sym as usize] as usize + match sym {
+                16 => 2,
+                17 => 3,
+                18 => 7,
+                _ => 0,
+            };
+        }
+        bits + histogram.data_bits(&self.literal_lengths, &self.dist_lengths)
+    }
+}
+
+//
+// Codes a run of code lengths with repeat symbols 16, 17 and 18.
+//
+fn run_length_code(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut symbols = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let length = lengths[i];
+        let mut run = lengths[i ..].iter().take_while(|&&l| l == length).count();
+        i += run;
+        if length == 0 {
+            while run >= 11 {
+                let count = cmp::min(run, 138);
+                symbols.push((18, (count - 11) as u8));
+                run -= count;
+            }
+            if run >= 3 {
+                symbols.push((17, (run - 3) as u8));
+                run = 0;
+            }
+        } else {
+            symbols.push((length, 0));
+            run -= 1;
+            while run >= 3 {
+                let count = cmp::min(run, 6);
+                symbols.push((16, (count - 3) as u8));
+                run -= count;
+            }
+        }
+        for _ in 0 .. run {
+            symbols.push((length, 0));
+        }
+    }
+    symbols
+}
+
+//
+// Writes bits to the output, starting from the low bit of each byte.
+//
+struct BitWriter<'a> {
+    output: &'a mut Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(output: &'a mut Vec<u8>) -> BitWriter<'a> {
+        BitWriter {
+            output,
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= u64::from(value) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.output.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.output.push(self.bits as u8);
+            self.bits = 0;
+            self.count = 0;
+        }
+    }
+
+    fn write_stored(&mut self, data: &[u8], last: bool) {
+        let mut blocks = data.chunks(MAX_STORED).peekable();
+        if data.is_empty() {
+            self.write(last as u32, 1);
+            self.write(0, 2);
+            self.align();
+            self.write(0, 16);
+            self.write(0xffff, 16);
+        }
+        while let Some(block) = blocks.next() {
+            self.write((last && blocks.peek().is_none()) as u32, 1);
+            self.write(0, 2);
+            self.align();
+            self.write(block.len() as u32, 16);
+            self.write((!block.len() & 0xffff) as u32, 16);
+            self.output.extend_from_slice(block);
+        }
+    }
+
+    fn write_items(&mut self, items: &[Item], literal_lengths: &[u8], dist_lengths: &[u8]) {
+        let literal_codes = canonical_codes(literal_lengths);
+        let dist_codes = canonical_codes(dist_lengths);
+        for item in items {
+            if item.dist == 0 {
+                let sym = item.litlen as usize;
+                self.write(u32::from(literal_codes[sym]), u32::from(literal_lengths[sym]));
+            } else {
+                let len = item.litlen as usize;
+                let code = LENGTH_CODES[len] as usize;
+                self.write(u32::from(literal_codes[257 + code]), u32::from(literal_lengths[257 + code]));
+                self.write((len - LENGTH_BASE[code] as usize) as u32, u32::from(LENGTH_EXTRA[code]));
+
+                let dist = item.dist as usize;
+                let code = dist_code(dist);
+                self.write(u32::from(dist_codes[code]), u32::from(dist_lengths[code]));
+                self.write((dist - DIST_BASE[code] as usize) as u32, u32::from(DIST_EXTRA[code]));
+            }
+        }
+        self.write(u32::from(literal_codes[END_OF_BLOCK]), u32::from(literal_lengths[END_OF_BLOCK]));
+    }
+
+    //
+    // Writes a block with whichever coding comes out smallest,
+    // or always fixed codes for the Fixed strategy.
+    //
+    fn write_block(&mut self, items: &[Item], data: &[u8], last: bool, strategy: Strategy) {
+        let histogram = Histogram::new(items);
+        let fixed_lengths = fixed_literal_lengths();
+        let fixed_dist_lengths = [5u8; DIST_SYMBOLS];
+        let fixed_bits = 3 + histogram.data_bits(&fixed_lengths, &fixed_dist_lengths);
+
+        // Stored blocks start on a byte boundary and have a four byte
+        // header, so count the worst case for padding.
+        let stored_bits = cmp::max(1, (data.len() + MAX_STORED - 1) / MAX_STORED) * (3 + 7 + 32) + 8 * data.len();
+
+        if let Strategy::Fixed = strategy {
+            self.write(last as u32, 1);
+            self.write(1, 2);
+            self.write_items(items, &fixed_lengths, &fixed_dist_lengths);
+            return;
+        }
+
+        let codes = DynamicCodes::new(&histogram);
+        let dynamic_bits = codes.bits(&histogram);
+
+        if stored_bits < cmp::min(fixed_bits, dynamic_bits) {
+            self.write_stored(data, last);
+        } else if fixed_bits <= dynamic_bits {
+            self.write(last as u32, 1);
+            self.write(1, 2);
+            self.write_items(items, &fixed_lengths, &fixed_dist_lengths);
+        } else {
+            self.write(last as u32, 1);
+            self.write(2, 2);
+            self.write((codes.literal_count - 257) as u32, 5);
+            self.write((codes.dist_count - 1) as u32, 5);
+            self.write((codes.header_count - 4) as u32, 4);
+            for &sym in &CODE_LENGTH_ORDER[.. codes.header_count] {
+                self.write(u32::from(codes.header_lengths[sym]), 3);
+            }
+
+            let header_codes = canonical_codes(&codes.header_lengths);
+            for &(sym, extra) in &codes.header {
+                let sym = sym as usize;
+                self.write(u32::from(header_codes[sym]), u32::from(codes.header_lengths[sym]));
+                match sym {
+                    16 => self.write(u32::from(extra), 2),
+                    17 => self.write(u32::from(extra), 3),
+                    18 => self.write(u32::from(extra), 7),
+                    _ => {},
+                }
+            }
+
+            self.write_items(items, &codes.literal_lengths, &codes.dist_lengths);
+        }
+    }
+}
+
+//
+// Compresses data as a segment of a raw deflate stream, appending it to
+// the output. Matches may refer back into the last 32 KiB of the
+// dictionary. The last segment ends with a final block; others end with
+// a sync flush.
+//
+// HuffmanOnly and RLE restrict matching as they do in zlib, and Fixed
+// uses only the fixed codes; any other strategy searches everything.
+//
+pub fn compress(dictionary: Option<&[u8]>, data: &[u8], strategy: Strategy, last: bool, output: &mut Vec<u8>) {
+    let dictionary = dictionary.unwrap_or(&[]);
+    let dictionary = &dictionary[dictionary.len().saturating_sub(WINDOW_SIZE) ..];
+
+    let mut window = Vec::with_capacity(dictionary.len() + data.len());
+    window.extend_from_slice(dictionary);
+    window.extend_from_slice(data);
+    let matches = Matches::find(&window, dictionary.len(), strategy);
+
+    let mut writer = BitWriter::new(output);
+    if data.is_empty() {
+        if last {
+            writer.write_block(&[], &[], true, Strategy::Fixed);
+        }
+    } else {
+        let items = optimize(data, &matches, 0, data.len(), strategy);
+        let splits = match strategy {
+            Strategy::Fixed => vec![0, items.len()],
+            _ => split_blocks(&items),
+        };
+
+        if splits.len() == 2 {
+            writer.write_block(&items, data, last, strategy);
+        } else {
+            // Each block gets a cost model of its own.
+            let mut start = 0;
+            for (n, span) in splits.windows(2).enumerate() {
+                let whole = &items[span[0] .. span[1]];
+                let end = start + whole.iter().map(Item::len).sum::<usize>();
+                let reparsed = optimize(data, &matches, start, end, strategy);
+                let block = if block_bits(&reparsed) < block_bits(whole) {
+                    &reparsed[..]
+                } else {
+                    whole
+                };
+                writer.write_block(block, &data[start .. end], last && n == splits.len() - 2, strategy);
+                start = end;
+            }
+        }
+    }
+
+    if !last {
+        writer.write_stored(&[], false);
+    }
+    writer.align();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::code_lengths;
+    use super::compress;
+    use super::super::Strategy;
+    use super::super::deflate;
+    use super::super::inflate::Inflate;
+
+    fn kraft_sum(lengths: &[u8], max_bits: usize) -> usize {
+        lengths.iter()
+            .filter(|&&length| length > 0)
+            .map(|&length| 1 << (max_bits - length as usize))
+            .sum()
+    }
+
+    #[test]
+    fn length_limits() {
+        // Fibonacci weights would need very long codes unlimited.
+        let mut counts = vec![1usize, 1];
+        while counts.len() < 30 {
+            let next = counts[counts.len() - 1] + counts[counts.len() - 2];
+            counts.push(next);
+        }
+        let lengths = code_lengths(&counts, 7);
+        assert!(lengths.iter().all(|length| (1 ..= 7).contains(length)));
+        assert_eq!(kraft_sum(&lengths, 7), 1 << 7);
+
+        let lengths = code_lengths(&[0, 0, 5, 0], 15);
+        assert_eq!(kraft_sum(&lengths, 15), 1 << 15);
+    }
+
+    #[test]
+    fn round_trip() {
+        let first: Vec<u8> = (0 .. 5000).map(|i| (i * i / 7 % 13 + i / 100) as u8).collect();
+        let second: Vec<u8> = (0 .. 7000).map(|i| (i * i / 7 % 13 + i / 90) as u8).collect();
+
+        for &strategy in &[Strategy::Default, Strategy::RLE, Strategy::HuffmanOnly, Strategy::Fixed] {
+            // A zlib stream of two segments, the second one using
+            // the first as its dictionary.
+            let mut stream = vec![0x78, 0xda];
+            compress(None, &first, strategy, false, &mut stream);
+            compress(Some(&first), &second, strategy, true, &mut stream);
+            let sum = deflate::adler32(deflate::adler32(deflate::adler32_initial(), &first), &second);
+            stream.extend_from_slice(&sum.to_be_bytes());
+
+            let mut inflate = Inflate::new();
+            inflate.set_input(stream);
+            let mut output = vec![0u8; first.len() + second.len() + 1];
+            let mut len = 0;
+            while !inflate.is_finished() {
+                len += inflate.read(&mut output[len ..]).unwrap();
+            }
+            assert_eq!(len, first.len() + second.len());
+            assert!(output[.. first.len()] == first[..]);
+            assert!(output[first.len() .. len] == second[..]);
+        }
+    }
+}
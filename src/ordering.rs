@@ -0,0 +1,337 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// ordering.rs - PNG chunk placement rules and output ordering state
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+use std::io;
+use std::str;
+
+use super::utils::*;
+
+//
+// Stages of the output stream, in the order they occur.
+//
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    // Nothing written yet.
+    Start,
+    // IHDR written.
+    Header,
+    // PLTE written.
+    Palette,
+    // IDAT or fdAT chunks in progress; nothing may come between them.
+    ImageData,
+    // All image data written; only trailing chunks and IEND remain.
+    AfterImageData,
+    // IEND written.
+    End,
+}
+
+//
+// Where a chunk may appear relative to the critical chunks.
+// https://www.w3.org/TR/png-3/#5ChunkOrdering
+//
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Placement {
+    // IHDR only.
+    First,
+    // PLTE only.
+    Palette,
+    // After IHDR, before PLTE and image data.
+    BeforePalette,
+    // Before image data, and PLTE may not follow.
+    AfterPalette,
+    // After IHDR, before image data.
+    BeforeImageData,
+    // Among the image data, written by the encoder itself.
+    ImageData,
+    // Anywhere after IHDR, but not among the image data.
+    Anywhere,
+    // IEND only.
+    Last,
+}
+
+#[derive(Copy, Clone)]
+struct Rule {
+    placement: Placement,
+    multiple: bool,
+    // Written only by the encoder's own methods, not write_chunk.
+    managed: bool,
+}
+
+const fn rule(placement: Placement, multiple: bool, managed: bool) -> Rule {
+    Rule {
+        placement,
+        multiple,
+        managed,
+    }
+}
+
+//
+// Placement rules for known chunk types.
+//
+fn known_rule(tag: &[u8]) -> Option<Rule> {
+    use self::Placement::*;
+    Some(match tag {
+        b"IHDR" => rule(First, false, true),
+        b"PLTE" => rule(Palette, false, true),
+        b"IDAT" => rule(ImageData, true, true),
+        b"IEND" => rule(Last, false, true),
+
+        // Color space information.
+        b"cHRM" | b"gAMA" | b"iCCP" | b"sBIT" | b"sRGB" |
+        b"cICP" | b"mDCV" | b"cLLI" => rule(BeforePalette, false, false),
+
+        // Information relating to palette entries or samples.
+        b"bKGD" | b"hIST" | b"tRNS" => rule(AfterPalette, false, false),
+
+        b"eXIf" | b"pHYs" | b"oFFs" | b"pCAL" | b"sCAL" | b"sTER" => rule(BeforeImageData, false, false),
+        b"sPLT" => rule(BeforeImageData, true, false),
+
+        b"tIME" => rule(Anywhere, false, false),
+        b"tEXt" | b"zTXt" | b"iTXt" | b"gIFg" | b"gIFx" => rule(Anywhere, true, false),
+
+        // APNG chunks, which carry sequence numbers.
+        // https://www.w3.org/TR/png-3/#apng-frame-based-animation
+        b"acTL" => rule(BeforeImageData, false, true),
+        b"fcTL" | b"fdAT" => rule(ImageData, true, true),
+
+        _ => return None,
+    })
+}
+
+//
+// Placement rules for chunk types we don't know. The property
+// bits in the case of each letter of the tag say whether one may
+// be written at all; it may then go anywhere after the header.
+// https://www.w3.org/TR/png-3/#5Chunk-naming-conventions
+//
+fn unknown_rule(tag: &[u8]) -> io::Result<Rule> {
+    if tag[0].is_ascii_uppercase() {
+        return Err(invalid_input("Cannot write unknown critical chunk, which decoders would reject"));
+    }
+    if tag[2].is_ascii_lowercase() {
+        return Err(invalid_input("Chunk tag has the reserved bit set"));
+    }
+    Ok(rule(Placement::Anywhere, true, false))
+}
+
+//
+// Tracks the chunks written so far, and checks that each new
+// chunk is allowed at the current point in the stream.
+//
+pub struct ChunkOrder {
+    phase: Phase,
+    written: Vec<[u8; 4]>,
+}
+
+impl ChunkOrder {
+    pub fn new() -> ChunkOrder {
+        ChunkOrder {
+            phase: Phase::Start,
+            written: Vec::new(),
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    pub fn has_written(&self, tag: &[u8]) -> bool {
+        self.written.iter().any(|written| written[..] == *tag)
+    }
+
+    //
+    // Check a chunk that the caller is writing directly, which
+    // may not be one of those the encoder writes itself.
+    //
+    pub fn check_custom(&self, tag: &[u8]) -> IoResult {
+        if let Some(rule) = self.rule(tag)? {
+            if rule.managed {
+                return Err(invalid_input(&format!("{} chunks must be written with their own Encoder method.", name(tag))));
+            }
+        }
+        self.check(tag)
+    }
+
+    //
+    // Check that a chunk may be written at this point.
+    //
+    pub fn check(&self, tag: &[u8]) -> IoResult {
+        let rule = match self.rule(tag)? {
+            Some(rule) => rule,
+            None => unknown_rule(tag)?,
+        };
+        let chunk = name(tag);
+
+        if !rule.multiple && self.has_written(tag) {
+            return Err(invalid_input(&format!("Cannot write {} a second time.", chunk)));
+        }
+
+        match (rule.placement, self.phase) {
+            (Placement::First, Phase::Start) => {},
+            (_, Phase::Start) => {
+                return Err(invalid_input(&format!("Cannot write {} before IHDR.", chunk)));
+            },
+            (_, Phase::End) => {
+                return Err(invalid_input(&format!("Cannot write {} after IEND.", chunk)));
+            },
+
+            (Placement::Palette, Phase::Header) => {
+                if let Some(after) = self.written.iter().find(|written| {
+                    matches!(known_rule(&written[..]), Some(Rule { placement: Placement::AfterPalette, .. }))
+                }) {
+                    return Err(invalid_input(&format!("Cannot write PLTE after {}.", name(after))));
+                }
+            },
+            (Placement::BeforePalette, Phase::Header) => {},
+            (Placement::BeforePalette, Phase::Palette) => {
+                return Err(invalid_input(&format!("Cannot write {} after PLTE.", chunk)));
+            },
+            (Placement::AfterPalette, Phase::Header) |
+            (Placement::AfterPalette, Phase::Palette) |
+            (Placement::BeforeImageData, Phase::Header) |
+            (Placement::BeforeImageData, Phase::Palette) |
+            (Placement::ImageData, Phase::Header) |
+            (Placement::ImageData, Phase::Palette) |
+            (Placement::ImageData, Phase::ImageData) |
+            (Placement::Anywhere, Phase::Header) |
+            (Placement::Anywhere, Phase::Palette) |
+            (Placement::Anywhere, Phase::AfterImageData) |
+            (Placement::Last, Phase::AfterImageData) => {},
+
+            (Placement::Last, _) => {
+                return Err(invalid_input("Cannot write IEND before all image data."));
+            },
+            (_, Phase::ImageData) => {
+                return Err(invalid_input(&format!("Cannot write {} while image data is being written.", chunk)));
+            },
+            (_, Phase::AfterImageData) => {
+                return Err(invalid_input(&format!("Cannot write {} after image data.", chunk)));
+            },
+            (_, _) => {
+                return Err(invalid_input(&format!("Cannot write {} at this point in the file.", chunk)));
+            },
+        }
+        Ok(())
+    }
+
+    //
+    // Record that a chunk has been written, having been checked.
+    //
+    pub fn record(&mut self, tag: &[u8]) {
+        match tag {
+            b"IHDR" => self.phase = Phase::Header,
+            b"PLTE" => self.phase = Phase::Palette,
+            b"IDAT" | b"fdAT" => self.phase = Phase::ImageData,
+            b"IEND" => self.phase = Phase::End,
+            _ => {},
+        }
+        if !self.has_written(tag) {
+            let mut copy = [0u8; 4];
+            copy.clone_from_slice(tag);
+            self.written.push(copy);
+        }
+    }
+
+    //
+    // Move on to trailing chunks once all image data is out.
+    //
+    pub fn finish_image_data(&mut self) {
+        self.phase = Phase::AfterImageData;
+    }
+
+    fn rule(&self, tag: &[u8]) -> io::Result<Option<Rule>> {
+        if tag.len() != 4 || !tag.iter().all(|c| c.is_ascii_alphabetic()) {
+            return Err(invalid_input("Chunk tags must be four ASCII letters."));
+        }
+        Ok(known_rule(tag))
+    }
+}
+
+fn name(tag: &[u8]) -> &str {
+    str::from_utf8(tag).unwrap_or("chunk")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChunkOrder;
+    use super::Phase;
+
+    #[test]
+    fn known_order() {
+        let mut order = ChunkOrder::new();
+        assert!(order.check(b"gAMA").is_err());
+        order.check(b"IHDR").unwrap();
+        order.record(b"IHDR");
+
+        order.check(b"gAMA").unwrap();
+        order.record(b"gAMA");
+        assert!(order.check(b"gAMA").is_err());
+
+        order.record(b"tRNS");
+        assert!(order.check(b"PLTE").is_err());
+    }
+
+    #[test]
+    fn image_data_phases() {
+        let mut order = ChunkOrder::new();
+        order.record(b"IHDR");
+        order.record(b"PLTE");
+        assert!(order.check(b"sRGB").is_err());
+        order.check(b"bKGD").unwrap();
+        assert!(order.check(b"IEND").is_err());
+
+        order.record(b"IDAT");
+        assert_eq!(order.phase(), Phase::ImageData);
+        assert!(order.check(b"tEXt").is_err());
+        order.check(b"IDAT").unwrap();
+
+        order.finish_image_data();
+        order.check(b"tEXt").unwrap();
+        order.check(b"tIME").unwrap();
+        assert!(order.check(b"pHYs").is_err());
+        assert!(order.check(b"IDAT").is_err());
+        order.check(b"IEND").unwrap();
+        order.record(b"IEND");
+        assert!(order.check(b"tEXt").is_err());
+    }
+
+    #[test]
+    fn unknown_chunks() {
+        let mut order = ChunkOrder::new();
+        order.record(b"IHDR");
+        assert!(order.check_custom(b"IDAT").is_err());
+        assert!(order.check_custom(b"acTL").is_err());
+        assert!(order.check_custom(b"ABCD").is_err());
+        assert!(order.check_custom(b"abcd").is_err());
+        assert!(order.check_custom(b"ab1d").is_err());
+        order.check_custom(b"abCd").unwrap();
+        order.check_custom(b"abCD").unwrap();
+
+        order.record(b"IDAT");
+        order.finish_image_data();
+        order.check_custom(b"abCd").unwrap();
+        order.check_custom(b"abCD").unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// palette.rs - indexed-color palettes and index validation
+//
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+use std::io;
+
+use super::Header;
+
+use super::utils::*;
+
+/// Indexed-color palette of RGBA entries.
+///
+/// Written as a PLTE chunk of the colors together with a tRNS chunk
+/// of the alpha values, which is trimmed after the last entry that
+/// isn't fully opaque.
+#[derive(Clone, Default)]
+pub struct Palette {
+    entries: Vec<[u8; 4]>,
+}
+
+impl Palette {
+    /// Create a new, empty Palette.
+    pub fn new() -> Palette {
+        Palette {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Create a Palette from PLTE data and optional tRNS data.
+    ///
+    /// Entries without an alpha value in the tRNS data are opaque.
+    pub fn from_chunks(palette: &[u8], transparency: &[u8]) -> io::Result<Palette> {
+        if palette.len() % 3 != 0 {
+            return Err(invalid_input("Palette must have an integral number of entries."));
+        }
+        if palette.len() / 3 > 256 {
+            return Err(invalid_input("Palette cannot have more than 256 entries."));
+        }
+        if transparency.len() > palette.len() / 3 {
+            return Err(invalid_input("Transparency data cannot contain more entries than palette."));
+        }
+        let entries = palette.chunks(3).enumerate().map(|(i, rgb)| {
+            [rgb[0], rgb[1], rgb[2], *transparency.get(i).unwrap_or(&255)]
+        }).collect();
+        Ok(Palette {
+            entries,
+        })
+    }
+
+    /// Get the number of entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get the RGBA entries, in index order.
+    pub fn entries(&self) -> &[[u8; 4]] {
+        &self.entries
+    }
+
+    /// Find the index of the first entry with the given RGBA value.
+    pub fn index_of(&self, rgba: [u8; 4]) -> Option<u8> {
+        self.entries.iter().position(|&entry| entry == rgba).map(|index| index as u8)
+    }
+
+    /// Add an RGBA entry to the end, returning its index.
+    ///
+    /// Returns an error if the palette already has 256 entries.
+    pub fn push(&mut self, rgba: [u8; 4]) -> io::Result<u8> {
+        if self.entries.len() >= 256 {
+            return Err(invalid_input("Palette cannot have more than 256 entries."));
+        }
+        self.entries.push(rgba);
+        Ok((self.entries.len() - 1) as u8)
+    }
+
+    /// Remove repeated entries, keeping the first of each.
+    ///
+    /// Returns a map from each old index to its new index, for
+    /// rewriting image data that used the old indices.
+    pub fn dedup(&mut self) -> Vec<u8> {
+        let mut kept: Vec<[u8; 4]> = Vec::with_capacity(self.entries.len());
+        let map = self.entries.iter().map(|&entry| {
+            match kept.iter().position(|&other| other == entry) {
+                Some(index) => index as u8,
+                None => {
+                    kept.push(entry);
+                    (kept.len() - 1) as u8
+                }
+            }
+        }).collect();
+        self.entries = kept;
+        map
+    }
+
+    /// Move entries that aren't fully opaque to the front, most
+    /// transparent first, so the tRNS chunk needs fewer bytes.
+    /// Opaque entries keep their relative order.
+    ///
+    /// Returns a map from each old index to its new index, for
+    /// rewriting image data that used the old indices.
+    pub fn sort_by_alpha(&mut self) -> Vec<u8> {
+        let mut order: Vec<usize> = (0..self.entries.len()).collect();
+        order.sort_by_key(|&index| self.entries[index][3]);
+        self.apply_order(&order)
+    }
+
+    /// Rearrange the entries so that each new index i holds the
+    /// entry that was at index order[i].
+    ///
+    /// Returns a map from each old index to its new index, for
+    /// rewriting image data that used the old indices, or an error
+    /// if the order doesn't list every index exactly once.
+    pub fn reorder(&mut self, order: &[usize]) -> io::Result<Vec<u8>> {
+        let mut seen = vec![false; self.entries.len()];
+        if order.len() != seen.len() {
+            return Err(invalid_input("Palette order must list every entry."));
+        }
+        for &index in order {
+            match seen.get_mut(index) {
+                Some(seen) if !*seen => *seen = true,
+                _ => return Err(invalid_input("Palette order must list every entry exactly once.")),
+            }
+        }
+        Ok(self.apply_order(order))
+    }
+
+    fn apply_order(&mut self, order: &[usize]) -> Vec<u8> {
+        let mut map = vec![0u8; order.len()];
+        for (new, &old) in order.iter().enumerate() {
+            map[old] = new as u8;
+        }
+        self.entries = order.iter().map(|&old| self.entries[old]).collect();
+        map
+    }
+
+    /// Get the PLTE chunk data: three color bytes per entry.
+    pub fn palette_data(&self) -> Vec<u8> {
+        self.entries.iter().flat_map(|entry| entry[..3].to_vec()).collect()
+    }
+
+    /// Get the tRNS chunk data: one alpha byte per entry, up to the
+    /// last that isn't fully opaque. Empty if every entry is opaque.
+    pub fn transparency_data(&self) -> Vec<u8> {
+        let len = self.entries.iter().rposition(|entry| entry[3] < 255)
+                                     .map_or(0, |index| index + 1);
+        self.entries[..len].iter().map(|entry| entry[3]).collect()
+    }
+}
+
+//
+// Check that every index in a row of indexed-color data
+// refers to one of the given number of palette entries.
+//
+pub fn check_indices(header: &Header, entries: usize, row: &[u8]) -> IoResult {
+    let depth = header.depth() as usize;
+    if entries >= 1 << depth {
+        return Ok(());
+    }
+    let mask = ((1u16 << depth) - 1) as u8;
+    for x in 0..header.width() as usize {
+        let bit = x * depth;
+        let index = (row[bit >> 3] >> (8 - depth - (bit & 7))) & mask;
+        if index as usize >= entries {
+            return Err(invalid_input(&format!("Palette index {} is out of range for {} entries.", index, entries)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Palette;
+    use super::check_indices;
+    use super::super::ColorType;
+    use super::super::Header;
+
+    #[test]
+    fn chunks() {
+        let mut palette = Palette::new();
+        palette.push([1, 2, 3, 255]).unwrap();
+        palette.push([4, 5, 6, 0]).unwrap();
+        palette.push([7, 8, 9, 255]).unwrap();
+        assert_eq!(palette.palette_data(), vec![1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(palette.transparency_data(), vec![255, 0]);
+
+        let copy = Palette::from_chunks(&palette.palette_data(), &palette.transparency_data()).unwrap();
+        assert_eq!(copy.entries(), palette.entries());
+
+        for _ in 3..256 {
+            palette.push([0, 0, 0, 255]).unwrap();
+        }
+        assert!(palette.push([0, 0, 0, 255]).is_err());
+    }
+
+    #[test]
+    fn dedup_and_sort() {
+        let mut palette = Palette::new();
+        for &entry in &[[1, 1, 1, 255], [2, 2, 2, 128], [1, 1, 1, 255], [3, 3, 3, 0], [4, 4, 4, 255]] {
+            palette.push(entry).unwrap();
+        }
+        assert_eq!(palette.dedup(), vec![0, 1, 0, 2, 3]);
+        assert_eq!(palette.len(), 4);
+
+        assert_eq!(palette.sort_by_alpha(), vec![2, 1, 0, 3]);
+        assert_eq!(palette.entries(), &[[3, 3, 3, 0], [2, 2, 2, 128], [1, 1, 1, 255], [4, 4, 4, 255]]);
+        assert_eq!(palette.transparency_data(), vec![0, 128]);
+        assert_eq!(palette.index_of([4, 4, 4, 255]), Some(3));
+
+        assert!(palette.reorder(&[0, 1, 2]).is_err());
+        assert!(palette.reorder(&[0, 1, 2, 2]).is_err());
+        assert_eq!(palette.reorder(&[3, 2, 1, 0]).unwrap(), vec![3, 2, 1, 0]);
+        assert_eq!(palette.entries()[0], [4, 4, 4, 255]);
+    }
+
+    #[test]
+    fn indices() {
+        let mut header = Header::new();
+        header.set_size(5, 1).unwrap();
+        header.set_color(ColorType::IndexedColor, 2).unwrap();
+
+        // Indices 0, 1, 2, 1, 0 with the padding bits set.
+        let row = [0b00_01_10_01, 0b00_11_11_11];
+        check_indices(&header, 3, &row).unwrap();
+        assert!(check_indices(&header, 2, &row).is_err());
+        check_indices(&header, 4, &[0xff, 0xff]).unwrap();
+    }
+}
@@ -0,0 +1,715 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// quantize.rs - lossy quantization of truecolor images to a palette
+//
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Lossy quantization of truecolor images to an indexed-color palette.
+//!
+//! Builds a palette of at most a given number of colors with median cut
+//! and a few rounds of k-means refinement, then maps each pixel to its
+//! nearest entry, optionally with Floyd–Steinberg dithering. Colors are
+//! compared premultiplied by alpha, so differences in color count for
+//! less the more transparent a pixel is.
+//!
+//! Histogram, refinement and mapping passes run in parallel on the
+//! current rayon thread pool; call within `ThreadPool::install` to use
+//! another.
+
+use rayon::prelude::*;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::hash::BuildHasherDefault;
+use std::hash::Hasher;
+use std::io;
+
+use super::ColorType;
+use super::Header;
+use super::Palette;
+
+use super::utils::*;
+
+/// Options for quantizing an image to a palette.
+#[derive(Copy, Clone)]
+pub struct Options {
+    max_colors: usize,
+    dither_strength: f32,
+}
+
+impl Options {
+    /// Create a new Options struct using default options:
+    /// * max_colors: 256
+    /// * dither_strength: 0, for no dithering
+    pub fn new() -> Options {
+        Options {
+            max_colors: 256,
+            dither_strength: 0.0,
+        }
+    }
+
+    /// Get the most colors the palette may have.
+    pub fn max_colors(&self) -> usize {
+        self.max_colors
+    }
+
+    /// Get the strength of dithering.
+    pub fn dither_strength(&self) -> f32 {
+        self.dither_strength
+    }
+
+    /// Set the most colors the palette may have, from 1 to 256.
+    ///
+    /// Images with no more distinct colors than this are
+    /// converted exactly.
+    pub fn set_max_colors(&mut self, max_colors: usize) -> IoResult {
+        if !(1..=256).contains(&max_colors) {
+            return Err(invalid_input("Palette size must be from 1 to 256 colors"));
+        }
+        self.max_colors = max_colors;
+        Ok(())
+    }
+
+    /// Set the strength of Floyd–Steinberg dithering, as the share of
+    /// each pixel's quantization error passed on to its neighbours:
+    /// from 0 for none to 1 for full error diffusion.
+    ///
+    /// Dithering restarts every 64 rows so that bands of the image
+    /// can be processed in parallel.
+    pub fn set_dither_strength(&mut self, dither_strength: f32) -> IoResult {
+        if !(0.0..=1.0).contains(&dither_strength) {
+            return Err(invalid_input("Dither strength must be from 0 to 1"));
+        }
+        self.dither_strength = dither_strength;
+        Ok(())
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An image quantized to a palette, ready to hand to the encoder.
+pub struct Quantized {
+    header: Header,
+    palette: Palette,
+    data: Vec<u8>,
+}
+
+impl Quantized {
+    /// The header to write: indexed color, at the smallest depth
+    /// that can index the palette.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The palette, with its translucent entries first. Write it
+    /// with `Encoder::write_rgba_palette` for PLTE and tRNS.
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    /// The packed rows of palette indices.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+//
+// Target size of each group of rows handed to a worker thread
+// when building the histogram.
+//
+const CHUNK_BYTES: usize = 256 * 1024;
+
+//
+// Rows in each band mapped to the palette on a worker thread.
+// Dithering error isn't carried from one band to the next.
+//
+const BAND_ROWS: usize = 64;
+
+//
+// Bits kept from each channel when grouping similar colors
+// in the histogram.
+//
+const HISTOGRAM_BITS: u32 = 6;
+
+//
+// Rounds of k-means refinement after the median cut.
+//
+const REFINE_ROUNDS: usize = 3;
+
+//
+// Read a pixel as 8-bit RGBA, with every fully transparent
+// pixel the same.
+//
+fn read_pixel(header: &Header, row: &[u8], x: usize) -> [u8; 4] {
+    let depth = header.depth();
+    let channels = header.color_type().channels();
+    let mut pixel = [0, 0, 0, 255];
+    if depth == 8 {
+        pixel[..channels].copy_from_slice(&row[x * channels..(x + 1) * channels]);
+    } else {
+        for (c, sample) in pixel.iter_mut().enumerate().take(channels) {
+            *sample = (get_sample(row, x * channels + c, depth) >> 8) as u8;
+        }
+    }
+    if pixel[3] == 0 {
+        [0; 4]
+    } else {
+        pixel
+    }
+}
+
+fn premultiply(rgba: [u8; 4]) -> [f32; 4] {
+    let alpha = rgba[3] as f32 / 255.0;
+    [rgba[0] as f32 * alpha,
+     rgba[1] as f32 * alpha,
+     rgba[2] as f32 * alpha,
+     rgba[3] as f32]
+}
+
+fn unpremultiply(color: [f32; 4]) -> [u8; 4] {
+    let alpha = color[3].round().clamp(0.0, 255.0);
+    if alpha == 0.0 {
+        return [0; 4];
+    }
+    let channel = |value: f32| (value * 255.0 / alpha).round().clamp(0.0, 255.0) as u8;
+    [channel(color[0]), channel(color[1]), channel(color[2]), alpha as u8]
+}
+
+fn distance(a: &[f32; 4], b: &[f32; 4]) -> f32 {
+    a.iter().zip(b.iter()).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+fn nearest(colors: &[[f32; 4]], color: &[f32; 4]) -> usize {
+    let mut best = 0;
+    let mut best_distance = f32::MAX;
+    for (i, other) in colors.iter().enumerate() {
+        let d = distance(color, other);
+        if d < best_distance {
+            best = i;
+            best_distance = d;
+        }
+    }
+    best
+}
+
+//
+// Cheap hashing for maps keyed by packed RGBA colors, which are
+// looked up for every pixel.
+//
+#[derive(Default)]
+struct ColorHasher(u64);
+
+impl Hasher for ColorHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u32(byte as u32);
+        }
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.0 = (self.0.rotate_left(5) ^ value as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+type ColorMap<V> = HashMap<u32, V, BuildHasherDefault<ColorHasher>>;
+type ColorSet = HashSet<u32, BuildHasherDefault<ColorHasher>>;
+
+#[derive(Copy, Clone, Default)]
+struct Bucket {
+    count: u64,
+    sum: [u64; 4],
+}
+
+//
+// Counts of similar colors, and the exact colors while
+// there are few enough to use them as the palette.
+//
+struct Histogram {
+    limit: usize,
+    exact: Option<ColorSet>,
+    buckets: ColorMap<Bucket>,
+}
+
+impl Histogram {
+    fn new(limit: usize) -> Histogram {
+        Histogram {
+            limit,
+            exact: Some(ColorSet::default()),
+            buckets: ColorMap::default(),
+        }
+    }
+
+    fn add(&mut self, pixel: [u8; 4], count: u64) {
+        let shift = 8 - HISTOGRAM_BITS;
+        let key = u32::from_be_bytes([pixel[0] >> shift, pixel[1] >> shift, pixel[2] >> shift, pixel[3] >> shift]);
+        let bucket = self.buckets.entry(key).or_default();
+        bucket.count += count;
+        for (sum, &sample) in bucket.sum.iter_mut().zip(pixel.iter()) {
+            *sum += sample as u64 * count;
+        }
+
+        let overflow = match self.exact {
+            Some(ref mut exact) => {
+                exact.insert(u32::from_be_bytes(pixel));
+                exact.len() > self.limit
+            },
+            None => false,
+        };
+        if overflow {
+            self.exact = None;
+        }
+    }
+
+    fn merge(mut self, other: Histogram) -> Histogram {
+        for (key, more) in other.buckets {
+            let bucket = self.buckets.entry(key).or_default();
+            bucket.count += more.count;
+            for (sum, more) in bucket.sum.iter_mut().zip(more.sum.iter()) {
+                *sum += more;
+            }
+        }
+        self.exact = match (self.exact, other.exact) {
+            (Some(mut exact), Some(more)) => {
+                exact.extend(more);
+                if exact.len() > self.limit {
+                    None
+                } else {
+                    Some(exact)
+                }
+            },
+            _ => None,
+        };
+        self
+    }
+}
+
+//
+// A histogram bucket's mean color, for clustering.
+//
+#[derive(Copy, Clone)]
+struct Point {
+    color: [f32; 4],
+    weight: f64,
+}
+
+//
+// A range of points, and how it would best be split.
+//
+struct ColorBox {
+    start: usize,
+    end: usize,
+    channel: usize,
+    score: f64,
+}
+
+impl ColorBox {
+    fn new(points: &[Point], start: usize, end: usize) -> ColorBox {
+        let mut min = [f32::MAX; 4];
+        let mut max = [f32::MIN; 4];
+        let mut weight = 0.0;
+        for point in &points[start..end] {
+            for c in 0..4 {
+                min[c] = min[c].min(point.color[c]);
+                max[c] = max[c].max(point.color[c]);
+            }
+            weight += point.weight;
+        }
+        let mut channel = 0;
+        for c in 1..4 {
+            if max[c] - min[c] > max[channel] - min[channel] {
+                channel = c;
+            }
+        }
+        let range = (max[channel] - min[channel]) as f64;
+        ColorBox {
+            start,
+            end,
+            channel,
+            // Favour splitting boxes that cover many pixels widely.
+            score: if end - start > 1 {
+                range * weight
+            } else {
+                0.0
+            },
+        }
+    }
+
+    fn mean(&self, points: &[Point]) -> [f32; 4] {
+        let mut sum = [0f64; 4];
+        let mut weight = 0.0;
+        for point in &points[self.start..self.end] {
+            for (sum, &value) in sum.iter_mut().zip(point.color.iter()) {
+                *sum += value as f64 * point.weight;
+            }
+            weight += point.weight;
+        }
+        [(sum[0] / weight) as f32,
+         (sum[1] / weight) as f32,
+         (sum[2] / weight) as f32,
+         (sum[3] / weight) as f32]
+    }
+}
+
+//
+// Divide the points into at most max_colors boxes, each time
+// splitting the widest box at its weighted median.
+//
+fn median_cut(points: &mut [Point], max_colors: usize) -> Vec<[f32; 4]> {
+    let mut boxes = vec![ColorBox::new(points, 0, points.len())];
+    while boxes.len() < max_colors {
+        let mut best = 0;
+        for (i, candidate) in boxes.iter().enumerate() {
+            if candidate.score > boxes[best].score {
+                best = i;
+            }
+        }
+        if boxes[best].score <= 0.0 {
+            break;
+        }
+
+        let (start, end, channel) = (boxes[best].start, boxes[best].end, boxes[best].channel);
+        let slice = &mut points[start..end];
+        slice.sort_by(|a, b| a.color[channel].total_cmp(&b.color[channel]));
+        let half = slice.iter().map(|point| point.weight).sum::<f64>() / 2.0;
+        let mut total = 0.0;
+        let mut split = 1;
+        for (i, point) in slice.iter().enumerate() {
+            total += point.weight;
+            if total >= half {
+                split = i + 1;
+                break;
+            }
+        }
+        let split = start + cmp::min(split, slice.len() - 1);
+        boxes[best] = ColorBox::new(points, start, split);
+        boxes.push(ColorBox::new(points, split, end));
+    }
+    boxes.iter().map(|b| b.mean(points)).collect()
+}
+
+//
+// Move each color to the mean of the points nearest to it.
+//
+fn refine(points: &[Point], colors: &mut [[f32; 4]]) {
+    let len = colors.len();
+    for _ in 0..REFINE_ROUNDS {
+        let sums = points.par_chunks(4096).map(|chunk| {
+            let mut sums = vec![[0f64; 5]; len];
+            for point in chunk {
+                let sum = &mut sums[nearest(colors, &point.color)];
+                for (sum, &value) in sum.iter_mut().zip(point.color.iter()) {
+                    *sum += value as f64 * point.weight;
+                }
+                sum[4] += point.weight;
+            }
+            sums
+        }).reduce(|| vec![[0f64; 5]; len], |mut a, b| {
+            for (a, b) in a.iter_mut().zip(b.iter()) {
+                for c in 0..5 {
+                    a[c] += b[c];
+                }
+            }
+            a
+        });
+        for (color, sum) in colors.iter_mut().zip(sums.iter()) {
+            if sum[4] > 0.0 {
+                for c in 0..4 {
+                    color[c] = (sum[c] / sum[4]) as f32;
+                }
+            }
+        }
+    }
+}
+
+//
+// Map a band of rows to palette indices, diffusing the
+// quantization error if dithering.
+//
+fn map_band(header: &Header,
+            entries: &[[u8; 4]],
+            colors: &[[f32; 4]],
+            strength: f32,
+            depth: u8,
+            input: &[u8],
+            output: &mut [u8]) {
+    let width = header.width() as usize;
+    let stride = header.stride();
+    let out_stride = output.len() / (input.len() / stride);
+    let mut cache: ColorMap<u8> = ColorMap::default();
+    let mut last = None;
+    let mut lookup = |pixel: [u8; 4]| -> u8 {
+        let key = u32::from_be_bytes(pixel);
+        match last {
+            Some((last_key, index)) if last_key == key => index,
+            _ => {
+                let index = *cache.entry(key).or_insert_with(|| {
+                    nearest(colors, &premultiply(pixel)) as u8
+                });
+                last = Some((key, index));
+                index
+            }
+        }
+    };
+
+    // Error carried to this row and the next, with a pixel
+    // of padding at each end.
+    let mut errors = vec![[0f32; 4]; width + 2];
+    let mut next = vec![[0f32; 4]; width + 2];
+    for (row, out_row) in input.chunks(stride).zip(output.chunks_mut(out_stride)) {
+        for x in 0..width {
+            let pixel = read_pixel(header, row, x);
+            let index = if strength > 0.0 && pixel[3] > 0 {
+                let mut wanted = [0f32; 4];
+                let mut rounded = [0u8; 4];
+                for c in 0..4 {
+                    wanted[c] = (pixel[c] as f32 + errors[x + 1][c]).clamp(0.0, 255.0);
+                    rounded[c] = wanted[c].round() as u8;
+                }
+                let index = lookup(if rounded[3] == 0 {
+                    [0; 4]
+                } else {
+                    rounded
+                });
+                let entry = entries[index as usize];
+                for c in 0..4 {
+                    let error = (wanted[c] - entry[c] as f32) * strength;
+                    errors[x + 2][c] += error * 7.0 / 16.0;
+                    next[x][c] += error * 3.0 / 16.0;
+                    next[x + 1][c] += error * 5.0 / 16.0;
+                    next[x + 2][c] += error / 16.0;
+                }
+                index
+            } else {
+                lookup(pixel)
+            };
+            put_sample(out_row, x, depth, index as u16);
+        }
+        errors.copy_from_slice(&next);
+        for error in next.iter_mut() {
+            *error = [0.0; 4];
+        }
+    }
+}
+
+/// Quantize a truecolor image to an indexed-color palette of at most
+/// `options.max_colors()` entries.
+///
+/// Takes the header and packed rows of a Truecolor or TruecolorAlpha
+/// image at 8 or 16 bits; 16-bit samples are first cut to 8 bits.
+/// Returns the indexed-color header, the palette to write as PLTE and
+/// tRNS, and the rows of indices. The interlace method is kept.
+///
+/// Returns an error for other color types, or if the data is the
+/// wrong length for the header.
+pub fn quantize(header: &Header, data: &[u8], options: &Options) -> io::Result<Quantized> {
+    if !matches!(header.color_type(), ColorType::Truecolor | ColorType::TruecolorAlpha) {
+        return Err(invalid_input("Quantization requires a truecolor image"));
+    }
+    let stride = header.stride();
+    if data.len() != stride * header.height() as usize {
+        return Err(invalid_input("Image data must hold exactly the rows of the header"));
+    }
+    let width = header.width() as usize;
+    let max_colors = options.max_colors;
+
+    let rows = cmp::max(1, CHUNK_BYTES / stride);
+    let histogram = data.par_chunks(rows * stride).map(|rows| {
+        let mut histogram = Histogram::new(max_colors);
+        for row in rows.chunks(stride) {
+            // Count runs of the same color at once.
+            let mut run = (read_pixel(header, row, 0), 0);
+            for x in 0..width {
+                let pixel = read_pixel(header, row, x);
+                if pixel != run.0 {
+                    histogram.add(run.0, run.1);
+                    run = (pixel, 0);
+                }
+                run.1 += 1;
+            }
+            histogram.add(run.0, run.1);
+        }
+        histogram
+    }).reduce(|| Histogram::new(max_colors), Histogram::merge);
+
+    let mut palette = Palette::new();
+    match histogram.exact {
+        Some(exact) => {
+            let mut colors: Vec<u32> = exact.into_iter().collect();
+            colors.sort_unstable();
+            for color in colors {
+                palette.push(color.to_be_bytes())?;
+            }
+        },
+        None => {
+            let mut points: Vec<Point> = histogram.buckets.values().map(|bucket| {
+                let mut mean = [0u8; 4];
+                for (mean, &sum) in mean.iter_mut().zip(bucket.sum.iter()) {
+                    *mean = ((sum + bucket.count / 2) / bucket.count) as u8;
+                }
+                Point {
+                    color: premultiply(mean),
+                    weight: bucket.count as f64,
+                }
+            }).collect();
+            let mut colors = median_cut(&mut points, max_colors);
+            refine(&points, &mut colors);
+            for color in colors {
+                palette.push(unpremultiply(color))?;
+            }
+            palette.dedup();
+        },
+    }
+    palette.sort_by_alpha();
+
+    let depth = match palette.len() {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    };
+    let mut out_header = *header;
+    out_header.set_color(ColorType::IndexedColor, depth)?;
+    let out_stride = out_header.stride();
+
+    let entries = palette.entries();
+    let colors: Vec<[f32; 4]> = entries.iter().map(|&entry| premultiply(entry)).collect();
+    let mut out = vec![0u8; out_stride * header.height() as usize];
+    data.par_chunks(BAND_ROWS * stride)
+        .zip(out.par_chunks_mut(BAND_ROWS * out_stride))
+        .for_each(|(input, output)| {
+            map_band(header, entries, &colors, options.dither_strength, depth, input, output);
+        });
+
+    Ok(Quantized {
+        header: out_header,
+        palette,
+        data: out,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::quantize;
+    use super::Options;
+    use super::super::ColorType;
+    use super::super::Header;
+    use super::super::Palette;
+
+    fn header(width: u32, height: u32, color_type: ColorType) -> Header {
+        let mut header = Header::new();
+        header.set_size(width, height).unwrap();
+        header.set_color(color_type, 8).unwrap();
+        header
+    }
+
+    // Look up the color of every pixel, for 8-bit indices.
+    fn colors(palette: &Palette, data: &[u8]) -> Vec<[u8; 4]> {
+        data.iter().map(|&index| palette.entries()[index as usize]).collect()
+    }
+
+    #[test]
+    fn exact() {
+        let pixels = [[255, 0, 0, 255], [0, 255, 0, 128], [0, 0, 255, 255], [9, 9, 9, 0]];
+        let data: Vec<u8> = (0..64).flat_map(|i| pixels[i % 4].to_vec()).collect();
+        let out = quantize(&header(8, 8, ColorType::TruecolorAlpha), &data, &Options::new()).unwrap();
+        assert_eq!(out.header().color_type() as u8, ColorType::IndexedColor as u8);
+        assert_eq!(out.header().depth(), 2);
+        assert_eq!(out.palette().len(), 4);
+        // Transparent entries come first; invisible color is dropped.
+        assert_eq!(out.palette().transparency_data(), vec![0, 128]);
+        let first = out.data()[0] >> 6;
+        assert_eq!(out.palette().entries()[first as usize], [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn gradient() {
+        let data: Vec<u8> = (0..64 * 256).flat_map(|i| {
+            let x = (i % 256) as u8;
+            let y = (i / 256) as u8;
+            vec![x, y * 4, 255 - x]
+        }).collect();
+        let mut options = Options::new();
+        options.set_max_colors(64).unwrap();
+        let out = quantize(&header(256, 64, ColorType::Truecolor), &data, &options).unwrap();
+        assert_eq!(out.header().depth(), 8);
+        assert!(out.palette().len() <= 64);
+
+        let mapped = colors(out.palette(), out.data());
+        let error: u64 = mapped.iter().zip(data.chunks(3)).map(|(entry, pixel)| {
+            (0..3).map(|c| (entry[c] as i32 - pixel[c] as i32).unsigned_abs() as u64).sum::<u64>()
+        }).sum();
+        // Within a few levels per channel on average.
+        assert!(error / mapped.len() as u64 <= 24, "mean error {}", error / mapped.len() as u64);
+    }
+
+    #[test]
+    fn dither() {
+        // A grey ramp quantized to four levels.
+        let data: Vec<u8> = (0..64 * 256).map(|i| (i % 256) as u8).flat_map(|v| vec![v, v, v]).collect();
+        let header = header(256, 64, ColorType::Truecolor);
+        let mut options = Options::new();
+        options.set_max_colors(4).unwrap();
+
+        // Column averages follow the ramp more closely with dithering.
+        let column_error = |options: &Options| -> i64 {
+            let out = quantize(&header, &data, options).unwrap();
+            let mut index_data = vec![0u8; 256 * 64];
+            for (i, index) in index_data.iter_mut().enumerate() {
+                let byte = out.data()[i / 4];
+                *index = (byte >> (6 - 2 * (i % 4))) & 3;
+            }
+            let mapped = colors(out.palette(), &index_data);
+            (0..256).map(|x| {
+                let sum: i64 = (0..64).map(|y| mapped[y * 256 + x][0] as i64).sum();
+                (sum / 64 - x as i64).abs()
+            }).sum()
+        };
+        let plain = column_error(&options);
+        options.set_dither_strength(1.0).unwrap();
+        let dithered = column_error(&options);
+        assert!(dithered < plain / 2, "{} vs {}", dithered, plain);
+    }
+
+    #[test]
+    fn invalid() {
+        let mut options = Options::new();
+        assert!(options.set_max_colors(0).is_err());
+        assert!(options.set_max_colors(257).is_err());
+        assert!(options.set_dither_strength(1.5).is_err());
+        let indexed = {
+            let mut header = Header::new();
+            header.set_size(2, 2).unwrap();
+            header.set_color(ColorType::IndexedColor, 8).unwrap();
+            header
+        };
+        assert!(quantize(&indexed, &[0; 4], &options).is_err());
+        assert!(quantize(&header(2, 2, ColorType::Truecolor), &[0; 11], &options).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// reader.rs - low-level PNG chunk reader
+//
+// Copyright (c) 2018 Brion Vibber
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+use crc::crc32;
+use crc::Hasher32;
+
+use std::convert::TryFrom;
+use std::io;
+use std::io::Read;
+
+use super::ColorType;
+use super::Header;
+use super::InterlaceMethod;
+
+use super::utils::*;
+
+//
+// A chunk as read from the input stream, with its checksum verified.
+//
+pub struct Chunk {
+    pub tag: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    //
+    // Critical chunks have an uppercase first letter.
+    // https://www.w3.org/TR/PNG/#5Chunk-naming-conventions
+    //
+    pub fn is_critical(&self) -> bool {
+        self.tag[0] & 0x20 == 0
+    }
+}
+
+pub struct Reader<R: Read> {
+    input: R,
+}
+
+impl<R: Read> Reader<R> {
+    //
+    // Creates a new PNG chunk stream reader.
+    // Consumes the input Read object, but will
+    // give it back to you via Reader::finish().
+    //
+    pub fn new(input: R) -> Reader<R> {
+        Reader {
+            input,
+        }
+    }
+
+    //
+    // Close out the reader and return the Read
+    // passed in originally so it can be used for
+    // further input if necessary.
+    //
+    // Consumes the reader.
+    //
+    pub fn finish(self: Reader<R>) -> R {
+        self.input
+    }
+
+    //
+    // Read and check the PNG file signature.
+    // https://www.w3.org/TR/PNG/#5PNG-file-signature
+    //
+    pub fn read_signature(&mut self) -> IoResult {
+        let mut bytes = [0u8; 8];
+        self.input.read_exact(&mut bytes)?;
+        if bytes == *b"\x89PNG\r\n\x1a\n" {
+            Ok(())
+        } else {
+            Err(invalid_data("Not a PNG file"))
+        }
+    }
+
+    fn read_be32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0u8; 4];
+        self.input.read_exact(&mut bytes)?;
+        Ok(read_be32(&bytes))
+    }
+
+    //
+    // Read a chunk from the input stream, verifying its checksum.
+    //
+    // https://www.w3.org/TR/PNG/#5DataRep
+    // https://www.w3.org/TR/PNG/#5CRC-algorithm
+    //
+    pub fn read_chunk(&mut self) -> io::Result<Chunk> {
+        let len = self.read_be32()?;
+        if len > i32::MAX as u32 {
+            return Err(invalid_data("Chunk length exceeds 2 GiB - 1 byte"));
+        }
+
+        let mut tag = [0u8; 4];
+        self.input.read_exact(&mut tag)?;
+        if !tag.iter().all(|c| c.is_ascii_alphabetic()) {
+            return Err(invalid_data("Invalid chunk tag"));
+        }
+
+        let mut data = Vec::new();
+        (&mut self.input).take(u64::from(len)).read_to_end(&mut data)?;
+        if data.len() != len as usize {
+            return Err(invalid_data("Unexpected end of file in chunk data"));
+        }
+
+        let mut digest = crc32::Digest::new(crc32::IEEE);
+        digest.write(&tag);
+        digest.write(&data);
+        if self.read_be32()? != digest.sum32() {
+            return Err(invalid_data("Chunk checksum mismatch"));
+        }
+
+        Ok(Chunk {
+            tag,
+            data,
+        })
+    }
+
+    //
+    // IHDR - first chunk in the file.
+    // https://www.w3.org/TR/PNG/#11IHDR
+    //
+    pub fn read_header(&mut self) -> io::Result<Header> {
+        let chunk = self.read_chunk()?;
+        if chunk.tag != *b"IHDR" {
+            return Err(invalid_data("First chunk must be IHDR"));
+        }
+        let data = &chunk.data;
+        if data.len() != 13 {
+            return Err(invalid_data("IHDR chunk must be 13 bytes"));
+        }
+
+        let width = read_be32(&data[0 .. 4]);
+        let height = read_be32(&data[4 .. 8]);
+        if width > i32::MAX as u32 || height > i32::MAX as u32 {
+            return Err(invalid_data("Image dimensions exceed 2^31 - 1"));
+        }
+        if data[10] != 0 {
+            return Err(invalid_data("Unsupported compression method"));
+        }
+        if data[11] != 0 {
+            return Err(invalid_data("Unsupported filter method"));
+        }
+
+        let mut header = Header::new();
+        header.set_size(width, height)?;
+        header.set_color(ColorType::try_from(data[9])?, data[8])?;
+        header.set_interlace_method(InterlaceMethod::try_from(data[12])?)?;
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::Reader;
+    use super::super::writer::Writer;
+    use super::super::Header;
+    use super::super::ColorType;
+
+    fn test_reader(bytes: &[u8]) -> Reader<io::Cursor<&[u8]>> {
+        Reader::new(io::Cursor::new(bytes))
+    }
+
+    #[test]
+    fn chunk_round_trip() {
+        let mut writer = Writer::new(Vec::<u8>::new());
+        writer.write_signature().unwrap();
+        writer.write_chunk(b"tEXt", b"Comment\0hello").unwrap();
+        let output = writer.finish().unwrap();
+
+        let mut reader = test_reader(&output);
+        reader.read_signature().unwrap();
+        let chunk = reader.read_chunk().unwrap();
+        assert_eq!(&chunk.tag, b"tEXt");
+        assert_eq!(&chunk.data[..], &b"Comment\0hello"[..]);
+        assert!(!chunk.is_critical());
+        assert!(reader.read_chunk().is_err());
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut header = Header::new();
+        header.set_size(640, 480).unwrap();
+        header.set_color(ColorType::GreyscaleAlpha, 16).unwrap();
+
+        let mut writer = Writer::new(Vec::<u8>::new());
+        writer.write_header(header).unwrap();
+        let output = writer.finish().unwrap();
+
+        let read = test_reader(&output).read_header().unwrap();
+        assert_eq!(read.width(), 640);
+        assert_eq!(read.height(), 480);
+        assert_eq!(read.depth(), 16);
+        assert_eq!(read.stride(), header.stride());
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut writer = Writer::new(Vec::<u8>::new());
+        writer.write_chunk(b"IDAT", b"0123").unwrap();
+        let mut output = writer.finish().unwrap();
+        output[9] ^= 1;
+
+        assert!(test_reader(&output).read_chunk().is_err());
+    }
+}
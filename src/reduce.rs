@@ -0,0 +1,961 @@
+//
+// mtpng - a multithreaded parallel PNG encoder in Rust
+// reduce.rs - lossless reduction of color type and bit depth
+//
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//
+
+//! Lossless reduction of an image to its smallest color type and depth,
+//! and palette ordering for indexed-color images.
+//!
+//! Scans packed image rows in parallel on the current rayon thread pool,
+//! and repacks them as greyscale, indexed or truecolor data at the lowest
+//! bit depth that keeps every pixel value, with PLTE and tRNS to match.
+
+use rayon::prelude::*;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::io;
+
+use super::ColorType;
+use super::Filter;
+use super::Header;
+use super::Mode;
+use super::Mode::{Adaptive, Fixed};
+use super::Palette;
+
+use super::deflate;
+use super::deflate::Deflate;
+use super::deflate::Flush;
+use super::filter::AdaptiveFilter;
+use super::heuristic::MinSum;
+
+use super::utils::*;
+
+/// An image rewritten in its reduced form, ready to hand to the encoder.
+pub struct Reduction {
+    header: Header,
+    palette: Vec<u8>,
+    transparency: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl Reduction {
+    /// The header to write, with the new color type and depth.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// PLTE data to write, or an empty slice if the image isn't indexed.
+    pub fn palette(&self) -> &[u8] {
+        &self.palette
+    }
+
+    /// tRNS data to write, or an empty slice if none is needed.
+    pub fn transparency(&self) -> &[u8] {
+        &self.transparency
+    }
+
+    /// The image rows, packed to match the new header.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+//
+// Target size of each group of rows handed to a worker thread.
+//
+const CHUNK_BYTES: usize = 256 * 1024;
+
+//
+// Reads pixels of the input image as RGBA, with samples at 16 bits
+// if the input has 16-bit samples and at 8 bits otherwise.
+//
+struct Source<'a> {
+    header: &'a Header,
+    palette: &'a [u8],
+    transparency: &'a [u8],
+    stride: usize,
+    scale: u8,
+}
+
+impl<'a> Source<'a> {
+    fn new(header: &'a Header, palette: &'a [u8], transparency: &'a [u8]) -> io::Result<Source<'a>> {
+        let entries = palette.len() / 3;
+        match header.color_type() {
+            ColorType::IndexedColor => {
+                if palette.is_empty() || palette.len() % 3 != 0 || entries > 256 {
+                    return Err(invalid_input("Indexed images need a palette of 1 to 256 RGB entries"));
+                }
+                if transparency.len() > entries {
+                    return Err(invalid_input("Transparency has more entries than the palette"));
+                }
+            },
+            ColorType::Greyscale | ColorType::Truecolor => {
+                let key = header.color_type().channels() * 2;
+                if !transparency.is_empty() && transparency.len() != key {
+                    return Err(invalid_input(&format!("Transparency must be {} bytes for this color type", key)));
+                }
+            },
+            ColorType::GreyscaleAlpha | ColorType::TruecolorAlpha => {
+                if !transparency.is_empty() {
+                    return Err(invalid_input("Transparency is not allowed with an alpha channel"));
+                }
+            },
+        }
+        Ok(Source {
+            header,
+            palette,
+            transparency,
+            stride: header.stride(),
+            scale: if header.depth() == 16 {
+                16
+            } else {
+                8
+            },
+        })
+    }
+
+    fn max(&self) -> u16 {
+        max_value(self.scale)
+    }
+
+    fn sample(&self, row: &[u8], index: usize) -> u16 {
+        get_sample(row, index, self.header.depth())
+    }
+
+    fn key_matches(&self, samples: &[u16]) -> bool {
+        self.transparency.len() == samples.len() * 2 &&
+            samples.iter().enumerate().all(|(i, &sample)| {
+                read_be16(&self.transparency[i * 2..]) == sample
+            })
+    }
+
+    fn pixel(&self, row: &[u8], x: usize) -> io::Result<[u16; 4]> {
+        let max = self.max();
+        Ok(match self.header.color_type() {
+            ColorType::Greyscale => {
+                let raw = self.sample(row, x);
+                let alpha = if self.key_matches(&[raw]) {
+                    0
+                } else {
+                    max
+                };
+                // Sub-byte samples are scaled up to 8 bits.
+                let grey = raw * (max_value(8) / max_value(cmp::min(self.header.depth(), 8)));
+                [grey, grey, grey, alpha]
+            },
+            ColorType::GreyscaleAlpha => {
+                let grey = self.sample(row, x * 2);
+                [grey, grey, grey, self.sample(row, x * 2 + 1)]
+            },
+            ColorType::Truecolor => {
+                let rgb = [self.sample(row, x * 3),
+                           self.sample(row, x * 3 + 1),
+                           self.sample(row, x * 3 + 2)];
+                let alpha = if self.key_matches(&rgb) {
+                    0
+                } else {
+                    max
+                };
+                [rgb[0], rgb[1], rgb[2], alpha]
+            },
+            ColorType::TruecolorAlpha => {
+                [self.sample(row, x * 4),
+                 self.sample(row, x * 4 + 1),
+                 self.sample(row, x * 4 + 2),
+                 self.sample(row, x * 4 + 3)]
+            },
+            ColorType::IndexedColor => {
+                let index = self.sample(row, x) as usize;
+                let entry = match self.palette.get(index * 3..index * 3 + 3) {
+                    Some(entry) => entry,
+                    None => return Err(invalid_input(&format!("Palette index {} is out of range", index))),
+                };
+                let alpha = *self.transparency.get(index).unwrap_or(&255);
+                [entry[0] as u16, entry[1] as u16, entry[2] as u16, alpha as u16]
+            },
+        })
+    }
+
+    //
+    // Split the image into groups of whole rows for the worker threads.
+    //
+    fn chunks<'b>(&self, data: &'b [u8]) -> rayon::slice::Chunks<'b, u8> {
+        let rows = cmp::max(1, CHUNK_BYTES / self.stride);
+        data.par_chunks(rows * self.stride)
+    }
+
+    //
+    // Call func with each pixel in a group of rows, skipping
+    // runs of the same value.
+    //
+    fn each_pixel<F>(&self, rows: &[u8], mut func: F) -> IoResult
+        where F: FnMut([u16; 4])
+    {
+        let mut last = None;
+        for row in rows.chunks(self.stride) {
+            for x in 0..self.header.width() as usize {
+                let pixel = self.pixel(row, x)?;
+                if last != Some(pixel) {
+                    func(pixel);
+                    last = Some(pixel);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn max_value(depth: u8) -> u16 {
+    ((1u32 << depth) - 1) as u16
+}
+
+//
+// Smallest greyscale depth that holds an 8-bit value exactly.
+//
+fn grey_depth(value: u16) -> u8 {
+    if value % 255 == 0 {
+        1
+    } else if value % 85 == 0 {
+        2
+    } else if value % 17 == 0 {
+        4
+    } else {
+        8
+    }
+}
+
+//
+// What a group of rows needs to be stored losslessly.
+//
+struct Stats {
+    grey: bool,
+    opaque: bool,
+    // Every alpha value is either 0 or the maximum.
+    binary_alpha: bool,
+    // Some 16-bit sample has a low byte that differs from its high byte.
+    wide: bool,
+    grey_depth: u8,
+    // Distinct colors, until there are too many for a palette.
+    colors: Option<HashSet<[u16; 4]>>,
+    // Distinct colors of fully transparent pixels, up to two.
+    keys: Vec<[u16; 3]>,
+}
+
+impl Stats {
+    fn new() -> Stats {
+        Stats {
+            grey: true,
+            opaque: true,
+            binary_alpha: true,
+            wide: false,
+            grey_depth: 1,
+            colors: Some(HashSet::new()),
+            keys: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, pixel: [u16; 4], scale: u8) {
+        let max = max_value(scale);
+        if pixel[0] != pixel[1] || pixel[1] != pixel[2] {
+            self.grey = false;
+        }
+        if pixel[3] != max {
+            self.opaque = false;
+            if pixel[3] != 0 {
+                self.binary_alpha = false;
+            }
+        }
+        if pixel[3] == 0 {
+            self.add_key([pixel[0], pixel[1], pixel[2]]);
+        }
+        if scale == 16 {
+            if pixel.iter().any(|&sample| sample >> 8 != sample & 0xff) {
+                self.wide = true;
+            }
+            self.grey_depth = cmp::max(self.grey_depth, grey_depth(pixel[0] >> 8));
+        } else {
+            self.grey_depth = cmp::max(self.grey_depth, grey_depth(pixel[0]));
+        }
+        let overflow = match self.colors {
+            Some(ref mut colors) => {
+                colors.insert(pixel);
+                colors.len() > 256
+            },
+            None => false,
+        };
+        if overflow {
+            self.colors = None;
+        }
+    }
+
+    fn add_key(&mut self, key: [u16; 3]) {
+        if self.keys.len() < 2 && !self.keys.contains(&key) {
+            self.keys.push(key);
+        }
+    }
+
+    fn merge(mut self, other: Stats) -> Stats {
+        self.grey &= other.grey;
+        self.opaque &= other.opaque;
+        self.binary_alpha &= other.binary_alpha;
+        self.wide |= other.wide;
+        self.grey_depth = cmp::max(self.grey_depth, other.grey_depth);
+        self.colors = match (self.colors, other.colors) {
+            (Some(mut colors), Some(more)) => {
+                colors.extend(more);
+                if colors.len() > 256 {
+                    None
+                } else {
+                    Some(colors)
+                }
+            },
+            _ => None,
+        };
+        for key in other.keys {
+            self.add_key(key);
+        }
+        self
+    }
+}
+
+//
+// Scale a sample from the source to the target depth, which
+// the analysis has found to hold it exactly.
+//
+fn narrow(sample: u16, scale: u8, depth: u8) -> u16 {
+    let sample = if scale == 16 && depth < 16 {
+        sample >> 8
+    } else {
+        sample
+    };
+    if depth < 8 {
+        sample / (max_value(8) / max_value(depth))
+    } else {
+        sample
+    }
+}
+
+//
+// Encoded size of a candidate, with the palette and key it carries.
+//
+fn cost(header: &Header, color_type: ColorType, depth: u8, extra: usize) -> usize {
+    let bits = color_type.channels() * depth as usize * header.width() as usize;
+    ((bits + 7) >> 3) * header.height() as usize + extra
+}
+
+/// Find the smallest lossless color type and bit depth for an image,
+/// and repack its rows to match.
+///
+/// Takes the header, PLTE and tRNS data the image would otherwise be
+/// written with, and its packed rows without filter bytes. Pass empty
+/// slices when there is no palette or transparency.
+///
+/// Candidates are greyscale at 1 to 16 bits, truecolor, indexed color
+/// at 1 to 8 bits with the translucent entries first so tRNS stays
+/// short, and a tRNS color key in place of an alpha channel when
+/// fully transparent pixels all share one color. 16-bit samples drop
+/// to 8 bits when every low byte repeats its high byte. The interlace
+/// method is kept.
+///
+/// When both an indexed and a greyscale or truecolor form would work,
+/// the one with less uncompressed row data plus PLTE and tRNS bytes is
+/// picked. Nothing is trial-compressed, so the choice may not give the
+/// smallest file.
+///
+/// Rows are scanned and repacked in parallel on the current rayon
+/// thread pool; call this within `ThreadPool::install` to use another.
+///
+/// Returns an error if the data is the wrong length for the header,
+/// or refers to palette entries that don't exist.
+pub fn reduce(header: &Header, palette: &[u8], transparency: &[u8], data: &[u8]) -> io::Result<Reduction> {
+    let source = Source::new(header, palette, transparency)?;
+    if data.len() != source.stride * header.height() as usize {
+        return Err(invalid_input("Image data must hold exactly the rows of the header"));
+    }
+
+    let stats = source.chunks(data).map(|rows| {
+        let mut stats = Stats::new();
+        source.each_pixel(rows, |pixel| stats.add(pixel, source.scale))?;
+        Ok(stats)
+    }).reduce(|| Ok(Stats::new()), |a: io::Result<Stats>, b: io::Result<Stats>| {
+        Ok(a?.merge(b?))
+    })?;
+
+    // A single transparent color can stand in for the alpha channel,
+    // as long as no opaque pixel has the same color.
+    let max = source.max();
+    let mut key = None;
+    if !stats.opaque && stats.binary_alpha && stats.keys.len() == 1 {
+        let rgb = stats.keys[0];
+        let opaque = [rgb[0], rgb[1], rgb[2], max];
+        let clash = match stats.colors {
+            Some(ref colors) => colors.contains(&opaque),
+            None => source.chunks(data).map(|rows| {
+                let mut found = false;
+                source.each_pixel(rows, |pixel| found |= pixel == opaque)?;
+                Ok(found)
+            }).reduce(|| Ok(false), |a: io::Result<bool>, b: io::Result<bool>| {
+                Ok(a? || b?)
+            })?,
+        };
+        if !clash {
+            key = Some(rgb);
+        }
+    }
+
+    let depth = if stats.wide {
+        16
+    } else {
+        8
+    };
+    let solid = stats.opaque || key.is_some();
+    let (mut color_type, mut out_depth) = match (stats.grey, solid) {
+        (true, true) => (ColorType::Greyscale, if stats.wide {
+            16
+        } else {
+            stats.grey_depth
+        }),
+        (true, false) => (ColorType::GreyscaleAlpha, depth),
+        (false, true) => (ColorType::Truecolor, depth),
+        (false, false) => (ColorType::TruecolorAlpha, depth),
+    };
+    let mut transparency = Vec::new();
+    if let Some(rgb) = key {
+        let channels = if stats.grey {
+            1
+        } else {
+            3
+        };
+        for &sample in &rgb[..channels] {
+            write_be16(&mut transparency, narrow(sample, source.scale, out_depth))?;
+        }
+    }
+
+    // A palette pays off when its indices and entries take less room.
+    let mut palette = Vec::new();
+    let mut indices = HashMap::new();
+    if let (false, Some(colors)) = (stats.wide, stats.colors) {
+        let mut entries: Vec<[u8; 4]> = colors.into_iter().map(|color| {
+            let mut entry = [0; 4];
+            for (out, &sample) in entry.iter_mut().zip(color.iter()) {
+                *out = narrow(sample, source.scale, 8) as u8;
+            }
+            entry
+        }).collect();
+        entries.sort();
+        let mut candidate = Palette::new();
+        for entry in entries {
+            candidate.push(entry)?;
+        }
+        candidate.sort_by_alpha();
+
+        let index_depth = match candidate.len() {
+            0..=2 => 1,
+            3..=4 => 2,
+            5..=16 => 4,
+            _ => 8,
+        };
+        let alphas = candidate.transparency_data();
+        if cost(header, ColorType::IndexedColor, index_depth, candidate.len() * 3 + alphas.len()) <
+           cost(header, color_type, out_depth, transparency.len()) {
+            color_type = ColorType::IndexedColor;
+            out_depth = index_depth;
+            transparency = alphas;
+            palette = candidate.palette_data();
+            for (index, entry) in candidate.entries().iter().enumerate() {
+                // Look up by the source value of the color.
+                let mut color = [0u16; 4];
+                for (out, &sample) in color.iter_mut().zip(entry.iter()) {
+                    *out = if source.scale == 16 {
+                        sample as u16 * 257
+                    } else {
+                        sample as u16
+                    };
+                }
+                indices.insert(color, index as u16);
+            }
+        }
+    }
+
+    let mut out_header = *header;
+    out_header.set_color(color_type, out_depth)?;
+    let out_stride = out_header.stride();
+    let width = header.width() as usize;
+
+    let chunks: io::Result<Vec<Vec<u8>>> = source.chunks(data).map(|rows| {
+        let mut out = vec![0u8; rows.len() / source.stride * out_stride];
+        for (row, out_row) in rows.chunks(source.stride).zip(out.chunks_mut(out_stride)) {
+            for x in 0..width {
+                let pixel = source.pixel(row, x)?;
+                let samples: &[usize] = match color_type {
+                    ColorType::Greyscale => &[0],
+                    ColorType::GreyscaleAlpha => &[0, 3],
+                    ColorType::Truecolor => &[0, 1, 2],
+                    ColorType::TruecolorAlpha => &[0, 1, 2, 3],
+                    ColorType::IndexedColor => {
+                        put_sample(out_row, x, out_depth, indices[&pixel]);
+                        continue;
+                    },
+                };
+                for (i, &channel) in samples.iter().enumerate() {
+                    let sample = narrow(pixel[channel], source.scale, out_depth);
+                    put_sample(out_row, x * samples.len() + i, out_depth, sample);
+                }
+            }
+        }
+        Ok(out)
+    }).collect();
+
+    Ok(Reduction {
+        header: out_header,
+        palette,
+        transparency,
+        data: chunks?.concat(),
+    })
+}
+
+fn check_indexed(header: &Header, data: &[u8]) -> IoResult {
+    if !matches!(header.color_type(), ColorType::IndexedColor) {
+        return Err(invalid_input("Palette indices require an indexed-color image"));
+    }
+    if data.len() != header.stride() * header.height() as usize {
+        return Err(invalid_input("Image data must hold exactly the rows of the header"));
+    }
+    Ok(())
+}
+
+//
+// Rewrite one row of indices through a map, leaving any
+// padding bits at the end of the row alone.
+//
+fn remap_row(header: &Header, map: &[u8], row: &mut [u8]) -> IoResult {
+    let depth = header.depth();
+    for x in 0..header.width() as usize {
+        let index = get_sample(row, x, depth) as usize;
+        let new = match map.get(index) {
+            Some(&new) => new as u16,
+            None => return Err(invalid_input(&format!("Palette index {} is out of range", index))),
+        };
+        if depth < 8 {
+            // Clear the old bits before setting the new ones.
+            let bits = depth as usize;
+            let bit = x * bits;
+            row[bit >> 3] &= !((((1u16 << bits) - 1) as u8) << (8 - bits - (bit & 7)));
+        }
+        put_sample(row, x, depth, new);
+    }
+    Ok(())
+}
+
+/// Rewrite the pixel indices of indexed-color image data through a
+/// map from each old index to its new one, as returned when entries
+/// of a `Palette` are deduplicated or reordered.
+///
+/// Rows are processed in parallel on the current rayon thread pool.
+/// Returns an error if an index has no entry in the map.
+pub fn remap_indices(header: &Header, map: &[u8], data: &mut [u8]) -> IoResult {
+    check_indexed(header, data)?;
+    let stride = header.stride();
+    let rows = cmp::max(1, CHUNK_BYTES / stride);
+    data.par_chunks_mut(rows * stride).try_for_each(|rows| {
+        for row in rows.chunks_mut(stride) {
+            remap_row(header, map, row)?;
+        }
+        Ok(())
+    })
+}
+
+//
+// How often each palette entry is used, and how often each
+// pair of different entries sit side by side in a row.
+//
+struct Usage {
+    counts: Vec<u64>,
+    pairs: Vec<u64>,
+}
+
+impl Usage {
+    fn new() -> Usage {
+        Usage {
+            counts: vec![0; 256],
+            pairs: vec![0; 256 * 256],
+        }
+    }
+
+    fn pair(&self, a: usize, b: usize) -> u64 {
+        self.pairs[cmp::min(a, b) * 256 + cmp::max(a, b)]
+    }
+
+    fn add_pair(&mut self, a: usize, b: usize) {
+        self.pairs[cmp::min(a, b) * 256 + cmp::max(a, b)] += 1;
+    }
+
+    fn merge(mut self, other: Usage) -> Usage {
+        for (a, b) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *a += b;
+        }
+        for (a, b) in self.pairs.iter_mut().zip(other.pairs.iter()) {
+            *a += b;
+        }
+        self
+    }
+}
+
+//
+// Order entries by brightness, so gradients get runs of
+// neighbouring indices.
+//
+fn luminance_order(palette: &Palette) -> Vec<usize> {
+    let entries = palette.entries();
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.sort_by_key(|&i| {
+        let [r, g, b, a] = entries[i];
+        (299 * r as u32 + 587 * g as u32 + 114 * b as u32, a)
+    });
+    order
+}
+
+//
+// Order entries by use, most common first.
+//
+fn popularity_order(usage: &Usage, len: usize) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..len).collect();
+    order.sort_by_key(|&i| cmp::Reverse(usage.counts[i]));
+    order
+}
+
+//
+// Grow a chain of entries from the most common one, adding at
+// either end whichever remaining entry most often sits beside
+// that end in the image.
+//
+fn adjacency_order(usage: &Usage, len: usize) -> Vec<usize> {
+    let popular = popularity_order(usage, len);
+    let mut chain = VecDeque::with_capacity(len);
+    let mut used = vec![false; len];
+    chain.push_back(popular[0]);
+    used[popular[0]] = true;
+    while chain.len() < len {
+        let front = chain[0];
+        let back = chain[chain.len() - 1];
+        let mut best = None;
+        // Ties go to the more common entry, and to the back.
+        for &i in popular.iter().filter(|&&i| !used[i]) {
+            for &(end, at_back) in &[(back, true), (front, false)] {
+                let weight = usage.pair(end, i);
+                if best.map_or(true, |(best_weight, _, _)| weight > best_weight) {
+                    best = Some((weight, i, at_back));
+                }
+            }
+        }
+        if let Some((_, i, at_back)) = best {
+            if at_back {
+                chain.push_back(i);
+            } else {
+                chain.push_front(i);
+            }
+            used[i] = true;
+        }
+    }
+    chain.into_iter().collect()
+}
+
+//
+// Compressed size of the image data and palette chunks
+// with entries rearranged into the given order.
+//
+fn trial_size(header: &Header,
+              filter_mode: Mode<Filter>,
+              palette: &Palette,
+              data: &[u8],
+              order: &[usize]) -> io::Result<usize>
+{
+    let mut map = vec![0u8; order.len()];
+    for (new, &old) in order.iter().enumerate() {
+        map[old] = new as u8;
+    }
+    // The encoder doesn't filter indexed images in adaptive mode.
+    let mode = match filter_mode {
+        Adaptive => Fixed(Filter::None),
+        mode => mode,
+    };
+    let mut filter = AdaptiveFilter::new(*header, mode, &MinSum);
+    let stride = header.stride();
+    let mut prev = vec![0u8; stride];
+    let mut row = vec![0u8; stride];
+    let mut rows = Vec::with_capacity(data.len() + header.height() as usize);
+    for input in data.chunks(stride) {
+        row.copy_from_slice(input);
+        remap_row(header, &map, &mut row)?;
+        rows.extend_from_slice(filter.filter(&prev, &row)?);
+        prev.copy_from_slice(&row);
+    }
+    let mut encoder = Deflate::new(deflate::Options::new(), Vec::new());
+    encoder.write(&rows, Flush::Finish)?;
+    let compressed = encoder.finish()?.len();
+
+    let alphas = order.iter().rposition(|&old| palette.entries()[old][3] < 255)
+                             .map_or(0, |index| index + 1);
+    Ok(compressed + alphas)
+}
+
+/// Reorder the palette of an indexed-color image so that its data
+/// compresses better, and rewrite the pixel indices to match.
+///
+/// Usage and side-by-side counts are gathered from the rows in
+/// parallel, then orderings by luminance, popularity and adjacency
+/// are each trial-compressed alongside the current order with the
+/// filter mode the image will be encoded with, and the smallest is
+/// kept. The pixel values don't change.
+///
+/// Filters predict each index from its neighbours, so they do best
+/// when similar colors have nearby indices. Without filtering, which
+/// is what adaptive mode uses for indexed images, 8-bit indices
+/// compress the same in any order, and reordering helps only sub-byte
+/// depths and the length of tRNS.
+///
+/// Runs on the current rayon thread pool; call this within
+/// `ThreadPool::install` to use another.
+///
+/// Returns an error if the image isn't indexed color, the data is the
+/// wrong length for the header, or an index is outside the palette.
+pub fn optimize_palette(header: &Header,
+                        filter_mode: Mode<Filter>,
+                        palette: &mut Palette,
+                        data: &mut [u8]) -> IoResult
+{
+    check_indexed(header, data)?;
+    let len = palette.len();
+    if len == 0 {
+        return Err(invalid_input("Palette must have at least one entry."));
+    }
+    let stride = header.stride();
+    let depth = header.depth();
+    let width = header.width() as usize;
+    let rows = cmp::max(1, CHUNK_BYTES / stride);
+
+    let usage = data.par_chunks(rows * stride).map(|rows| {
+        let mut usage = Usage::new();
+        for row in rows.chunks(stride) {
+            let mut last = None;
+            for x in 0..width {
+                let index = get_sample(row, x, depth) as usize;
+                if index >= len {
+                    return Err(invalid_input(&format!("Palette index {} is out of range", index)));
+                }
+                usage.counts[index] += 1;
+                if let Some(last) = last.filter(|&last| last != index) {
+                    usage.add_pair(last, index);
+                }
+                last = Some(index);
+            }
+        }
+        Ok(usage)
+    }).reduce(|| Ok(Usage::new()), |a: io::Result<Usage>, b: io::Result<Usage>| {
+        Ok(a?.merge(b?))
+    })?;
+
+    let orders = [
+        (0..len).collect(),
+        luminance_order(palette),
+        popularity_order(&usage, len),
+        adjacency_order(&usage, len),
+    ];
+    let sizes = orders.par_iter().map(|order| {
+        trial_size(header, filter_mode, palette, data, order)
+    }).collect::<io::Result<Vec<usize>>>()?;
+
+    // Keep the current order unless another is strictly smaller.
+    let mut best = 0;
+    for (i, &size) in sizes.iter().enumerate() {
+        if size < sizes[best] {
+            best = i;
+        }
+    }
+    if best > 0 {
+        let map = palette.reorder(&orders[best])?;
+        remap_indices(header, &map, data)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+
+    use super::optimize_palette;
+    use super::reduce;
+    use super::remap_indices;
+    use super::trial_size;
+    use super::super::ColorType;
+    use super::super::Filter;
+    use super::super::Header;
+    use super::super::Mode::{Adaptive, Fixed};
+    use super::super::Palette;
+
+    fn header(width: u32, height: u32, color_type: ColorType, depth: u8) -> Header {
+        let mut header = Header::new();
+        header.set_size(width, height).unwrap();
+        header.set_color(color_type, depth).unwrap();
+        header
+    }
+
+    fn color_type(header: &Header) -> u8 {
+        header.color_type() as u8
+    }
+
+    #[test]
+    fn drop_alpha() {
+        // Enough distinct colors that a palette doesn't win.
+        let data: Vec<u8> = (0..300u32).flat_map(|i| {
+            vec![i as u8, (i >> 8) as u8, (i * 7) as u8, 255]
+        }).collect();
+        let out = reduce(&header(300, 1, ColorType::TruecolorAlpha, 8), &[], &[], &data).unwrap();
+        assert_eq!(color_type(out.header()), ColorType::Truecolor as u8);
+        assert_eq!(out.header().depth(), 8);
+        assert!(out.palette().is_empty());
+        assert_eq!(&out.data()[..6], &[0, 0, 0, 1, 0, 7]);
+    }
+
+    #[test]
+    fn grey() {
+        let data = [0, 0, 0, 255, 255, 255, 255, 255, 255, 0, 0, 0];
+        let out = reduce(&header(2, 2, ColorType::Truecolor, 8), &[], &[], &data).unwrap();
+        assert_eq!(color_type(out.header()), ColorType::Greyscale as u8);
+        assert_eq!(out.header().depth(), 1);
+        assert_eq!(out.data(), &[0b0100_0000, 0b1000_0000]);
+    }
+
+    #[test]
+    fn palette() {
+        let colors = [[200, 10, 10, 255], [10, 200, 10, 128], [10, 10, 200, 255]];
+        let data: Vec<u8> = (0..64).flat_map(|i| colors[i % 3].to_vec()).collect();
+        let out = reduce(&header(8, 8, ColorType::TruecolorAlpha, 8), &[], &[], &data).unwrap();
+        assert_eq!(color_type(out.header()), ColorType::IndexedColor as u8);
+        assert_eq!(out.header().depth(), 2);
+        assert_eq!(out.palette(), &[10, 200, 10, 10, 10, 200, 200, 10, 10]);
+        assert_eq!(out.transparency(), &[128]);
+        // Indices 2, 0, 1, 2 packed two bits each.
+        assert_eq!(out.data()[0], 0b10_00_01_10);
+    }
+
+    #[test]
+    fn color_key() {
+        let mut data = Vec::new();
+        for i in 0..300u32 {
+            if i % 10 == 0 {
+                data.extend(&[1, 2, 3, 0]);
+            } else {
+                data.extend(&[i as u8, (i >> 8) as u8 + 10, 9, 255]);
+            }
+        }
+        let out = reduce(&header(300, 1, ColorType::TruecolorAlpha, 8), &[], &[], &data).unwrap();
+        assert_eq!(color_type(out.header()), ColorType::Truecolor as u8);
+        assert_eq!(out.transparency(), &[0, 1, 0, 2, 0, 3]);
+        assert_eq!(&out.data()[..6], &[1, 2, 3, 1, 10, 9]);
+
+        // The key color may not appear in opaque pixels too.
+        data[4..8].copy_from_slice(&[1, 2, 3, 255]);
+        let out = reduce(&header(300, 1, ColorType::TruecolorAlpha, 8), &[], &[], &data).unwrap();
+        assert_eq!(color_type(out.header()), ColorType::TruecolorAlpha as u8);
+    }
+
+    #[test]
+    fn narrow_depth() {
+        let data: Vec<u8> = (0..300u32).flat_map(|i| {
+            let (r, g) = (i as u8, (i >> 8) as u8);
+            vec![r, r, g, g, 7, 7]
+        }).collect();
+        let out = reduce(&header(300, 1, ColorType::Truecolor, 16), &[], &[], &data).unwrap();
+        assert_eq!(color_type(out.header()), ColorType::Truecolor as u8);
+        assert_eq!(out.header().depth(), 8);
+        assert_eq!(&out.data()[..6], &[0, 0, 7, 1, 0, 7]);
+
+        let mut wide = data.clone();
+        wide[1] = 1;
+        let out = reduce(&header(300, 1, ColorType::Truecolor, 16), &[], &[], &wide).unwrap();
+        assert_eq!(out.header().depth(), 16);
+        assert_eq!(out.data(), &wide[..]);
+    }
+
+    #[test]
+    fn bad_index() {
+        let header = header(4, 1, ColorType::IndexedColor, 8);
+        assert!(reduce(&header, &[0, 0, 0, 255, 255, 255], &[], &[0, 1, 2, 1]).is_err());
+        let out = reduce(&header, &[0, 0, 0, 255, 255, 255], &[], &[0, 1, 1, 0]).unwrap();
+        assert_eq!(color_type(out.header()), ColorType::Greyscale as u8);
+        assert_eq!(out.header().depth(), 1);
+    }
+
+    #[test]
+    fn remap() {
+        let header = header(3, 2, ColorType::IndexedColor, 2);
+        // Indices 0, 1, 2 with the padding bits set.
+        let mut data = vec![0b00_01_10_11, 0b10_01_00_11];
+        remap_indices(&header, &[2, 0, 1], &mut data).unwrap();
+        assert_eq!(data, vec![0b10_00_01_11, 0b01_00_10_11]);
+        assert!(remap_indices(&header, &[2, 0], &mut data).is_err());
+    }
+
+    #[test]
+    fn optimize() {
+        // A smooth gradient through a shuffled palette.
+        let header = header(256, 64, ColorType::IndexedColor, 8);
+        let mut palette = Palette::new();
+        for i in 0..256u32 {
+            let value = (i * 167 % 256) as u8;
+            palette.push([value, value, 255 - value, 255]).unwrap();
+        }
+        let colors: Vec<[u8; 4]> = (0..256u32).map(|x| {
+            let value = x as u8;
+            [value, value, 255 - value, 255]
+        }).collect();
+        // Jitter each pixel by a little so rows don't repeat.
+        let mut seed = 1u32;
+        let mut data: Vec<u8> = (0..256 * 64).map(|x| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let value = cmp::min(x % 256 + (seed >> 29) as usize, 255);
+            palette.index_of(colors[value]).unwrap()
+        }).collect();
+
+        let pixels = |palette: &Palette, data: &[u8]| -> Vec<[u8; 4]> {
+            data.iter().map(|&index| palette.entries()[index as usize]).collect()
+        };
+        let original = pixels(&palette, &data);
+
+        let identity: Vec<usize> = (0..256).collect();
+        let mode = Fixed(Filter::Sub);
+        let before = trial_size(&header, mode, &palette, &data, &identity).unwrap();
+        optimize_palette(&header, mode, &mut palette, &mut data).unwrap();
+        let after = trial_size(&header, mode, &palette, &data, &identity).unwrap();
+        assert!(after < before);
+        assert!(pixels(&palette, &data) == original);
+
+        data[5] = 0;
+        let mut small = Palette::new();
+        small.push([0, 0, 0, 255]).unwrap();
+        assert!(optimize_palette(&header, Adaptive, &mut small, &mut data).is_err());
+    }
+}
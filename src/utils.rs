@@ -33,6 +33,11 @@ pub fn invalid_input(payload: &str) -> Error
     Error::new(ErrorKind::InvalidInput, payload)
 }
 
+pub fn invalid_data(payload: &str) -> Error
+{
+    Error::new(ErrorKind::InvalidData, payload)
+}
+
 pub fn other(payload: &str) -> Error
 {
     Error::new(ErrorKind::Other, payload)
@@ -48,7 +53,60 @@ pub fn write_be32<W: Write>(w: &mut W, val: u32) -> IoResult {
     w.write_all(&bytes)
 }
 
+pub fn write_be16<W: Write>(w: &mut W, val: u16) -> IoResult {
+    let bytes = [
+        (val >> 8 & 0xff) as u8,
+        (val & 0xff) as u8,
+    ];
+    w.write_all(&bytes)
+}
+
 pub fn write_byte<W: Write>(w: &mut W, val: u8) -> IoResult {
     let bytes = [val];
     w.write_all(&bytes)
 }
+
+pub fn read_be16(bytes: &[u8]) -> u16 {
+    u16::from(bytes[0]) << 8 |
+    u16::from(bytes[1])
+}
+
+pub fn read_be32(bytes: &[u8]) -> u32 {
+    u32::from(bytes[0]) << 24 |
+    u32::from(bytes[1]) << 16 |
+    u32::from(bytes[2]) << 8 |
+    u32::from(bytes[3])
+}
+
+//
+// Read a sample from a packed row, most significant bits first.
+//
+pub fn get_sample(row: &[u8], index: usize, depth: u8) -> u16 {
+    match depth {
+        16 => read_be16(&row[index * 2..]),
+        8 => row[index] as u16,
+        _ => {
+            let bits = depth as usize;
+            let bit = index * bits;
+            ((row[bit >> 3] >> (8 - bits - (bit & 7))) & ((1 << bits) - 1)) as u16
+        }
+    }
+}
+
+//
+// Store a sample into a packed row, most significant bits first.
+//
+pub fn put_sample(row: &mut [u8], index: usize, depth: u8, sample: u16) {
+    match depth {
+        16 => {
+            row[index * 2] = (sample >> 8) as u8;
+            row[index * 2 + 1] = sample as u8;
+        },
+        8 => row[index] = sample as u8,
+        _ => {
+            let bits = depth as usize;
+            let bit = index * bits;
+            row[bit >> 3] |= (sample as u8) << (8 - bits - (bit & 7));
+        }
+    }
+}
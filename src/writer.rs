@@ -29,10 +29,61 @@ use crc::Hasher32;
 use std::io;
 use std::io::Write;
 
+use super::Cicp;
+use super::ContentLightLevel;
+use super::FrameControl;
 use super::Header;
+use super::MasteringDisplay;
+use super::PhysicalUnit;
+use super::RenderingIntent;
+
+use super::deflate;
+use super::deflate::Deflate;
+use super::deflate::Flush;
 
 use super::utils::*;
 
+//
+// Keywords are 1-79 bytes of printable Latin-1, with no leading,
+// trailing, or consecutive spaces.
+// https://www.w3.org/TR/PNG/#11keywords
+//
+fn check_keyword(keyword: &[u8]) -> IoResult {
+    if keyword.is_empty() || keyword.len() > 79 {
+        return Err(invalid_input("Text keyword must be 1-79 bytes long"));
+    }
+    if !keyword.iter().all(|&c| (32 ..= 126).contains(&c) || c >= 161) {
+        return Err(invalid_input("Text keyword must contain only printable Latin-1 characters"));
+    }
+    if keyword[0] == b' ' || keyword[keyword.len() - 1] == b' ' ||
+       keyword.windows(2).any(|pair| pair == b"  ") {
+        return Err(invalid_input("Text keyword must not have leading, trailing, or consecutive spaces"));
+    }
+    Ok(())
+}
+
+//
+// Convert a string to Latin-1, as required for tEXt and zTXt.
+//
+fn latin1(text: &str) -> io::Result<Vec<u8>> {
+    text.chars().map(|c| {
+        match c as u32 {
+            0 => Err(invalid_input("Text must not contain null characters")),
+            val if val > 0xff => Err(invalid_input("Text must contain only Latin-1 characters")),
+            val => Ok(val as u8),
+        }
+    }).collect()
+}
+
+//
+// Compress data as a complete zlib stream, for zTXt, iTXt, and iCCP.
+//
+fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = Deflate::new(deflate::Options::new(), Vec::<u8>::new());
+    encoder.write(data, Flush::Finish)?;
+    encoder.finish()
+}
+
 pub struct Writer<W: Write> {
     output: W,
 }
@@ -131,6 +182,258 @@ impl<W: Write> Writer<W> {
         self.write_chunk(b"IHDR", &data)
     }
 
+    //
+    // acTL - declares an animated PNG, before the first IDAT.
+    // https://wiki.mozilla.org/APNG_Specification#.60acTL.60:_The_Animation_Control_Chunk
+    //
+    pub fn write_animation_control(&mut self, num_frames: u32, num_plays: u32) -> IoResult {
+        let mut data = Vec::<u8>::new();
+        write_be32(&mut data, num_frames)?;
+        write_be32(&mut data, num_plays)?;
+
+        self.write_chunk(b"acTL", &data)
+    }
+
+    //
+    // fcTL - precedes each frame's image data.
+    // https://wiki.mozilla.org/APNG_Specification#.60fcTL.60:_The_Frame_Control_Chunk
+    //
+    pub fn write_frame_control(&mut self, sequence_number: u32, frame: &FrameControl) -> IoResult {
+        let mut data = Vec::<u8>::new();
+        write_be32(&mut data, sequence_number)?;
+        write_be32(&mut data, frame.width)?;
+        write_be32(&mut data, frame.height)?;
+        write_be32(&mut data, frame.x_offset)?;
+        write_be32(&mut data, frame.y_offset)?;
+        write_be16(&mut data, frame.delay_num)?;
+        write_be16(&mut data, frame.delay_den)?;
+        write_byte(&mut data, frame.dispose_op as u8)?;
+        write_byte(&mut data, frame.blend_op as u8)?;
+
+        self.write_chunk(b"fcTL", &data)
+    }
+
+    //
+    // fdAT - image data for frames after the default image,
+    // prefixed with a sequence number.
+    // https://wiki.mozilla.org/APNG_Specification#.60fdAT.60:_The_Frame_Data_Chunk
+    //
+    pub fn write_frame_data(&mut self, sequence_number: u32, data: &[u8]) -> IoResult {
+        let mut chunk = Vec::<u8>::with_capacity(data.len() + 4);
+        write_be32(&mut chunk, sequence_number)?;
+        chunk.extend_from_slice(data);
+
+        self.write_chunk(b"fdAT", &chunk)
+    }
+
+    //
+    // gAMA - image gamma, times 100000.
+    // https://www.w3.org/TR/PNG/#11gAMA
+    //
+    pub fn write_gamma(&mut self, gamma: u32) -> IoResult {
+        let mut data = Vec::<u8>::new();
+        write_be32(&mut data, gamma)?;
+
+        self.write_chunk(b"gAMA", &data)
+    }
+
+    //
+    // cHRM - white point and primaries as x,y pairs, times 100000.
+    // https://www.w3.org/TR/PNG/#11cHRM
+    //
+    pub fn write_chromaticities(&mut self, points: &[(u32, u32); 4]) -> IoResult {
+        let mut data = Vec::<u8>::new();
+        for &(x, y) in points.iter() {
+            write_be32(&mut data, x)?;
+            write_be32(&mut data, y)?;
+        }
+
+        self.write_chunk(b"cHRM", &data)
+    }
+
+    //
+    // sRGB - standard RGB color space with a rendering intent.
+    // https://www.w3.org/TR/PNG/#11sRGB
+    //
+    pub fn write_srgb(&mut self, intent: RenderingIntent) -> IoResult {
+        self.write_chunk(b"sRGB", &[intent as u8])
+    }
+
+    //
+    // iCCP - named, compressed ICC color profile.
+    // https://www.w3.org/TR/PNG/#11iCCP
+    //
+    pub fn write_icc_profile(&mut self, name: &str, profile: &[u8]) -> IoResult {
+        let mut data = latin1(name)?;
+        check_keyword(&data)?;
+        write_byte(&mut data, 0)?;
+        write_byte(&mut data, 0)?; // compression method
+        data.extend(compress(profile)?);
+
+        self.write_chunk(b"iCCP", &data)
+    }
+
+    //
+    // cICP - H.273 code points; matrix coefficients are always 0 for RGB.
+    // https://www.w3.org/TR/png-3/#cICP-chunk
+    //
+    pub fn write_cicp(&mut self, cicp: &Cicp) -> IoResult {
+        self.write_chunk(b"cICP", &[cicp.color_primaries,
+                                     cicp.transfer_function,
+                                     0,
+                                     cicp.full_range as u8])
+    }
+
+    //
+    // mDCV - mastering display color volume.
+    // Early drafts of the third edition named this mDCv.
+    // https://www.w3.org/TR/png-3/#mDCV-chunk
+    //
+    pub fn write_mastering_display(&mut self, display: &MasteringDisplay) -> IoResult {
+        let mut data = Vec::<u8>::new();
+        for &(x, y) in display.primaries.iter() {
+            write_be16(&mut data, x)?;
+            write_be16(&mut data, y)?;
+        }
+        write_be16(&mut data, display.white_point.0)?;
+        write_be16(&mut data, display.white_point.1)?;
+        write_be32(&mut data, display.max_luminance)?;
+        write_be32(&mut data, display.min_luminance)?;
+
+        self.write_chunk(b"mDCV", &data)
+    }
+
+    //
+    // cLLI - content light level information.
+    // Early drafts of the third edition named this cLLi.
+    // https://www.w3.org/TR/png-3/#cLLI-chunk
+    //
+    pub fn write_content_light_level(&mut self, level: &ContentLightLevel) -> IoResult {
+        let mut data = Vec::<u8>::new();
+        write_be32(&mut data, level.max_content)?;
+        write_be32(&mut data, level.max_frame_average)?;
+
+        self.write_chunk(b"cLLI", &data)
+    }
+
+    //
+    // pHYs - physical pixel dimensions or aspect ratio.
+    // https://www.w3.org/TR/PNG/#11pHYs
+    //
+    pub fn write_physical_dimensions(&mut self, x: u32, y: u32, unit: PhysicalUnit) -> IoResult {
+        let mut data = Vec::<u8>::new();
+        write_be32(&mut data, x)?;
+        write_be32(&mut data, y)?;
+        write_byte(&mut data, unit as u8)?;
+
+        self.write_chunk(b"pHYs", &data)
+    }
+
+    //
+    // tIME - time of last modification, in UTC.
+    // https://www.w3.org/TR/PNG/#11tIME
+    //
+    pub fn write_time(&mut self, year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> IoResult {
+        let mut data = Vec::<u8>::new();
+        write_be16(&mut data, year)?;
+        data.extend_from_slice(&[month, day, hour, minute, second]);
+
+        self.write_chunk(b"tIME", &data)
+    }
+
+    //
+    // hIST - approximate usage frequency of each palette entry.
+    // https://www.w3.org/TR/PNG/#11hIST
+    //
+    pub fn write_histogram(&mut self, frequencies: &[u16]) -> IoResult {
+        let mut data = Vec::<u8>::with_capacity(frequencies.len() * 2);
+        for &frequency in frequencies.iter() {
+            write_be16(&mut data, frequency)?;
+        }
+
+        self.write_chunk(b"hIST", &data)
+    }
+
+    //
+    // sPLT - named suggested palette, with entries in the
+    // given sample depth followed by a frequency.
+    // https://www.w3.org/TR/PNG/#11sPLT
+    //
+    pub fn write_suggested_palette(&mut self, name: &str, depth: u8, entries: &[u8]) -> IoResult {
+        let mut data = latin1(name)?;
+        check_keyword(&data)?;
+        write_byte(&mut data, 0)?;
+        write_byte(&mut data, depth)?;
+        data.extend_from_slice(entries);
+
+        self.write_chunk(b"sPLT", &data)
+    }
+
+    //
+    // tEXt - uncompressed Latin-1 text.
+    // https://www.w3.org/TR/PNG/#11tEXt
+    //
+    pub fn write_text(&mut self, keyword: &str, text: &str) -> IoResult {
+        let mut data = latin1(keyword)?;
+        check_keyword(&data)?;
+        write_byte(&mut data, 0)?;
+        data.extend(latin1(text)?);
+
+        self.write_chunk(b"tEXt", &data)
+    }
+
+    //
+    // zTXt - zlib-compressed Latin-1 text.
+    // https://www.w3.org/TR/PNG/#11zTXt
+    //
+    pub fn write_compressed_text(&mut self, keyword: &str, text: &str) -> IoResult {
+        let mut data = latin1(keyword)?;
+        check_keyword(&data)?;
+        write_byte(&mut data, 0)?;
+        write_byte(&mut data, 0)?; // compression method
+        data.extend(compress(&latin1(text)?)?);
+
+        self.write_chunk(b"zTXt", &data)
+    }
+
+    //
+    // iTXt - UTF-8 text, optionally compressed, with a language
+    // tag and a translation of the keyword.
+    // https://www.w3.org/TR/PNG/#11iTXt
+    //
+    pub fn write_international_text(&mut self,
+                                    keyword: &str,
+                                    language_tag: &str,
+                                    translated_keyword: &str,
+                                    text: &str,
+                                    compressed: bool)
+    -> IoResult
+    {
+        if !language_tag.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-') {
+            return Err(invalid_input("Language tag must contain only ASCII letters, digits, and hyphens"));
+        }
+        if translated_keyword.contains('\0') {
+            return Err(invalid_input("Translated keyword must not contain null characters"));
+        }
+
+        let mut data = latin1(keyword)?;
+        check_keyword(&data)?;
+        write_byte(&mut data, 0)?;
+        write_byte(&mut data, compressed as u8)?;
+        write_byte(&mut data, 0)?; // compression method
+        data.extend_from_slice(language_tag.as_bytes());
+        write_byte(&mut data, 0)?;
+        data.extend_from_slice(translated_keyword.as_bytes());
+        write_byte(&mut data, 0)?;
+        if compressed {
+            data.extend(compress(text.as_bytes())?);
+        } else {
+            data.extend_from_slice(text.as_bytes());
+        }
+
+        self.write_chunk(b"iTXt", &data)
+    }
+
     //
     // IEND - last chunk in the file.
     // https://www.w3.org/TR/PNG/#11IEND
@@ -153,6 +456,7 @@ mod tests {
 
     use super::Writer;
     use super::IoResult;
+    use super::super::inflate::Inflate;
 
     fn test_writer<F, G>(test_func: F, assert_func: G)
         where F: Fn(&mut Writer<Vec<u8>>) -> IoResult,
@@ -226,4 +530,44 @@ mod tests {
             assert_eq!(output[20..24], b"\xa3\x0a\x15\xe3"[..], "expected crc32");
         })
     }
+
+    #[test]
+    fn text_works() {
+        test_writer(|writer| {
+            writer.write_text("Comment", "caf\u{e9}")
+        }, |output| {
+            assert_eq!(output[4..8], b"tEXt"[..]);
+            assert_eq!(output[8..20], b"Comment\0caf\xe9"[..]);
+        })
+    }
+
+    #[test]
+    fn compressed_text_works() {
+        test_writer(|writer| {
+            writer.write_international_text("XML:com.adobe.xmp", "", "", "<x:xmpmeta/>", true)
+        }, |output| {
+            assert_eq!(output[4..8], b"iTXt"[..]);
+            assert_eq!(output[8..30], b"XML:com.adobe.xmp\0\x01\0\0\0"[..]);
+
+            let mut inflate = Inflate::new();
+            inflate.set_input(output[30 .. output.len() - 4].to_vec());
+            let mut text = [0u8; 64];
+            let len = inflate.read(&mut text).unwrap();
+            assert!(inflate.is_finished());
+            assert_eq!(text[..len], b"<x:xmpmeta/>"[..]);
+        })
+    }
+
+    #[test]
+    fn invalid_text() {
+        let mut writer = Writer::new(Vec::<u8>::new());
+        assert!(writer.write_text("", "text").is_err());
+        assert!(writer.write_text(" Title", "text").is_err());
+        assert!(writer.write_text("Two  spaces", "text").is_err());
+        assert!(writer.write_text(&"x".repeat(80), "text").is_err());
+        assert!(writer.write_text("Title", "\u{263a}").is_err());
+        assert!(writer.write_compressed_text("Title", "nul\0").is_err());
+        assert!(writer.write_international_text("Title", "en GB", "", "", false).is_err());
+        assert_eq!(writer.finish().unwrap().len(), 0);
+    }
 }